@@ -2,17 +2,14 @@ use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use rustcompress::compressors::lzma::codecs::{
-    header_codec::{LzmaHeader, LzmaHeaderProps},
-    length_codec::MATCH_LEN_MAX,
-    lzma_stream_codec::{
-        encoders::{
-            instructions_fast::LZMAFastInstructionPicker, match_finding::hc4::HC4MatchFinder,
-            LZMAEncoderInput,
-        },
-        LZMACodecEncoder,
+use rustcompress::compressors::lzma::{
+    codecs::{
+        header_codec::{LzmaHeader, LzmaHeaderProps},
+        lzma_stream_codec::LZMACodecEncoder,
+        range_codec::RangeEncoder,
     },
-    range_codec::RangeEncoder,
+    one_shot::compress,
+    options::{LzmaMode, LzmaOptions, MatchFinderKind},
 };
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -40,26 +37,17 @@ fn criterion_benchmark(c: &mut Criterion) {
             let mut compressed = Vec::new();
 
             let mut rc = RangeEncoder::new(&mut compressed);
-            let nice_len = 270;
-            let picker = LZMAFastInstructionPicker::new(nice_len);
-            let mut encoder = LZMACodecEncoder::new(
-                header.dict_size,
-                header.props.lc as u32,
-                header.props.lp as u32,
-                header.props.pb as u32,
-                nice_len,
-                picker,
-            );
-
-            let mut encoder_buffer = LZMAEncoderInput::new(
-                HC4MatchFinder::new(header.dict_size, nice_len, MATCH_LEN_MAX as u32, 48),
-                header.dict_size,
-            );
-
-            for _ in 0..header.dict_size {
-                encoder_buffer.append_data(&[0]);
-                encoder_buffer.increment_pos();
+            let options = LzmaOptions {
+                dict_size: header.dict_size,
+                mode: LzmaMode::Fast,
+                nice_len: 270,
+                match_finder: MatchFinderKind::HC4,
+                depth: 48,
+                ..Default::default()
             }
+            .with_props(&header.props);
+            let (mut encoder, mut encoder_buffer) =
+                LZMACodecEncoder::from_options(&options).unwrap();
 
             let mut written = 0;
             let mut passed = 0;
@@ -97,6 +85,17 @@ fn criterion_benchmark(c: &mut Criterion) {
             rc.finish().unwrap();
         })
     });
+    c.bench_function("compress one-shot", |b| {
+        let options = LzmaOptions {
+            dict_size: 0x4000,
+            mode: LzmaMode::Fast,
+            nice_len: 270,
+            match_finder: MatchFinderKind::HC4,
+            depth: 48,
+            ..Default::default()
+        };
+        b.iter(|| compress(&data, &options).unwrap())
+    });
     c.finish();
 }
 
@@ -5,10 +5,13 @@ use std::{
 
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use rustcompress::compressors::lzma::codecs::{
-    header_codec::parse_lzma_header,
-    lzma_stream_codec::{data_buffers::DecoderDataBuffer, LZMACodecDecoder},
-    range_codec::RangeDecoder,
+use rustcompress::compressors::lzma::{
+    codecs::{
+        header_codec::parse_lzma_header,
+        lzma_stream_codec::{data_buffers::DecoderDataBuffer, LZMACodecDecoder},
+        range_codec::{RangeDecoder, SliceRangeDecoder},
+    },
+    one_shot::decompress_into,
 };
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -57,6 +60,58 @@ fn criterion_benchmark(c: &mut Criterion) {
             }
         })
     });
+    c.bench_function("decompress small mine slice range decoder", |b| {
+        b.iter(|| {
+            let mut reader = Cursor::new(&compressed);
+
+            let header = parse_lzma_header(&mut reader).unwrap();
+            let mut out_buffer = DecoderDataBuffer::new(header.dict_size, header.uncompressed_size);
+
+            let input = &compressed[reader.position() as usize..];
+            let mut rc = SliceRangeDecoder::new(input).unwrap();
+            let mut decoder = LZMACodecDecoder::new(
+                header.props.lc as u32,
+                header.props.lp as u32,
+                header.props.pb as u32,
+            );
+
+            let mut flushed = 0;
+
+            while flushed < header.uncompressed_size as usize {
+                decoder.decode_one_packet(&mut rc, &mut out_buffer).unwrap();
+                flushed += out_buffer.flush(&mut output[flushed..]);
+            }
+        })
+    });
+    c.bench_function("decompress small mine decode until", |b| {
+        b.iter(|| {
+            let mut reader = Cursor::new(&compressed);
+
+            let header = parse_lzma_header(&mut reader).unwrap();
+            let mut out_buffer = DecoderDataBuffer::new(header.dict_size, header.uncompressed_size);
+
+            let input = &compressed[reader.position() as usize..];
+            let mut rc = SliceRangeDecoder::new(input).unwrap();
+            let mut decoder = LZMACodecDecoder::new(
+                header.props.lc as u32,
+                header.props.lp as u32,
+                header.props.pb as u32,
+            );
+
+            let mut flushed = 0;
+
+            while flushed < header.uncompressed_size as usize {
+                let max_bytes = (output.len() - flushed) as u64;
+                decoder
+                    .decode_until(&mut rc, &mut out_buffer, max_bytes)
+                    .unwrap();
+                flushed += out_buffer.flush(&mut output[flushed..]);
+            }
+        })
+    });
+    c.bench_function("decompress small mine one-shot", |b| {
+        b.iter(|| decompress_into(&compressed, &mut output).unwrap())
+    });
     c.finish();
 }
 
@@ -5,6 +5,7 @@ use std::{
 
 use criterion::{criterion_group, criterion_main, Criterion};
 use lzma::{LzmaReader, LzmaWriter};
+use rustcompress::compressors::lzma::xz_streams::XzReader;
 
 fn criterion_benchmark(c: &mut Criterion) {
     let data = include_bytes!("../src/compressors/lzma/codecs/range_codec.rs");
@@ -31,6 +32,18 @@ fn criterion_benchmark(c: &mut Criterion) {
             reader.read_to_end(&mut output).unwrap()
         })
     });
+    // The same file through this crate's decoder, which decodes with `decode_until`
+    c.bench_function("decompress small sdk mine", |b| {
+        b.iter(|| {
+            output.clear();
+
+            let reader = Cursor::new(&compressed[..]);
+            let mut reader = XzReader::new(reader).unwrap();
+
+            reader.read_to_end(&mut output).unwrap()
+        })
+    });
+    c.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
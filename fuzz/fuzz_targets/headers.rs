@@ -0,0 +1,32 @@
+//! Parses arbitrary bytes as each of the headers, footers and indexes the readers parse.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustcompress::compressors::lzma::codecs::{
+    header_codec::parse_lzma_header,
+    lzip_codec,
+    xz_codec::{self, block_header::BlockHeader, index::parse_index},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_lzma_header(data);
+
+    if let Ok(header) = data.try_into() {
+        let _ = lzip_codec::parse_header(header);
+    }
+    if let Ok(header) = data.try_into() {
+        let _ = xz_codec::parse_stream_header(header);
+    }
+    if let Ok(footer) = data.try_into() {
+        let _ = xz_codec::parse_stream_footer(footer);
+    }
+
+    // The first byte of a block header is never 0, which marks the index instead
+    if let Some((&size_byte, rest)) = data.split_first() {
+        if size_byte != 0 {
+            let _ = BlockHeader::parse(size_byte, rest);
+        }
+    }
+    let _ = parse_index(data);
+});
@@ -0,0 +1,77 @@
+//! Decodes arbitrary bytes as a raw LZMA stream, with the properties taken from the first byte.
+//! The pull, push and one-shot decoders must agree on the output.
+
+#![no_main]
+
+use std::io::Read;
+
+use libfuzzer_sys::fuzz_target;
+use rustcompress::compressors::lzma::{
+    codecs::header_codec::{parse_props_from_u8, LzmaHeader},
+    limits::DecoderLimits,
+    one_shot::decompress_raw_into,
+    push_decoder::{DecodeStatus, LzmaPushDecoder},
+    streams::{LzmaReader, UNKNOWN_UNCOMPRESSED_SIZE},
+};
+
+const DICT_SIZE: u32 = 1 << 16;
+const MAX_OUTPUT_SIZE: u64 = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&props, data)) = data.split_first() else {
+        return;
+    };
+    let Ok(props) = parse_props_from_u8(props) else {
+        return;
+    };
+
+    let limits = DecoderLimits {
+        memory_limit: None,
+        max_output_size: Some(MAX_OUTPUT_SIZE),
+    };
+    let header = LzmaHeader {
+        props: props.clone(),
+        dict_size: DICT_SIZE,
+        uncompressed_size: UNKNOWN_UNCOMPRESSED_SIZE,
+    };
+
+    let mut pulled = Vec::new();
+    let pull_result = LzmaReader::new_with_header_and_limits(data, header, limits)
+        .and_then(|mut reader| reader.read_to_end(&mut pulled));
+
+    let mut decoder = LzmaPushDecoder::new_raw(props.clone(), DICT_SIZE, None);
+    let mut pushed = Vec::new();
+    let mut output = [0; 1000];
+    let mut input = data;
+    let push_result = loop {
+        // Feed the input in small pieces, to exercise resuming in the middle of packets
+        let piece = &input[..input.len().min(7)];
+        match decoder.decode(piece, &mut output) {
+            Ok(progress) => {
+                input = &input[progress.consumed..];
+                pushed.extend_from_slice(&output[..progress.written]);
+
+                if progress.status == DecodeStatus::Finished {
+                    break Ok(());
+                }
+                if pushed.len() as u64 > MAX_OUTPUT_SIZE {
+                    break Err(());
+                }
+                if progress.status == DecodeStatus::NeedsInput && input.is_empty() {
+                    break Err(());
+                }
+            }
+            Err(_) => break Err(()),
+        }
+    };
+
+    if pull_result.is_ok() && push_result.is_ok() {
+        assert_eq!(pulled, pushed);
+    }
+
+    let mut output = vec![0; MAX_OUTPUT_SIZE as usize];
+    let one_shot_result = decompress_raw_into(data, props, DICT_SIZE, None, &mut output);
+    if let (Ok(_), Ok(written)) = (&pull_result, one_shot_result) {
+        assert_eq!(pulled, &output[..written]);
+    }
+});
@@ -0,0 +1,30 @@
+//! Decodes arbitrary bytes with each stream reader, which must return errors instead of panicking.
+//! Only the readers with a hard memory limit are included, as the fuzzer treats huge allocations as crashes.
+
+#![no_main]
+
+use std::io::{self, Read};
+
+use libfuzzer_sys::fuzz_target;
+use rustcompress::compressors::lzma::{
+    limits::DecoderLimits, lzip_streams::LzipReader, lzma2_streams::Lzma2Reader,
+    streams::LzmaReader, xz_streams::XzReader,
+};
+
+const LIMITS: DecoderLimits = DecoderLimits {
+    memory_limit: Some(64 << 20),
+    max_output_size: Some(4 << 20),
+};
+
+fn drain(reader: io::Result<impl Read>) {
+    if let Ok(mut reader) = reader {
+        let _ = io::copy(&mut reader, &mut io::sink());
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    drain(LzmaReader::new_with_limits(data, LIMITS));
+    drain(Lzma2Reader::new_with_limits(data, 1 << 16, LIMITS));
+    drain(XzReader::new_with_limits(data, LIMITS));
+    drain(LzipReader::new_with_limits(data, LIMITS));
+});
@@ -0,0 +1,636 @@
+//! BCJ (branch/call/jump) filters, which make executable code compress better.
+//!
+//! Branch instructions usually store their target as an offset relative to their own position, so
+//! calls to the same function look different everywhere in the code. The encoder converts these
+//! offsets into absolute addresses, which repeat, and the decoder converts them back. Each filter
+//! only recognizes the instructions of one architecture, but is safe to use on any data.
+//!
+//! The conversions match the ones in liblzma and 7-Zip, so the filters can be used in `.xz` and
+//! `.7z` files. Positions are counted from a start offset, which defaults to 0.
+
+use super::Filter;
+
+/// The architectures there are BCJ filters for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BcjKind {
+    X86,
+    PowerPc,
+    Ia64,
+    Arm,
+    ArmThumb,
+    Sparc,
+    Arm64,
+    RiscV,
+}
+
+impl BcjKind {
+    pub const ALL: [BcjKind; 8] = [
+        BcjKind::X86,
+        BcjKind::PowerPc,
+        BcjKind::Ia64,
+        BcjKind::Arm,
+        BcjKind::ArmThumb,
+        BcjKind::Sparc,
+        BcjKind::Arm64,
+        BcjKind::RiscV,
+    ];
+
+    /// The filter ID used in `.xz` block headers.
+    pub fn xz_filter_id(self) -> u64 {
+        match self {
+            BcjKind::X86 => 0x04,
+            BcjKind::PowerPc => 0x05,
+            BcjKind::Ia64 => 0x06,
+            BcjKind::Arm => 0x07,
+            BcjKind::ArmThumb => 0x08,
+            BcjKind::Sparc => 0x09,
+            BcjKind::Arm64 => 0x0A,
+            BcjKind::RiscV => 0x0B,
+        }
+    }
+
+    pub fn from_xz_filter_id(id: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.xz_filter_id() == id)
+    }
+
+    /// The method ID used in `.7z` archives.
+    pub fn seven_zip_method_id(self) -> u64 {
+        match self {
+            BcjKind::X86 => 0x0303_0103,
+            BcjKind::PowerPc => 0x0303_0205,
+            BcjKind::Ia64 => 0x0303_0401,
+            BcjKind::Arm => 0x0303_0501,
+            BcjKind::ArmThumb => 0x0303_0701,
+            BcjKind::Sparc => 0x0303_0805,
+            BcjKind::Arm64 => 0x0A,
+            BcjKind::RiscV => 0x0B,
+        }
+    }
+
+    pub fn from_seven_zip_method_id(id: u64) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.seven_zip_method_id() == id)
+    }
+
+    /// The instruction alignment of the architecture. The start offset should be a multiple of it.
+    pub fn alignment(self) -> u32 {
+        match self {
+            BcjKind::X86 => 1,
+            BcjKind::ArmThumb | BcjKind::RiscV => 2,
+            BcjKind::PowerPc | BcjKind::Arm | BcjKind::Sparc | BcjKind::Arm64 => 4,
+            BcjKind::Ia64 => 16,
+        }
+    }
+}
+
+/// A BCJ encoder or decoder. The position in the data carries across calls, along with the
+/// state the x86 filter uses to skip over bytes that are unlikely to be instructions.
+#[derive(Debug, Clone)]
+pub struct BcjFilter {
+    kind: BcjKind,
+    encoder: bool,
+    pos: u32,
+    x86_prev_mask: u32,
+    x86_prev_pos: u32,
+}
+
+impl BcjFilter {
+    pub fn new_encoder(kind: BcjKind, start_offset: u32) -> Self {
+        Self::new(kind, true, start_offset)
+    }
+
+    pub fn new_decoder(kind: BcjKind, start_offset: u32) -> Self {
+        Self::new(kind, false, start_offset)
+    }
+
+    fn new(kind: BcjKind, encoder: bool, start_offset: u32) -> Self {
+        Self {
+            kind,
+            encoder,
+            pos: start_offset,
+            x86_prev_mask: 0,
+            x86_prev_pos: 0u32.wrapping_sub(5),
+        }
+    }
+
+    pub fn kind(&self) -> BcjKind {
+        self.kind
+    }
+
+    fn x86(&mut self, buf: &mut [u8]) -> usize {
+        const MASK_TO_ALLOWED: [bool; 8] = [true, true, true, false, true, false, false, false];
+        const MASK_TO_BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+        /// Whether the byte is 0x00 or 0xFF, the most significant byte of a nearby offset
+        fn is_offset_ms_byte(b: u8) -> bool {
+            b.wrapping_add(1) & 0xFE == 0
+        }
+
+        if buf.len() < 5 {
+            return 0;
+        }
+
+        let now_pos = self.pos;
+        let mut prev_mask = self.x86_prev_mask;
+        let mut prev_pos = self.x86_prev_pos;
+        if now_pos.wrapping_sub(prev_pos) > 5 {
+            prev_pos = now_pos.wrapping_sub(5);
+        }
+
+        let limit = buf.len() - 5;
+        let mut i = 0;
+        while i <= limit {
+            let b = buf[i];
+            if b != 0xE8 && b != 0xE9 {
+                i += 1;
+                continue;
+            }
+
+            let pos = now_pos.wrapping_add(i as u32);
+            let offset = pos.wrapping_sub(prev_pos);
+            prev_pos = pos;
+
+            if offset > 5 {
+                prev_mask = 0;
+            } else {
+                for _ in 0..offset {
+                    prev_mask &= 0x77;
+                    prev_mask <<= 1;
+                }
+            }
+
+            let b = buf[i + 4];
+            if is_offset_ms_byte(b)
+                && MASK_TO_ALLOWED[(prev_mask >> 1) as usize & 7]
+                && (prev_mask >> 1) < 0x10
+            {
+                let mut src = u32::from_le_bytes(buf[i + 1..i + 5].try_into().unwrap());
+                let mut dest;
+                loop {
+                    let next_pos = pos.wrapping_add(5);
+                    dest = if self.encoder {
+                        src.wrapping_add(next_pos)
+                    } else {
+                        src.wrapping_sub(next_pos)
+                    };
+
+                    if prev_mask == 0 {
+                        break;
+                    }
+
+                    let bit = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                    if !is_offset_ms_byte((dest >> (24 - bit * 8)) as u8) {
+                        break;
+                    }
+
+                    src = dest ^ ((1 << (32 - bit * 8)) - 1);
+                }
+
+                // Sign extend bit 24 into the most significant byte
+                let dest = (dest & 0x01FF_FFFF).wrapping_sub((dest & 0x0100_0000) << 1);
+                buf[i + 1..i + 5].copy_from_slice(&dest.to_le_bytes());
+                i += 5;
+                prev_mask = 0;
+            } else {
+                i += 1;
+                prev_mask |= 1;
+                if is_offset_ms_byte(b) {
+                    prev_mask |= 0x10;
+                }
+            }
+        }
+
+        self.x86_prev_mask = prev_mask;
+        self.x86_prev_pos = prev_pos;
+        i
+    }
+
+    fn powerpc(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len() & !3;
+        for i in (0..size).step_by(4) {
+            let instr = u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+            // A branch with the link bit set and the absolute bit clear
+            if instr & 0xFC00_0003 != 0x4800_0001 {
+                continue;
+            }
+
+            let src = instr & 0x03FF_FFFC;
+            let pos = self.pos.wrapping_add(i as u32);
+            let dest = if self.encoder {
+                src.wrapping_add(pos)
+            } else {
+                src.wrapping_sub(pos)
+            };
+
+            let instr = 0x4800_0000 | (dest & 0x03FF_FFFF) | (instr & 3);
+            buf[i..i + 4].copy_from_slice(&instr.to_be_bytes());
+        }
+        size
+    }
+
+    fn ia64(&mut self, buf: &mut [u8]) -> usize {
+        /// Which of the three instruction slots are branches, for each bundle template
+        const BRANCH_TABLE: [u32; 32] = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            4, 4, 6, 6, 0, 0, 7, 7, 4, 4, 0, 0, 4, 4, 0, 0,
+        ];
+
+        let size = buf.len() & !15;
+        for i in (0..size).step_by(16) {
+            let mask = BRANCH_TABLE[buf[i] as usize & 0x1F];
+
+            for slot in 0..3 {
+                if (mask >> slot) & 1 == 0 {
+                    continue;
+                }
+
+                let bit_pos = 5 + 41 * slot;
+                let byte_pos = i + bit_pos / 8;
+                let bit_res = bit_pos % 8;
+
+                let mut bytes = [0u8; 8];
+                bytes[..6].copy_from_slice(&buf[byte_pos..byte_pos + 6]);
+                let instruction = u64::from_le_bytes(bytes);
+                let mut norm = instruction >> bit_res;
+
+                if (norm >> 37) & 0xF != 0x5 || (norm >> 9) & 0x7 != 0 {
+                    continue;
+                }
+
+                let mut src = ((norm >> 13) & 0xF_FFFF) as u32;
+                src |= (((norm >> 36) & 1) as u32) << 20;
+                src <<= 4;
+
+                let pos = self.pos.wrapping_add(i as u32);
+                let dest = if self.encoder {
+                    src.wrapping_add(pos)
+                } else {
+                    src.wrapping_sub(pos)
+                } >> 4;
+
+                norm &= !(0x8F_FFFFu64 << 13);
+                norm |= ((dest & 0xF_FFFF) as u64) << 13;
+                norm |= ((dest & 0x10_0000) as u64) << (36 - 20);
+
+                let instruction = (instruction & ((1 << bit_res) - 1)) | (norm << bit_res);
+                buf[byte_pos..byte_pos + 6].copy_from_slice(&instruction.to_le_bytes()[..6]);
+            }
+        }
+        size
+    }
+
+    fn arm(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len() & !3;
+        for i in (0..size).step_by(4) {
+            // BL with the "always" condition
+            if buf[i + 3] != 0xEB {
+                continue;
+            }
+
+            let src = u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], 0]) << 2;
+            let pos = self.pos.wrapping_add(i as u32 + 8);
+            let dest = if self.encoder {
+                src.wrapping_add(pos)
+            } else {
+                src.wrapping_sub(pos)
+            } >> 2;
+
+            buf[i..i + 3].copy_from_slice(&dest.to_le_bytes()[..3]);
+        }
+        size
+    }
+
+    fn arm_thumb(&mut self, buf: &mut [u8]) -> usize {
+        let mut i = 0;
+        while i + 4 <= buf.len() {
+            // A BL instruction pair
+            if buf[i + 1] & 0xF8 != 0xF0 || buf[i + 3] & 0xF8 != 0xF8 {
+                i += 2;
+                continue;
+            }
+
+            let src = (((buf[i + 1] as u32 & 7) << 19)
+                | ((buf[i] as u32) << 11)
+                | ((buf[i + 3] as u32 & 7) << 8)
+                | buf[i + 2] as u32)
+                << 1;
+            let pos = self.pos.wrapping_add(i as u32 + 4);
+            let dest = if self.encoder {
+                src.wrapping_add(pos)
+            } else {
+                src.wrapping_sub(pos)
+            } >> 1;
+
+            buf[i + 1] = 0xF0 | ((dest >> 19) & 7) as u8;
+            buf[i] = (dest >> 11) as u8;
+            buf[i + 3] = 0xF8 | ((dest >> 8) & 7) as u8;
+            buf[i + 2] = dest as u8;
+            i += 4;
+        }
+        i
+    }
+
+    fn sparc(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len() & !3;
+        for i in (0..size).step_by(4) {
+            // A call whose displacement fits in 22 bits, sign extended
+            if !(buf[i] == 0x40 && buf[i + 1] & 0xC0 == 0x00
+                || buf[i] == 0x7F && buf[i + 1] & 0xC0 == 0xC0)
+            {
+                continue;
+            }
+
+            let src = u32::from_be_bytes(buf[i..i + 4].try_into().unwrap()) << 2;
+            let pos = self.pos.wrapping_add(i as u32);
+            let dest = if self.encoder {
+                src.wrapping_add(pos)
+            } else {
+                src.wrapping_sub(pos)
+            } >> 2;
+
+            let dest = ((0u32.wrapping_sub((dest >> 22) & 1) << 22) & 0x3FFF_FFFF)
+                | (dest & 0x3F_FFFF)
+                | 0x4000_0000;
+            buf[i..i + 4].copy_from_slice(&dest.to_be_bytes());
+        }
+        size
+    }
+
+    fn arm64(&mut self, buf: &mut [u8]) -> usize {
+        let size = buf.len() & !3;
+        for i in (0..size).step_by(4) {
+            let pos = self.pos.wrapping_add(i as u32);
+            let instr = u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+
+            let instr = if instr >> 26 == 0x25 {
+                // BL
+                let pc = pos >> 2;
+                let pc = if self.encoder { pc } else { pc.wrapping_neg() };
+                0x9400_0000 | (instr.wrapping_add(pc) & 0x03FF_FFFF)
+            } else if instr & 0x9F00_0000 == 0x9000_0000 {
+                // ADRP, only converted if the target is within +/-512 MiB so that
+                // small values that happen to look like ADRP are left alone
+                let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001F_FFFC);
+                if src.wrapping_add(0x0002_0000) & 0x001C_0000 != 0 {
+                    continue;
+                }
+
+                let pc = pos >> 12;
+                let pc = if self.encoder { pc } else { pc.wrapping_neg() };
+                let dest = src.wrapping_add(pc);
+                (instr & 0x9000_001F)
+                    | ((dest & 3) << 29)
+                    | ((dest & 0x0003_FFFC) << 3)
+                    | (0u32.wrapping_sub(dest & 0x0002_0000) & 0x00E0_0000)
+            } else {
+                continue;
+            };
+
+            buf[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+        }
+        size
+    }
+
+    fn riscv(&mut self, buf: &mut [u8]) -> usize {
+        // Every instruction the filter converts is followed by enough bytes to look at a pair
+        if buf.len() < 8 {
+            return 0;
+        }
+
+        let limit = buf.len() - 8;
+        let mut i = 0;
+        while i <= limit {
+            let pos = self.pos.wrapping_add(i as u32);
+            let step = if self.encoder {
+                riscv_encode_at(buf, i, pos)
+            } else {
+                riscv_decode_at(buf, i, pos)
+            };
+            i += step;
+        }
+        i
+    }
+}
+
+fn read_u32_le(buf: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(buf[i..i + 4].try_into().unwrap())
+}
+
+fn write_u32_le(buf: &mut [u8], i: usize, value: u32) {
+    buf[i..i + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Whether an AUIPC and the instruction after it don't form a pair, i.e. the second instruction
+/// doesn't use the register the AUIPC wrote to, or isn't a 32-bit instruction.
+fn riscv_not_auipc_pair(auipc: u32, inst2: u32) -> bool {
+    ((auipc << 8) ^ inst2.wrapping_sub(3)) & 0xF8003 != 0
+}
+
+/// Whether an AUIPC with `rd` of x0 or x2 isn't in the special format the encoder writes
+/// converted pairs in, which stores the second instruction's `rs1` in the top bits.
+fn riscv_not_special_auipc(auipc: u32, inst2_rs1: u32) -> bool {
+    (auipc.wrapping_sub(0x3117) << 18) >= (inst2_rs1 & 0x1D)
+}
+
+/// Decode the JAL jump offset into an absolute address, or back, in place.
+/// Its offset bits are scrambled as `imm[20|10:1|11|19:12]`.
+fn riscv_jal(buf: &mut [u8], i: usize, pos: u32, encoder: bool) {
+    let b1 = buf[i + 1] as u32;
+    let b2 = buf[i + 2] as u32;
+    let b3 = buf[i + 3] as u32;
+
+    if encoder {
+        let addr = ((b1 & 0xF0) << 8)
+            | ((b2 & 0x0F) << 16)
+            | ((b2 & 0x10) << 7)
+            | ((b2 & 0xE0) >> 4)
+            | ((b3 & 0x7F) << 4)
+            | ((b3 & 0x80) << 13);
+        let addr = addr.wrapping_add(pos);
+
+        // Store the address in big endian order, which compresses better
+        buf[i + 1] = ((b1 & 0x0F) | ((addr >> 13) & 0xF0)) as u8;
+        buf[i + 2] = (addr >> 9) as u8;
+        buf[i + 3] = (addr >> 1) as u8;
+    } else {
+        let addr = ((b1 & 0xF0) << 13) | (b2 << 9) | (b3 << 1);
+        let addr = addr.wrapping_sub(pos);
+
+        buf[i + 1] = ((b1 & 0x0F) | ((addr >> 8) & 0xF0)) as u8;
+        buf[i + 2] = (((addr >> 16) & 0x0F) | ((addr >> 7) & 0x10) | ((addr << 4) & 0xE0)) as u8;
+        buf[i + 3] = (((addr >> 4) & 0x7F) | ((addr >> 13) & 0x80)) as u8;
+    }
+}
+
+/// Encode the instruction at `i` if it's a JAL or the start of an AUIPC pair, returning how far
+/// to move forward.
+fn riscv_encode_at(buf: &mut [u8], i: usize, pos: u32) -> usize {
+    let first = buf[i] as u32;
+
+    if first == 0xEF {
+        // JAL, only converted if rd is x1 or x5, which are used for calls
+        if buf[i + 1] & 0x0D != 0 {
+            return 2;
+        }
+        riscv_jal(buf, i, pos, true);
+        return 4;
+    }
+
+    if first & 0x7F != 0x17 {
+        return 2;
+    }
+
+    let inst = read_u32_le(buf, i);
+    if inst & 0xE80 != 0 {
+        // AUIPC with rd other than x0 or x2
+        let inst2 = read_u32_le(buf, i + 4);
+        if riscv_not_auipc_pair(inst, inst2) {
+            return 6;
+        }
+
+        // The second instruction's immediate is sign extended and added to the AUIPC's
+        let addr = (inst & 0xFFFF_F000)
+            .wrapping_add(inst2 >> 20)
+            .wrapping_sub((inst2 >> 19) & 0x1000)
+            .wrapping_add(pos);
+
+        // Write the pair in the special format: an AUIPC with rd = x2 holding the rest of the
+        // second instruction, followed by the address in big endian
+        write_u32_le(buf, i, 0x17 | (2 << 7) | (inst2 << 12));
+        buf[i + 4..i + 8].copy_from_slice(&addr.to_be_bytes());
+    } else {
+        // AUIPC with rd of x0 or x2. If it looks like the special format, it's swapped around
+        // so that the decoder doesn't mistake it for a converted pair.
+        let fake_rs1 = inst >> 27;
+        if riscv_not_special_auipc(inst, fake_rs1) {
+            return 4;
+        }
+
+        let fake_addr = read_u32_le(buf, i + 4);
+        let fake_inst2 = (inst >> 12) | (fake_addr << 20);
+        write_u32_le(buf, i, 0x17 | (fake_rs1 << 7) | (fake_addr & 0xFFFF_F000));
+        write_u32_le(buf, i + 4, fake_inst2);
+    }
+
+    8
+}
+
+/// The reverse of `riscv_encode_at()`.
+fn riscv_decode_at(buf: &mut [u8], i: usize, pos: u32) -> usize {
+    let first = buf[i] as u32;
+
+    if first == 0xEF {
+        if buf[i + 1] & 0x0D != 0 {
+            return 2;
+        }
+        riscv_jal(buf, i, pos, false);
+        return 4;
+    }
+
+    if first & 0x7F != 0x17 {
+        return 2;
+    }
+
+    let inst = read_u32_le(buf, i);
+    let (inst, inst2) = if inst & 0xE80 != 0 {
+        // An AUIPC pair the encoder swapped around
+        let inst2 = read_u32_le(buf, i + 4);
+        if riscv_not_auipc_pair(inst, inst2) {
+            return 6;
+        }
+
+        let addr = (inst & 0xFFFF_F000).wrapping_add(inst2 >> 20);
+        (0x17 | (2 << 7) | (inst2 << 12), addr)
+    } else {
+        // A converted pair in the special format
+        let inst2_rs1 = inst >> 27;
+        if riscv_not_special_auipc(inst, inst2_rs1) {
+            return 4;
+        }
+
+        let addr = u32::from_be_bytes(buf[i + 4..i + 8].try_into().unwrap()).wrapping_sub(pos);
+        let inst2 = (inst >> 12) | (addr << 20);
+        // The second instruction's immediate is sign extended, so the AUIPC makes up for it
+        let auipc = 0x17 | (inst2_rs1 << 7) | (addr.wrapping_add(0x800) & 0xFFFF_F000);
+        (auipc, inst2)
+    };
+
+    write_u32_le(buf, i, inst);
+    write_u32_le(buf, i + 4, inst2);
+    8
+}
+
+impl Filter for BcjFilter {
+    fn convert(&mut self, buf: &mut [u8]) -> usize {
+        let converted = match self.kind {
+            BcjKind::X86 => self.x86(buf),
+            BcjKind::PowerPc => self.powerpc(buf),
+            BcjKind::Ia64 => self.ia64(buf),
+            BcjKind::Arm => self.arm(buf),
+            BcjKind::ArmThumb => self.arm_thumb(buf),
+            BcjKind::Sparc => self.sparc(buf),
+            BcjKind::Arm64 => self.arm64(buf),
+            BcjKind::RiscV => self.riscv(buf),
+        };
+        self.pos = self.pos.wrapping_add(converted as u32);
+        converted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        for kind in BcjKind::ALL {
+            assert_eq!(BcjKind::from_xz_filter_id(kind.xz_filter_id()), Some(kind));
+            assert_eq!(
+                BcjKind::from_seven_zip_method_id(kind.seven_zip_method_id()),
+                Some(kind)
+            );
+        }
+        assert_eq!(BcjKind::from_xz_filter_id(0x21), None);
+        assert_eq!(BcjKind::from_seven_zip_method_id(0x21), None);
+    }
+
+    #[test]
+    fn test_known_conversions() {
+        // x86 call with a relative offset of 0 at position 0x10, which becomes an absolute 0x15
+        let mut buf = [0x90; 0x20];
+        buf[0x10..0x15].copy_from_slice(&[0xE8, 0x00, 0x00, 0x00, 0x00]);
+        let mut encoder = BcjFilter::new_encoder(BcjKind::X86, 0);
+        let converted = encoder.convert(&mut buf);
+        assert!(converted >= 0x15);
+        assert_eq!(buf[0x10..0x15], [0xE8, 0x15, 0x00, 0x00, 0x00]);
+
+        // ARM BL at position 4 with a start offset of 0x100 and a 0x20 byte offset, so it targets 0x12C
+        let mut buf = [0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0xEB];
+        let mut encoder = BcjFilter::new_encoder(BcjKind::Arm, 0x100);
+        assert_eq!(encoder.convert(&mut buf), 8);
+        assert_eq!(buf[4..], [0x4B, 0x00, 0x00, 0xEB]);
+
+        let mut decoder = BcjFilter::new_decoder(BcjKind::Arm, 0x100);
+        decoder.convert(&mut buf);
+        assert_eq!(buf[4..], [0x08, 0x00, 0x00, 0xEB]);
+    }
+
+    #[test]
+    fn test_unconverted_tail() {
+        // Instructions can't be converted until all of their bytes are there
+        let mut buf = [0xE8, 0x00, 0x00, 0x00];
+        assert_eq!(BcjFilter::new_encoder(BcjKind::X86, 0).convert(&mut buf), 0);
+
+        let mut buf = [0u8; 7];
+        assert_eq!(
+            BcjFilter::new_encoder(BcjKind::RiscV, 0).convert(&mut buf),
+            0
+        );
+        assert_eq!(BcjFilter::new_encoder(BcjKind::Arm, 0).convert(&mut buf), 4);
+        assert_eq!(
+            BcjFilter::new_encoder(BcjKind::Ia64, 0).convert(&mut buf),
+            0
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Several filters applied one after another, as a single filter.
+//!
+//! Every filter converts the data in the same buffer, each one only as far as the filter before it
+//! got. Filters that are ahead of the others remember how far they got, so the bytes they already
+//! converted aren't converted again when the buffer comes back around.
+
+use super::Filter;
+
+/// A chain of filters, applied in order to the same data.
+///
+/// To decode data that was encoded by a chain, the decoders must be chained in the reverse order.
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter + Send>>,
+    /// How many bytes at the start of the next buffer each filter already converted
+    converted: Vec<usize>,
+}
+
+impl FilterChain {
+    pub fn new(filters: Vec<Box<dyn Filter + Send>>) -> Self {
+        let converted = vec![0; filters.len()];
+        Self { filters, converted }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl Filter for FilterChain {
+    fn convert(&mut self, buf: &mut [u8]) -> usize {
+        let mut end = buf.len();
+        for (filter, converted) in self.filters.iter_mut().zip(&mut self.converted) {
+            // Each filter is at most as far as the one before it
+            *converted += filter.convert(&mut buf[*converted..end]);
+            end = *converted;
+        }
+
+        for converted in &mut self.converted {
+            *converted -= end;
+        }
+        end
+    }
+
+    fn finish(&mut self, buf: &mut [u8]) {
+        // Once the filters before it are finished, all of the data is ready for the next one
+        for (filter, converted) in self.filters.iter_mut().zip(&mut self.converted) {
+            let start = *converted + filter.convert(&mut buf[*converted..]);
+            filter.finish(&mut buf[start..]);
+            *converted = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressors::filters::bcj::{BcjFilter, BcjKind};
+
+    fn test_data() -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..20_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                // Plenty of x86 call opcodes and ARM branch bytes
+                match (state >> 16) % 8 {
+                    0 => 0xE8,
+                    1 => 0xEB,
+                    2 => 0x00,
+                    3 => 0xFF,
+                    _ => (state >> 24) as u8,
+                }
+            })
+            .collect()
+    }
+
+    fn chain(encoder: bool) -> FilterChain {
+        let mut kinds = vec![BcjKind::X86, BcjKind::Arm];
+        if !encoder {
+            kinds.reverse();
+        }
+        FilterChain::new(
+            kinds
+                .into_iter()
+                .map(|kind| -> Box<dyn Filter + Send> {
+                    if encoder {
+                        Box::new(BcjFilter::new_encoder(kind, 0))
+                    } else {
+                        Box::new(BcjFilter::new_decoder(kind, 0))
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Run a filter over the data in chunks, passing the unconverted bytes back in each time
+    fn convert_in_chunks(filter: &mut impl Filter, data: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut output = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            buf.extend_from_slice(chunk);
+            let converted = filter.convert(&mut buf);
+            output.extend(buf.drain(..converted));
+        }
+        filter.finish(&mut buf);
+        output.extend(buf);
+        output
+    }
+
+    #[test]
+    fn test_chain_matches_separate_filters() {
+        let data = test_data();
+
+        let mut separate = data.clone();
+        for kind in [BcjKind::X86, BcjKind::Arm] {
+            separate = convert_in_chunks(&mut BcjFilter::new_encoder(kind, 0), &separate, 1 << 20);
+        }
+
+        for chunk_size in [1, 7, 100, 1 << 20] {
+            let encoded = convert_in_chunks(&mut chain(true), &data, chunk_size);
+            assert_eq!(encoded, separate);
+            assert_eq!(
+                convert_in_chunks(&mut chain(false), &encoded, chunk_size),
+                data
+            );
+        }
+    }
+}
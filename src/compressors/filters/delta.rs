@@ -0,0 +1,142 @@
+//! The delta filter, which makes data made of fixed size samples compress better.
+//!
+//! The encoder replaces every byte with its difference from the byte `distance` bytes before it, e.g.
+//! for 16-bit stereo audio a distance of 4 subtracts each sample from the previous one of the same
+//! channel. Slowly changing values turn into small, repetitive differences.
+//!
+//! The properties are a single byte, `distance - 1`, in both `.xz` and `.7z` files.
+
+use std::io;
+
+use super::Filter;
+
+pub const DISTANCE_MIN: usize = 1;
+pub const DISTANCE_MAX: usize = 256;
+
+/// The filter ID used in `.xz` block headers.
+pub const XZ_FILTER_ID: u64 = 0x03;
+/// The method ID used in `.7z` archives.
+pub const SEVEN_ZIP_METHOD_ID: u64 = 0x03;
+
+/// Encode the distance as the properties byte.
+pub fn props_from_distance(distance: usize) -> u8 {
+    debug_assert!((DISTANCE_MIN..=DISTANCE_MAX).contains(&distance));
+    (distance - 1) as u8
+}
+
+pub fn distance_from_props(props: &[u8]) -> Option<usize> {
+    match props {
+        &[byte] => Some(byte as usize + 1),
+        _ => None,
+    }
+}
+
+/// A delta encoder or decoder. The last 256 bytes are kept across calls, so every call
+/// converts the whole buffer.
+#[derive(Debug, Clone)]
+pub struct DeltaFilter {
+    distance: usize,
+    encoder: bool,
+    /// The previous bytes of the original data, as a ring buffer
+    history: [u8; DISTANCE_MAX],
+    pos: usize,
+}
+
+impl DeltaFilter {
+    pub fn new_encoder(distance: usize) -> io::Result<Self> {
+        Self::new(distance, true)
+    }
+
+    pub fn new_decoder(distance: usize) -> io::Result<Self> {
+        Self::new(distance, false)
+    }
+
+    fn new(distance: usize, encoder: bool) -> io::Result<Self> {
+        if !(DISTANCE_MIN..=DISTANCE_MAX).contains(&distance) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The delta distance must be between 1 and 256",
+            ));
+        }
+
+        Ok(Self {
+            distance,
+            encoder,
+            history: [0; DISTANCE_MAX],
+            pos: 0,
+        })
+    }
+
+    pub fn distance(&self) -> usize {
+        self.distance
+    }
+}
+
+impl Filter for DeltaFilter {
+    fn convert(&mut self, buf: &mut [u8]) -> usize {
+        for byte in buf.iter_mut() {
+            let prev = self.history[(self.pos + DISTANCE_MAX - self.distance) % DISTANCE_MAX];
+            if self.encoder {
+                self.history[self.pos] = *byte;
+                *byte = byte.wrapping_sub(prev);
+            } else {
+                *byte = byte.wrapping_add(prev);
+                self.history[self.pos] = *byte;
+            }
+            self.pos = (self.pos + 1) % DISTANCE_MAX;
+        }
+        buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_conversion() {
+        let mut buf = [10, 20, 11, 22, 12, 24, 13, 26];
+        let mut encoder = DeltaFilter::new_encoder(2).unwrap();
+        assert_eq!(encoder.convert(&mut buf[..3]), 3);
+        assert_eq!(encoder.convert(&mut buf[3..]), 5);
+        assert_eq!(buf, [10, 20, 1, 2, 1, 2, 1, 2]);
+
+        let mut decoder = DeltaFilter::new_decoder(2).unwrap();
+        for chunk in buf.chunks_mut(3) {
+            decoder.convert(chunk);
+        }
+        assert_eq!(buf, [10, 20, 11, 22, 12, 24, 13, 26]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * i / 7) as u8).collect();
+        for distance in [1, 3, 255, 256] {
+            let mut encoded = data.clone();
+            let mut encoder = DeltaFilter::new_encoder(distance).unwrap();
+            for chunk in encoded.chunks_mut(97) {
+                encoder.convert(chunk);
+            }
+            assert_eq!(encoded[..distance], data[..distance]);
+
+            let mut decoder = DeltaFilter::new_decoder(distance).unwrap();
+            decoder.convert(&mut encoded);
+            assert_eq!(encoded, data);
+        }
+    }
+
+    #[test]
+    fn test_props() {
+        for distance in [1, 4, 256] {
+            let props = props_from_distance(distance);
+            assert_eq!(distance_from_props(&[props]), Some(distance));
+        }
+        assert_eq!(props_from_distance(256), 0xFF);
+        assert_eq!(distance_from_props(&[]), None);
+
+        for distance in [0, 257] {
+            let err = DeltaFilter::new_encoder(distance).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
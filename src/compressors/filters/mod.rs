@@ -0,0 +1,33 @@
+//! # Filters that transform data to make it compress better
+//!
+//! Filters are applied to the data before it's compressed, and reversed after it's decompressed.
+//! They don't change the size of the data, so they convert it in place.
+
+pub mod bcj;
+pub mod chain;
+pub mod delta;
+pub mod streams;
+
+/// A filter that converts data in place, either encoding or decoding it.
+///
+/// Some filters need to look ahead to convert a byte, so they may not be able to convert the
+/// whole buffer at once. The bytes they couldn't convert must be passed again at the start of
+/// the next call, followed by the data after them.
+pub trait Filter {
+    /// Convert as much of the buffer as possible, returning the number of bytes converted.
+    fn convert(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Handle the bytes at the end of the data that `convert()` couldn't convert, because
+    /// there's nothing left to look ahead at. Most filters leave them as they are.
+    fn finish(&mut self, _buf: &mut [u8]) {}
+}
+
+impl<F: Filter + ?Sized> Filter for Box<F> {
+    fn convert(&mut self, buf: &mut [u8]) -> usize {
+        (**self).convert(buf)
+    }
+
+    fn finish(&mut self, buf: &mut [u8]) {
+        (**self).finish(buf)
+    }
+}
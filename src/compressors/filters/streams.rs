@@ -0,0 +1,335 @@
+//! # Streaming wrappers that apply a filter
+//!
+//! The reader decodes the data read from the inner reader, e.g. the output of a decompressor, and the
+//! writer encodes the data before writing it to the inner writer, e.g. the input of a compressor.
+
+use std::io::{self, Read, Write};
+
+use super::Filter;
+
+const BUFFER_SIZE: usize = 1 << 16;
+
+/// Holds the data a filter is reading through, so that it's converted in place.
+pub(crate) struct FilterBuffer {
+    buf: Box<[u8]>,
+    /// Converted bytes in `buf[pos..converted]` are ready to be read
+    pos: usize,
+    converted: usize,
+    /// Bytes in `buf[converted..filled]` are waiting for more data before they can be converted
+    filled: usize,
+    eof: bool,
+}
+
+impl FilterBuffer {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            converted: 0,
+            filled: 0,
+            eof: false,
+        }
+    }
+
+    /// Read data from `inner` and convert it with the filter, until there's something to return.
+    pub(crate) fn read<F: Filter + ?Sized>(
+        &mut self,
+        filter: &mut F,
+        mut inner: impl Read,
+        out: &mut [u8],
+    ) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        // With nothing buffered, the data can be converted directly in the output buffer
+        if self.pos == self.filled && !self.eof {
+            let len = out.len().min(self.buf.len());
+            let read = inner.read(&mut out[..len])?;
+            let converted = filter.convert(&mut out[..read]);
+
+            // Anything that couldn't be converted yet is kept until the next read
+            self.buf[..read - converted].copy_from_slice(&out[converted..read]);
+            self.pos = 0;
+            self.converted = 0;
+            self.filled = read - converted;
+
+            if read == 0 {
+                self.eof = true;
+                filter.finish(&mut []);
+            }
+            if converted > 0 || read == 0 {
+                return Ok(converted);
+            }
+        }
+
+        while self.pos == self.converted {
+            if self.eof {
+                return Ok(0);
+            }
+
+            self.buf.copy_within(self.converted..self.filled, 0);
+            self.filled -= self.converted;
+            self.pos = 0;
+            self.converted = 0;
+
+            let read = inner.read(&mut self.buf[self.filled..])?;
+            if read == 0 {
+                self.eof = true;
+                filter.finish(&mut self.buf[..self.filled]);
+                self.converted = self.filled;
+            } else {
+                self.filled += read;
+                self.converted = filter.convert(&mut self.buf[..self.filled]);
+            }
+        }
+
+        let len = out.len().min(self.converted - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Convert the data with the filter and write out everything that could be converted,
+/// keeping the rest in `pending` until more data arrives.
+pub(crate) fn write_filtered<F: Filter + ?Sized>(
+    filter: &mut F,
+    pending: &mut Vec<u8>,
+    mut inner: impl Write,
+    data: &[u8],
+) -> io::Result<()> {
+    pending.extend_from_slice(data);
+    let converted = filter.convert(pending);
+    inner.write_all(&pending[..converted])?;
+    pending.drain(..converted);
+    Ok(())
+}
+
+/// Convert and write out the bytes still pending at the end of the data.
+pub(crate) fn finish_filtered<F: Filter + ?Sized>(
+    filter: &mut F,
+    pending: &mut Vec<u8>,
+    mut inner: impl Write,
+) -> io::Result<()> {
+    filter.finish(pending);
+    inner.write_all(pending)?;
+    pending.clear();
+    Ok(())
+}
+
+/// A reader that decodes the data from the inner reader with a filter.
+pub struct FilterReader<R: Read, F: Filter> {
+    inner: R,
+    filter: F,
+    buffer: FilterBuffer,
+}
+
+impl<R: Read, F: Filter> FilterReader<R, F> {
+    /// The filter should be a decoder.
+    pub fn new(inner: R, filter: F) -> Self {
+        Self {
+            inner,
+            filter,
+            buffer: FilterBuffer::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read, F: Filter> Read for FilterReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.buffer.read(&mut self.filter, &mut self.inner, buf)
+    }
+}
+
+/// A writer that encodes data with a filter before writing it to the inner writer.
+///
+/// The filter may hold on to a few bytes until it sees what comes after them, so the data must
+/// be completed by calling `finish()`. Dropping the writer will try to finish it, ignoring any errors.
+pub struct FilterWriter<W: Write, F: Filter> {
+    inner: Option<W>,
+    filter: F,
+    pending: Vec<u8>,
+}
+
+impl<W: Write, F: Filter> FilterWriter<W, F> {
+    /// The filter should be an encoder.
+    pub fn new(inner: W, filter: F) -> Self {
+        Self {
+            inner: Some(inner),
+            filter,
+            pending: Vec::new(),
+        }
+    }
+
+    fn finish_data(&mut self) -> io::Result<W> {
+        let mut inner = self
+            .inner
+            .take()
+            .expect("FilterWriter used after finishing");
+        finish_filtered(&mut self.filter, &mut self.pending, &mut inner)?;
+        Ok(inner)
+    }
+
+    /// Write out the last pending bytes, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_data()
+    }
+}
+
+impl<W: Write, F: Filter> Write for FilterWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("FilterWriter used after finishing");
+        let len = buf.len().min(BUFFER_SIZE);
+        write_filtered(&mut self.filter, &mut self.pending, inner, &buf[..len])?;
+        Ok(len)
+    }
+
+    /// Flush the inner writer. Bytes the filter is still holding on to aren't written.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write, F: Filter> Drop for FilterWriter<W, F> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish_data();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::{
+            filters::{
+                bcj::{BcjFilter, BcjKind},
+                delta::DeltaFilter,
+            },
+            lzma::streams::{LzmaReader, LzmaWriter},
+        },
+        test_utils::PROPS,
+    };
+    use std::io::Cursor;
+
+    /// A reader that returns at most a few bytes at a time
+    struct SlowReader<R: Read>(R);
+
+    impl<R: Read> Read for SlowReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn test_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..5000u32 {
+            // x86 calls to a handful of functions, with some filler
+            let target = 0x1000 * (i % 7) as i32 - (data.len() as i32 + 5);
+            data.push(0xE8);
+            data.extend_from_slice(&target.to_le_bytes());
+            data.extend_from_slice(&[0x90, 0x48, 0x89, 0xC7][..(i % 4) as usize]);
+        }
+        data
+    }
+
+    fn encode(kind: BcjKind, data: &[u8]) -> Vec<u8> {
+        let mut writer = FilterWriter::new(Vec::new(), BcjFilter::new_encoder(kind, 0));
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = test_data();
+        for kind in BcjKind::ALL {
+            let encoded = encode(kind, &data);
+            assert_eq!(encoded.len(), data.len());
+
+            let mut reader = FilterReader::new(
+                SlowReader(Cursor::new(&encoded)),
+                BcjFilter::new_decoder(kind, 0),
+            );
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_with_lzma() {
+        let data = test_data();
+
+        let compress = |data: &[u8]| {
+            let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x10000, None).unwrap();
+            writer.write_all(data).unwrap();
+            writer.finish().unwrap()
+        };
+
+        let mut writer = FilterWriter::new(
+            LzmaWriter::new(Vec::new(), PROPS, 0x10000, None).unwrap(),
+            BcjFilter::new_encoder(BcjKind::X86, 0),
+        );
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap().finish().unwrap();
+
+        // The converted calls repeat, so they compress much better
+        assert!(compressed.len() < compress(&data).len() / 2);
+
+        let mut reader = FilterReader::new(
+            LzmaReader::new(Cursor::new(&compressed)).unwrap(),
+            BcjFilter::new_decoder(BcjKind::X86, 0),
+        );
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_delta_with_lzma() {
+        // 16-bit samples that change slowly
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| ((i / 3) as u16).to_le_bytes())
+            .collect();
+
+        let mut writer = FilterWriter::new(
+            LzmaWriter::new(Vec::new(), PROPS, 0x10000, None).unwrap(),
+            DeltaFilter::new_encoder(2).unwrap(),
+        );
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap().finish().unwrap();
+
+        // Reads of any size, some converted directly in the output buffer and some buffered
+        for read_size in [1, 1000, 1 << 20] {
+            let mut reader = FilterReader::new(
+                LzmaReader::new(Cursor::new(&compressed)).unwrap(),
+                DeltaFilter::new_decoder(2).unwrap(),
+            );
+            let mut output = Vec::new();
+            let mut buf = vec![0u8; read_size];
+            loop {
+                let read = reader.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..read]);
+            }
+            assert_eq!(output, data);
+        }
+    }
+}
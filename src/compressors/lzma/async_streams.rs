@@ -0,0 +1,677 @@
+//! # Async adapters for the `.lzma` and `.xz` streams
+//!
+//! These work with tokio's `AsyncRead` and `AsyncWrite`, compressing and decompressing the data
+//! as it passes through, without blocking a thread while waiting on the inner stream. They behave
+//! the same way as the sync streams, except that the writers are finished by shutting them down.
+//!
+//! Only available with the `tokio` feature.
+
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{
+    codecs::{
+        header_codec::{LzmaHeader, LzmaHeaderProps},
+        xz_codec::checks::CheckType,
+    },
+    limits::DecoderLimits,
+    options::LzmaOptions,
+    push_decoder::{DecodeStatus, LzmaPushDecoder},
+    streams::LzmaWriter,
+    xz_push_decoder::XzPushDecoder,
+    xz_streams::XzWriter,
+};
+
+/// How much compressed data the readers read from the inner reader at once
+const INPUT_BUFFER_SIZE: usize = 1 << 16;
+
+/// An async reader that decompresses a `.lzma` stream from the inner reader.
+///
+/// The inner reader is read ahead in chunks. Any data after the stream that was read along with it
+/// is handed back by `into_inner()`.
+pub struct AsyncLzmaReader<R: AsyncRead + Unpin> {
+    inner: R,
+    decoder: LzmaPushDecoder,
+    input: Box<[u8]>,
+    input_pos: usize,
+    input_end: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncLzmaReader<R> {
+    /// Decompress a `.lzma` stream. The header is parsed once the first data is read.
+    pub fn new(inner: R) -> Self {
+        Self::with_decoder(inner, LzmaPushDecoder::new())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. Use this for untrusted files.
+    pub fn new_with_limits(inner: R, limits: DecoderLimits) -> Self {
+        Self::with_decoder(inner, LzmaPushDecoder::new_with_limits(limits))
+    }
+
+    /// Decompress a raw LZMA stream without a header, e.g. from inside a zip or 7z archive.
+    ///
+    /// If the uncompressed size is unknown, the stream must end with an end of stream marker.
+    /// If it is known, an end of stream marker is allowed but not required.
+    pub fn new_raw(
+        inner: R,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+    ) -> Self {
+        let decoder = LzmaPushDecoder::new_raw(props, dict_size, uncompressed_size);
+        Self::with_decoder(inner, decoder)
+    }
+
+    /// Like `new_raw()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow.
+    pub fn new_raw_with_limits(
+        inner: R,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+        limits: DecoderLimits,
+    ) -> io::Result<Self> {
+        let decoder =
+            LzmaPushDecoder::new_raw_with_limits(props, dict_size, uncompressed_size, limits)?;
+        Ok(Self::with_decoder(inner, decoder))
+    }
+
+    fn with_decoder(inner: R, decoder: LzmaPushDecoder) -> Self {
+        Self {
+            inner,
+            decoder,
+            input: vec![0; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            input_pos: 0,
+            input_end: 0,
+        }
+    }
+
+    /// The header of the stream, once it has been parsed.
+    pub fn header(&self) -> Option<&LzmaHeader> {
+        self.decoder.header()
+    }
+
+    /// The inner reader, and the data that was read from it but not used by the stream. Once the
+    /// stream has been read to its end, that data is the start of whatever follows the stream.
+    pub fn into_inner(self) -> (R, Vec<u8>) {
+        let unused = self.input[self.input_pos..self.input_end].to_vec();
+        (self.inner, unused)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncLzmaReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            let input = &this.input[this.input_pos..this.input_end];
+            let progress = this.decoder.decode(input, buf.initialize_unfilled())?;
+            this.input_pos += progress.consumed;
+            buf.advance(progress.written);
+
+            if progress.status != DecodeStatus::NeedsInput || progress.written > 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // The decoder keeps any input it couldn't use yet, so the whole buffer can be refilled
+            let mut read_buf = ReadBuf::new(&mut this.input);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            this.input_pos = 0;
+            this.input_end = read_buf.filled().len();
+
+            if this.input_end == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "LZMA stream ended unexpectedly",
+                )));
+            }
+        }
+    }
+}
+
+/// An async reader that decompresses an `.xz` file from the inner reader.
+///
+/// The file is read until the inner reader ends, since more streams can follow each stream.
+pub struct AsyncXzReader<R: AsyncRead + Unpin> {
+    inner: R,
+    decoder: XzPushDecoder,
+    input: Box<[u8]>,
+    input_pos: usize,
+    input_end: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncXzReader<R> {
+    /// Decompress an `.xz` file. The stream header is parsed once the first data is read.
+    pub fn new(inner: R) -> Self {
+        Self::with_decoder(inner, XzPushDecoder::new())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The memory limit applies to each block separately.
+    pub fn new_with_limits(inner: R, limits: DecoderLimits) -> Self {
+        Self::with_decoder(inner, XzPushDecoder::new_with_limits(limits))
+    }
+
+    fn with_decoder(inner: R, decoder: XzPushDecoder) -> Self {
+        Self {
+            inner,
+            decoder,
+            input: vec![0; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            input_pos: 0,
+            input_end: 0,
+        }
+    }
+
+    /// The check type of the stream currently being read.
+    pub fn check_type(&self) -> CheckType {
+        self.decoder.check_type()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncXzReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            let input = &this.input[this.input_pos..this.input_end];
+            let progress = this.decoder.decode(input, buf.initialize_unfilled())?;
+            this.input_pos += progress.consumed;
+            buf.advance(progress.written);
+
+            if progress.status != DecodeStatus::NeedsInput || progress.written > 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut read_buf = ReadBuf::new(&mut this.input);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            this.input_pos = 0;
+            this.input_end = read_buf.filled().len();
+
+            if this.input_end == 0 {
+                if this.decoder.is_finished() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "xz file ended unexpectedly",
+                )));
+            }
+        }
+    }
+}
+
+/// A sync stream writer that the async writers drive, which writes its output into a `Vec<u8>`.
+trait VecEncoder: Write {
+    fn output(&mut self) -> &mut Vec<u8>;
+    fn finish(self) -> io::Result<Vec<u8>>;
+}
+
+impl VecEncoder for LzmaWriter<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        LzmaWriter::finish(self)
+    }
+}
+
+impl VecEncoder for XzWriter<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        XzWriter::finish(self)
+    }
+}
+
+/// Compresses the data with a sync writer, then writes its output to the async inner writer.
+struct EncoderAdapter<E: VecEncoder, W: AsyncWrite + Unpin> {
+    /// `None` after the writer was shut down
+    encoder: Option<E>,
+    inner: W,
+    /// Compressed data that's being written to the inner writer
+    output: Vec<u8>,
+    output_pos: usize,
+}
+
+impl<E: VecEncoder, W: AsyncWrite + Unpin> EncoderAdapter<E, W> {
+    fn new(encoder: E, inner: W) -> Self {
+        Self {
+            encoder: Some(encoder),
+            inner,
+            output: Vec::new(),
+            output_pos: 0,
+        }
+    }
+
+    /// Write all the compressed data so far to the inner writer.
+    fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.output_pos == self.output.len() {
+                // Take the data that the encoder wrote since, reusing the allocations
+                self.output.clear();
+                self.output_pos = 0;
+                if let Some(encoder) = &mut self.encoder {
+                    std::mem::swap(&mut self.output, encoder.output());
+                }
+
+                if self.output.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let output = &self.output[self.output_pos..];
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, output))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output_pos += written;
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Only take more data once the previous output was written, so the output can't pile up
+        ready!(self.poll_write_output(cx))?;
+
+        let encoder = self.encoder.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "Writer used after shutting down")
+        })?;
+        Poll::Ready(encoder.write(buf))
+    }
+
+    /// Write the compressed data so far. The encoder holds on to some of the data until the stream is finished.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_output(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    /// Finish the compressed stream, then shut down the inner writer.
+    fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(encoder) = self.encoder.take() {
+            let rest = encoder.finish()?;
+            self.output.extend_from_slice(&rest);
+        }
+
+        ready!(self.poll_write_output(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// An async writer that compresses data into a `.lzma` stream, writing it to the inner writer.
+///
+/// The stream must be completed by calling `shutdown()`, which also shuts down the inner writer.
+pub struct AsyncLzmaWriter<W: AsyncWrite + Unpin> {
+    adapter: EncoderAdapter<LzmaWriter<Vec<u8>>, W>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncLzmaWriter<W> {
+    /// Compress with the default preset, using the given properties and dictionary size.
+    /// See `LzmaWriter::new()`.
+    pub fn new(
+        inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let encoder = LzmaWriter::new(Vec::new(), props, dict_size, uncompressed_size)?;
+        Ok(Self {
+            adapter: EncoderAdapter::new(encoder, inner),
+        })
+    }
+
+    pub fn new_with_options(
+        inner: W,
+        options: &LzmaOptions,
+        uncompressed_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let encoder = LzmaWriter::new_with_options(Vec::new(), options, uncompressed_size)?;
+        Ok(Self {
+            adapter: EncoderAdapter::new(encoder, inner),
+        })
+    }
+
+    /// Write a raw LZMA stream without a header. See `LzmaWriter::new_raw()`.
+    pub fn new_raw(
+        inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        use_end_marker: bool,
+    ) -> io::Result<Self> {
+        let encoder = LzmaWriter::new_raw(Vec::new(), props, dict_size, use_end_marker)?;
+        Ok(Self {
+            adapter: EncoderAdapter::new(encoder, inner),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.adapter.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncLzmaWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().adapter.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().adapter.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().adapter.poll_shutdown(cx)
+    }
+}
+
+/// An async writer that compresses data into an `.xz` file, writing it to the inner writer.
+///
+/// The stream must be completed by calling `shutdown()`, which also shuts down the inner writer.
+pub struct AsyncXzWriter<W: AsyncWrite + Unpin> {
+    adapter: EncoderAdapter<XzWriter<Vec<u8>>, W>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncXzWriter<W> {
+    /// Compress data with LZMA2, which requires `lc + lp <= 4`. See `XzWriter::new()`.
+    pub fn new(
+        inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        check: CheckType,
+    ) -> io::Result<Self> {
+        let encoder = XzWriter::new(Vec::new(), props, dict_size, check)?;
+        Ok(Self {
+            adapter: EncoderAdapter::new(encoder, inner),
+        })
+    }
+
+    /// Split the data into blocks of `block_size` uncompressed bytes. See `XzWriter::set_block_size()`.
+    pub fn set_block_size(&mut self, block_size: Option<u64>) -> io::Result<()> {
+        self.encoder().set_block_size(block_size)
+    }
+
+    fn encoder(&mut self) -> &mut XzWriter<Vec<u8>> {
+        self.adapter
+            .encoder
+            .as_mut()
+            .expect("AsyncXzWriter used after shutting down")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.adapter.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncXzWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().adapter.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().adapter.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().adapter.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::{streams::LzmaReader, xz_streams::XzReader},
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::{Cursor, Read};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    /// Write the data in uneven chunks, then shut the writer down.
+    async fn write_chunks(mut writer: impl AsyncWrite + Unpin, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).await?;
+        }
+        writer.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn test_lzma_roundtrip() {
+        let data = test_data(60_000);
+
+        for size in [Some(data.len() as u64), None] {
+            // A small pipe, so both sides have to wait on each other all the time
+            let (client, server) = duplex(64);
+            let writer = AsyncLzmaWriter::new(client, PROPS, 1 << 16, size).unwrap();
+            let mut reader = AsyncLzmaReader::new(server);
+
+            let mut output = Vec::new();
+            let (written, read) =
+                tokio::join!(write_chunks(writer, &data), reader.read_to_end(&mut output));
+            written.unwrap();
+            read.unwrap();
+
+            assert_eq!(output, data);
+            assert_eq!(reader.header().unwrap().dict_size, 1 << 16);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lzma_same_as_sync() {
+        let data = test_data(60_000);
+
+        // The output depends on how the data is split into writes, so use the same chunks
+        let mut sync_writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, None).unwrap();
+        for chunk in data.chunks(1000) {
+            sync_writer.write_all(chunk).unwrap();
+        }
+        let expected = sync_writer.finish().unwrap();
+
+        let (client, mut server) = duplex(1 << 10);
+        let writer = AsyncLzmaWriter::new(client, PROPS, 1 << 16, None).unwrap();
+        let mut compressed = Vec::new();
+        let (written, read) = tokio::join!(
+            write_chunks(writer, &data),
+            server.read_to_end(&mut compressed)
+        );
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(compressed, expected);
+
+        // And the sync reader reads the same data as the async one
+        let mut output = Vec::new();
+        let mut reader = AsyncLzmaReader::new(&compressed[..]);
+        reader.read_to_end(&mut output).await.unwrap();
+        let mut sync_output = Vec::new();
+        LzmaReader::new(Cursor::new(&compressed))
+            .unwrap()
+            .read_to_end(&mut sync_output)
+            .unwrap();
+        assert_eq!(output, sync_output);
+    }
+
+    #[tokio::test]
+    async fn test_lzma_raw() {
+        let data = test_data(60_000);
+
+        let (client, server) = duplex(256);
+        let writer = AsyncLzmaWriter::new_raw(client, PROPS, 1 << 16, true).unwrap();
+        let mut reader = AsyncLzmaReader::new_raw(server, PROPS, 1 << 16, None);
+
+        let mut output = Vec::new();
+        let (written, read) =
+            tokio::join!(write_chunks(writer, &data), reader.read_to_end(&mut output));
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(output, data);
+
+        let mut sync_writer = LzmaWriter::new_raw(Vec::new(), PROPS, 1 << 16, true).unwrap();
+        sync_writer.write_all(&data).unwrap();
+        let compressed = sync_writer.finish().unwrap();
+        let limits = DecoderLimits {
+            memory_limit: None,
+            max_output_size: Some(data.len() as u64 - 1),
+        };
+        let mut reader =
+            AsyncLzmaReader::new_raw_with_limits(&compressed[..], PROPS, 1 << 16, None, limits)
+                .unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+
+        let limits = DecoderLimits {
+            memory_limit: Some(1 << 10),
+            max_output_size: None,
+        };
+        let err = AsyncLzmaReader::new_raw_with_limits(&[][..], PROPS, 1 << 16, None, limits)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[tokio::test]
+    async fn test_lzma_data_after_stream() {
+        let data = test_data(60_000);
+        let mut sync_writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, None).unwrap();
+        sync_writer.write_all(&data).unwrap();
+        let compressed = sync_writer.finish().unwrap();
+        let trailer = test_data(5000);
+
+        let (mut client, server) = duplex(256);
+        let sent = async {
+            client.write_all(&compressed).await?;
+            client.write_all(&trailer).await?;
+            client.shutdown().await
+        };
+        let received = async {
+            let mut reader = AsyncLzmaReader::new(server);
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).await?;
+            let (mut server, mut rest) = reader.into_inner();
+            server.read_to_end(&mut rest).await?;
+            Ok::<_, io::Error>((output, rest))
+        };
+        let (sent, received) = tokio::join!(sent, received);
+        sent.unwrap();
+        let (output, rest) = received.unwrap();
+
+        assert_eq!(output, data);
+        assert_eq!(rest, trailer);
+    }
+
+    #[tokio::test]
+    async fn test_lzma_truncated() {
+        let data = test_data(60_000);
+        let mut sync_writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, None).unwrap();
+        sync_writer.write_all(&data).unwrap();
+        let compressed = sync_writer.finish().unwrap();
+
+        let truncated = &compressed[..compressed.len() - 1];
+        let mut reader = AsyncLzmaReader::new(truncated);
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_xz_writer() {
+        let data = test_data(60_000);
+
+        let mut sync_writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc64).unwrap();
+        sync_writer.set_block_size(Some(50_000)).unwrap();
+        for chunk in data.chunks(1000) {
+            sync_writer.write_all(chunk).unwrap();
+        }
+        let expected = sync_writer.finish().unwrap();
+
+        let (client, mut server) = duplex(64);
+        let mut writer = AsyncXzWriter::new(client, PROPS, 1 << 16, CheckType::Crc64).unwrap();
+        writer.set_block_size(Some(50_000)).unwrap();
+        let mut compressed = Vec::new();
+        let (written, read) = tokio::join!(
+            write_chunks(writer, &data),
+            server.read_to_end(&mut compressed)
+        );
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(compressed, expected);
+
+        let mut output = Vec::new();
+        XzReader::new(Cursor::new(&compressed))
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[tokio::test]
+    async fn test_xz_roundtrip() {
+        let data = test_data(60_000);
+
+        for block_size in [None, Some(50_000)] {
+            let (client, server) = duplex(64);
+            let mut writer = AsyncXzWriter::new(client, PROPS, 1 << 16, CheckType::Sha256).unwrap();
+            writer.set_block_size(block_size).unwrap();
+            let mut reader = AsyncXzReader::new(server);
+
+            let mut output = Vec::new();
+            let (written, read) =
+                tokio::join!(write_chunks(writer, &data), reader.read_to_end(&mut output));
+            written.unwrap();
+            read.unwrap();
+
+            assert_eq!(output, data);
+            assert_eq!(reader.check_type(), CheckType::Sha256);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_xz_truncated() {
+        let data = test_data(60_000);
+        let mut sync_writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc32).unwrap();
+        sync_writer.write_all(&data).unwrap();
+        let compressed = sync_writer.finish().unwrap();
+
+        let mut output = Vec::new();
+        AsyncXzReader::new(&compressed[..])
+            .read_to_end(&mut output)
+            .await
+            .unwrap();
+        assert_eq!(output, data);
+
+        for len in [6, compressed.len() / 2, compressed.len() - 1] {
+            let mut reader = AsyncXzReader::new(&compressed[..len]);
+            let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
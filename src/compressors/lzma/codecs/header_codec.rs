@@ -1,10 +1,15 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{Error, ErrorKind};
 
 pub const DICT_SIZE_MIN: u32 = 4096;
 pub const DICT_SIZE_MAX: u32 = u32::MAX & !(15 as u32);
 
+/// The size of the `.lzma` header: the properties byte, the dictionary size and the uncompressed size.
+pub const LZMA_HEADER_SIZE: u64 = 13;
+
 #[derive(Debug, Clone)]
 pub struct LzmaHeaderProps {
     pub pb: u8,
@@ -19,12 +24,13 @@ pub struct LzmaHeader {
     pub uncompressed_size: u64,
 }
 
-fn parse_props_from_u8(props: u8) -> io::Result<LzmaHeaderProps> {
+pub fn parse_props_from_u8(props: u8) -> io::Result<LzmaHeaderProps> {
     if props > (4 * 5 + 4) * 9 + 8 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
+        return Err(Error::with_message(
+            ErrorKind::InvalidProperties,
             "Invalid LZMA properties byte",
-        ));
+        )
+        .into());
     }
 
     let pb = props / (9 * 5);
@@ -35,16 +41,21 @@ fn parse_props_from_u8(props: u8) -> io::Result<LzmaHeaderProps> {
     Ok(LzmaHeaderProps { pb, lp, lc })
 }
 
+pub fn props_to_u8(props: &LzmaHeaderProps) -> u8 {
+    (props.pb * 5 + props.lp) * 9 + props.lc
+}
+
 pub fn parse_lzma_header(mut reader: impl Read) -> io::Result<LzmaHeader> {
     let props = parse_props_from_u8(reader.read_u8()?)?;
     let dict_size = reader.read_u32::<LittleEndian>()?;
     let uncompressed_size = reader.read_u64::<LittleEndian>()?;
 
     if dict_size > DICT_SIZE_MAX || dict_size < DICT_SIZE_MIN {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
+        return Err(Error::with_message(
+            ErrorKind::InvalidDictSize,
             "Invalid LZMA dictionary size",
-        ));
+        )
+        .into());
     }
 
     Ok(LzmaHeader {
@@ -53,3 +64,11 @@ pub fn parse_lzma_header(mut reader: impl Read) -> io::Result<LzmaHeader> {
         uncompressed_size,
     })
 }
+
+pub fn write_lzma_header(mut writer: impl Write, header: &LzmaHeader) -> io::Result<()> {
+    writer.write_u8(props_to_u8(&header.props))?;
+    writer.write_u32::<LittleEndian>(header.dict_size)?;
+    writer.write_u64::<LittleEndian>(header.uncompressed_size)?;
+
+    Ok(())
+}
@@ -1,8 +1,8 @@
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 
 use crate::utils::const_variable_arr::ConstVariableArr;
 
-use super::range_codec::{RangeDecoder, RangeEncPrice, RangeEncProbability, RangeEncoder};
+use super::range_codec::{RangeDecode, RangeEncPrice, RangeEncProbability, RangeEncoder};
 
 /// A length-value codec for LZMA, storing probabilities for each bit in a tree.
 ///
@@ -27,6 +27,10 @@ impl<const BITS_EXP: usize> LengthValueCodec<BITS_EXP> {
         }
     }
 
+    pub fn reset(&mut self) {
+        self.probs.fill(RangeEncProbability::new());
+    }
+
     pub fn encode_bit_tree(
         &mut self,
         enc: &mut RangeEncoder<impl Write>,
@@ -74,7 +78,7 @@ impl<const BITS_EXP: usize> LengthValueCodec<BITS_EXP> {
         Ok(())
     }
 
-    pub fn decode_bit_tree(&mut self, dec: &mut RangeDecoder<impl Read>) -> io::Result<u32> {
+    pub fn decode_bit_tree(&mut self, dec: &mut impl RangeDecode) -> io::Result<u32> {
         let mut symbol: u32 = 1;
         loop {
             symbol = (symbol << 1) | dec.decode_bit(&mut self.probs[symbol as usize])?;
@@ -85,10 +89,7 @@ impl<const BITS_EXP: usize> LengthValueCodec<BITS_EXP> {
         Ok(symbol - self.probs.len() as u32)
     }
 
-    pub fn decode_reverse_bit_tree(
-        &mut self,
-        dec: &mut RangeDecoder<impl Read>,
-    ) -> io::Result<u32> {
+    pub fn decode_reverse_bit_tree(&mut self, dec: &mut impl RangeDecode) -> io::Result<u32> {
         let mut symbol: u32 = 1;
         let mut i = 0;
         let mut result = 0;
@@ -217,6 +218,18 @@ impl LengthCodec {
             pos_states: ConstVariableArr::new(LengthCodecPosState::new(), 1 << pb),
         }
     }
+
+    /// Reset all the probabilities in place.
+    pub fn reset(&mut self) {
+        self.first_bit = RangeEncProbability::new();
+        self.second_bit = RangeEncProbability::new();
+        self.high.reset();
+
+        for pos_state in self.pos_states.as_mut_slice() {
+            pos_state.low.reset();
+            pos_state.mid.reset();
+        }
+    }
 }
 
 pub struct LengthCodecEncoder {
@@ -239,6 +252,15 @@ impl LengthCodecEncoder {
         }
     }
 
+    /// Reset the probabilities, and make the prices get recalculated on the next update.
+    pub fn reset(&mut self) {
+        self.codec.reset();
+
+        for prices in self.pos_state_prices.as_mut_slice() {
+            prices.counter = 0;
+        }
+    }
+
     pub fn encode(
         &mut self,
         enc: &mut RangeEncoder<impl Write>,
@@ -352,6 +374,7 @@ impl LengthCodecEncoder {
     }
 }
 
+#[derive(Clone)]
 pub struct LengthCodecDecoder {
     codec: LengthCodec,
 }
@@ -363,7 +386,16 @@ impl LengthCodecDecoder {
         }
     }
 
-    pub fn decode(&mut self, dec: &mut RangeDecoder<impl Read>, pos_state: u32) -> io::Result<u32> {
+    pub fn reset(&mut self) {
+        self.codec.reset();
+    }
+
+    /// Reset the probabilities with a new pb value.
+    pub fn reset_with_pb(&mut self, pb: u32) {
+        self.codec = LengthCodec::new(pb);
+    }
+
+    pub fn decode(&mut self, dec: &mut impl RangeDecode, pos_state: u32) -> io::Result<u32> {
         if dec.decode_bit(&mut self.codec.first_bit)? == 0 {
             let l = self.codec.pos_states[pos_state as usize]
                 .low
@@ -394,6 +426,7 @@ impl LengthCodecDecoder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compressors::lzma::codecs::range_codec::RangeDecoder;
     use std::io::Cursor;
 
     #[test]
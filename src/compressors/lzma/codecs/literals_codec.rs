@@ -1,14 +1,15 @@
 mod subcoder;
 
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 
 use self::subcoder::LiteralSubcoder;
 
-use super::range_codec::{RangeDecoder, RangeEncPrice, RangeEncoder};
+use super::range_codec::{RangeDecode, RangeEncPrice, RangeEncoder};
 
 /// A struct that helps choose the probability set to use for encoding/decoding
 /// the next literal based on the previous uncompressed byte. lp and lc are
 /// LZMA parameters.
+#[derive(Clone)]
 pub(crate) struct LiteralCoderContextBits {
     lc: u32,
     literal_pos_mask: u32,
@@ -31,6 +32,7 @@ impl LiteralCoderContextBits {
     }
 }
 
+#[derive(Clone)]
 pub struct LiteralCodec {
     coder: LiteralCoderContextBits,
     sub_decoders: Vec<LiteralSubcoder>,
@@ -52,6 +54,23 @@ impl LiteralCodec {
         }
     }
 
+    /// Reset all the probabilities in place.
+    fn reset(&mut self) {
+        self.sub_decoders
+            .iter_mut()
+            .for_each(LiteralSubcoder::reset);
+    }
+
+    /// Reset all the probabilities with new lc and lp values, reusing the allocation where possible.
+    fn reset_with_props(&mut self, lc: u32, lp: u32) {
+        self.coder = LiteralCoderContextBits::new(lc, lp);
+
+        let count = 1 << (lc + lp);
+        self.sub_decoders.truncate(count);
+        self.reset();
+        self.sub_decoders.resize(count, LiteralSubcoder::new());
+    }
+
     fn get_subcoder_mut(&mut self, prev_byte: u32, pos: u32) -> &mut LiteralSubcoder {
         let i = self.coder.get_sub_coder_index(prev_byte, pos);
         &mut self.sub_decoders[i as usize]
@@ -63,6 +82,7 @@ impl LiteralCodec {
     }
 }
 
+#[derive(Clone)]
 pub struct LiteralCodecDecoder {
     codec: LiteralCodec,
 }
@@ -74,9 +94,22 @@ impl LiteralCodecDecoder {
         }
     }
 
-    pub fn decode_normal<R: Read>(
+    /// Get the memory usage of the probabilities in bytes
+    pub fn get_mem_usage(lc: u32, lp: u32) -> u64 {
+        (1u64 << (lc + lp)) * std::mem::size_of::<LiteralSubcoder>() as u64
+    }
+
+    pub fn reset(&mut self) {
+        self.codec.reset();
+    }
+
+    pub fn reset_with_props(&mut self, lc: u32, lp: u32) {
+        self.codec.reset_with_props(lc, lp);
+    }
+
+    pub fn decode_normal(
         &mut self,
-        rc: &mut RangeDecoder<R>,
+        rc: &mut impl RangeDecode,
         prev_byte: u8,
         pos: usize,
     ) -> io::Result<u8> {
@@ -84,9 +117,9 @@ impl LiteralCodecDecoder {
         subcoder.decode_normal_literal(rc)
     }
 
-    pub fn decode_matched<R: Read>(
+    pub fn decode_matched(
         &mut self,
-        rc: &mut RangeDecoder<R>,
+        rc: &mut impl RangeDecode,
         prev_byte: u8,
         pos: usize,
         prev_match_byte: u8,
@@ -107,6 +140,10 @@ impl LiteralCodecEncoder {
         }
     }
 
+    pub fn reset(&mut self) {
+        self.codec.reset();
+    }
+
     pub fn encode_normal<W: Write>(
         &mut self,
         rc: &mut RangeEncoder<W>,
@@ -1,6 +1,6 @@
-use std::io::{Read, Write};
+use std::io::Write;
 
-use super::super::range_codec::{RangeDecoder, RangeEncPrice, RangeEncProbability, RangeEncoder};
+use super::super::range_codec::{RangeDecode, RangeEncPrice, RangeEncProbability, RangeEncoder};
 
 #[derive(Debug, Clone)]
 pub(crate) struct LiteralSubcoder {
@@ -14,6 +14,10 @@ impl LiteralSubcoder {
         }
     }
 
+    pub fn reset(&mut self) {
+        self.probs.fill(RangeEncProbability::new());
+    }
+
     pub fn encode_normal_literal(
         &mut self,
         rc: &mut RangeEncoder<impl Write>,
@@ -67,7 +71,7 @@ impl LiteralSubcoder {
 
     pub fn decode_normal_literal(
         &mut self,
-        rc: &mut RangeDecoder<impl Read>,
+        rc: &mut impl RangeDecode,
     ) -> Result<u8, std::io::Error> {
         let mut symbol: u32 = 1;
         loop {
@@ -82,7 +86,7 @@ impl LiteralSubcoder {
 
     pub fn decode_matched_literal(
         &mut self,
-        rc: &mut RangeDecoder<impl Read>,
+        rc: &mut impl RangeDecode,
         match_byte: u8,
     ) -> Result<u8, std::io::Error> {
         let mut symbol: u32 = 1;
@@ -151,6 +155,7 @@ impl LiteralSubcoder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compressors::lzma::codecs::range_codec::RangeDecoder;
     use std::io::Cursor;
 
     #[test]
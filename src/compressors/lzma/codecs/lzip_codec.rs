@@ -0,0 +1,227 @@
+//! # The `.lz` (lzip) container format
+//!
+//! An lzip file is made of one or more members, each holding a raw LZMA stream that ends with an
+//! end of stream marker, always with `lc=3`, `lp=0` and `pb=2`. Members look like this:
+//!
+//! - The header: the magic bytes `LZIP`, the version (always 1), and the coded dictionary size.
+//! - The LZMA stream.
+//! - The trailer: the CRC32 of the uncompressed data, the uncompressed size, and the size of the
+//!   whole member including the header and trailer, all little endian.
+//!
+//! The dictionary size is coded in a single byte: the low 5 bits are the log2 of a base size, and the
+//! top 3 bits are how many sixteenths of the base size to subtract from it.
+
+use std::{error::Error, fmt, io};
+
+use super::header_codec::LzmaHeaderProps;
+
+pub const HEADER_MAGIC: [u8; 4] = *b"LZIP";
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 6;
+pub const TRAILER_SIZE: usize = 20;
+
+pub const DICT_SIZE_MIN: u32 = 1 << 12;
+pub const DICT_SIZE_MAX: u32 = 1 << 29;
+
+/// The LZMA properties every lzip member uses.
+pub const PROPS: LzmaHeaderProps = LzmaHeaderProps {
+    lc: 3,
+    lp: 0,
+    pb: 2,
+};
+
+/// The ways an `.lz` file can be malformed or unsupported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LzipError {
+    InvalidHeaderMagic,
+    UnsupportedVersion(u8),
+    InvalidDictSize,
+    CrcMismatch,
+    DataSizeMismatch,
+    MemberSizeMismatch,
+    /// Data after the last member that isn't another member
+    TrailingData,
+}
+
+impl fmt::Display for LzipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LzipError::InvalidHeaderMagic => write!(f, "Invalid lzip header magic bytes"),
+            LzipError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported lzip version {}", version)
+            }
+            LzipError::InvalidDictSize => write!(f, "Invalid lzip dictionary size"),
+            LzipError::CrcMismatch => write!(f, "lzip member CRC32 mismatch"),
+            LzipError::DataSizeMismatch => write!(f, "lzip member data size mismatch"),
+            LzipError::MemberSizeMismatch => write!(f, "lzip member size mismatch"),
+            LzipError::TrailingData => write!(f, "Unexpected data after the last lzip member"),
+        }
+    }
+}
+
+impl Error for LzipError {}
+
+impl From<LzipError> for io::Error {
+    fn from(err: LzipError) -> Self {
+        let kind = match err {
+            LzipError::UnsupportedVersion(_) => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Code the dictionary size into a byte, rounding it up to the nearest size that can be represented.
+/// Sizes outside of the valid range are clamped to it.
+pub fn encode_dict_size(dict_size: u32) -> u8 {
+    let dict_size = dict_size.clamp(DICT_SIZE_MIN, DICT_SIZE_MAX);
+    let bits = 32 - (dict_size - 1).leading_zeros();
+    let base = 1u32 << bits;
+
+    let fraction = (1..8u32)
+        .rev()
+        .find(|&fraction| base - fraction * (base / 16) >= dict_size)
+        .unwrap_or(0);
+    (bits | fraction << 5) as u8
+}
+
+pub fn decode_dict_size(coded: u8) -> Result<u32, LzipError> {
+    let bits = coded & 0x1F;
+    if !(12..=29).contains(&bits) {
+        return Err(LzipError::InvalidDictSize);
+    }
+
+    let base = 1u32 << bits;
+    if base == DICT_SIZE_MIN {
+        return Ok(base);
+    }
+    Ok(base - (base / 16) * (coded >> 5) as u32)
+}
+
+pub fn encode_header(dict_size: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&HEADER_MAGIC);
+    header[4] = VERSION;
+    header[5] = encode_dict_size(dict_size);
+    header
+}
+
+/// Parse a member header, returning the dictionary size.
+pub fn parse_header(header: &[u8; HEADER_SIZE]) -> Result<u32, LzipError> {
+    if header[..4] != HEADER_MAGIC {
+        return Err(LzipError::InvalidHeaderMagic);
+    }
+    if header[4] != VERSION {
+        return Err(LzipError::UnsupportedVersion(header[4]));
+    }
+    decode_dict_size(header[5])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LzipTrailer {
+    pub crc32: u32,
+    pub data_size: u64,
+    /// The size of the whole member, including the header and trailer
+    pub member_size: u64,
+}
+
+impl LzipTrailer {
+    pub fn encode(&self) -> [u8; TRAILER_SIZE] {
+        let mut trailer = [0u8; TRAILER_SIZE];
+        trailer[..4].copy_from_slice(&self.crc32.to_le_bytes());
+        trailer[4..12].copy_from_slice(&self.data_size.to_le_bytes());
+        trailer[12..].copy_from_slice(&self.member_size.to_le_bytes());
+        trailer
+    }
+
+    pub fn parse(trailer: &[u8; TRAILER_SIZE]) -> Self {
+        Self {
+            crc32: u32::from_le_bytes(trailer[..4].try_into().unwrap()),
+            data_size: u64::from_le_bytes(trailer[4..12].try_into().unwrap()),
+            member_size: u64::from_le_bytes(trailer[12..].try_into().unwrap()),
+        }
+    }
+
+    /// Check the trailer against what was actually read from the member.
+    pub fn validate(&self, actual: &LzipTrailer) -> Result<(), LzipError> {
+        if self.crc32 != actual.crc32 {
+            return Err(LzipError::CrcMismatch);
+        }
+        if self.data_size != actual.data_size {
+            return Err(LzipError::DataSizeMismatch);
+        }
+        if self.member_size != actual.member_size {
+            return Err(LzipError::MemberSizeMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dict_size_coding() {
+        assert_eq!(encode_dict_size(1 << 23), 23);
+        assert_eq!(decode_dict_size(23), Ok(1 << 23));
+
+        // 8 MiB minus 4/16 of it
+        assert_eq!(encode_dict_size(6 << 20), 23 | 4 << 5);
+        assert_eq!(decode_dict_size(23 | 4 << 5), Ok(6 << 20));
+
+        // Rounded up to the next size that can be coded
+        assert_eq!(
+            decode_dict_size(encode_dict_size((6 << 20) + 1)),
+            Ok(13 << 19)
+        );
+        assert_eq!(encode_dict_size(0), 12);
+        assert_eq!(encode_dict_size(u32::MAX), 29);
+
+        for coded in [11, 30, 31] {
+            assert_eq!(decode_dict_size(coded), Err(LzipError::InvalidDictSize));
+        }
+
+        for dict_size in [4096, 4097, 65_535, 1 << 20, 3_000_000, DICT_SIZE_MAX] {
+            let decoded = decode_dict_size(encode_dict_size(dict_size)).unwrap();
+            assert!(decoded >= dict_size);
+            assert!(decoded - dict_size < decoded / 8);
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let header = encode_header(1 << 16);
+        assert_eq!(header, *b"LZIP\x01\x10");
+        assert_eq!(parse_header(&header), Ok(1 << 16));
+
+        assert_eq!(
+            parse_header(b"LZIQ\x01\x10"),
+            Err(LzipError::InvalidHeaderMagic)
+        );
+        assert_eq!(
+            parse_header(b"LZIP\x00\x10"),
+            Err(LzipError::UnsupportedVersion(0))
+        );
+    }
+
+    #[test]
+    fn test_trailer() {
+        let trailer = LzipTrailer {
+            crc32: 0x1234_5678,
+            data_size: 5,
+            member_size: 36,
+        };
+        let encoded = trailer.encode();
+        assert_eq!(encoded[..4], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(LzipTrailer::parse(&encoded), trailer);
+
+        let mut actual = trailer;
+        assert_eq!(trailer.validate(&actual), Ok(()));
+        actual.member_size += 1;
+        assert_eq!(
+            trailer.validate(&actual),
+            Err(LzipError::MemberSizeMismatch)
+        );
+    }
+}
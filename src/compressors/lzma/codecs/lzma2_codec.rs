@@ -0,0 +1,489 @@
+//! # LZMA2 chunks
+//!
+//! LZMA2 splits LZMA data into chunks, so that incompressible data can be stored as is and
+//! the encoder state can be reset along the way. Each chunk starts with a control byte:
+//!
+//! - `0x00`: The end of the stream.
+//! - `0x01`: An uncompressed chunk, with a dictionary reset.
+//! - `0x02`: An uncompressed chunk, without a dictionary reset.
+//! - `0x80..=0xFF`: An LZMA chunk. Bits 0-4 are bits 16-20 of the uncompressed size minus one,
+//!   and bits 5-6 are the reset level:
+//!   - 0: Nothing is reset.
+//!   - 1: The state and probabilities are reset.
+//!   - 2: The state and probabilities are reset, and new properties follow.
+//!   - 3: Same as above, but the dictionary is also reset.
+//!
+//! Uncompressed chunks are followed by their size minus one as a big endian u16, and then the data.
+//!
+//! LZMA chunks are followed by the low 16 bits of the uncompressed size minus one and the compressed
+//! size minus one, both as big endian u16, then the properties byte if it is being reset, and then
+//! the range coded data. The range coder is restarted for every LZMA chunk, and chunks don't use
+//! end of stream markers.
+
+use std::io::{self, Read, Take, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::{Error, ErrorKind};
+
+use super::{
+    header_codec::{parse_props_from_u8, props_to_u8, LzmaHeaderProps},
+    length_codec::MATCH_LEN_MAX,
+    lzma_stream_codec::{
+        data_buffers::{DecoderDataBuffer, EncoderBuffer},
+        encoders::{match_finding::MatchFinder, LZMAEncoderInput, LZMAInstructionPicker},
+        DecodeOutcome, LZMACodecDecoder, LZMACodecEncoder,
+    },
+    range_codec::{RangeDecoder, RangeEncoder},
+};
+
+/// The maximum uncompressed size of a single chunk.
+pub const UNCOMPRESSED_SIZE_MAX: u32 = 2 << 20;
+
+/// The maximum compressed size of a single chunk.
+pub const COMPRESSED_SIZE_MAX: u32 = 64 << 10;
+
+/// Chunks are finished once they pass these limits, which leaves room for one more packet.
+const UNCOMPRESSED_LIMIT: u32 = UNCOMPRESSED_SIZE_MAX - MATCH_LEN_MAX as u32;
+const COMPRESSED_LIMIT: u32 = COMPRESSED_SIZE_MAX - 26;
+
+/// The amount of history the encoder input needs on top of the dictionary, so that a chunk that
+/// didn't compress can be copied out as uncompressed chunks.
+///
+/// A chunk is only stored uncompressed when it's smaller than its compressed size, but the
+/// instruction picker may also have looked ahead past the end of the chunk.
+pub const ENCODER_EXTRA_HISTORY: u32 = COMPRESSED_SIZE_MAX * 2;
+
+const CONTROL_END: u8 = 0x00;
+const CONTROL_UNCOMPRESSED_RESET_DICT: u8 = 0x01;
+const CONTROL_UNCOMPRESSED: u8 = 0x02;
+const CONTROL_LZMA: u8 = 0x80;
+const CONTROL_LZMA_RESET_STATE: u8 = 0x80 | (1 << 5);
+const CONTROL_LZMA_RESET_PROPS: u8 = 0x80 | (2 << 5);
+const CONTROL_LZMA_RESET_DICT: u8 = 0x80 | (3 << 5);
+
+fn invalid_chunk(message: &'static str) -> io::Error {
+    Error::with_message(ErrorKind::InvalidLzma2Chunk, message).into()
+}
+
+/// Get the size of the chunk at the start of `input`, including its header, or `None` if the header
+/// isn't complete. The end of the stream is a 1 byte chunk, and so is an invalid control byte.
+pub(crate) fn chunk_size(input: &[u8]) -> Option<usize> {
+    let control = *input.first()?;
+    let (header_size, data_size) = if control >= CONTROL_LZMA {
+        let header_size = if control >= CONTROL_LZMA_RESET_PROPS {
+            6
+        } else {
+            5
+        };
+        (header_size, input.get(3..5)?)
+    } else if control == CONTROL_UNCOMPRESSED_RESET_DICT || control == CONTROL_UNCOMPRESSED {
+        (3, input.get(1..3)?)
+    } else {
+        return Some(1);
+    };
+
+    Some(header_size + u16::from_be_bytes([data_size[0], data_size[1]]) as usize + 1)
+}
+
+/// Check that the properties are valid for LZMA2, which limits lc + lp to 4.
+pub fn are_props_valid(props: &LzmaHeaderProps) -> bool {
+    props.lc + props.lp <= 4 && props.pb <= 4
+}
+
+pub struct LZMA2CodecEncoder<Mode: LZMAInstructionPicker> {
+    encoder: LZMACodecEncoder<Mode>,
+    props: u8,
+
+    /// The range encoder for the current chunk. It writes to a buffer, as the chunk header
+    /// can only be written once the compressed size is known.
+    rc: Option<RangeEncoder<Vec<u8>>>,
+    chunk_start: u64,
+
+    need_dict_reset: bool,
+    need_props: bool,
+    need_state_reset: bool,
+}
+
+impl<Mode: LZMAInstructionPicker> LZMA2CodecEncoder<Mode> {
+    /// The encoder input must have been created with at least `ENCODER_EXTRA_HISTORY` extra history.
+    pub fn new(encoder: LZMACodecEncoder<Mode>, props: &LzmaHeaderProps) -> Self {
+        debug_assert!(are_props_valid(props));
+
+        Self {
+            chunk_start: encoder.position(),
+            encoder,
+            props: props_to_u8(props),
+            rc: Some(RangeEncoder::new(Vec::new())),
+            need_dict_reset: true,
+            need_props: true,
+            need_state_reset: true,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.encoder.position()
+    }
+
+    /// Load a preset dictionary into the input. The stream then doesn't start with a dictionary reset.
+    ///
+    /// This must be called before anything is encoded.
+    pub fn load_preset_dict(
+        &mut self,
+        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        dict: &[u8],
+    ) {
+        if dict.is_empty() {
+            return;
+        }
+
+        self.encoder.load_preset_dict(input, dict);
+        self.chunk_start = self.encoder.position();
+        self.need_dict_reset = false;
+    }
+
+    /// Encode a single packet into the current chunk, writing the chunk to `output` once it's full.
+    pub fn encode_one_packet(
+        &mut self,
+        output: &mut impl Write,
+        input: &mut LZMAEncoderInput<impl MatchFinder>,
+    ) -> io::Result<u32> {
+        let rc = self
+            .rc
+            .as_mut()
+            .expect("LZMA2 encoder used after finishing");
+        let len = self.encoder.encode_one_packet(rc, input)?;
+
+        let uncompressed_size = self.encoder.position() - self.chunk_start;
+        let compressed_size = rc.inner().len() as u64 + rc.pending_size() as u64;
+        if uncompressed_size > UNCOMPRESSED_LIMIT as u64
+            || compressed_size > COMPRESSED_LIMIT as u64
+        {
+            self.write_chunk(output, input)?;
+        }
+
+        Ok(len)
+    }
+
+    /// Write out the current chunk, if it contains any data. If the data didn't compress,
+    /// it gets written as uncompressed chunks instead.
+    pub fn write_chunk(
+        &mut self,
+        output: &mut impl Write,
+        input: &LZMAEncoderInput<impl MatchFinder>,
+    ) -> io::Result<()> {
+        let uncompressed_size = self.encoder.position() - self.chunk_start;
+        if uncompressed_size == 0 {
+            return Ok(());
+        }
+
+        let rc = self.rc.take().expect("LZMA2 encoder used after finishing");
+        let mut compressed = rc.finish()?;
+
+        if compressed.len() as u64 + 2 < uncompressed_size {
+            self.write_lzma_chunk(output, uncompressed_size as u32, &compressed)?;
+        } else {
+            // The decoder won't see the packets in this chunk, so the encoder has to start over.
+            // This also includes any data the instruction picker looked ahead at.
+            self.encoder.reset(input);
+            let uncompressed_size = self.encoder.position() - self.chunk_start;
+            self.write_uncompressed_chunks(output, input, uncompressed_size as u32)?;
+        }
+
+        self.chunk_start = self.encoder.position();
+
+        compressed.clear();
+        self.rc = Some(RangeEncoder::new(compressed));
+
+        Ok(())
+    }
+
+    fn write_lzma_chunk(
+        &mut self,
+        output: &mut impl Write,
+        uncompressed_size: u32,
+        compressed: &[u8],
+    ) -> io::Result<()> {
+        debug_assert!(uncompressed_size <= UNCOMPRESSED_SIZE_MAX);
+        debug_assert!(compressed.len() <= COMPRESSED_SIZE_MAX as usize);
+
+        let control = if self.need_props {
+            if self.need_dict_reset {
+                CONTROL_LZMA_RESET_DICT
+            } else {
+                CONTROL_LZMA_RESET_PROPS
+            }
+        } else if self.need_state_reset {
+            CONTROL_LZMA_RESET_STATE
+        } else {
+            CONTROL_LZMA
+        };
+
+        let uncompressed_size = uncompressed_size - 1;
+        output.write_u8(control | (uncompressed_size >> 16) as u8)?;
+        output.write_u16::<BigEndian>(uncompressed_size as u16)?;
+        output.write_u16::<BigEndian>((compressed.len() - 1) as u16)?;
+
+        if self.need_props {
+            output.write_u8(self.props)?;
+        }
+
+        output.write_all(compressed)?;
+
+        self.need_dict_reset = false;
+        self.need_props = false;
+        self.need_state_reset = false;
+
+        Ok(())
+    }
+
+    fn write_uncompressed_chunks(
+        &mut self,
+        output: &mut impl Write,
+        input: &LZMAEncoderInput<impl MatchFinder>,
+        uncompressed_size: u32,
+    ) -> io::Result<()> {
+        // The chunk's data is behind the current input position
+        let history_start = (input.pos() - self.chunk_start) as i32;
+        let mut written = 0;
+
+        let mut data = Vec::with_capacity(uncompressed_size.min(COMPRESSED_SIZE_MAX) as usize);
+        while written < uncompressed_size {
+            let chunk_size = (uncompressed_size - written).min(COMPRESSED_SIZE_MAX);
+
+            let control = if self.need_dict_reset {
+                CONTROL_UNCOMPRESSED_RESET_DICT
+            } else {
+                CONTROL_UNCOMPRESSED
+            };
+            output.write_u8(control)?;
+            output.write_u16::<BigEndian>((chunk_size - 1) as u16)?;
+
+            data.clear();
+            for i in written..written + chunk_size {
+                data.push(input.buffer().get_byte(i as i32 - history_start));
+            }
+            output.write_all(&data)?;
+
+            written += chunk_size;
+            self.need_dict_reset = false;
+        }
+
+        self.need_state_reset = true;
+
+        Ok(())
+    }
+
+    /// Write the remaining chunk and the end of stream byte. All the input must have been encoded beforehand.
+    pub fn finish(
+        &mut self,
+        output: &mut impl Write,
+        input: &LZMAEncoderInput<impl MatchFinder>,
+    ) -> io::Result<()> {
+        self.write_chunk(output, input)?;
+
+        // The new range encoder never gets used, but it still needs finishing
+        if let Some(rc) = self.rc.take() {
+            rc.finish()?;
+        }
+
+        output.write_u8(CONTROL_END)
+    }
+}
+
+impl<Mode: LZMAInstructionPicker> Drop for LZMA2CodecEncoder<Mode> {
+    fn drop(&mut self) {
+        // Avoid the range encoder's unfinished panic if the stream was abandoned, as the chunk is
+        // only a buffer and nothing would get truncated.
+        if let Some(rc) = self.rc.take() {
+            let _ = rc.finish();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    /// The next byte is a control byte.
+    Header,
+    Lzma {
+        remaining: u32,
+    },
+    Uncompressed {
+        remaining: u32,
+    },
+    Finished,
+}
+
+pub struct LZMA2CodecDecoder {
+    decoder: LZMACodecDecoder,
+    chunk: ChunkState,
+
+    need_dict_reset: bool,
+    need_props: bool,
+}
+
+impl LZMA2CodecDecoder {
+    pub fn new() -> Self {
+        Self {
+            // The properties are always set by the first LZMA chunk
+            decoder: LZMACodecDecoder::new(0, 0, 0),
+            chunk: ChunkState::Header,
+            need_dict_reset: true,
+            need_props: true,
+        }
+    }
+
+    /// Load a preset dictionary into the output. The stream then doesn't need to start with a dictionary reset.
+    ///
+    /// This must be called before anything is decoded.
+    pub fn load_preset_dict(&mut self, output: &mut DecoderDataBuffer, dict: &[u8]) {
+        if dict.is_empty() {
+            return;
+        }
+
+        output.load_preset_dict(dict);
+        self.need_dict_reset = false;
+    }
+
+    /// Decode LZMA packets until at least `max_bytes` bytes were appended, the output is full or the
+    /// chunk ends, or copy up to `MATCH_LEN_MAX` bytes of an uncompressed chunk.
+    /// Reading chunk headers doesn't decode anything, so this may return without appending any data.
+    ///
+    /// The limit of the `Take` reader is managed by the decoder, to keep the range decoder within each chunk.
+    pub fn decode_until(
+        &mut self,
+        rc: &mut RangeDecoder<Take<impl Read>>,
+        output: &mut DecoderDataBuffer,
+        max_bytes: u64,
+    ) -> io::Result<DecodeOutcome> {
+        match self.chunk {
+            ChunkState::Header => self.start_chunk(rc, output),
+            ChunkState::Lzma { remaining } => self.decode_lzma(rc, output, remaining, max_bytes),
+            ChunkState::Uncompressed { remaining } => {
+                let mut data = [0; MATCH_LEN_MAX];
+                let data = &mut data[..(remaining as usize).min(MATCH_LEN_MAX)];
+                rc.inner().read_exact(data)?;
+                output.append_bytes(data);
+
+                let remaining = remaining - data.len() as u32;
+                self.chunk = if remaining == 0 {
+                    ChunkState::Header
+                } else {
+                    ChunkState::Uncompressed { remaining }
+                };
+
+                Ok(DecodeOutcome::Packet)
+            }
+            ChunkState::Finished => Ok(DecodeOutcome::EndOfStream),
+        }
+    }
+
+    fn start_chunk(
+        &mut self,
+        rc: &mut RangeDecoder<Take<impl Read>>,
+        output: &mut DecoderDataBuffer,
+    ) -> io::Result<DecodeOutcome> {
+        let input = rc.inner();
+        input.set_limit(u64::MAX);
+
+        let control = input.read_u8()?;
+        if control == CONTROL_END {
+            self.chunk = ChunkState::Finished;
+            return Ok(DecodeOutcome::EndOfStream);
+        }
+
+        if control >= CONTROL_LZMA_RESET_DICT || control == CONTROL_UNCOMPRESSED_RESET_DICT {
+            self.need_props = true;
+            self.need_dict_reset = false;
+            output.reset_dict();
+        } else if self.need_dict_reset {
+            return Err(invalid_chunk(
+                "LZMA2 stream doesn't start with a dictionary reset",
+            ));
+        }
+
+        if control >= CONTROL_LZMA {
+            let uncompressed_size =
+                ((control as u32 & 0x1F) << 16) + input.read_u16::<BigEndian>()? as u32 + 1;
+            let compressed_size = input.read_u16::<BigEndian>()? as u64 + 1;
+
+            if control >= CONTROL_LZMA_RESET_PROPS {
+                let props = parse_props_from_u8(input.read_u8()?)?;
+                if !are_props_valid(&props) {
+                    return Err(Error::with_message(
+                        ErrorKind::InvalidProperties,
+                        "Invalid LZMA2 properties",
+                    )
+                    .into());
+                }
+
+                self.decoder
+                    .reset_with_props(props.lc as u32, props.lp as u32, props.pb as u32);
+                self.need_props = false;
+            } else if self.need_props {
+                return Err(invalid_chunk("LZMA2 chunk is missing the LZMA properties"));
+            } else if control >= CONTROL_LZMA_RESET_STATE {
+                self.decoder.reset();
+            }
+
+            input.set_limit(compressed_size);
+            rc.reset()?;
+
+            self.chunk = ChunkState::Lzma {
+                remaining: uncompressed_size,
+            };
+        } else if control > CONTROL_UNCOMPRESSED {
+            return Err(invalid_chunk("Invalid LZMA2 control byte"));
+        } else {
+            let size = input.read_u16::<BigEndian>()? as u32 + 1;
+            input.set_limit(size as u64);
+
+            self.chunk = ChunkState::Uncompressed { remaining: size };
+        }
+
+        Ok(DecodeOutcome::Packet)
+    }
+
+    fn decode_lzma(
+        &mut self,
+        rc: &mut RangeDecoder<Take<impl Read>>,
+        output: &mut DecoderDataBuffer,
+        remaining: u32,
+        max_bytes: u64,
+    ) -> io::Result<DecodeOutcome> {
+        let start = output.decoded_bytes();
+        let max_bytes = max_bytes.min(remaining as u64);
+        if self.decoder.decode_until(rc, output, max_bytes)? == DecodeOutcome::EndOfStream {
+            return Err(invalid_chunk("LZMA2 chunks can't contain end markers"));
+        }
+
+        let decoded = output.decoded_bytes() - start;
+        if decoded > remaining as u64 {
+            return Err(invalid_chunk(
+                "LZMA2 chunk is longer than its uncompressed size",
+            ));
+        }
+
+        let remaining = remaining - decoded as u32;
+        if remaining == 0 {
+            rc.normalize()?;
+            if !rc.is_finished() || rc.inner().limit() != 0 {
+                return Err(invalid_chunk(
+                    "LZMA2 chunk doesn't match its compressed size",
+                ));
+            }
+
+            self.chunk = ChunkState::Header;
+        } else {
+            self.chunk = ChunkState::Lzma { remaining };
+        }
+
+        Ok(DecodeOutcome::Packet)
+    }
+}
+
+impl Default for LZMA2CodecDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
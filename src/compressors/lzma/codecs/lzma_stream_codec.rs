@@ -3,22 +3,26 @@ pub mod encoders;
 mod prices;
 mod state;
 
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 
 use self::{
-    data_buffers::DecoderDataBuffer,
+    data_buffers::{DecoderOutput, EncoderBuffer, EncoderSliceBuffer},
     encoders::{
-        match_finding::{Match, MatchFinder},
-        EncodeInstruction, LZMAEncoderInput, LZMAInstructionPicker, LiteralCtx,
+        match_finding::{AnyMatchFinder, Match, MatchFinder},
+        AnyInstructionPicker, EncodeInstruction, LZMAEncoderInput, LZMAInstructionPicker,
+        LiteralCtx,
     },
     prices::EncoderPriceCalc,
     state::State,
 };
 
+use crate::compressors::lzma::options::LzmaOptions;
+use crate::error::{Error, ErrorKind};
+
 use super::{
     length_codec::{LengthCodecDecoder, LengthCodecEncoder, LengthValueCodec},
     literals_codec::{LiteralCodecDecoder, LiteralCodecEncoder},
-    range_codec::{RangeDecoder, RangeEncPrice, RangeEncProbability, RangeEncoder},
+    range_codec::{RangeDecode, RangeEncPrice, RangeEncProbability, RangeEncoder},
 };
 
 // TODO: Clean up all these constants
@@ -35,6 +39,8 @@ const ALIGN_BITS: usize = 4;
 const ALIGN_SIZE: usize = 1 << ALIGN_BITS;
 const ALIGN_MASK: usize = ALIGN_SIZE - 1;
 
+const END_MARKER_DISTANCE: u32 = u32::MAX;
+
 const DIST_PRICE_UPDATE_INTERVAL: u32 = FULL_DISTANCES as u32;
 const ALIGN_PRICE_UPDATE_INTERVAL: u32 = ALIGN_SIZE as u32;
 
@@ -80,6 +86,20 @@ fn get_dist_slot(dist: u32) -> u32 {
     return (i << 1) + ((dist >> (i - 1)) & 1);
 }
 
+type DistSpecialProbs = (
+    LengthValueCodec<2>,
+    LengthValueCodec<2>,
+    LengthValueCodec<4>,
+    LengthValueCodec<4>,
+    LengthValueCodec<8>,
+    LengthValueCodec<8>,
+    LengthValueCodec<16>,
+    LengthValueCodec<16>,
+    LengthValueCodec<32>,
+    LengthValueCodec<32>,
+);
+
+#[derive(Clone)]
 pub struct LZMACodec {
     pos_mask: u32,
     state: State,
@@ -95,18 +115,7 @@ pub struct LZMACodec {
     // I'm not sure if doing this the static way or the array way is faster
     // But I didn't know that this would be necessary when I initially implemented the const generic
     // TODO: Test the performance of this, as opposed to array indexing and passing around probability array slices.
-    dist_special_probs: (
-        LengthValueCodec<2>,
-        LengthValueCodec<2>,
-        LengthValueCodec<4>,
-        LengthValueCodec<4>,
-        LengthValueCodec<8>,
-        LengthValueCodec<8>,
-        LengthValueCodec<16>,
-        LengthValueCodec<16>,
-        LengthValueCodec<32>,
-        LengthValueCodec<32>,
-    ),
+    dist_special_probs: DistSpecialProbs,
     dist_align_probs: LengthValueCodec<ALIGN_SIZE>,
 }
 
@@ -140,12 +149,47 @@ impl LZMACodec {
             dist_align_probs: LengthValueCodec::new(),
         }
     }
+
+    /// Reset the state and all the probabilities in place.
+    pub fn reset(&mut self) {
+        self.state.reset();
+
+        let probs = self
+            .is_match_probs
+            .iter_mut()
+            .flatten()
+            .chain(self.is_rep_probs.iter_mut())
+            .chain(self.is_rep0_probs.iter_mut())
+            .chain(self.is_rep1_probs.iter_mut())
+            .chain(self.is_rep2_probs.iter_mut())
+            .chain(self.is_rep0_long_probs.iter_mut().flatten());
+        for prob in probs {
+            *prob = RangeEncProbability::new();
+        }
+
+        self.dist_slot_probs
+            .iter_mut()
+            .for_each(LengthValueCodec::reset);
+
+        let special = &mut self.dist_special_probs;
+        special.0.reset();
+        special.1.reset();
+        special.2.reset();
+        special.3.reset();
+        special.4.reset();
+        special.5.reset();
+        special.6.reset();
+        special.7.reset();
+        special.8.reset();
+        special.9.reset();
+
+        self.dist_align_probs.reset();
+    }
 }
 
 pub struct LZMACodecEncoder<Mode: LZMAInstructionPicker> {
     codec: LZMACodec,
     position: u64,
-    dict_size: u32,
 
     literal_encoder: LiteralCodecEncoder,
     match_len_encoder: LengthCodecEncoder,
@@ -156,32 +200,98 @@ pub struct LZMACodecEncoder<Mode: LZMAInstructionPicker> {
     picker: Mode,
 }
 
+impl LZMACodecEncoder<AnyInstructionPicker> {
+    /// Validate the options, then create an encoder with the mode they choose,
+    /// along with an input that uses their match finder.
+    pub fn from_options(
+        options: &LzmaOptions,
+    ) -> io::Result<(Self, LZMAEncoderInput<AnyMatchFinder>)> {
+        options.validate()?;
+
+        let encoder = Self::new(options, options.new_instruction_picker());
+        let input = LZMAEncoderInput::new(options.new_match_finder(), options.dict_size);
+        Ok((encoder, input))
+    }
+
+    /// Like `from_options()`, but with an input that borrows all of `data` instead of
+    /// copying it into a buffer as it's appended.
+    pub fn from_options_with_slice<'a>(
+        options: &LzmaOptions,
+        data: &'a [u8],
+    ) -> io::Result<(
+        Self,
+        LZMAEncoderInput<AnyMatchFinder, EncoderSliceBuffer<'a>>,
+    )> {
+        options.validate()?;
+
+        let encoder = Self::new(options, options.new_instruction_picker());
+        let input =
+            LZMAEncoderInput::from_slice(options.new_match_finder(), options.dict_size, data);
+        Ok((encoder, input))
+    }
+}
+
 impl<Mode: LZMAInstructionPicker> LZMACodecEncoder<Mode> {
-    // TODO: Simplify these args by using structs
-    pub fn new(dict_size: u32, lc: u32, lp: u32, pb: u32, nice_len: u32, picker: Mode) -> Self {
+    /// Create an encoder with a specific instruction picker. The options must already be validated.
+    pub fn new(options: &LzmaOptions, picker: Mode) -> Self {
         Self {
-            codec: LZMACodec::new(pb),
-            position: dict_size as u64,
-            dict_size,
+            codec: LZMACodec::new(options.pb),
+            position: 0,
 
-            literal_encoder: LiteralCodecEncoder::new(lc, lp),
-            match_len_encoder: LengthCodecEncoder::new(pb, nice_len),
-            rep_len_encoder: LengthCodecEncoder::new(pb, nice_len),
+            literal_encoder: LiteralCodecEncoder::new(options.lc, options.lp),
+            match_len_encoder: LengthCodecEncoder::new(options.pb, options.nice_len),
+            rep_len_encoder: LengthCodecEncoder::new(options.pb, options.nice_len),
 
-            data: LZMAEncoderData::new(dict_size),
+            data: LZMAEncoderData::new(options.dict_size),
 
             picker,
         }
     }
 
     pub fn position(&self) -> u64 {
-        self.position - self.dict_size as u64
+        self.position
+    }
+
+    /// Reset the state and probabilities, as if a new stream started at the current input position.
+    ///
+    /// Any input that the instruction picker already looked ahead at is counted as encoded,
+    /// so it must be stored by other means, e.g. as an LZMA2 uncompressed chunk.
+    pub fn reset(&mut self, input: &LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>) {
+        self.codec.reset();
+        self.literal_encoder.reset();
+        self.match_len_encoder.reset();
+        self.rep_len_encoder.reset();
+
+        // Make the prices get recalculated for the new probabilities
+        self.data.dist_price_count = 0;
+        self.data.align_price_count = 0;
+
+        self.picker.reset();
+        self.position = input.pos();
+    }
+
+    /// Load a preset dictionary into the input, which the encoded data can then reference.
+    /// The position of the encoder includes the preset dictionary bytes.
+    ///
+    /// This must be called before anything is encoded.
+    pub fn load_preset_dict(
+        &mut self,
+        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        dict: &[u8],
+    ) {
+        assert!(
+            self.position == 0,
+            "Preset dictionaries must be loaded before any data"
+        );
+
+        input.load_preset_dict(dict);
+        self.position = input.pos();
     }
 
     /// Get the next instruction, progressing the input buffer forwards by the according ammount
     fn get_next_instruction(
         &mut self,
-        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        input: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
     ) -> EncodeInstruction {
         let mut price_calc = EncoderPriceCalc {
             data: &mut self.data,
@@ -210,9 +320,9 @@ impl<Mode: LZMAInstructionPicker> LZMACodecEncoder<Mode> {
     pub fn encode_one_packet(
         &mut self,
         rc: &mut RangeEncoder<impl Write>,
-        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        input: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
     ) -> io::Result<u32> {
-        let pos = self.position - input.dict_size() as u64;
+        let pos = self.position;
 
         let pos_state = pos as u32 & self.codec.pos_mask;
         let state_idx = self.codec.state.get_idx() as usize;
@@ -248,6 +358,24 @@ impl<Mode: LZMAInstructionPicker> LZMACodecEncoder<Mode> {
         Ok(instruction.length())
     }
 
+    /// Encode the end of stream marker. All the input must have been encoded beforehand.
+    pub fn encode_end_marker(&mut self, rc: &mut RangeEncoder<impl Write>) -> io::Result<()> {
+        let pos_state = self.position() as u32 & self.codec.pos_mask;
+        let state_idx = self.codec.state.get_idx() as usize;
+
+        let is_match_prob = &mut self.codec.is_match_probs[state_idx][pos_state as usize];
+        rc.encode_bit(is_match_prob, 1)?;
+
+        let is_rep_prob = &mut self.codec.is_rep_probs[state_idx];
+        rc.encode_bit(is_rep_prob, 0)?;
+
+        let marker = Match {
+            distance: END_MARKER_DISTANCE,
+            len: MATCH_LEN_MIN as u32,
+        };
+        self.encode_match(rc, marker, pos_state)
+    }
+
     fn encode_literal(
         &mut self,
         rc: &mut RangeEncoder<impl Write>,
@@ -399,6 +527,21 @@ impl LZMAEncoderData {
     }
 }
 
+/// The result of decoding packets from the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeOutcome {
+    /// A literal or a match was decoded and appended to the output buffer.
+    /// From `decode_until()`, decoding stopped before the end of the stream.
+    Packet,
+    /// The end of stream marker was decoded. Nothing was appended to the output buffer.
+    EndOfStream,
+}
+
+fn invalid_distance() -> io::Error {
+    Error::new(ErrorKind::DistanceBeyondWindow).into()
+}
+
+#[derive(Clone)]
 pub struct LZMACodecDecoder {
     codec: LZMACodec,
 
@@ -418,21 +561,139 @@ impl LZMACodecDecoder {
         }
     }
 
+    /// Get the memory usage of the decoder in bytes, not including the dictionary buffer
+    pub fn get_mem_usage(lc: u32, lp: u32) -> u64 {
+        std::mem::size_of::<Self>() as u64 + LiteralCodecDecoder::get_mem_usage(lc, lp)
+    }
+
+    /// Reset the state and probabilities in place, keeping the current properties.
+    pub fn reset(&mut self) {
+        self.codec.reset();
+        self.literal_decoder.reset();
+        self.match_len_decoder.reset();
+        self.rep_len_decoder.reset();
+    }
+
+    /// Reset the state and probabilities with new properties.
+    pub fn reset_with_props(&mut self, lc: u32, lp: u32, pb: u32) {
+        self.codec.reset();
+        self.codec.pos_mask = (1 << pb) - 1;
+        self.literal_decoder.reset_with_props(lc, lp);
+        self.match_len_decoder.reset_with_pb(pb);
+        self.rep_len_decoder.reset_with_pb(pb);
+    }
+
     pub fn decode_one_packet(
         &mut self,
-        rc: &mut RangeDecoder<impl Read>,
-        output: &mut DecoderDataBuffer,
-    ) -> io::Result<()> {
-        let pos_state = output.position() as u32 & self.codec.pos_mask;
-        let index = self.codec.state.get_idx() as usize;
+        rc: &mut impl RangeDecode,
+        output: &mut impl DecoderOutput,
+    ) -> io::Result<DecodeOutcome> {
+        let mut packets = PacketDecoder::new(self);
+        let result = packets.decode_packet(rc, output);
+        self.codec.state = packets.state;
+        result
+    }
+
+    /// Decode packets until at least `max_bytes` bytes were appended to the output, the output is full,
+    /// or the end of stream marker is found. The last match can go past `max_bytes`, so callers with
+    /// a known uncompressed size still have to check it.
+    ///
+    /// This decodes the same as calling `decode_one_packet()` in a loop, but the state and reps are
+    /// copied into a local and the probability tables are borrowed once for the whole loop, instead
+    /// of being reached through `self` for every packet.
+    pub fn decode_until(
+        &mut self,
+        rc: &mut impl RangeDecode,
+        output: &mut impl DecoderOutput,
+        max_bytes: u64,
+    ) -> io::Result<DecodeOutcome> {
+        let end = output.position().saturating_add(max_bytes);
+        let mut packets = PacketDecoder::new(self);
+
+        let result = loop {
+            if output.position() >= end || output.is_full() {
+                break Ok(DecodeOutcome::Packet);
+            }
+
+            match packets.decode_packet(rc, output) {
+                Ok(DecodeOutcome::Packet) => {}
+                result => break result,
+            }
+        };
+
+        self.codec.state = packets.state;
+        result
+    }
+}
+
+/// The decoder split into its parts, so a decoding loop can keep the state and reps in a local
+/// and hold on to each probability table directly.
+struct PacketDecoder<'a> {
+    state: State,
+    pos_mask: u32,
+
+    is_match_probs: &'a mut [[RangeEncProbability; POS_STATES_MAX]; state::STATES],
+    is_rep_probs: &'a mut [RangeEncProbability; state::STATES],
+    is_rep0_probs: &'a mut [RangeEncProbability; state::STATES],
+    is_rep1_probs: &'a mut [RangeEncProbability; state::STATES],
+    is_rep2_probs: &'a mut [RangeEncProbability; state::STATES],
+    is_rep0_long_probs: &'a mut [[RangeEncProbability; POS_STATES_MAX]; state::STATES],
+
+    dist_slot_probs: &'a mut [LengthValueCodec<DIST_SLOTS>; DIST_STATES],
+    dist_special_probs: &'a mut DistSpecialProbs,
+    dist_align_probs: &'a mut LengthValueCodec<ALIGN_SIZE>,
+
+    literal_decoder: &'a mut LiteralCodecDecoder,
+    match_len_decoder: &'a mut LengthCodecDecoder,
+    rep_len_decoder: &'a mut LengthCodecDecoder,
+}
+
+impl<'a> PacketDecoder<'a> {
+    fn new(decoder: &'a mut LZMACodecDecoder) -> Self {
+        let LZMACodecDecoder {
+            codec,
+            literal_decoder,
+            match_len_decoder,
+            rep_len_decoder,
+        } = decoder;
 
-        let prob = &mut self.codec.is_match_probs[index][pos_state as usize];
+        Self {
+            state: codec.state,
+            pos_mask: codec.pos_mask,
+
+            is_match_probs: &mut codec.is_match_probs,
+            is_rep_probs: &mut codec.is_rep_probs,
+            is_rep0_probs: &mut codec.is_rep0_probs,
+            is_rep1_probs: &mut codec.is_rep1_probs,
+            is_rep2_probs: &mut codec.is_rep2_probs,
+            is_rep0_long_probs: &mut codec.is_rep0_long_probs,
+
+            dist_slot_probs: &mut codec.dist_slot_probs,
+            dist_special_probs: &mut codec.dist_special_probs,
+            dist_align_probs: &mut codec.dist_align_probs,
+
+            literal_decoder,
+            match_len_decoder,
+            rep_len_decoder,
+        }
+    }
+
+    #[inline(always)]
+    fn decode_packet(
+        &mut self,
+        rc: &mut impl RangeDecode,
+        output: &mut impl DecoderOutput,
+    ) -> io::Result<DecodeOutcome> {
+        let pos_state = output.position() as u32 & self.pos_mask;
+        let index = self.state.get_idx() as usize;
+
+        let prob = &mut self.is_match_probs[index][pos_state as usize];
         let bit = rc.decode_bit(prob)?;
 
         if bit == 0 {
             self.decode_literal(rc, output)?;
         } else {
-            let prob = &mut self.codec.is_rep_probs[index];
+            let prob = &mut self.is_rep_probs[index];
 
             let match_ = if rc.decode_bit(prob)? == 0 {
                 self.decode_match(pos_state, rc)?
@@ -440,16 +701,24 @@ impl LZMACodecDecoder {
                 self.decode_rep_match(pos_state, rc)?
             };
 
+            // The end of stream marker is encoded as a match with the maximum possible distance
+            if match_.distance == END_MARKER_DISTANCE {
+                return Ok(DecodeOutcome::EndOfStream);
+            }
+
+            if !output.is_valid_distance(match_.distance) {
+                return Err(invalid_distance());
+            }
             output.append_match(match_.distance, match_.len);
         }
 
-        Ok(())
+        Ok(DecodeOutcome::Packet)
     }
 
     fn decode_literal(
         &mut self,
-        rc: &mut RangeDecoder<impl Read>,
-        output: &mut DecoderDataBuffer,
+        rc: &mut impl RangeDecode,
+        output: &mut impl DecoderOutput,
     ) -> io::Result<()> {
         let last_byte = if output.is_empty() {
             0
@@ -457,11 +726,17 @@ impl LZMACodecDecoder {
             output.get_byte(0)
         };
 
-        let byte = if self.codec.state.is_literal() {
+        let byte = if self.state.is_literal() {
             self.literal_decoder
                 .decode_normal(rc, last_byte, output.position() as usize)?
         } else {
-            let prev_match_byte = output.get_byte(self.codec.state.get_rep(0));
+            // The previous match was checked, unless decoding continued after an error
+            let rep0 = self.state.get_rep(0);
+            if !output.is_valid_distance(rep0) {
+                return Err(invalid_distance());
+            }
+
+            let prev_match_byte = output.get_byte(rep0);
             self.literal_decoder.decode_matched(
                 rc,
                 last_byte,
@@ -471,18 +746,14 @@ impl LZMACodecDecoder {
         };
 
         output.append_byte(byte);
-        self.codec.state.update_literal();
+        self.state.update_literal();
 
         Ok(())
     }
 
-    fn decode_match(
-        &mut self,
-        pos_state: u32,
-        rc: &mut RangeDecoder<impl Read>,
-    ) -> io::Result<Match> {
+    fn decode_match(&mut self, pos_state: u32, rc: &mut impl RangeDecode) -> io::Result<Match> {
         let len = self.match_len_decoder.decode(rc, pos_state)?;
-        let slot_decoder = &mut self.codec.dist_slot_probs[get_dist_state(len)];
+        let slot_decoder = &mut self.dist_slot_probs[get_dist_state(len)];
         let dist_slot = slot_decoder.decode_bit_tree(rc)?;
 
         let distance = if dist_slot < DIST_MODEL_START {
@@ -496,23 +767,23 @@ impl LZMACodecDecoder {
                 rep0 |= self.decode_special_dist_slot(rc, dist_slots_index)?;
             } else {
                 rep0 |= rc.decode_direct_bits(limit as u32 - ALIGN_BITS as u32)? << ALIGN_BITS;
-                rep0 |= self.codec.dist_align_probs.decode_reverse_bit_tree(rc)?;
+                rep0 |= self.dist_align_probs.decode_reverse_bit_tree(rc)?;
             }
 
             rep0
         };
 
-        self.codec.state.update_match(distance);
+        self.state.update_match(distance);
 
         Ok(Match { len, distance })
     }
 
     fn decode_special_dist_slot(
         &mut self,
-        rc: &mut RangeDecoder<impl Read>,
+        rc: &mut impl RangeDecode,
         index: usize,
     ) -> io::Result<u32> {
-        let probs = &mut self.codec.dist_special_probs;
+        let probs = &mut *self.dist_special_probs;
         Ok(match index {
             0 => probs.0.decode_reverse_bit_tree(rc)?,
             1 => probs.1.decode_reverse_bit_tree(rc)?,
@@ -528,31 +799,27 @@ impl LZMACodecDecoder {
         })
     }
 
-    fn decode_rep_match(
-        &mut self,
-        pos_state: u32,
-        rc: &mut RangeDecoder<impl Read>,
-    ) -> io::Result<Match> {
-        let index = self.codec.state.get_idx() as usize;
+    fn decode_rep_match(&mut self, pos_state: u32, rc: &mut impl RangeDecode) -> io::Result<Match> {
+        let index = self.state.get_idx() as usize;
 
-        let prob = &mut self.codec.is_rep0_probs[index];
+        let prob = &mut self.is_rep0_probs[index];
         let rep = if rc.decode_bit(prob)? == 0 {
-            let prob = &mut self.codec.is_rep0_long_probs[index][pos_state as usize];
+            let prob = &mut self.is_rep0_long_probs[index][pos_state as usize];
             if rc.decode_bit(prob)? == 0 {
-                self.codec.state.update_short_rep();
+                self.state.update_short_rep();
                 return Ok(Match {
                     len: 1,
-                    distance: self.codec.state.get_rep(0),
+                    distance: self.state.get_rep(0),
                 });
             }
 
             0
         } else {
-            let prob = &mut self.codec.is_rep1_probs[index];
+            let prob = &mut self.is_rep1_probs[index];
             if rc.decode_bit(prob)? == 0 {
                 1
             } else {
-                let prob = &mut self.codec.is_rep2_probs[index];
+                let prob = &mut self.is_rep2_probs[index];
                 if rc.decode_bit(prob)? == 0 {
                     2
                 } else {
@@ -561,7 +828,7 @@ impl LZMACodecDecoder {
             }
         };
 
-        let distance = self.codec.state.update_long_rep(rep);
+        let distance = self.state.update_long_rep(rep);
         let len = self.rep_len_decoder.decode(rc, pos_state as _)?;
 
         Ok(Match { len, distance })
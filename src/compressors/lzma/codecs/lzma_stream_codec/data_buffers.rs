@@ -4,6 +4,41 @@ use self::cyclic_buffer::CyclicBuffer;
 
 mod cyclic_buffer;
 
+/// The data the encoder reads from. Positions are relative to the encoder's head, so offset 0
+/// is the next byte to encode, and negative offsets are bytes that were already encoded.
+pub trait EncoderBuffer {
+    /// The number of bytes ahead that are currently in the buffer
+    fn forwards_bytes(&self) -> usize;
+
+    /// The number of bytes behind the head that can be read.
+    fn backwards_bytes(&self) -> usize;
+
+    fn pos(&self) -> u64;
+
+    fn skip(&mut self, len: u32);
+
+    fn increment_pos(&mut self);
+
+    /// Get the byte with offset relative to the compress reader head. 0 is the next unread byte.
+    fn get_byte(&self, offset: i32) -> u8;
+
+    /// Same as `get_byte`, except bytes before the start of the stream are read as 0.
+    /// This matches how the decoder treats the previous byte at the start of the stream.
+    fn get_byte_or_zero(&self, offset: i32) -> u8;
+
+    /// Check if a match distance points to bytes that have already been passed in the stream.
+    fn is_distance_valid(&self, distance: u32) -> bool;
+
+    /// Check if bytes ahead match bytes backwards at a certain delta.
+    fn do_bytes_match_at(&self, delta: u32, len: u32) -> bool;
+
+    /// Check if the next `len` bytes match the bytes at a certain delta back.
+    fn is_match_at_least_longer_than(&self, delta: u32, len: u32) -> bool;
+
+    /// Extend a match of `start_len` bytes at a certain delta back, up to `max_len` bytes.
+    fn get_match_length(&self, start_len: u32, delta: u32, max_len: u32) -> u32;
+}
+
 pub struct EncoderDataBuffer {
     compress_pos: u64,
     max_forwards_bytes: u32,
@@ -22,20 +57,6 @@ impl EncoderDataBuffer {
         }
     }
 
-    /// The number of bytes ahead that are currently in the buffer
-    pub fn forwards_bytes(&self) -> usize {
-        (self.buf.pos() - self.compress_pos) as usize
-    }
-
-    /// The number of dictionary bytes. Can be higher than dict_size if fowards_bytes() is smaller than max_forwards_bytes.
-    pub fn backwards_bytes(&self) -> usize {
-        self.buf.capacity() - self.forwards_bytes()
-    }
-
-    pub fn pos(&self) -> u64 {
-        self.compress_pos
-    }
-
     /// The number of free bytes that could safely be appended without overwriting the dictionary
     pub fn available_append_bytes(&self) -> usize {
         self.max_forwards_bytes as usize - self.forwards_bytes()
@@ -54,7 +75,27 @@ impl EncoderDataBuffer {
         self.buf.push_slice(input)
     }
 
-    pub fn skip(&mut self, len: u32) {
+    /// Index of the offset for the underlying cyclical buffer
+    fn get_byte_index(&self, offset: i32) -> usize {
+        (self.forwards_bytes() as i32 - offset - 1) as usize
+    }
+}
+
+impl EncoderBuffer for EncoderDataBuffer {
+    fn forwards_bytes(&self) -> usize {
+        (self.buf.pos() - self.compress_pos) as usize
+    }
+
+    /// The number of dictionary bytes. Can be higher than dict_size if fowards_bytes() is smaller than max_forwards_bytes.
+    fn backwards_bytes(&self) -> usize {
+        self.buf.capacity() - self.forwards_bytes()
+    }
+
+    fn pos(&self) -> u64 {
+        self.compress_pos
+    }
+
+    fn skip(&mut self, len: u32) {
         debug_assert!(
             len <= self.forwards_bytes() as u32,
             "len: {}, forwards_bytes(): {}",
@@ -65,7 +106,7 @@ impl EncoderDataBuffer {
         self.compress_pos += len as u64;
     }
 
-    pub fn increment_pos(&mut self) {
+    fn increment_pos(&mut self) {
         debug_assert!(
             self.forwards_bytes() > 0,
             "forwards_bytes(): {}",
@@ -75,20 +116,26 @@ impl EncoderDataBuffer {
         self.compress_pos += 1;
     }
 
-    /// Index of the offset for the underlying cyclical buffer
-    fn get_byte_index(&self, offset: i32) -> usize {
-        (self.forwards_bytes() as i32 - offset - 1) as usize
+    fn get_byte(&self, offset: i32) -> u8 {
+        self.buf.get_relative(self.get_byte_index(offset))
     }
 
-    /// Get the byte with offset relative to the compress reader head. 0 is the next unread byte.
-    pub fn get_byte(&self, offset: i32) -> u8 {
-        self.buf.get_relative(self.get_byte_index(offset))
+    fn get_byte_or_zero(&self, offset: i32) -> u8 {
+        if offset < 0 && -(offset as i64) as u64 > self.compress_pos {
+            0
+        } else {
+            self.get_byte(offset)
+        }
+    }
+
+    fn is_distance_valid(&self, distance: u32) -> bool {
+        (distance as u64) < self.compress_pos
     }
 
     /// Check if bytes ahead match bytes backwards at a certain delta.
     ///
     /// TODO: Check if we need to use modulo of the delta/len for cases when len is bigger than delta.
-    pub fn do_bytes_match_at(&self, delta: u32, len: u32) -> bool {
+    fn do_bytes_match_at(&self, delta: u32, len: u32) -> bool {
         debug_assert!(
             delta as usize <= self.backwards_bytes(),
             "delta: {}, backwards_bytes(): {}",
@@ -109,7 +156,7 @@ impl EncoderDataBuffer {
         front == back
     }
 
-    pub fn is_match_at_least_longer_than(&self, delta: u32, len: u32) -> bool {
+    fn is_match_at_least_longer_than(&self, delta: u32, len: u32) -> bool {
         let src_index = self.get_byte_index(0);
         let src = self.buf.as_slices_after(src_index + 1);
         let dst_index = self.get_byte_index(-(delta as i32) - 1);
@@ -139,7 +186,7 @@ impl EncoderDataBuffer {
         true
     }
 
-    pub fn get_match_length(&self, start_len: u32, delta: u32, max_len: u32) -> u32 {
+    fn get_match_length(&self, start_len: u32, delta: u32, max_len: u32) -> u32 {
         // The below code is equivalent to
         //
         // ```
@@ -211,6 +258,100 @@ impl EncoderDataBuffer {
     }
 }
 
+/// An encoder input that borrows all of the data at once, for data that is already in memory.
+/// Nothing is copied, and matches are compared directly in the slice, without wrapping around.
+pub struct EncoderSliceBuffer<'a> {
+    data: &'a [u8],
+    compress_pos: usize,
+}
+
+impl<'a> EncoderSliceBuffer<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            compress_pos: 0,
+        }
+    }
+
+    /// The index in the slice of the byte at an offset from the head
+    fn get_byte_index(&self, offset: i32) -> usize {
+        (self.compress_pos as isize + offset as isize) as usize
+    }
+}
+
+impl EncoderBuffer for EncoderSliceBuffer<'_> {
+    fn forwards_bytes(&self) -> usize {
+        self.data.len() - self.compress_pos
+    }
+
+    /// Everything before the head is kept, so this is the whole encoded part of the slice.
+    fn backwards_bytes(&self) -> usize {
+        self.compress_pos
+    }
+
+    fn pos(&self) -> u64 {
+        self.compress_pos as u64
+    }
+
+    fn skip(&mut self, len: u32) {
+        debug_assert!(
+            len as usize <= self.forwards_bytes(),
+            "len: {}, forwards_bytes(): {}",
+            len,
+            self.forwards_bytes()
+        );
+
+        self.compress_pos += len as usize;
+    }
+
+    fn increment_pos(&mut self) {
+        self.skip(1);
+    }
+
+    fn get_byte(&self, offset: i32) -> u8 {
+        self.data[self.get_byte_index(offset)]
+    }
+
+    fn get_byte_or_zero(&self, offset: i32) -> u8 {
+        if offset < 0 && -(offset as i64) as u64 > self.compress_pos as u64 {
+            0
+        } else {
+            self.get_byte(offset)
+        }
+    }
+
+    fn is_distance_valid(&self, distance: u32) -> bool {
+        (distance as usize) < self.compress_pos
+    }
+
+    fn do_bytes_match_at(&self, delta: u32, len: u32) -> bool {
+        self.get_byte(len as i32) == self.get_byte(len as i32 - delta as i32 - 1)
+    }
+
+    fn is_match_at_least_longer_than(&self, delta: u32, len: u32) -> bool {
+        // Like the cyclic buffer, only the bytes that are there are compared
+        let len = (len as usize).min(self.forwards_bytes());
+        let src = &self.data[self.compress_pos..][..len];
+        let dst = &self.data[self.get_byte_index(-(delta as i32) - 1)..][..len];
+
+        src == dst
+    }
+
+    fn get_match_length(&self, start_len: u32, delta: u32, max_len: u32) -> u32 {
+        if start_len >= max_len {
+            return start_len;
+        }
+
+        let src =
+            &self.data[self.get_byte_index(start_len as i32)..][..(max_len - start_len) as usize];
+        let dst_start = self.get_byte_index(start_len as i32 - delta as i32 - 1);
+        let dst = &self.data[dst_start..][..src.len()];
+
+        let matching = src.iter().zip(dst).take_while(|(a, b)| a == b).count();
+        start_len + matching as u32
+    }
+}
+
 /// Given two pairs of slices, split and align them both into [&[T]; 3] each so that
 /// the first two slices are the same length and the last slice is the remainder.
 ///
@@ -242,10 +383,38 @@ fn align_slices<'a, T>(
     (left, right)
 }
 
+/// Where the LZMA decoder writes its output, which is also the dictionary that matches copy from.
+pub trait DecoderOutput {
+    /// The position in the current dictionary, which the literal and position contexts depend on.
+    fn position(&self) -> u64;
+
+    /// Whether the current dictionary is empty.
+    fn is_empty(&self) -> bool;
+
+    fn append_byte(&mut self, byte: u8);
+
+    /// Copy `len` bytes from `dist + 1` bytes back. The distance must be checked with `is_valid_distance()` first.
+    fn append_match(&mut self, dist: u32, len: u32);
+
+    /// Whether a match can copy from `dist + 1` bytes back.
+    fn is_valid_distance(&self, dist: u32) -> bool;
+
+    fn get_byte(&self, dist: u32) -> u8;
+
+    /// Whether decoding has to stop until the output is flushed, because another packet might not fit.
+    fn is_full(&self) -> bool;
+}
+
 pub struct DecoderDataBuffer {
     flushed_pos: u64,
     buf: CyclicBuffer<u8>,
 
+    /// The number of preset dictionary bytes at the start of the buffer, which are never flushed
+    preset_dict_len: u64,
+
+    /// The buffer position where the current dictionary started. This only changes with LZMA2 dictionary resets.
+    dict_start_pos: u64,
+
     /// The length of the overall output stream
     total_file_length: u64,
 }
@@ -255,22 +424,58 @@ impl DecoderDataBuffer {
         Self {
             buf: CyclicBuffer::new(dict_size as usize),
             flushed_pos: 0,
+            preset_dict_len: 0,
+            dict_start_pos: 0,
             total_file_length,
         }
     }
 
+    /// Load a preset dictionary that the stream can reference, without outputting it.
+    /// Only the last `dict_size` bytes of the dictionary are used.
+    ///
+    /// This must be called before anything is decoded.
+    pub fn load_preset_dict(&mut self, dict: &[u8]) {
+        assert!(
+            self.is_empty(),
+            "Preset dictionaries must be loaded before any data"
+        );
+
+        let dict = &dict[dict.len().saturating_sub(self.buf.max_capacity())..];
+        self.buf.push_slice(dict);
+        self.flushed_pos = self.buf.pos();
+        self.preset_dict_len = self.buf.pos();
+    }
+
+    /// Start a new dictionary at the current position. Previous data can no longer be
+    /// referenced, and positions are counted from here again.
+    pub fn reset_dict(&mut self) {
+        self.dict_start_pos = self.buf.pos();
+    }
+
+    /// Whether the current dictionary is empty.
     pub fn is_empty(&self) -> bool {
-        self.buf.pos() == 0
+        self.buf.pos() == self.dict_start_pos
     }
 
+    /// The position in the current dictionary, including any preset dictionary bytes.
     pub fn position(&self) -> u64 {
-        self.buf.pos()
+        self.buf.pos() - self.dict_start_pos
+    }
+
+    /// The number of bytes decoded so far, excluding any preset dictionary bytes.
+    pub fn decoded_bytes(&self) -> u64 {
+        self.buf.pos() - self.preset_dict_len
     }
 
     pub fn append_byte(&mut self, byte: u8) {
         self.buf.push(byte);
     }
 
+    /// Append uncompressed bytes, e.g. from an LZMA2 uncompressed chunk.
+    pub fn append_bytes(&mut self, bytes: &[u8]) {
+        self.buf.push_slice(bytes);
+    }
+
     pub fn append_match(&mut self, dist: u32, len: u32) {
         debug_assert!(
             dist < self.buf.capacity() as u32,
@@ -279,8 +484,10 @@ impl DecoderDataBuffer {
             self.buf.capacity()
         );
 
+        // The copy can't be done in one go if it overlaps with the bytes it's copying, either
+        // because the match repeats itself, or because it wraps around to the start of the copied bytes.
         let overlaps_head = len > dist;
-        let overlaps_tail = self.buf.max_capacity() as u32 - dist > len;
+        let overlaps_tail = self.buf.max_capacity() as u32 - dist <= len;
 
         if overlaps_head || overlaps_tail {
             for _ in 0..len {
@@ -295,6 +502,12 @@ impl DecoderDataBuffer {
         }
     }
 
+    /// Whether a match can copy from `dist + 1` bytes back, i.e. from inside the current dictionary.
+    /// Corrupt streams can have any distance, so the decoder checks this before `append_match()`.
+    pub fn is_valid_distance(&self, dist: u32) -> bool {
+        (dist as u64) < self.position() && (dist as usize) < self.buf.max_capacity()
+    }
+
     pub fn available_bytes_back(&self) -> u32 {
         self.buf.capacity() as u32
     }
@@ -318,13 +531,15 @@ impl DecoderDataBuffer {
     /// An important condition for the encoder is that it must flush the buffer before it gets full.
     /// I didn't want to add protection for this when actually appending data because it would be slow.
     pub fn must_flush_now_or_data_will_be_lost(&self) -> bool {
-        let safe_bytes = self.buf.capacity() as u32 - self.flushable_bytes();
-        safe_bytes < MATCH_LEN_MAX as u32 - 1
+        // A single packet can append up to MATCH_LEN_MAX bytes, and any unflushed bytes past
+        // the buffer's max capacity would be overwritten.
+        let safe_bytes = self.buf.max_capacity() as u32 - self.flushable_bytes();
+        safe_bytes < MATCH_LEN_MAX as u32
     }
 
     /// The number of bytes remaining in the file that we haven't flushed yet.
     pub fn remaining_file_bytes(&self) -> u64 {
-        self.total_file_length - self.flushed_pos
+        self.total_file_length - (self.flushed_pos - self.preset_dict_len)
     }
 
     pub fn flush(&mut self, buf: &mut [u8]) -> usize {
@@ -344,6 +559,120 @@ impl DecoderDataBuffer {
     }
 }
 
+impl DecoderOutput for DecoderDataBuffer {
+    fn position(&self) -> u64 {
+        DecoderDataBuffer::position(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        DecoderDataBuffer::is_empty(self)
+    }
+
+    fn append_byte(&mut self, byte: u8) {
+        DecoderDataBuffer::append_byte(self, byte)
+    }
+
+    fn append_match(&mut self, dist: u32, len: u32) {
+        DecoderDataBuffer::append_match(self, dist, len)
+    }
+
+    fn is_valid_distance(&self, dist: u32) -> bool {
+        DecoderDataBuffer::is_valid_distance(self, dist)
+    }
+
+    fn get_byte(&self, dist: u32) -> u8 {
+        DecoderDataBuffer::get_byte(self, dist)
+    }
+
+    fn is_full(&self) -> bool {
+        self.must_flush_now_or_data_will_be_lost()
+    }
+}
+
+/// A decoder output that writes straight into a slice holding the whole uncompressed data,
+/// and uses the slice as the dictionary. Nothing needs to be flushed.
+///
+/// A packet that doesn't fit is cut off at the end of the slice, but still counted by
+/// `decoded_bytes()`, so the caller can tell that the data was too long and stop decoding.
+pub struct SliceDataBuffer<'a> {
+    buf: &'a mut [u8],
+    /// The number of bytes decoded, which is past the end of the slice if the last packet didn't fit
+    pos: usize,
+    dict_size: u32,
+}
+
+impl<'a> SliceDataBuffer<'a> {
+    pub fn new(buf: &'a mut [u8], dict_size: u32) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            dict_size,
+        }
+    }
+
+    /// The number of bytes decoded so far, including any that didn't fit in the slice.
+    pub fn decoded_bytes(&self) -> u64 {
+        self.pos as u64
+    }
+
+    /// Whether the decoded data is longer than the slice. Nothing more can be decoded after this.
+    pub fn is_overflowed(&self) -> bool {
+        self.pos > self.buf.len()
+    }
+}
+
+impl DecoderOutput for SliceDataBuffer<'_> {
+    fn position(&self) -> u64 {
+        self.pos as u64
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    fn append_byte(&mut self, byte: u8) {
+        if let Some(slot) = self.buf.get_mut(self.pos) {
+            *slot = byte;
+        }
+        self.pos += 1;
+    }
+
+    fn append_match(&mut self, dist: u32, len: u32) {
+        let dist = dist as usize;
+        let start = self.pos;
+        let end = (start + len as usize).min(self.buf.len());
+        self.pos += len as usize;
+
+        if start >= end {
+            return;
+        }
+
+        if end - start <= dist + 1 {
+            self.buf
+                .copy_within(start - dist - 1..end - dist - 1, start);
+        } else {
+            // The match repeats the bytes it's copying, so they have to be copied one at a time
+            for i in start..end {
+                self.buf[i] = self.buf[i - dist - 1];
+            }
+        }
+    }
+
+    fn is_valid_distance(&self, dist: u32) -> bool {
+        (dist as usize) < self.pos.min(self.buf.len()) && dist < self.dict_size
+    }
+
+    fn get_byte(&self, dist: u32) -> u8 {
+        self.buf[self.pos - dist as usize - 1]
+    }
+
+    /// The slice is never flushed, so this is only full once a packet didn't fit.
+    /// The last packet can end exactly at the end of the slice and still be followed by an end marker.
+    fn is_full(&self) -> bool {
+        self.is_overflowed()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -352,6 +681,130 @@ mod tests {
     // when there's other type errors anywhere else in the project, causing an error in this file for no reason.
     const EMPTY: &[i32] = &[];
 
+    #[test]
+    fn test_append_match_near_dict_size() {
+        let dict_size = 4096;
+        let mut buffer = DecoderDataBuffer::new(dict_size, u64::MAX);
+        let mut expected = Vec::new();
+        let mut output = vec![0; dict_size as usize];
+
+        for i in 0..dict_size * 3 {
+            let byte = (i * 7 + i / 13) as u8;
+            buffer.append_byte(byte);
+            expected.push(byte);
+            if buffer.must_flush_now_or_data_will_be_lost() {
+                buffer.flush(&mut output);
+            }
+        }
+        buffer.flush(&mut output);
+
+        // Matches from the far end of the dictionary wrap around onto the bytes they're copying
+        for (dist, len) in [
+            (dict_size - 1, 273),
+            (dict_size - 100, 200),
+            (5000 % dict_size, 10),
+        ] {
+            assert!(buffer.is_valid_distance(dist));
+            buffer.append_match(dist, len);
+            for _ in 0..len {
+                let byte = expected[expected.len() - 1 - dist as usize];
+                expected.push(byte);
+            }
+
+            let flushed = buffer.flush(&mut output);
+            assert_eq!(&output[..flushed], &expected[expected.len() - flushed..]);
+        }
+
+        assert!(!buffer.is_valid_distance(dict_size));
+    }
+
+    #[test]
+    fn test_encoder_slice_buffer_matches_cyclic() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 7 + i / 500) as u8).collect();
+        let mut slice = EncoderSliceBuffer::new(&data);
+        let mut cyclic = EncoderDataBuffer::new(4096, data.len() as u32);
+        cyclic.append_data(&data);
+
+        slice.skip(1000);
+        cyclic.skip(1000);
+        while slice.forwards_bytes() > 0 {
+            assert_eq!(slice.forwards_bytes(), cyclic.forwards_bytes());
+            assert_eq!(slice.get_byte(0), cyclic.get_byte(0));
+            assert_eq!(slice.get_byte(-1000), cyclic.get_byte(-1000));
+
+            let max_len = slice.forwards_bytes().min(MATCH_LEN_MAX) as u32;
+            for delta in [0, 6, 13, 499, 999] {
+                assert!(slice.is_distance_valid(delta));
+                assert_eq!(
+                    slice.get_match_length(0, delta, max_len),
+                    cyclic.get_match_length(0, delta, max_len)
+                );
+                assert_eq!(
+                    slice.is_match_at_least_longer_than(delta, 3),
+                    cyclic.is_match_at_least_longer_than(delta, 3)
+                );
+            }
+
+            slice.increment_pos();
+            cyclic.increment_pos();
+        }
+    }
+
+    #[test]
+    fn test_encoder_slice_buffer_start() {
+        let data = [1, 2, 3, 1, 2, 3, 4];
+        let mut buffer = EncoderSliceBuffer::new(&data);
+        assert_eq!(buffer.get_byte_or_zero(-1), 0);
+        assert!(!buffer.is_distance_valid(0));
+
+        buffer.skip(3);
+        assert_eq!(buffer.get_byte_or_zero(-1), 3);
+        assert!(buffer.is_distance_valid(2));
+        assert!(!buffer.is_distance_valid(3));
+        assert_eq!(buffer.get_match_length(0, 2, 4), 3);
+        assert!(buffer.is_match_at_least_longer_than(2, 3));
+        assert!(!buffer.is_match_at_least_longer_than(2, 4));
+    }
+
+    #[test]
+    fn test_slice_buffer_matches() {
+        let mut output = [0; 16];
+        let mut buffer = SliceDataBuffer::new(&mut output, 4096);
+
+        assert!(!buffer.is_valid_distance(0));
+        buffer.append_byte(1);
+        buffer.append_byte(2);
+        buffer.append_byte(3);
+        assert!(buffer.is_valid_distance(2));
+        assert!(!buffer.is_valid_distance(3));
+        assert_eq!(buffer.get_byte(2), 1);
+
+        // A match that repeats itself, then one that doesn't overlap
+        buffer.append_match(1, 5);
+        buffer.append_match(6, 4);
+        assert_eq!(buffer.decoded_bytes(), 12);
+        assert!(!buffer.is_overflowed());
+
+        // The part that doesn't fit is dropped, but still counted
+        buffer.append_match(0, 10);
+        assert!(buffer.is_overflowed());
+        assert_eq!(buffer.decoded_bytes(), 22);
+        assert_eq!(output, [1, 2, 3, 2, 3, 2, 3, 2, 2, 3, 2, 3, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_slice_buffer_dict_size() {
+        let mut output = [0; 16];
+        let mut buffer = SliceDataBuffer::new(&mut output, 4);
+        for byte in 0..8 {
+            buffer.append_byte(byte);
+        }
+
+        // Matches can't reach further back than the dictionary size, like with a cyclic buffer
+        assert!(buffer.is_valid_distance(3));
+        assert!(!buffer.is_valid_distance(4));
+    }
+
     #[test]
     fn test_align_slices() {
         let left = (&[1, 2, 3][..], &[4, 5, 6, 7][..]);
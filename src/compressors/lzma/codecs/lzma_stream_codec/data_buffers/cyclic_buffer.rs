@@ -147,7 +147,7 @@ impl<T: Copy + Default> CyclicBuffer<T> {
     }
 
     pub fn push_slice(&mut self, val: &[T]) {
-        let index = (self.pos % self.buf.len() as u64) as usize;
+        let mut index = (self.pos % self.buf.len() as u64) as usize;
         let mut written = 0;
         while written < val.len() {
             let distance_to_end = self.buf.len() - index;
@@ -156,6 +156,9 @@ impl<T: Copy + Default> CyclicBuffer<T> {
             self.buf[index..(index + to_write)]
                 .copy_from_slice(&val[written..(written + to_write)]);
             written += to_write;
+
+            // Wrap around to the start of the buffer for the next part of the slice
+            index = (index + to_write) % self.buf.len();
         }
 
         self.pos += val.len() as u64;
@@ -318,3 +321,20 @@ impl<T: Copy + Default> CyclicBuffer<T> {
         todo!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_slice_wrapping() {
+        let mut buf = CyclicBuffer::<u8>::new(8);
+        buf.push_slice(&[1, 2, 3, 4, 5, 6]);
+        buf.push_slice(&[7, 8, 9, 10, 11]);
+
+        assert_eq!(buf.pos(), 11);
+        for i in 0..8 {
+            assert_eq!(buf.get_relative(i), 11 - i as u8);
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::compressors::lzma::codecs::{
 };
 
 use super::{
+    super::data_buffers::EncoderBuffer,
     match_finding::{Match, MatchFinder},
     EncodeInstruction, LZMAEncoderInput, LZMAInstructionPicker, LiteralCtx,
 };
@@ -25,9 +26,13 @@ fn is_distance_sufficiently_shorter(small_dist: u32, big_dist: u32) -> bool {
 }
 
 impl LZMAInstructionPicker for LZMAFastInstructionPicker {
+    fn reset(&mut self) {
+        // Instructions are never picked ahead of time
+    }
+
     fn get_next_symbol(
         &mut self,
-        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        input: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         _price_calc: &mut EncoderPriceCalc,
         state: &State,
     ) -> EncodeInstruction {
@@ -35,8 +40,10 @@ impl LZMAInstructionPicker for LZMAFastInstructionPicker {
 
         // Make a literal here so we can easily return it in other places later
         let next_byte = input.buffer().get_byte(0);
-        let prev_byte = input.buffer().get_byte(-1);
-        let match_byte = input.buffer().get_byte(-(state.reps()[0] as i32) - 1);
+        let prev_byte = input.buffer().get_byte_or_zero(-1);
+        let match_byte = input
+            .buffer()
+            .get_byte_or_zero(-(state.reps()[0] as i32) - 1);
         let literal_ctx = LiteralCtx {
             byte: next_byte,
             match_byte,
@@ -48,10 +55,15 @@ impl LZMAInstructionPicker for LZMAFastInstructionPicker {
             return EncodeInstruction::Literal(literal_ctx);
         }
 
-        // Cache the lengths as they're used multiple times
-        let rep_lens = state
-            .reps()
-            .map(|rep| input.buffer().get_match_length(0, rep, avail as u32));
+        // Cache the lengths as they're used multiple times.
+        // Reps that point before the start of the stream can't be used.
+        let rep_lens = state.reps().map(|rep| {
+            if input.buffer().is_distance_valid(rep) {
+                input.buffer().get_match_length(0, rep, avail as u32)
+            } else {
+                0
+            }
+        });
 
         let mut best_rep_len = 0;
         let mut best_rep_index = 0;
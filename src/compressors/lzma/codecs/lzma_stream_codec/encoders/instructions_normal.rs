@@ -9,6 +9,7 @@ use crate::compressors::lzma::codecs::{
 };
 
 use super::{
+    super::data_buffers::EncoderBuffer,
     match_finding::{Match, MatchFinder},
     EncodeInstruction, LZMAEncoderInput, LZMAInstructionPicker, LiteralCtx,
 };
@@ -52,7 +53,10 @@ impl LZMANormalInstructionPicker {
         }
     }
 
-    fn get_curr_node_index(&self, input: &LZMAEncoderInput<impl MatchFinder>) -> usize {
+    fn get_curr_node_index(
+        &self,
+        input: &LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
+    ) -> usize {
         let pos = input.pos();
         debug_assert!(pos >= self.graph_start_pos);
         (pos - self.graph_start_pos) as usize
@@ -60,7 +64,7 @@ impl LZMANormalInstructionPicker {
 
     fn reset_and_prepare_graph(
         &mut self,
-        input: &LZMAEncoderInput<impl MatchFinder>,
+        input: &LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         state: State,
     ) {
         self.node_graph.clear();
@@ -74,7 +78,7 @@ impl LZMANormalInstructionPicker {
     /// - Literal + rep0
     fn try_one_length_opts(
         &mut self,
-        input: &LZMAEncoderInput<impl MatchFinder>,
+        input: &LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         price_calc: &EncoderPriceCalc,
         any_rep_price: AnyRepPrice,
     ) {
@@ -87,8 +91,8 @@ impl LZMANormalInstructionPicker {
         let available = input.buffer().forwards_bytes().min(MATCH_LEN_MAX); // We assume this is >= 1
 
         let curr_byte = input.buffer().get_byte(0);
-        let prev_byte = input.buffer().get_byte(-1);
-        let match_byte = input.buffer().get_byte(-(rep0 as i32) - 1);
+        let prev_byte = input.buffer().get_byte_or_zero(-1);
+        let match_byte = input.buffer().get_byte_or_zero(-(rep0 as i32) - 1);
 
         let literal_ctx = LiteralCtx {
             byte: curr_byte,
@@ -106,7 +110,7 @@ impl LZMANormalInstructionPicker {
                 &node.state,
             );
 
-        let can_be_short_rep0 = curr_byte == match_byte;
+        let can_be_short_rep0 = input.buffer().is_distance_valid(rep0) && curr_byte == match_byte;
 
         // Short rep price
         let price_short_rep = price + any_rep_price.get_short_rep_price();
@@ -126,8 +130,12 @@ impl LZMANormalInstructionPicker {
             }
         }
 
-        // Get rep0 length
-        let rep0_len = input.buffer().get_match_length(1, rep0, available as u32) - 1;
+        // Get rep0 length. After the literal, rep0 can also point to the current byte.
+        let rep0_len = if input.buffer().is_distance_valid(rep0) || rep0 as u64 == pos {
+            input.buffer().get_match_length(1, rep0, available as u32) - 1
+        } else {
+            0
+        };
 
         // Get the rep0 price of the next position ahead
         let mut lit_state = node.state;
@@ -153,7 +161,11 @@ impl LZMANormalInstructionPicker {
 
     /// Try:
     /// - All reps
-    fn try_reps(&mut self, input: &LZMAEncoderInput<impl MatchFinder>, any_rep_price: AnyRepPrice) {
+    fn try_reps(
+        &mut self,
+        input: &LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
+        any_rep_price: AnyRepPrice,
+    ) {
         let current_node_idx = self.get_curr_node_index(input);
         let node = self.node_graph[current_node_idx];
         let price = node.price;
@@ -162,6 +174,10 @@ impl LZMANormalInstructionPicker {
 
         for rep_id in 0..node.state.reps().len() {
             let rep_dist = node.state.get_rep(rep_id);
+            if !input.buffer().is_distance_valid(rep_dist) {
+                continue;
+            }
+
             let rep_len = input
                 .buffer()
                 .get_match_length(0, rep_dist, available as u32);
@@ -192,7 +208,7 @@ impl LZMANormalInstructionPicker {
     /// - All matches
     fn try_matches(
         &mut self,
-        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        input: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         normal_match_price: NormalMatchPrice,
     ) {
         let current_node_idx = self.get_curr_node_index(input);
@@ -270,9 +286,13 @@ impl LZMANormalInstructionPicker {
 }
 
 impl LZMAInstructionPicker for LZMANormalInstructionPicker {
+    fn reset(&mut self) {
+        self.instruction_cache_stack.clear();
+    }
+
     fn get_next_symbol(
         &mut self,
-        input: &mut LZMAEncoderInput<impl MatchFinder>,
+        input: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         price_calc: &mut EncoderPriceCalc,
         state: &State,
     ) -> EncodeInstruction {
@@ -285,8 +305,10 @@ impl LZMAInstructionPicker for LZMANormalInstructionPicker {
         if avail < MATCH_LEN_MIN as u32 {
             // Just return a literal
             let next_byte = input.buffer().get_byte(0);
-            let prev_byte = input.buffer().get_byte(-1);
-            let match_byte = input.buffer().get_byte(-(state.reps()[0] as i32) - 1);
+            let prev_byte = input.buffer().get_byte_or_zero(-1);
+            let match_byte = input
+                .buffer()
+                .get_byte_or_zero(-(state.reps()[0] as i32) - 1);
             let literal_ctx = LiteralCtx {
                 byte: next_byte,
                 match_byte,
@@ -296,6 +318,8 @@ impl LZMAInstructionPicker for LZMANormalInstructionPicker {
             return EncodeInstruction::Literal(literal_ctx);
         }
 
+        // Refresh the distance and length prices that have counted down since the last graph
+        price_calc.update_prices();
         self.reset_and_prepare_graph(input, *state);
 
         while self.node_graph.len() < MAX_NODE_GRAPH_LEN
@@ -305,7 +329,9 @@ impl LZMAInstructionPicker for LZMANormalInstructionPicker {
             let pos = input.pos();
             let pos_state = pos as u32 & self.pos_mask;
 
-            let any_match_price = price_calc.get_any_match_price(state, pos_state);
+            // Packets are priced in the state of the node they start from, not the graph's first node
+            let node_state = self.node_graph[self.get_curr_node_index(input)].state;
+            let any_match_price = price_calc.get_any_match_price(&node_state, pos_state);
             let any_rep_price = any_match_price.get_any_rep_price();
             let normal_match_price = any_match_price.get_normal_match_price();
 
@@ -427,3 +453,180 @@ impl PriceNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressors::lzma::{
+        codecs::{
+            lzma_stream_codec::{encoders::match_finding::hc4::HC4MatchFinder, LZMACodecEncoder},
+            range_codec::RangeEncoder,
+        },
+        options::LzmaOptions,
+    };
+
+    const DICT_SIZE: u32 = 1 << 16;
+
+    /// Text made of a limited vocabulary, so every graph has lots of matches and reps to pick from
+    fn test_data() -> Vec<u8> {
+        let words = [
+            "price", "node", "graph", "state", "literal", "match", "rep", "the",
+        ];
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        while data.len() < 100_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.extend_from_slice(words[(seed >> 16) as usize % words.len()].as_bytes());
+            data.push(if (seed >> 8) & 7 == 0 { b'\n' } else { b' ' });
+        }
+        data
+    }
+
+    fn new_encoder() -> (
+        LZMACodecEncoder<LZMANormalInstructionPicker>,
+        LZMAEncoderInput<HC4MatchFinder>,
+    ) {
+        let options = LzmaOptions {
+            dict_size: DICT_SIZE,
+            nice_len: 273,
+            ..Default::default()
+        };
+        let picker = LZMANormalInstructionPicker::new(273, 2);
+        let encoder = LZMACodecEncoder::new(&options, picker);
+        let input = LZMAEncoderInput::new(HC4MatchFinder::new(DICT_SIZE, 273, 273, 0), DICT_SIZE);
+        (encoder, input)
+    }
+
+    /// Append as much of `data` as fits, returning whether there's enough of it ahead to pick a packet
+    fn append_input(
+        input: &mut LZMAEncoderInput<HC4MatchFinder>,
+        data: &[u8],
+        written: &mut usize,
+    ) -> bool {
+        let len = input.available_append_bytes().min(data.len() - *written);
+        input.append_data(&data[*written..*written + len]);
+        *written += len;
+        *written == data.len() || input.forward_bytes() > MATCH_LEN_MAX
+    }
+
+    #[test]
+    fn test_graph_prices_match_encoded_size() {
+        let data = test_data();
+        let (mut encoder, mut input) = new_encoder();
+        let mut rc = RangeEncoder::new(Vec::new());
+
+        // Add up the price of the path picked from each graph
+        let mut estimated = RangeEncPrice::zero();
+        let mut graph_start_pos = None;
+        let mut written = 0;
+        while encoder.position() < data.len() as u64 {
+            if !append_input(&mut input, &data, &mut written) {
+                continue;
+            }
+            encoder.encode_one_packet(&mut rc, &mut input).unwrap();
+
+            let picker = &encoder.picker;
+            if graph_start_pos != Some(picker.graph_start_pos) {
+                graph_start_pos = Some(picker.graph_start_pos);
+                estimated += picker.node_graph.last().unwrap().price;
+            }
+        }
+        let compressed = rc.finish().unwrap();
+
+        // The probabilities keep adapting while a path is encoded, so the prices are only estimates
+        let actual = RangeEncPrice::get_direct_bits_price(compressed.len() as u32 * 8);
+        assert!(estimated * 10 > actual * 9);
+        assert!(estimated * 10 < actual * 11);
+    }
+
+    #[test]
+    fn test_path_priced_in_node_states() {
+        let data = test_data();
+        let (mut encoder, mut input) = new_encoder();
+        let mut rc = RangeEncoder::new(Vec::new());
+
+        // Encode until the probabilities differ between states, then build a fresh graph
+        let mut written = 0;
+        loop {
+            if !append_input(&mut input, &data, &mut written) {
+                continue;
+            }
+            if encoder.position() >= 50_000 && encoder.picker.instruction_cache_stack.is_empty() {
+                break;
+            }
+            encoder.encode_one_packet(&mut rc, &mut input).unwrap();
+        }
+        rc.finish().unwrap();
+        encoder.get_next_instruction(&mut input);
+
+        let price_calc = EncoderPriceCalc {
+            data: &mut encoder.data,
+            codec: &encoder.codec,
+            literal_encoder: &mut encoder.literal_encoder,
+            match_len_encoder: &mut encoder.match_len_encoder,
+            rep_len_encoder: &mut encoder.rep_len_encoder,
+        };
+        let picker = &encoder.picker;
+        let graph = &picker.node_graph;
+        let start_state = graph[0].state;
+
+        let mut path = Vec::new();
+        let mut index = graph.len() - 1;
+        while index != 0 {
+            path.push(index);
+            index -= graph[index].len as usize;
+        }
+
+        let mut checked_in_other_states = 0;
+        for &index in path.iter().rev() {
+            let node = graph[index];
+            let prev = graph[index - node.len as usize];
+            let pos = (picker.graph_start_pos + (index - node.len as usize) as u64) as u32;
+            let any_match_price =
+                price_calc.get_any_match_price(&prev.state, pos & picker.pos_mask);
+
+            let price = match node.instruction {
+                NodeInstruction::None => unreachable!(),
+                NodeInstruction::Literal { ctx } => price_calc.get_literal_price(
+                    ctx.byte,
+                    ctx.match_byte,
+                    ctx.prev_byte,
+                    pos,
+                    &prev.state,
+                ),
+                NodeInstruction::Rep { .. } if node.len == 1 => {
+                    any_match_price.get_any_rep_price().get_short_rep_price()
+                }
+                NodeInstruction::Rep { rep_index } => any_match_price
+                    .get_any_rep_price()
+                    .get_long_rep_price(rep_index as u32)
+                    .get_price_with_len(node.len),
+                NodeInstruction::Match { distance } => any_match_price
+                    .get_normal_match_price()
+                    .get_price_with_dist_len(distance, node.len),
+                NodeInstruction::LiteralThenRep0 { literal_ctx } => {
+                    let mut lit_state = prev.state;
+                    lit_state.update_literal();
+                    price_calc.get_literal_price(
+                        literal_ctx.byte,
+                        literal_ctx.match_byte,
+                        literal_ctx.prev_byte,
+                        pos,
+                        &prev.state,
+                    ) + price_calc
+                        .get_any_match_price(&lit_state, (pos + 1) & picker.pos_mask)
+                        .get_any_rep_price()
+                        .get_long_rep_price(0)
+                        .get_price_with_len(node.len - 1)
+                }
+            };
+            assert_eq!(node.price, prev.price + price);
+
+            let is_literal = matches!(node.instruction, NodeInstruction::Literal { .. });
+            if !is_literal && prev.state.get_idx() != start_state.get_idx() {
+                checked_in_other_states += 1;
+            }
+        }
+        assert!(checked_in_other_states > 0);
+    }
+}
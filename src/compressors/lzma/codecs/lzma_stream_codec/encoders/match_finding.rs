@@ -1,6 +1,10 @@
-use super::super::data_buffers::EncoderDataBuffer;
+use super::super::data_buffers::EncoderBuffer;
 
 pub mod brute_force;
+pub mod bt2;
+pub mod bt3;
+pub mod bt4;
+pub mod hc3;
 pub mod hc4;
 pub mod utils;
 
@@ -25,10 +29,211 @@ impl Ord for Match {
 pub trait MatchFinder {
     const MIN_FORWARDS_BYTES: u32;
 
+    /// The number of bytes that must be ahead of the current position to search for matches.
+    /// This is only different from `MIN_FORWARDS_BYTES` when the finder is picked at runtime.
+    fn min_forwards_bytes(&self) -> u32 {
+        Self::MIN_FORWARDS_BYTES
+    }
+
     fn find_and_write_matches(
         &mut self,
-        buffer: &EncoderDataBuffer,
+        buffer: &impl EncoderBuffer,
         output_matches_vec: &mut Vec<Match>,
     );
-    fn skip_byte(&mut self, buffer: &EncoderDataBuffer);
+
+    /// Index the byte at the current position without searching for matches.
+    /// This is called for every position that `find_and_write_matches` wasn't called for.
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer);
+}
+
+/// A match finder picked at runtime, e.g. from `LzmaOptions`.
+pub enum AnyMatchFinder {
+    HC3(hc3::HC3MatchFinder),
+    HC4(hc4::HC4MatchFinder),
+    BT2(bt2::BT2MatchFinder),
+    BT3(bt3::BT3MatchFinder),
+    BT4(bt4::BT4MatchFinder),
+}
+
+impl MatchFinder for AnyMatchFinder {
+    /// The largest minimum of all the finders. `min_forwards_bytes()` gives the picked finder's own.
+    const MIN_FORWARDS_BYTES: u32 = 4;
+
+    fn min_forwards_bytes(&self) -> u32 {
+        match self {
+            Self::HC3(mf) => mf.min_forwards_bytes(),
+            Self::HC4(mf) => mf.min_forwards_bytes(),
+            Self::BT2(mf) => mf.min_forwards_bytes(),
+            Self::BT3(mf) => mf.min_forwards_bytes(),
+            Self::BT4(mf) => mf.min_forwards_bytes(),
+        }
+    }
+
+    fn find_and_write_matches(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        output_matches_vec: &mut Vec<Match>,
+    ) {
+        match self {
+            Self::HC3(mf) => mf.find_and_write_matches(buffer, output_matches_vec),
+            Self::HC4(mf) => mf.find_and_write_matches(buffer, output_matches_vec),
+            Self::BT2(mf) => mf.find_and_write_matches(buffer, output_matches_vec),
+            Self::BT3(mf) => mf.find_and_write_matches(buffer, output_matches_vec),
+            Self::BT4(mf) => mf.find_and_write_matches(buffer, output_matches_vec),
+        }
+    }
+
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        match self {
+            Self::HC3(mf) => mf.skip_byte(buffer),
+            Self::HC4(mf) => mf.skip_byte(buffer),
+            Self::BT2(mf) => mf.skip_byte(buffer),
+            Self::BT3(mf) => mf.skip_byte(buffer),
+            Self::BT4(mf) => mf.skip_byte(buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bt2::*, bt3::*, bt4::*, hc3::*, hc4::*, *};
+    use crate::{
+        compressors::lzma::{
+            codecs::{
+                length_codec::MATCH_LEN_MAX,
+                lzma_stream_codec::{
+                    encoders::{
+                        instructions_fast::LZMAFastInstructionPicker,
+                        instructions_normal::LZMANormalInstructionPicker, LZMAEncoderInput,
+                        LZMAInstructionPicker,
+                    },
+                    LZMACodecEncoder,
+                },
+                range_codec::RangeEncoder,
+            },
+            options::{LzmaOptions, MatchFinderKind},
+            streams::LzmaReader,
+        },
+        test_utils::PROPS,
+    };
+    use std::io::{Cursor, Read};
+
+    const DICT_SIZE: u32 = 1 << 16;
+    const NICE_LEN: u32 = 64;
+
+    fn test_data() -> Vec<u8> {
+        let words = ["abc", "abd", "ab", "xyzzy", "hash", "tree", "chain", "a"];
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        while data.len() < 50_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.extend_from_slice(words[(seed >> 16) as usize % words.len()].as_bytes());
+            data.push(b'a' + (seed >> 8) as u8 % 3);
+        }
+        data
+    }
+
+    /// Compress the data into raw LZMA, then check that it decompresses back to the same data.
+    fn check_roundtrip(
+        data: &[u8],
+        match_finder: impl MatchFinder,
+        picker: impl LZMAInstructionPicker,
+    ) {
+        let options = LzmaOptions {
+            dict_size: DICT_SIZE,
+            nice_len: NICE_LEN,
+            ..Default::default()
+        };
+        let mut encoder = LZMACodecEncoder::new(&options, picker);
+        let mut input = LZMAEncoderInput::new(match_finder, DICT_SIZE);
+        let mut rc = RangeEncoder::new(Vec::new());
+
+        let mut written = 0;
+        while encoder.position() < data.len() as u64 {
+            let len = input.available_append_bytes().min(data.len() - written);
+            input.append_data(&data[written..written + len]);
+            written += len;
+
+            if written < data.len() && input.forward_bytes() <= MATCH_LEN_MAX {
+                continue;
+            }
+            encoder.encode_one_packet(&mut rc, &mut input).unwrap();
+        }
+        let compressed = rc.finish().unwrap();
+        assert!(compressed.len() < data.len() / 2);
+
+        let mut reader = LzmaReader::new_raw(
+            Cursor::new(&compressed),
+            PROPS,
+            DICT_SIZE,
+            Some(data.len() as u64),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    fn check_all_finders<P: LZMAInstructionPicker>(make_picker: impl Fn() -> P, data: &[u8]) {
+        let max_len = MATCH_LEN_MAX as u32;
+        check_roundtrip(
+            data,
+            HC3MatchFinder::new(DICT_SIZE, NICE_LEN, max_len, 0),
+            make_picker(),
+        );
+        check_roundtrip(
+            data,
+            HC4MatchFinder::new(DICT_SIZE, NICE_LEN, max_len, 0),
+            make_picker(),
+        );
+        check_roundtrip(
+            data,
+            BT2MatchFinder::new(DICT_SIZE, NICE_LEN, max_len, 0),
+            make_picker(),
+        );
+        check_roundtrip(
+            data,
+            BT3MatchFinder::new(DICT_SIZE, NICE_LEN, max_len, 0),
+            make_picker(),
+        );
+        check_roundtrip(
+            data,
+            BT4MatchFinder::new(DICT_SIZE, NICE_LEN, max_len, 0),
+            make_picker(),
+        );
+    }
+
+    #[test]
+    fn test_all_finders_with_fast_picker() {
+        check_all_finders(|| LZMAFastInstructionPicker::new(NICE_LEN), &test_data());
+    }
+
+    #[test]
+    fn test_all_finders_with_normal_picker() {
+        check_all_finders(
+            || LZMANormalInstructionPicker::new(NICE_LEN, 2),
+            &test_data(),
+        );
+    }
+
+    #[test]
+    fn test_match_at_end_through_options() {
+        // The last 3 bytes only repeat the first 3
+        let data = b"xyz0123456789abcdefxyz";
+        for match_finder in [MatchFinderKind::HC3, MatchFinderKind::BT3] {
+            let options = LzmaOptions {
+                match_finder,
+                ..Default::default()
+            };
+            let (_, mut input) = LZMACodecEncoder::from_options(&options).unwrap();
+            input.append_data(data);
+            input.skip(data.len() as u32 - 3);
+
+            let expected = Match {
+                distance: data.len() as u32 - 3 - 1,
+                len: 3,
+            };
+            assert_eq!(input.calc_matches(), &[expected]);
+        }
+    }
 }
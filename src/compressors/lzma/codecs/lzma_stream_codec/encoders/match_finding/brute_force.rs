@@ -4,7 +4,7 @@
 //! This is mainly used for testing to ensure that the more complex match finders are
 //! working correctly.
 
-use super::super::super::data_buffers::EncoderDataBuffer;
+use super::super::super::data_buffers::EncoderBuffer;
 
 use super::{Match, MatchFinder};
 
@@ -27,7 +27,7 @@ impl MatchFinder for BruteForceMatchFinder {
 
     fn find_and_write_matches(
         &mut self,
-        buffer: &EncoderDataBuffer,
+        buffer: &impl EncoderBuffer,
         output_matches_vec: &mut Vec<Match>,
     ) {
         output_matches_vec.clear();
@@ -59,7 +59,7 @@ impl MatchFinder for BruteForceMatchFinder {
         }
     }
 
-    fn skip_byte(&mut self, _buffer: &EncoderDataBuffer) {
+    fn skip_byte(&mut self, _buffer: &impl EncoderBuffer) {
         // N/A
     }
 }
@@ -0,0 +1,179 @@
+//! A binary tree match finder keyed on the first 2 bytes. Every 2 byte value has its own tree,
+//! so it finds the shortest matches of all the finders, at the cost of deeper searches.
+
+use super::super::super::data_buffers::EncoderBuffer;
+
+use super::utils::{
+    binary_tree::{extend_longest_match, BinaryTree},
+    hash234::Hash2,
+    match_positions::{MatchPos, MatchReadPos, PosIncrementResult},
+};
+use super::{Match, MatchFinder};
+
+pub struct BT2MatchFinder {
+    hash: Hash2<MatchPos>,
+    tree: BinaryTree,
+    nice_len: u32,
+    max_match_len: u32,
+
+    /// The relative index of the current "read head". The index isn't absolute as it gets shifted down once it reaches 0x7FFFFFFF.
+    lz_pos: MatchReadPos,
+}
+
+fn get_next_2_bytes(buffer: &impl EncoderBuffer) -> [u8; 2] {
+    [buffer.get_byte(0), buffer.get_byte(1)]
+}
+
+impl BT2MatchFinder {
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        Hash2::<MatchPos>::get_mem_usage() + BinaryTree::get_mem_usage(dict_size)
+    }
+
+    pub fn new(dict_size: u32, nice_len: u32, max_match_len: u32, depth_limit: i32) -> Self {
+        let depth_limit = if depth_limit > 0 {
+            depth_limit
+        } else {
+            16 + nice_len as i32 / 2
+        };
+        let tree = BinaryTree::new(dict_size, depth_limit);
+
+        Self {
+            hash: Hash2::new(),
+            lz_pos: MatchReadPos::new(tree.node_count() as u32),
+            tree,
+            nice_len,
+            max_match_len,
+        }
+    }
+
+    fn increment_pos(&mut self, buffer: &impl EncoderBuffer) {
+        if buffer.forwards_bytes() != 0 {
+            let result = self.lz_pos.increment();
+
+            if result == PosIncrementResult::ShouldNormalize {
+                let norm_offset = self.lz_pos.get_norm_offset(self.tree.node_count());
+
+                self.hash.map_all_values(|v| v.subtract_offset(norm_offset));
+                self.tree.subtract_offset(norm_offset);
+
+                self.lz_pos = self.lz_pos.subtract_offset(norm_offset);
+            }
+        }
+    }
+}
+
+impl MatchFinder for BT2MatchFinder {
+    const MIN_FORWARDS_BYTES: u32 = 2;
+
+    fn find_and_write_matches(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        output_matches_vec: &mut Vec<Match>,
+    ) {
+        output_matches_vec.clear();
+
+        self.increment_pos(buffer);
+        let avail = buffer.forwards_bytes() as u32;
+        if avail < Self::MIN_FORWARDS_BYTES {
+            if avail != 0 {
+                self.tree.push_empty();
+            }
+            return;
+        }
+
+        let max_match_len = self.max_match_len.min(avail);
+        let nice_len = self.nice_len.min(max_match_len);
+
+        let index = self.hash.calc_hash_index(get_next_2_bytes(buffer));
+        let cur_match = self.hash.get_table_value(index);
+        self.hash.update_table(index, self.lz_pos.as_match_pos());
+
+        // Every position in the tree starts with the same 2 bytes, so only longer matches are useful.
+        self.tree.insert(
+            buffer,
+            self.lz_pos,
+            cur_match,
+            nice_len,
+            1,
+            Some(output_matches_vec),
+        );
+        extend_longest_match(buffer, output_matches_vec, nice_len, max_match_len);
+    }
+
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        self.increment_pos(buffer);
+
+        if buffer.forwards_bytes() < Self::MIN_FORWARDS_BYTES as usize {
+            // There aren't enough bytes to hash, but the tree still needs a node to stay aligned
+            // with lz_pos. The empty position is always out of the dictionary range.
+            if buffer.forwards_bytes() != 0 {
+                self.tree.push_empty();
+            }
+            return;
+        }
+
+        let index = self.hash.calc_hash_index(get_next_2_bytes(buffer));
+        let cur_match = self.hash.get_table_value(index);
+        self.hash.update_table(index, self.lz_pos.as_match_pos());
+
+        let nice_len = self
+            .nice_len
+            .min(self.max_match_len)
+            .min(buffer.forwards_bytes() as u32);
+        self.tree
+            .insert(buffer, self.lz_pos, cur_match, nice_len, 0, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::brute_force::BruteForceMatchFinder;
+
+    use super::*;
+    use crate::compressors::lzma::codecs::lzma_stream_codec::data_buffers::EncoderDataBuffer;
+
+    #[test]
+    fn test_finds_longest_match() {
+        let mut data = vec![0; 1000];
+        let mut seed = 1u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // A large enough alphabet that many 2 byte matches have no 3 byte match
+            data.push(b'a' + (seed >> 16) as u8 % 40);
+        }
+
+        let mut buffer = EncoderDataBuffer::new(4000, 4000);
+        buffer.append_data(&data);
+        buffer.skip(1000);
+
+        // A depth limit high enough to search the whole tree, so nothing can be missed
+        let mut bt2 = BT2MatchFinder::new(3998, 16, 24, 10_000);
+        let mut brute = BruteForceMatchFinder::new(24, 3998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for i in 0..buffer.forwards_bytes() - 2 {
+            if i % 5 == 0 {
+                bt2.skip_byte(&buffer);
+                buffer.increment_pos();
+                continue;
+            }
+
+            bt2.find_and_write_matches(&buffer, &mut out_vec_1);
+            brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+            let best = out_vec_2.iter().map(|m| m.len).max().unwrap_or(0);
+            assert_eq!(out_vec_1.last().map_or(0, |m| m.len), best);
+
+            for m in &out_vec_1 {
+                let is_match = out_vec_2
+                    .iter()
+                    .any(|m2| m2.distance == m.distance && m2.len >= m.len);
+                assert!(is_match, "{:?} is not a match", m);
+            }
+
+            buffer.increment_pos();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! A binary tree match finder keyed on the first 3 bytes. It finds shorter matches than
+//! `BT4MatchFinder`, which suits small dictionaries and data with few long repeats.
+//!
+//! 2 byte matches are looked up in their own hash table first.
+
+use super::super::super::data_buffers::EncoderBuffer;
+
+use super::utils::{
+    binary_tree::{extend_longest_match, BinaryTree},
+    hash234::Hash23,
+    match_positions::{MatchPos, MatchReadPos, PosIncrementResult},
+};
+use super::{Match, MatchFinder};
+
+pub struct BT3MatchFinder {
+    hash: Hash23<MatchPos>,
+    tree: BinaryTree,
+    nice_len: u32,
+    max_match_len: u32,
+
+    /// The relative index of the current "read head". The index isn't absolute as it gets shifted down once it reaches 0x7FFFFFFF.
+    lz_pos: MatchReadPos,
+}
+
+fn get_next_3_bytes(buffer: &impl EncoderBuffer) -> [u8; 3] {
+    [buffer.get_byte(0), buffer.get_byte(1), buffer.get_byte(2)]
+}
+
+impl BT3MatchFinder {
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        Hash23::<MatchPos>::get_mem_usage(dict_size) + BinaryTree::get_mem_usage(dict_size)
+    }
+
+    pub fn new(dict_size: u32, nice_len: u32, max_match_len: u32, depth_limit: i32) -> Self {
+        let depth_limit = if depth_limit > 0 {
+            depth_limit
+        } else {
+            16 + nice_len as i32 / 2
+        };
+        let tree = BinaryTree::new(dict_size, depth_limit);
+
+        Self {
+            hash: Hash23::new(dict_size),
+            lz_pos: MatchReadPos::new(tree.node_count() as u32),
+            tree,
+            nice_len,
+            max_match_len,
+        }
+    }
+
+    fn increment_pos(&mut self, buffer: &impl EncoderBuffer) {
+        if buffer.forwards_bytes() != 0 {
+            let result = self.lz_pos.increment();
+
+            if result == PosIncrementResult::ShouldNormalize {
+                let norm_offset = self.lz_pos.get_norm_offset(self.tree.node_count());
+
+                self.hash.map_all_values(|v| v.subtract_offset(norm_offset));
+                self.tree.subtract_offset(norm_offset);
+
+                self.lz_pos = self.lz_pos.subtract_offset(norm_offset);
+            }
+        }
+    }
+}
+
+impl MatchFinder for BT3MatchFinder {
+    const MIN_FORWARDS_BYTES: u32 = 3;
+
+    fn find_and_write_matches(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        output_matches_vec: &mut Vec<Match>,
+    ) {
+        output_matches_vec.clear();
+
+        self.increment_pos(buffer);
+        let avail = buffer.forwards_bytes() as u32;
+        if avail == 0 {
+            return;
+        }
+
+        let max_match_len = self.max_match_len.min(avail);
+        let nice_len = self.nice_len.min(max_match_len);
+
+        let index = self.hash.calc_hash_index(get_next_3_bytes(buffer));
+        let positions = self.hash.get_table_values(&index);
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos());
+
+        // Positions are stored after `lz_pos` is incremented, so the previous byte has a delta of 0.
+        let delta2 = self.lz_pos - positions.hash2_value - 1;
+
+        let mut len_best = 0;
+
+        if self.tree.is_in_dict(delta2 + 1) && buffer.is_match_at_least_longer_than(delta2, 2) {
+            len_best = buffer.get_match_length(2, delta2, nice_len);
+            output_matches_vec.push(Match {
+                distance: delta2,
+                len: len_best,
+            });
+
+            // The tree still needs the current position, even if there's no need to search it.
+            if len_best >= nice_len {
+                self.tree.insert(
+                    buffer,
+                    self.lz_pos,
+                    positions.hash3_value,
+                    nice_len,
+                    len_best,
+                    None,
+                );
+                extend_longest_match(buffer, output_matches_vec, nice_len, max_match_len);
+                return;
+            }
+        }
+
+        // After this point, the best match length can only be 2 or higher,
+        // as the tree only has positions that start with the same 3 bytes.
+        len_best = len_best.max(2);
+
+        self.tree.insert(
+            buffer,
+            self.lz_pos,
+            positions.hash3_value,
+            nice_len,
+            len_best,
+            Some(output_matches_vec),
+        );
+        extend_longest_match(buffer, output_matches_vec, nice_len, max_match_len);
+    }
+
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        self.increment_pos(buffer);
+
+        if buffer.forwards_bytes() < Self::MIN_FORWARDS_BYTES as usize {
+            // There aren't enough bytes to hash, but the tree still needs a node to stay aligned
+            // with lz_pos. The empty position is always out of the dictionary range.
+            if buffer.forwards_bytes() != 0 {
+                self.tree.push_empty();
+            }
+            return;
+        }
+
+        let index = self.hash.calc_hash_index(get_next_3_bytes(buffer));
+        let positions = self.hash.get_table_values(&index);
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos());
+
+        let nice_len = self
+            .nice_len
+            .min(self.max_match_len)
+            .min(buffer.forwards_bytes() as u32);
+        self.tree.insert(
+            buffer,
+            self.lz_pos,
+            positions.hash3_value,
+            nice_len,
+            0,
+            None,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::brute_force::BruteForceMatchFinder;
+
+    use super::*;
+    use crate::compressors::lzma::codecs::lzma_stream_codec::data_buffers::EncoderDataBuffer;
+
+    #[test]
+    fn test_finds_longest_match() {
+        let mut data = vec![0; 1000];
+        let mut seed = 1u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // A large enough alphabet that many 3 byte matches have no 4 byte match
+            data.push(b'a' + (seed >> 16) as u8 % 12);
+        }
+
+        let mut buffer = EncoderDataBuffer::new(4000, 4000);
+        buffer.append_data(&data);
+        buffer.skip(1000);
+
+        // A depth limit high enough to search the whole tree, so nothing can be missed
+        let mut bt3 = BT3MatchFinder::new(3998, 16, 24, 10_000);
+        let mut brute = BruteForceMatchFinder::new(24, 3998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for i in 0..buffer.forwards_bytes() - 3 {
+            if i % 5 == 0 {
+                bt3.skip_byte(&buffer);
+                buffer.increment_pos();
+                continue;
+            }
+
+            bt3.find_and_write_matches(&buffer, &mut out_vec_1);
+            brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+            let best = out_vec_2.iter().map(|m| m.len).max().unwrap_or(0);
+            if best >= 3 {
+                assert_eq!(out_vec_1.last().unwrap().len, best);
+            }
+
+            for m in &out_vec_1 {
+                let is_match = out_vec_2
+                    .iter()
+                    .any(|m2| m2.distance == m.distance && m2.len >= m.len);
+                assert!(is_match, "{:?} is not a match", m);
+            }
+
+            buffer.increment_pos();
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! A binary tree match finder keyed on the first 4 bytes, which finds long matches much more
+//! consistently than `HC4MatchFinder` at high `nice_len` values.
+//!
+//! Like HC4, 2 and 3 byte matches are looked up in their own hash tables first.
+
+use super::super::super::data_buffers::EncoderBuffer;
+
+use super::utils::{
+    binary_tree::{extend_longest_match, BinaryTree},
+    hash234::Hash234,
+    match_positions::{MatchPos, MatchReadPos, PosIncrementResult},
+};
+use super::{Match, MatchFinder};
+
+pub struct BT4MatchFinder {
+    hash: Hash234<MatchPos>,
+    tree: BinaryTree,
+    nice_len: u32,
+    max_match_len: u32,
+
+    /// The relative index of the current "read head". The index isn't absolute as it gets shifted down once it reaches 0x7FFFFFFF.
+    lz_pos: MatchReadPos,
+}
+
+fn get_next_4_bytes(buffer: &impl EncoderBuffer) -> [u8; 4] {
+    [
+        buffer.get_byte(0),
+        buffer.get_byte(1),
+        buffer.get_byte(2),
+        buffer.get_byte(3),
+    ]
+}
+
+impl BT4MatchFinder {
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        Hash234::<MatchPos>::get_mem_usage(dict_size) + BinaryTree::get_mem_usage(dict_size)
+    }
+
+    pub fn new(dict_size: u32, nice_len: u32, max_match_len: u32, depth_limit: i32) -> Self {
+        let depth_limit = if depth_limit > 0 {
+            depth_limit
+        } else {
+            16 + nice_len as i32 / 2
+        };
+        let tree = BinaryTree::new(dict_size, depth_limit);
+
+        Self {
+            hash: Hash234::new(dict_size),
+            lz_pos: MatchReadPos::new(tree.node_count() as u32),
+            tree,
+            nice_len,
+            max_match_len,
+        }
+    }
+
+    fn increment_pos(&mut self, buffer: &impl EncoderBuffer) {
+        if buffer.forwards_bytes() != 0 {
+            let result = self.lz_pos.increment();
+
+            if result == PosIncrementResult::ShouldNormalize {
+                let norm_offset = self.lz_pos.get_norm_offset(self.tree.node_count());
+
+                self.hash.map_all_values(|v| v.subtract_offset(norm_offset));
+                self.tree.subtract_offset(norm_offset);
+
+                self.lz_pos = self.lz_pos.subtract_offset(norm_offset);
+            }
+        }
+    }
+
+    /// The delta of a match with a previous position. Positions are stored after `lz_pos`
+    /// is incremented, so the previous byte has a delta of 0.
+    fn delta_to(&self, pos: MatchPos) -> u32 {
+        self.lz_pos - pos - 1
+    }
+}
+
+impl MatchFinder for BT4MatchFinder {
+    const MIN_FORWARDS_BYTES: u32 = 4;
+
+    fn find_and_write_matches(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        output_matches_vec: &mut Vec<Match>,
+    ) {
+        output_matches_vec.clear();
+
+        self.increment_pos(buffer);
+        let avail = buffer.forwards_bytes() as u32;
+        if avail == 0 {
+            return;
+        }
+
+        let max_match_len = self.max_match_len.min(avail);
+        let nice_len = self.nice_len.min(max_match_len);
+
+        let bytes = get_next_4_bytes(buffer);
+        let index = self.hash.calc_hash_index(bytes); // Grab the guessed indexes for the byte values
+        let positions = self.hash.get_table_values(&index); // Get the delta values at those table indexes
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos()); // Update the tables with the new position
+
+        let delta2 = self.delta_to(positions.hash2_value);
+        let delta3 = self.delta_to(positions.hash3_value);
+
+        let mut len_best = 0;
+
+        if self.tree.is_in_dict(delta2 + 1) && buffer.is_match_at_least_longer_than(delta2, 2) {
+            len_best = 2;
+            output_matches_vec.push(Match {
+                distance: delta2,
+                len: 2,
+            });
+        }
+
+        let mut latest_delta = delta2;
+
+        if latest_delta != delta3
+            && self.tree.is_in_dict(delta3 + 1)
+            && buffer.is_match_at_least_longer_than(delta3, 3)
+        {
+            len_best = 3;
+            output_matches_vec.push(Match {
+                distance: delta3,
+                len: 3,
+            });
+            latest_delta = delta3;
+        }
+
+        if let Some(last) = output_matches_vec.last_mut() {
+            len_best = buffer.get_match_length(len_best, latest_delta, nice_len);
+            last.len = len_best;
+
+            // The tree still needs the current position, even if there's no need to search it.
+            if len_best >= nice_len {
+                self.tree.insert(
+                    buffer,
+                    self.lz_pos,
+                    positions.hash4_value,
+                    nice_len,
+                    len_best,
+                    None,
+                );
+                last.len = buffer.get_match_length(len_best, latest_delta, max_match_len);
+                return;
+            }
+        }
+
+        // After this point, the best match length can only be 3 or higher
+        // Because we're checking hash4_value which uses all 3 bytes.
+        len_best = len_best.max(3);
+
+        self.tree.insert(
+            buffer,
+            self.lz_pos,
+            positions.hash4_value,
+            nice_len,
+            len_best,
+            Some(output_matches_vec),
+        );
+        extend_longest_match(buffer, output_matches_vec, nice_len, max_match_len);
+    }
+
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        self.increment_pos(buffer);
+
+        if buffer.forwards_bytes() < Self::MIN_FORWARDS_BYTES as usize {
+            // There aren't enough bytes to hash, but the tree still needs a node to stay aligned
+            // with lz_pos. The empty position is always out of the dictionary range.
+            if buffer.forwards_bytes() != 0 {
+                self.tree.push_empty();
+            }
+            return;
+        }
+
+        let index = self.hash.calc_hash_index(get_next_4_bytes(buffer));
+        let positions = self.hash.get_table_values(&index);
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos());
+
+        let nice_len = self
+            .nice_len
+            .min(self.max_match_len)
+            .min(buffer.forwards_bytes() as u32);
+        self.tree.insert(
+            buffer,
+            self.lz_pos,
+            positions.hash4_value,
+            nice_len,
+            0,
+            None,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{brute_force::BruteForceMatchFinder, hc4::HC4MatchFinder};
+
+    use super::*;
+    use crate::{
+        compressors::lzma::{
+            codecs::{
+                length_codec::MATCH_LEN_MAX,
+                lzma_stream_codec::{
+                    data_buffers::EncoderDataBuffer,
+                    encoders::{
+                        instructions_normal::LZMANormalInstructionPicker, LZMAEncoderInput,
+                    },
+                    LZMACodecEncoder,
+                },
+                range_codec::RangeEncoder,
+            },
+            options::LzmaOptions,
+            streams::LzmaReader,
+        },
+        test_utils::PROPS,
+    };
+    use std::io::{Cursor, Read};
+
+    fn test_data() -> Vec<u8> {
+        let mut data = vec![0; 1000];
+        let mut seed = 1u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // A small alphabet, so there are lots of overlapping matches of every length
+            data.push(b'a' + (seed >> 16) as u8 % 4);
+        }
+        data
+    }
+
+    #[test]
+    fn test_finds_longest_match() {
+        let data = test_data();
+        let mut buffer = EncoderDataBuffer::new(4000, 4000);
+        buffer.append_data(&data);
+        buffer.skip(1000);
+
+        // A depth limit high enough to search the whole tree, so nothing can be missed
+        let mut bt4 = BT4MatchFinder::new(3998, 16, 24, 10_000);
+        let mut brute = BruteForceMatchFinder::new(24, 3998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for _ in 0..buffer.forwards_bytes() - 4 {
+            bt4.find_and_write_matches(&buffer, &mut out_vec_1);
+            brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+            let best = out_vec_2.iter().map(|m| m.len).max().unwrap_or(0);
+            if best >= 4 {
+                assert_eq!(out_vec_1.last().unwrap().len, best);
+            }
+
+            // Every reported match is real, and they get longer
+            for pair in out_vec_1.windows(2) {
+                assert!(pair[0].len < pair[1].len);
+            }
+            for m in &out_vec_1 {
+                let is_match = out_vec_2
+                    .iter()
+                    .any(|m2| m2.distance == m.distance && m2.len >= m.len);
+                assert!(is_match, "{:?} is not a match", m);
+            }
+
+            buffer.increment_pos();
+        }
+    }
+
+    #[test]
+    fn test_skipped_positions_are_indexed() {
+        let data = test_data();
+        let mut buffer = EncoderDataBuffer::new(4000, 4000);
+        buffer.append_data(&data);
+        buffer.skip(1000);
+
+        let mut bt4 = BT4MatchFinder::new(3998, 32, 32, 10_000);
+        let mut brute = BruteForceMatchFinder::new(32, 3998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for i in 0..buffer.forwards_bytes() - 4 {
+            if i % 3 == 0 {
+                bt4.skip_byte(&buffer);
+            } else {
+                bt4.find_and_write_matches(&buffer, &mut out_vec_1);
+                brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+                let best = out_vec_2.iter().map(|m| m.len).max().unwrap_or(0);
+                if best >= 4 {
+                    assert_eq!(out_vec_1.last().unwrap().len, best);
+                }
+            }
+
+            buffer.increment_pos();
+        }
+    }
+
+    #[test]
+    fn test_longer_matches_than_hc4() {
+        // Only two different bytes, so every hash has thousands of candidates
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..20_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push(b'a' + (seed >> 16) as u8 % 2);
+        }
+
+        let mut buffer = EncoderDataBuffer::new(20_000, 20_000);
+        buffer.append_data(&data);
+
+        // The same default depth limits as the encoders use
+        let mut bt4 = BT4MatchFinder::new(20_000, 64, 273, 0);
+        let mut hc4 = HC4MatchFinder::new(20_000, 64, 273, 0);
+        let mut out_vec = Vec::new();
+        let mut total_lens = [0, 0];
+
+        for _ in 0..buffer.forwards_bytes() - 4 {
+            bt4.find_and_write_matches(&buffer, &mut out_vec);
+            total_lens[0] += out_vec.last().map_or(0, |m| m.len);
+            hc4.find_and_write_matches(&buffer, &mut out_vec);
+            total_lens[1] += out_vec.last().map_or(0, |m| m.len);
+
+            buffer.increment_pos();
+        }
+
+        assert!(total_lens[0] > total_lens[1] * 5 / 4);
+    }
+
+    /// Compress raw LZMA with the normal instruction picker, the same way `LzmaWriter` does.
+    fn compress(data: &[u8], match_finder: impl MatchFinder, dict_size: u32) -> Vec<u8> {
+        let nice_len = 273;
+        let picker = LZMANormalInstructionPicker::new(nice_len, 2);
+        let options = LzmaOptions {
+            dict_size,
+            nice_len,
+            ..Default::default()
+        };
+        let mut encoder = LZMACodecEncoder::new(&options, picker);
+        let mut input = LZMAEncoderInput::new(match_finder, dict_size);
+        let mut rc = RangeEncoder::new(Vec::new());
+
+        let mut written = 0;
+        while encoder.position() < data.len() as u64 {
+            let len = input.available_append_bytes().min(data.len() - written);
+            input.append_data(&data[written..written + len]);
+            written += len;
+
+            if written < data.len() && input.forward_bytes() <= MATCH_LEN_MAX {
+                continue;
+            }
+            encoder.encode_one_packet(&mut rc, &mut input).unwrap();
+        }
+
+        rc.finish().unwrap()
+    }
+
+    #[test]
+    fn test_compress_with_normal_picker() {
+        // Text made of a limited vocabulary, with lots of repeats at varying distances
+        let words = [
+            "match", "finder", "tree", "hash", "chain", "the", "of", "binary",
+        ];
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        while data.len() < 100_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.extend_from_slice(words[(seed >> 16) as usize % words.len()].as_bytes());
+            data.push(if (seed >> 8) & 7 == 0 { b'\n' } else { b' ' });
+        }
+
+        let dict_size = 1 << 16;
+        let compressed_bt4 = compress(
+            &data,
+            BT4MatchFinder::new(dict_size, 273, 273, 0),
+            dict_size,
+        );
+        let compressed_hc4 = compress(
+            &data,
+            HC4MatchFinder::new(dict_size, 273, 273, 0),
+            dict_size,
+        );
+        assert!(compressed_bt4.len() < compressed_hc4.len());
+
+        let mut reader = LzmaReader::new_raw(
+            Cursor::new(&compressed_bt4),
+            PROPS,
+            dict_size,
+            Some(data.len() as u64),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+}
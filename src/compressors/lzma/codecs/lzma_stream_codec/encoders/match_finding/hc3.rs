@@ -0,0 +1,227 @@
+//! A hash chain match finder keyed on the first 3 bytes. It's the fastest finder for fast presets
+//! and small dictionaries, where HC4 would miss too many 3 byte matches.
+
+use super::super::super::data_buffers::EncoderBuffer;
+
+use super::utils::{
+    cyclic_vec::CyclicVec,
+    hash234::Hash23,
+    match_positions::{MatchPos, MatchReadPos, PosIncrementResult},
+};
+use super::{Match, MatchFinder};
+
+pub struct HC3MatchFinder {
+    hash: Hash23<MatchPos>,
+    chain: CyclicVec<MatchPos>,
+    depth_limit: i32,
+    nice_len: u32,
+    max_match_len: u32,
+
+    /// The relative index of the current "read head". The index isn't absolute as it gets shifted down once it reaches 0x7FFFFFFF.
+    lz_pos: MatchReadPos,
+}
+
+fn get_next_3_bytes(buffer: &impl EncoderBuffer) -> [u8; 3] {
+    [buffer.get_byte(0), buffer.get_byte(1), buffer.get_byte(2)]
+}
+
+impl HC3MatchFinder {
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        Hash23::<MatchPos>::get_mem_usage(dict_size)
+            + dict_size as u64 * std::mem::size_of::<MatchPos>() as u64
+    }
+
+    pub fn new(dict_size: u32, nice_len: u32, max_match_len: u32, depth_limit: i32) -> Self {
+        Self {
+            hash: Hash23::new(dict_size),
+            chain: CyclicVec::new(dict_size as usize + 1),
+            depth_limit: if depth_limit > 0 {
+                depth_limit
+            } else {
+                4 + nice_len as i32 / 4
+            },
+            nice_len,
+            max_match_len,
+            lz_pos: MatchReadPos::new(dict_size + 1),
+        }
+    }
+
+    fn increment_pos(&mut self, buffer: &impl EncoderBuffer) {
+        if buffer.forwards_bytes() != 0 {
+            let result = self.lz_pos.increment();
+
+            if result == PosIncrementResult::ShouldNormalize {
+                let norm_offset = self.lz_pos.get_norm_offset(self.chain.len());
+
+                self.hash.map_all_values(|v| v.subtract_offset(norm_offset));
+                self.chain
+                    .iter_mut()
+                    .for_each(|v| *v = v.subtract_offset(norm_offset));
+
+                self.lz_pos = self.lz_pos.subtract_offset(norm_offset);
+            }
+        }
+    }
+}
+
+impl MatchFinder for HC3MatchFinder {
+    const MIN_FORWARDS_BYTES: u32 = 3;
+
+    fn find_and_write_matches(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        output_matches_vec: &mut Vec<Match>,
+    ) {
+        output_matches_vec.clear();
+
+        let lz_pos = self.lz_pos;
+
+        self.increment_pos(buffer);
+        let avail = buffer.forwards_bytes() as u32;
+        if avail == 0 {
+            return;
+        }
+
+        let max_match_len = self.max_match_len.min(avail);
+        let nice_len = self.nice_len.min(max_match_len);
+
+        let index = self.hash.calc_hash_index(get_next_3_bytes(buffer));
+        let positions = self.hash.get_table_values(&index);
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos());
+
+        let delta2 = lz_pos - positions.hash2_value;
+        self.chain.push(positions.hash3_value);
+
+        let mut len_best = 0;
+
+        // Distances must be smaller than the dictionary size, which is one less than the chain length.
+        if delta2 + 1 < self.chain.len() as u32 && buffer.is_match_at_least_longer_than(delta2, 2) {
+            len_best = buffer.get_match_length(2, delta2, max_match_len);
+            output_matches_vec.push(Match {
+                distance: delta2,
+                len: len_best,
+            });
+
+            if len_best >= nice_len {
+                return;
+            }
+        }
+
+        // After this point, the best match length can only be 2 or higher,
+        // as the chain only has positions that start with the same 3 bytes.
+        let mut len_best = len_best.max(2);
+
+        // Iterate through the chain of past positions with the same hash3.
+        let mut current_match = Some(positions.hash3_value);
+        let chain_delta_iter = std::iter::from_fn(|| {
+            let val = current_match?;
+
+            let delta = lz_pos - val;
+            if delta + 1 < self.chain.len() as u32 {
+                // The current position was pushed last, so the match's own chain entry is `delta + 2` back.
+                // The oldest position in the dictionary has no entry left to follow.
+                current_match = if delta + 2 < self.chain.len() as u32 {
+                    Some(*self.chain.get_backwards(delta as usize + 2))
+                } else {
+                    None
+                };
+                Some(delta)
+            } else {
+                current_match = None;
+                None
+            }
+        });
+
+        for delta in chain_delta_iter.take(self.depth_limit as usize) {
+            if delta == delta2 {
+                // Already checked with the hash2 table
+                continue;
+            }
+
+            if buffer.do_bytes_match_at(delta, len_best) && buffer.do_bytes_match_at(delta, 0) {
+                let len = buffer.get_match_length(1, delta, max_match_len);
+
+                if len > len_best {
+                    len_best = len;
+                    output_matches_vec.push(Match {
+                        distance: delta,
+                        len,
+                    });
+
+                    if len >= nice_len {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        self.increment_pos(buffer);
+
+        if buffer.forwards_bytes() < Self::MIN_FORWARDS_BYTES as usize {
+            // There aren't enough bytes to hash, but the chain still needs an entry to stay aligned
+            // with lz_pos. The empty position is always out of the dictionary range.
+            if buffer.forwards_bytes() != 0 {
+                self.chain.push(MatchPos::new());
+            }
+            return;
+        }
+
+        let index = self.hash.calc_hash_index(get_next_3_bytes(buffer));
+        let positions = self.hash.get_table_values(&index);
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos());
+
+        self.chain.push(positions.hash3_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::brute_force::BruteForceMatchFinder;
+
+    use super::*;
+    use crate::compressors::lzma::codecs::lzma_stream_codec::data_buffers::EncoderDataBuffer;
+
+    #[test]
+    fn test_finds_3_byte_matches() {
+        let mut data = vec![0; 100];
+        for i in 0..50u8 {
+            // Every 3 byte sequence repeats, but none of the 4 byte ones do
+            data.extend_from_slice(&[255, i, i.wrapping_mul(7), 254 - i]);
+        }
+        for i in 0..50u8 {
+            data.extend_from_slice(&[255, i, i.wrapping_mul(7), 200]);
+        }
+
+        let mut buffer = EncoderDataBuffer::new(1000, 1000);
+        buffer.append_data(&data);
+        buffer.skip(100);
+
+        let mut hc3 = HC3MatchFinder::new(998, 8, 8, 0);
+        let mut brute = BruteForceMatchFinder::new(8, 998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for i in 0..buffer.forwards_bytes() - 3 {
+            hc3.find_and_write_matches(&buffer, &mut out_vec_1);
+            brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+            if i >= 200 && i % 4 == 0 {
+                let best = out_vec_2.iter().map(|m| m.len).max().unwrap();
+                assert_eq!(best, 3);
+                assert_eq!(out_vec_1.last().unwrap().len, best);
+            }
+
+            for m in &out_vec_1 {
+                let is_match = out_vec_2
+                    .iter()
+                    .any(|m2| m2.distance == m.distance && m2.len >= m.len);
+                assert!(is_match, "{:?} is not a match", m);
+            }
+
+            buffer.increment_pos();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::super::super::data_buffers::EncoderDataBuffer;
+use super::super::super::data_buffers::EncoderBuffer;
 
 use super::utils::{
     cyclic_vec::CyclicVec,
@@ -18,7 +18,7 @@ pub struct HC4MatchFinder {
     lz_pos: MatchReadPos,
 }
 
-fn get_next_4_bytes(buffer: &EncoderDataBuffer) -> [u8; 4] {
+fn get_next_4_bytes(buffer: &impl EncoderBuffer) -> [u8; 4] {
     [
         buffer.get_byte(0),
         buffer.get_byte(1),
@@ -49,7 +49,7 @@ impl HC4MatchFinder {
         }
     }
 
-    fn increment_pos(&mut self, buffer: &EncoderDataBuffer) {
+    fn increment_pos(&mut self, buffer: &impl EncoderBuffer) {
         if buffer.forwards_bytes() != 0 {
             let result = self.lz_pos.increment();
 
@@ -72,7 +72,7 @@ impl MatchFinder for HC4MatchFinder {
 
     fn find_and_write_matches(
         &mut self,
-        buffer: &EncoderDataBuffer,
+        buffer: &impl EncoderBuffer,
         output_matches_vec: &mut Vec<Match>,
     ) {
         output_matches_vec.clear();
@@ -107,7 +107,8 @@ impl MatchFinder for HC4MatchFinder {
 
         // Check if the byte at the current position matches the byte delta2 positions behind it.
         // If so, update the best match length and add a new match to the output vector.
-        if delta2 < self.chain.len() as u32 && buffer.is_match_at_least_longer_than(delta2, 2) {
+        // Distances must be smaller than the dictionary size, which is one less than the chain length.
+        if delta2 + 1 < self.chain.len() as u32 && buffer.is_match_at_least_longer_than(delta2, 2) {
             len_best = 2;
             output_matches_vec.push(Match {
                 distance: delta2,
@@ -121,7 +122,7 @@ impl MatchFinder for HC4MatchFinder {
         // If so, update the best match length and add a new match to the output vector.
         // Set delta2 to delta3 to check for longer matches in the next iteration.
         if latest_delta != delta3
-            && delta3 < self.chain.len() as u32
+            && delta3 + 1 < self.chain.len() as u32
             && buffer.is_match_at_least_longer_than(delta3, 3)
         {
             len_best = 3;
@@ -161,7 +162,13 @@ impl MatchFinder for HC4MatchFinder {
 
             let delta = lz_pos - val;
             if delta + 1 < self.chain.len() as u32 {
-                current_match = Some(*self.chain.get_backwards(delta as usize + 1));
+                // The current position was pushed last, so the match's own chain entry is `delta + 2` back.
+                // The oldest position in the dictionary has no entry left to follow.
+                current_match = if delta + 2 < self.chain.len() as u32 {
+                    Some(*self.chain.get_backwards(delta as usize + 2))
+                } else {
+                    None
+                };
                 Some(delta)
             } else {
                 current_match = None;
@@ -204,15 +211,23 @@ impl MatchFinder for HC4MatchFinder {
         }
     }
 
-    fn skip_byte(&mut self, buffer: &EncoderDataBuffer) {
-        if buffer.forwards_bytes() != 0 {
-            let index = self.hash.calc_hash_index(get_next_4_bytes(buffer)); // Grab the guessed indexes for the byte values
-            let positions = self.hash.get_table_values(&index); // Get the delta values at those table indexes
-            self.hash.update_tables(&index, self.lz_pos.as_match_pos()); // Update the tables with the new position
+    fn skip_byte(&mut self, buffer: &impl EncoderBuffer) {
+        self.increment_pos(buffer);
 
-            // We don't increment lz_pos so we don't need to increment the cyclic vec either
-            self.chain.replace_current(positions.hash4_value);
+        if buffer.forwards_bytes() < Self::MIN_FORWARDS_BYTES as usize {
+            // There aren't enough bytes to hash, but the chain still needs an entry to stay aligned
+            // with lz_pos. The empty position is always out of the dictionary range.
+            if buffer.forwards_bytes() != 0 {
+                self.chain.push(MatchPos::new());
+            }
+            return;
         }
+
+        let index = self.hash.calc_hash_index(get_next_4_bytes(buffer)); // Grab the guessed indexes for the byte values
+        let positions = self.hash.get_table_values(&index); // Get the delta values at those table indexes
+        self.hash.update_tables(&index, self.lz_pos.as_match_pos()); // Update the tables with the new position
+
+        self.chain.push(positions.hash4_value);
     }
 }
 
@@ -221,6 +236,7 @@ mod tests {
     use super::super::brute_force::BruteForceMatchFinder;
 
     use super::*;
+    use crate::compressors::lzma::codecs::lzma_stream_codec::data_buffers::EncoderDataBuffer;
 
     fn assert_matches_equal(matches_1: &[Match], matches_2: &[Match]) {
         let mut matches1 = matches_1.to_vec();
@@ -326,4 +342,65 @@ mod tests {
             buffer.increment_pos();
         }
     }
+
+    #[test]
+    fn test_follow_chain() {
+        let mut data = vec![0; 100];
+        data.extend(b"abcdefgh_abcdxxxx_abcdyyyy_abcdefgh_");
+
+        let mut buffer = EncoderDataBuffer::new(1000, 2000);
+        buffer.append_data(&data);
+
+        let mut hc4 = HC4MatchFinder::new(998, 32, 32, 20);
+        let mut out_vec = Vec::new();
+
+        for _ in 0..127 {
+            hc4.skip_byte(&buffer);
+            buffer.increment_pos();
+        }
+
+        // The longest match is the oldest one, behind two shorter ones with the same hash
+        hc4.find_and_write_matches(&buffer, &mut out_vec);
+        assert_eq!(
+            out_vec.last(),
+            Some(&Match {
+                distance: 26,
+                len: 9
+            })
+        );
+    }
+
+    #[test]
+    fn test_finds_longest_match() {
+        let mut data = vec![0; 1000];
+        let mut seed = 1u32;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            // A small alphabet, so every hash has a long chain of candidates
+            data.push(b'a' + (seed >> 16) as u8 % 4);
+        }
+
+        let mut buffer = EncoderDataBuffer::new(4000, 4000);
+        buffer.append_data(&data);
+        buffer.skip(1000);
+
+        // A depth limit high enough to walk the whole chain, so nothing can be missed
+        let mut hc4 = HC4MatchFinder::new(3998, 24, 24, 10_000);
+        let mut brute = BruteForceMatchFinder::new(24, 3998);
+
+        let mut out_vec_1 = Vec::new();
+        let mut out_vec_2 = Vec::new();
+
+        for _ in 0..buffer.forwards_bytes() - 4 {
+            hc4.find_and_write_matches(&buffer, &mut out_vec_1);
+            brute.find_and_write_matches(&buffer, &mut out_vec_2);
+
+            let best = out_vec_2.iter().map(|m| m.len).max().unwrap_or(0);
+            if best >= 4 {
+                assert_eq!(out_vec_1.last().unwrap().len, best);
+            }
+
+            buffer.increment_pos();
+        }
+    }
 }
@@ -0,0 +1,171 @@
+//! The binary tree shared by the BT match finders, which keeps the previous positions with the
+//! same hash sorted by the bytes that follow them.
+//!
+//! Every search walks down the tree from the most recent position, and re-roots it at the
+//! current position on the way. Each step halves the remaining candidates and knows how many
+//! bytes already match, so it finds long matches much more consistently than a hash chain.
+
+use super::super::super::super::data_buffers::EncoderBuffer;
+
+use super::super::Match;
+use super::{
+    cyclic_vec::CyclicVec,
+    match_positions::{MatchPos, MatchReadPos},
+};
+
+/// Index of the child in a tree node that holds positions with smaller following bytes
+const SMALLER: usize = 0;
+/// Index of the child in a tree node that holds positions with larger following bytes
+const LARGER: usize = 1;
+
+/// A reference to one of the two children of a tree node, by how far back the node is in the tree.
+#[derive(Clone, Copy)]
+struct ChildRef {
+    node: usize,
+    child: usize,
+}
+
+pub struct BinaryTree {
+    /// The children of every position in the dictionary, with the current position pushed last
+    nodes: CyclicVec<[MatchPos; 2]>,
+    depth_limit: i32,
+}
+
+impl BinaryTree {
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        (dict_size as u64 + 2) * std::mem::size_of::<[MatchPos; 2]>() as u64
+    }
+
+    pub fn new(dict_size: u32, depth_limit: i32) -> Self {
+        Self {
+            // One node for every distance up to dict_size, plus the current position
+            nodes: CyclicVec::new(dict_size as usize + 2),
+            depth_limit,
+        }
+    }
+
+    /// The number of nodes, which positions must be normalized relative to.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Check if a distance is within the dictionary, which also excludes empty positions.
+    pub fn is_in_dict(&self, distance: u32) -> bool {
+        (distance as usize) + 1 < self.nodes.len()
+    }
+
+    /// Normalize every position in the tree.
+    pub fn subtract_offset(&mut self, offset: u32) {
+        self.nodes.iter_mut().for_each(|node| {
+            *node = node.map(|child| child.subtract_offset(offset));
+        });
+    }
+
+    /// Add a position that can't be matched, keeping the tree aligned with the read position.
+    pub fn push_empty(&mut self) {
+        self.nodes.push([MatchPos::new(); 2]);
+    }
+
+    fn set_child(&mut self, child_ref: ChildRef, pos: MatchPos) {
+        self.nodes.get_backwards_mut(child_ref.node)[child_ref.child] = pos;
+    }
+
+    /// Insert the current position at the root of the tree, which starts at `cur_match`, writing
+    /// every match longer than `len_best` on the way down. `lz_pos` must already be incremented
+    /// for the current position.
+    ///
+    /// The search only compares up to `len_limit` bytes. When a position matches that far, it's
+    /// replaced by the current position, which takes over its children.
+    pub fn insert(
+        &mut self,
+        buffer: &impl EncoderBuffer,
+        lz_pos: MatchReadPos,
+        mut cur_match: MatchPos,
+        len_limit: u32,
+        mut len_best: u32,
+        mut output_matches_vec: Option<&mut Vec<Match>>,
+    ) {
+        self.push_empty();
+
+        // Where to link the next candidate that sorts before or after the current position,
+        // and how many bytes the candidates on each side are known to share with it.
+        let mut smaller = ChildRef {
+            node: 1,
+            child: SMALLER,
+        };
+        let mut larger = ChildRef {
+            node: 1,
+            child: LARGER,
+        };
+        let mut smaller_len = 0;
+        let mut larger_len = 0;
+
+        let mut depth = self.depth_limit;
+        loop {
+            let distance = lz_pos - cur_match;
+            if depth <= 0 || !self.is_in_dict(distance) {
+                self.set_child(smaller, MatchPos::new());
+                self.set_child(larger, MatchPos::new());
+                return;
+            }
+            depth -= 1;
+
+            let delta = distance - 1;
+            let node = distance as usize + 1;
+
+            let mut len = smaller_len.min(larger_len);
+            if buffer.do_bytes_match_at(delta, len) {
+                len = buffer.get_match_length(len + 1, delta, len_limit);
+
+                if len > len_best {
+                    len_best = len;
+                    if let Some(output_matches_vec) = output_matches_vec.as_mut() {
+                        output_matches_vec.push(Match {
+                            distance: delta,
+                            len,
+                        });
+                    }
+                }
+
+                if len == len_limit {
+                    let children = *self.nodes.get_backwards(node);
+                    self.set_child(smaller, children[SMALLER]);
+                    self.set_child(larger, children[LARGER]);
+                    return;
+                }
+            }
+
+            if buffer.get_byte(len as i32 - distance as i32) < buffer.get_byte(len as i32) {
+                self.set_child(smaller, cur_match);
+                smaller = ChildRef {
+                    node,
+                    child: LARGER,
+                };
+                cur_match = self.nodes.get_backwards(node)[LARGER];
+                smaller_len = len;
+            } else {
+                self.set_child(larger, cur_match);
+                larger = ChildRef {
+                    node,
+                    child: SMALLER,
+                };
+                cur_match = self.nodes.get_backwards(node)[SMALLER];
+                larger_len = len;
+            }
+        }
+    }
+}
+
+/// The tree only compares up to `nice_len` bytes, so extend the longest match if it got that far.
+pub fn extend_longest_match(
+    buffer: &impl EncoderBuffer,
+    output_matches_vec: &mut [Match],
+    nice_len: u32,
+    max_match_len: u32,
+) {
+    if let Some(last) = output_matches_vec.last_mut() {
+        if last.len == nice_len {
+            last.len = buffer.get_match_length(nice_len, last.distance, max_match_len);
+        }
+    }
+}
@@ -40,6 +40,17 @@ impl<T: Default> CyclicVec<T> {
         }
     }
 
+    pub fn get_backwards_mut(&mut self, index: usize) -> &mut T {
+        debug_assert!(index < self.buf.len());
+
+        if index > self.pos {
+            let index = self.pos + self.buf.len() - index;
+            &mut self.buf[index]
+        } else {
+            &mut self.buf[self.pos - index]
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &T> {
         let (a, b) = self.buf.split_at(self.pos);
         a.iter().rev().chain(b.iter().rev())
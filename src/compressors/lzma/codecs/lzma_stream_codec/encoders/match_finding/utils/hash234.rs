@@ -85,10 +85,10 @@ where
 
     #[inline(always)]
     pub fn calc_hash_index(&mut self, next_bytes: [u8; 4]) -> HashIndex {
-        let hash = CRC_TABLE[next_bytes[0] as usize] ^ (next_bytes[1] as u32);
+        let hash = hash_2_bytes(next_bytes[0], next_bytes[1]);
         let hash2_value = (hash & HASH2_MASK) as i32; // & for faster modulo
 
-        let hash = hash ^ ((next_bytes[2] as u32) << 8);
+        let hash = hash_3_bytes(hash, next_bytes[2]);
         let hash3_value = (hash & HASH3_MASK) as i32; // & for faster modulo
 
         let hash = hash ^ CRC_TABLE[next_bytes[3] as usize] << 5;
@@ -125,6 +125,156 @@ where
     }
 }
 
+#[inline(always)]
+fn hash_2_bytes(byte0: u8, byte1: u8) -> u32 {
+    CRC_TABLE[byte0 as usize] ^ (byte1 as u32)
+}
+
+/// Extend the hash of the first 2 bytes with the third byte.
+#[inline(always)]
+fn hash_3_bytes(hash_2: u32, byte2: u8) -> u32 {
+    hash_2 ^ ((byte2 as u32) << 8)
+}
+
+/// A reduced version of `Hash234` for finders that search for matches by their first 3 bytes,
+/// which also looks up 2 byte matches. The hash3 table is sized by the dictionary, the same as
+/// the hash4 table in `Hash234`.
+pub struct Hash23<T>
+where
+    T: Default + Copy,
+{
+    hash2_table: Vec<T>,
+    hash3_table: Vec<T>,
+
+    hash3_mask: u32,
+}
+
+pub struct Hash23Index {
+    hash2_pos: usize,
+    hash3_pos: usize,
+}
+
+pub struct Hash23Values<T> {
+    pub hash2_value: T,
+    pub hash3_value: T,
+}
+
+impl<T> Hash23<T>
+where
+    T: Default + Copy,
+{
+    /// The same as `Hash234::get_hash4_size()`, except there are only 2^24 different 3 byte values.
+    fn get_hash3_size(dict_size: u32) -> u32 {
+        let h = dict_size.next_power_of_two() / 2;
+        h.clamp(1 << 16, 1 << 24)
+    }
+
+    /// Get estimated memory usage in bytes
+    pub fn get_mem_usage(dict_size: u32) -> u64 {
+        let arrays_total_len = HASH2_SIZE + Self::get_hash3_size(dict_size);
+        arrays_total_len as u64 * std::mem::size_of::<T>() as u64
+    }
+
+    pub fn new(dict_size: u32) -> Self {
+        let hash3_size = Self::get_hash3_size(dict_size);
+
+        Self {
+            hash2_table: vec![Default::default(); HASH2_SIZE as _],
+            hash3_table: vec![Default::default(); hash3_size as _],
+            hash3_mask: hash3_size - 1,
+        }
+    }
+
+    #[inline(always)]
+    pub fn calc_hash_index(&mut self, next_bytes: [u8; 3]) -> Hash23Index {
+        let hash = hash_2_bytes(next_bytes[0], next_bytes[1]);
+        let hash2_pos = (hash & HASH2_MASK) as usize;
+
+        let hash = hash_3_bytes(hash, next_bytes[2]);
+        let hash3_pos = (hash & self.hash3_mask) as usize;
+
+        Hash23Index {
+            hash2_pos,
+            hash3_pos,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_table_values(&self, index: &Hash23Index) -> Hash23Values<T> {
+        Hash23Values {
+            hash2_value: self.hash2_table[index.hash2_pos],
+            hash3_value: self.hash3_table[index.hash3_pos],
+        }
+    }
+
+    #[inline(always)]
+    pub fn update_tables(&mut self, index: &Hash23Index, pos: T) {
+        self.hash2_table[index.hash2_pos] = pos;
+        self.hash3_table[index.hash3_pos] = pos;
+    }
+
+    pub fn map_all_values(&mut self, f: impl Fn(&mut T) -> T) {
+        let mut f = move |x: &mut T| *x = f(x);
+        self.hash2_table.iter_mut().for_each(&mut f);
+        self.hash3_table.iter_mut().for_each(&mut f);
+    }
+}
+
+/// The smallest hash, for finders that search for matches by their first 2 bytes. There are few
+/// enough 2 byte values that each one gets its own entry, so there are no collisions.
+pub struct Hash2<T>
+where
+    T: Default + Copy,
+{
+    table: Vec<T>,
+}
+
+impl<T> Hash2<T>
+where
+    T: Default + Copy,
+{
+    const SIZE: usize = 1 << 16;
+
+    /// Get estimated memory usage in bytes
+    pub fn get_mem_usage() -> u64 {
+        Self::SIZE as u64 * std::mem::size_of::<T>() as u64
+    }
+
+    pub fn new() -> Self {
+        Self {
+            table: vec![Default::default(); Self::SIZE],
+        }
+    }
+
+    #[inline(always)]
+    pub fn calc_hash_index(&self, next_bytes: [u8; 2]) -> usize {
+        u16::from_le_bytes(next_bytes) as usize
+    }
+
+    #[inline(always)]
+    pub fn get_table_value(&self, index: usize) -> T {
+        self.table[index]
+    }
+
+    #[inline(always)]
+    pub fn update_table(&mut self, index: usize, pos: T) {
+        self.table[index] = pos;
+    }
+
+    pub fn map_all_values(&mut self, f: impl Fn(&mut T) -> T) {
+        self.table.iter_mut().for_each(|x| *x = f(x));
+    }
+}
+
+impl<T> Default for Hash2<T>
+where
+    T: Default + Copy,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 const CRC_TABLE: [u32; 256] = [
     0x00000000, 0x77073096, 0xee0e612c, 0x990951ba, 0x076dc419, 0x706af48f, 0xe963a535, 0x9e6495a3,
     0x0edb8832, 0x79dcb8a4, 0xe0d5e91e, 0x97d2d988, 0x09b64c2b, 0x7eb17cbd, 0xe7b82d07, 0x90bf1d91,
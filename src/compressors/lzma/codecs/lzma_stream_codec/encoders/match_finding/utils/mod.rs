@@ -1,3 +1,4 @@
+pub mod binary_tree;
 pub mod cyclic_vec;
 pub mod hash234;
 pub mod match_positions;
@@ -2,7 +2,7 @@ use super::{super::length_codec::MATCH_LEN_MAX, state::State, EncoderPriceCalc};
 
 use self::match_finding::{Match, MatchFinder};
 
-use super::data_buffers::EncoderDataBuffer;
+use super::data_buffers::{EncoderBuffer, EncoderDataBuffer, EncoderSliceBuffer};
 
 pub mod instructions_fast;
 // pub mod instructions_normal;
@@ -39,14 +39,48 @@ pub trait LZMAInstructionPicker {
     /// The data buffer can't be progressed more than the returned instruction's length.
     fn get_next_symbol(
         &mut self,
-        data: &mut LZMAEncoderInput<impl MatchFinder>,
+        data: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
         price_calc: &mut EncoderPriceCalc,
         state: &State,
     ) -> EncodeInstruction;
+
+    /// Drop any instructions that were picked ahead of time, e.g. because the encoder state was reset.
+    fn reset(&mut self);
+}
+
+/// An instruction picker picked at runtime, e.g. from `LzmaOptions`.
+pub enum AnyInstructionPicker {
+    Fast(instructions_fast::LZMAFastInstructionPicker),
+    Normal(instructions_normal::LZMANormalInstructionPicker),
+}
+
+impl LZMAInstructionPicker for AnyInstructionPicker {
+    fn get_next_symbol(
+        &mut self,
+        data: &mut LZMAEncoderInput<impl MatchFinder, impl EncoderBuffer>,
+        price_calc: &mut EncoderPriceCalc,
+        state: &State,
+    ) -> EncodeInstruction {
+        match self {
+            Self::Fast(picker) => picker.get_next_symbol(data, price_calc, state),
+            Self::Normal(picker) => picker.get_next_symbol(data, price_calc, state),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Self::Fast(picker) => picker.reset(),
+            Self::Normal(picker) => picker.reset(),
+        }
+    }
 }
 
-pub struct LZMAEncoderInput<M: MatchFinder> {
-    buffer: EncoderDataBuffer,
+/// The data being encoded, along with the match finder that indexes it.
+///
+/// By default the data is streamed through a cyclic buffer with `append_data()`. Data that is
+/// already in memory can be borrowed as a whole with `from_slice()` instead.
+pub struct LZMAEncoderInput<M: MatchFinder, B: EncoderBuffer = EncoderDataBuffer> {
+    buffer: B,
     dict_size: u32,
 
     matches: Vec<Match>,
@@ -56,6 +90,12 @@ pub struct LZMAEncoderInput<M: MatchFinder> {
 
 impl<M: MatchFinder> LZMAEncoderInput<M> {
     pub fn new(match_finder: M, dict_size: u32) -> Self {
+        Self::new_with_extra_history(match_finder, dict_size, 0)
+    }
+
+    /// Keep `extra_history` bytes behind the dictionary in the buffer, e.g. so that LZMA2 can
+    /// copy the data of a chunk that didn't compress.
+    pub fn new_with_extra_history(match_finder: M, dict_size: u32, extra_history: u32) -> Self {
         Self {
             matches: Vec::new(),
             match_finder,
@@ -64,20 +104,15 @@ impl<M: MatchFinder> LZMAEncoderInput<M> {
             // TODO: Investigate `MATCH_LEN_MAX * 20`. It means that the maximum forwards bytes would be
             // 10 times the maximum match length, which lets us do less buffer copy operations
             // when feeding input data.
-            buffer: EncoderDataBuffer::new(dict_size, MATCH_LEN_MAX as u32 * 20),
+            buffer: EncoderDataBuffer::new(
+                dict_size.saturating_add(extra_history),
+                MATCH_LEN_MAX as u32 * 20,
+            ),
 
             dict_size,
         }
     }
 
-    pub fn pos(&self) -> u64 {
-        self.buffer.pos()
-    }
-
-    pub fn dict_size(&self) -> u32 {
-        self.dict_size
-    }
-
     /// The number of free bytes that could safely be appended without overwriting the dictionary
     pub fn available_append_bytes(&self) -> usize {
         self.buffer.available_append_bytes()
@@ -88,27 +123,85 @@ impl<M: MatchFinder> LZMAEncoderInput<M> {
         self.buffer.append_data(data);
     }
 
+    /// Load a preset dictionary, which the following data can reference as if it had been
+    /// encoded just before it. Only the last `dict_size` bytes of the dictionary are used.
+    ///
+    /// This must be called before any data is appended.
+    pub fn load_preset_dict(&mut self, dict: &[u8]) {
+        assert!(
+            self.pos() == 0 && self.forward_bytes() == 0,
+            "Preset dictionaries must be loaded before any data"
+        );
+
+        let mut remaining = &dict[dict.len().saturating_sub(self.dict_size as usize)..];
+        while !remaining.is_empty() {
+            let len = self.available_append_bytes().min(remaining.len());
+            self.append_data(&remaining[..len]);
+            remaining = &remaining[len..];
+
+            // Keep a few bytes ahead while there's more to load, so the match finder can hash
+            // the positions at the end of each appended chunk.
+            let lookahead = if remaining.is_empty() {
+                0
+            } else {
+                self.match_finder.min_forwards_bytes() as usize
+            };
+            let len = self.forward_bytes().saturating_sub(lookahead);
+            self.skip(len as u32);
+        }
+    }
+}
+
+impl<'a, M: MatchFinder> LZMAEncoderInput<M, EncoderSliceBuffer<'a>> {
+    /// Use all of `data` as the input, without copying it. The match finder still only looks
+    /// `dict_size` bytes back, since that's as far as the decoder can reach.
+    pub fn from_slice(match_finder: M, dict_size: u32, data: &'a [u8]) -> Self {
+        Self {
+            matches: Vec::new(),
+            match_finder,
+            matches_calculated: false,
+            buffer: EncoderSliceBuffer::new(data),
+            dict_size,
+        }
+    }
+}
+
+impl<M: MatchFinder, B: EncoderBuffer> LZMAEncoderInput<M, B> {
+    pub fn pos(&self) -> u64 {
+        self.buffer.pos()
+    }
+
+    pub fn dict_size(&self) -> u32 {
+        self.dict_size
+    }
+
     pub fn forward_bytes(&self) -> usize {
         self.buffer.forwards_bytes()
     }
 
-    pub fn buffer(&self) -> &EncoderDataBuffer {
+    pub fn buffer(&self) -> &B {
         &self.buffer
     }
 
     pub fn increment_pos(&mut self) {
-        self.buffer.increment_pos();
-        self.matches_calculated = false;
+        self.skip(1);
     }
 
+    /// Progress the buffer forwards, indexing every passed position in the match finder.
     pub fn skip(&mut self, len: u32) {
-        self.buffer.skip(len);
-        self.matches_calculated = false;
+        for _ in 0..len {
+            if !self.matches_calculated {
+                self.match_finder.skip_byte(&self.buffer);
+            }
+
+            self.buffer.increment_pos();
+            self.matches_calculated = false;
+        }
     }
 
     #[inline(always)]
     pub fn calc_matches(&mut self) -> &[Match] {
-        if self.forward_bytes() < M::MIN_FORWARDS_BYTES as usize {
+        if self.forward_bytes() < self.match_finder.min_forwards_bytes() as usize {
             return &[];
         }
 
@@ -29,13 +29,10 @@ impl State {
         }
     }
 
-    // pub fn reset(&mut self) {
-    //     self.state = LIT_LIT;
-    // }
-
-    // pub fn set(&mut self, other: State) {
-    //     self.state = other.state;
-    // }
+    pub fn reset(&mut self) {
+        self.state = LIT_LIT;
+        self.reps = [0; REPS];
+    }
 
     pub fn get_idx(&self) -> u8 {
         self.state
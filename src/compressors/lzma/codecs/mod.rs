@@ -1,5 +1,8 @@
 pub mod header_codec;
 pub mod length_codec;
 pub mod literals_codec;
+pub mod lzip_codec;
+pub mod lzma2_codec;
 pub mod lzma_stream_codec;
 pub mod range_codec;
+pub mod xz_codec;
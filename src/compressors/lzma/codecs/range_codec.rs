@@ -24,8 +24,9 @@ pub use probability::*;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use std::io::{ErrorKind, Write};
-use std::io::{Read, Result};
+use std::io::{Read, Result, Write};
+
+use crate::error::{Error, ErrorKind};
 
 const SHIFT_BITS: u32 = 8;
 const TOP_MASK: u32 = 0xFF000000;
@@ -41,9 +42,24 @@ pub struct RangeEncoder<W: Write> {
     cache_size: u32,
     cache: u8,
     stream: W,
+    finish_guard: FinishGuard,
+}
+
+/// Panics if the range encoder is dropped without being finished, as the output would be truncated.
+///
+/// This lives in its own struct so that `RangeEncoder::finish` can move the inner stream out.
+struct FinishGuard {
     finished: bool,
 }
 
+impl std::ops::Drop for FinishGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            panic!("RangeEncoder dropped without being finished");
+        }
+    }
+}
+
 impl<W: Write> RangeEncoder<W> {
     pub fn new(inner: W) -> Self {
         Self {
@@ -52,7 +68,7 @@ impl<W: Write> RangeEncoder<W> {
             cache_size: 1,
             cache: 0,
             stream: inner,
-            finished: false,
+            finish_guard: FinishGuard { finished: false },
         }
     }
 
@@ -60,14 +76,22 @@ impl<W: Write> RangeEncoder<W> {
         &mut self.stream
     }
 
-    pub fn finish(mut self) -> Result<()> {
+    /// The number of bytes that haven't been written to the stream yet, which `finish()` would write.
+    /// Together with the bytes already written, this is the compressed size so far.
+    pub fn pending_size(&self) -> u32 {
+        self.cache_size + 4
+    }
+
+    /// Flush the remaining state of the encoder, returning the inner stream.
+    pub fn finish(mut self) -> Result<W> {
+        // A failed write is returned as an error, so the guard shouldn't panic on the way out
+        self.finish_guard.finished = true;
+
         for _i in 0..5 {
             self.shift_low()?;
         }
 
-        self.finished = true;
-
-        Ok(())
+        Ok(self.stream)
     }
 
     fn write_byte(&mut self, b: u8) -> std::io::Result<()> {
@@ -136,86 +160,280 @@ impl<W: Write> RangeEncoder<W> {
     }
 }
 
-impl<T: Write> std::ops::Drop for RangeEncoder<T> {
-    fn drop(&mut self) {
-        if !self.finished {
-            panic!("RangeEncoder dropped without being finished");
-        }
-    }
+/// The operations the LZMA decoders need from a range decoder. `RangeDecoder` reads its input
+/// from any reader, while `SliceRangeDecoder` is faster for input that is already in memory.
+pub trait RangeDecode {
+    /// Read the next byte if the range got too small. Decoding does this lazily before each bit,
+    /// so this needs to be called after the last bit to consume the whole range coded stream.
+    fn normalize(&mut self) -> Result<()>;
+
+    /// Decode a bit with a given probability
+    fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> Result<u32>;
+
+    /// Decode bits assuming the probability is 50/50
+    fn decode_direct_bits(&mut self, count: u32) -> Result<u32>;
 }
 
 pub struct RangeDecoder<R: Read> {
     stream: R,
+    state: RangeDecoderState,
+}
+
+/// The state of a range decoder without its inner reader, so decoding can continue
+/// from a different reader, e.g. when the input arrives in separate buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeDecoderState {
     range: u32,
     code: u32,
 }
 
-impl<R: Read> RangeDecoder<R> {
-    pub fn new(mut stream: R) -> Result<Self> {
-        let b = stream.read_u8()?;
-        if b != 0x00 {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidInput,
+impl RangeDecoderState {
+    fn uninitialized() -> Self {
+        Self {
+            code: 0,
+            range: 0xFFFFFFFFu32,
+        }
+    }
+
+    /// Start a new range coded stream from its first 5 bytes: a null byte, then the initial code.
+    /// The first byte is checked on its own, so that the error is noticed as soon as it's read.
+    fn check_first_byte(byte: u8) -> Result<()> {
+        if byte != 0x00 {
+            return Err(Error::with_message(
+                ErrorKind::CorruptRangeCoder,
                 "First byte of the range decoder stream must be 0x00",
-            ));
+            )
+            .into());
         }
-        let code = stream.read_u32::<BigEndian>()?;
+        Ok(())
+    }
+
+    fn start(code: u32) -> Result<Self> {
+        // The code must always be below the range, which starts at its maximum
+        if code == 0xFFFFFFFFu32 {
+            return Err(Error::with_message(
+                ErrorKind::CorruptRangeCoder,
+                "Invalid range decoder stream start",
+            )
+            .into());
+        }
+
         Ok(Self {
-            stream,
             code,
-            range: (0xFFFFFFFFu32),
+            range: 0xFFFFFFFFu32,
         })
     }
 
-    pub fn is_finished(&self) -> bool {
-        self.code == 0
+    fn needs_input(&self) -> bool {
+        self.range < K_TOP_VALUE
     }
-}
 
-impl<R: Read> RangeDecoder<R> {
-    fn normalize(&mut self) -> Result<()> {
-        if self.range < K_TOP_VALUE {
-            let next = self.stream.read_u8()? as u32;
-            self.code = (self.code << SHIFT_BITS) | next;
-            self.range <<= SHIFT_BITS;
-        }
-        Ok(())
+    fn shift_in(&mut self, byte: u8) {
+        self.code = (self.code << SHIFT_BITS) | byte as u32;
+        self.range <<= SHIFT_BITS;
     }
 
-    /// Decode a bit with a given probability
-    pub fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> Result<u32> {
-        self.normalize()?;
+    /// Decode a bit with a given probability. The state must be normalized first.
+    fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> u32 {
         let bound = (self.range >> (BIT_MODEL_TOTAL_BITS as u32)) * prob.0 as u32;
         if self.code < bound {
             // Decode the bit as a 0, and update the probability
             self.range = bound;
             prob.increment();
-            Ok(0)
+            0
         } else {
             // Decode the bit as a 1, and update the probability
             self.range -= bound;
             self.code -= bound;
             prob.decrement();
-            Ok(1)
+            1
         }
     }
 
+    /// Decode a bit assuming the probability is 50/50. The state must be normalized first.
+    fn decode_direct_bit(&mut self) -> u32 {
+        self.range >>= 1;
+
+        let t = (self.code.wrapping_sub(self.range)) >> 31; // 0 or 1
+        self.code -= self.range & (t.wrapping_sub(1)); // If 0, subtract 0. If 1, subtract range
+        1 - t // Bit is 1 if t is 0, and 0 if t is 1
+    }
+}
+
+impl<R: Read> RangeDecoder<R> {
+    pub fn new(stream: R) -> Result<Self> {
+        let mut decoder = Self::new_uninitialized(stream);
+        decoder.reset()?;
+        Ok(decoder)
+    }
+
+    /// Create a decoder without reading the start of the range coded stream.
+    /// `reset()` must be called before anything is decoded.
+    pub fn new_uninitialized(stream: R) -> Self {
+        Self {
+            stream,
+            state: RangeDecoderState::uninitialized(),
+        }
+    }
+
+    /// Start decoding a new range coded stream from the inner reader, e.g. for each LZMA2 chunk.
+    pub fn reset(&mut self) -> Result<()> {
+        RangeDecoderState::check_first_byte(self.stream.read_u8()?)?;
+        self.state = RangeDecoderState::start(self.stream.read_u32::<BigEndian>()?)?;
+        Ok(())
+    }
+
+    /// Continue decoding from a state saved with `state()`, reading the rest of the stream from `stream`.
+    pub fn from_state(stream: R, state: RangeDecoderState) -> Self {
+        Self { stream, state }
+    }
+
+    pub fn state(&self) -> RangeDecoderState {
+        self.state
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.code == 0
+    }
+
+    pub fn inner(&mut self) -> &mut R {
+        &mut self.stream
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> R {
+        self.stream
+    }
+
+    /// Read the next byte if the range got too small. Decoding does this lazily before each bit,
+    /// so this needs to be called after the last bit to consume the whole range coded stream.
+    pub fn normalize(&mut self) -> Result<()> {
+        if self.state.needs_input() {
+            let next = self.stream.read_u8()?;
+            self.state.shift_in(next);
+        }
+        Ok(())
+    }
+
+    /// Decode a bit with a given probability
+    pub fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> Result<u32> {
+        self.normalize()?;
+        Ok(self.state.decode_bit(prob))
+    }
+
     /// Decode bits assuming the probability is 50/50
     pub fn decode_direct_bits(&mut self, count: u32) -> Result<u32> {
         let mut result = 0;
         for _ in 0..count {
             self.normalize()?;
+            result = (result << 1) | self.state.decode_direct_bit();
+        }
+        Ok(result)
+    }
+}
 
-            self.range >>= 1;
+impl<R: Read> RangeDecode for RangeDecoder<R> {
+    fn normalize(&mut self) -> Result<()> {
+        RangeDecoder::normalize(self)
+    }
 
-            let t = (self.code.wrapping_sub(self.range)) >> 31; // 0 or 1
-            self.code -= self.range & (t.wrapping_sub(1)); // If 0, subtract 0. If 1, subtract range
-            let bit = 1 - t; // Bit is 1 if t is 0, and 0 if t is 1
+    fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> Result<u32> {
+        RangeDecoder::decode_bit(self, prob)
+    }
 
-            // result = (result << 1) | bit;
-            result = (result << 1) | bit;
+    fn decode_direct_bits(&mut self, count: u32) -> Result<u32> {
+        RangeDecoder::decode_direct_bits(self, count)
+    }
+}
+
+/// A range decoder for input that is already in memory. It reads bytes straight from the slice,
+/// instead of going through `Read` for every byte like `RangeDecoder` does.
+pub struct SliceRangeDecoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    state: RangeDecoderState,
+}
+
+impl<'a> SliceRangeDecoder<'a> {
+    pub fn new(input: &'a [u8]) -> Result<Self> {
+        let mut decoder = Self::new_uninitialized(input);
+        decoder.reset()?;
+        Ok(decoder)
+    }
+
+    /// Create a decoder without reading the start of the range coded stream.
+    /// `reset()` must be called before anything is decoded.
+    pub fn new_uninitialized(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            state: RangeDecoderState::uninitialized(),
+        }
+    }
+
+    /// Start decoding a new range coded stream from the current position.
+    pub fn reset(&mut self) -> Result<()> {
+        RangeDecoderState::check_first_byte(self.next_byte()?)?;
+
+        let mut code = [0; 4];
+        for byte in &mut code {
+            *byte = self.next_byte()?;
         }
-        Ok(result as _)
+        self.state = RangeDecoderState::start(u32::from_be_bytes(code))?;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.code == 0
+    }
+
+    /// The number of bytes read from the input so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The input that hasn't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.input[self.pos..]
+    }
+
+    #[inline(always)]
+    fn next_byte(&mut self) -> Result<u8> {
+        match self.input.get(self.pos) {
+            Some(&byte) => {
+                self.pos += 1;
+                Ok(byte)
+            }
+            None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl RangeDecode for SliceRangeDecoder<'_> {
+    #[inline(always)]
+    fn normalize(&mut self) -> Result<()> {
+        if self.state.needs_input() {
+            let next = self.next_byte()?;
+            self.state.shift_in(next);
+        }
+        Ok(())
+    }
+
+    fn decode_bit(&mut self, prob: &mut RangeEncProbability) -> Result<u32> {
+        self.normalize()?;
+        Ok(self.state.decode_bit(prob))
+    }
+
+    fn decode_direct_bits(&mut self, count: u32) -> Result<u32> {
+        let mut result = 0;
+        for _ in 0..count {
+            self.normalize()?;
+            result = (result << 1) | self.state.decode_direct_bit();
+        }
+        Ok(result)
     }
 }
 
@@ -224,6 +442,67 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    #[test]
+    fn test_range_decoder_invalid_start() {
+        let err = RangeDecoder::new(Cursor::new([1, 0, 0, 0, 0]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(Error::from(err).kind(), ErrorKind::CorruptRangeCoder);
+
+        // The code can't be as big as the initial range
+        let err = RangeDecoder::new(Cursor::new([0, 0xFF, 0xFF, 0xFF, 0xFF]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(Error::from(err).kind(), ErrorKind::CorruptRangeCoder);
+
+        RangeDecoder::new(Cursor::new([0, 0xFF, 0xFF, 0xFF, 0xFE])).unwrap();
+    }
+
+    #[test]
+    fn test_slice_range_decoder() {
+        let mut buf = Vec::new();
+        let mut prob = RangeEncProbability::new();
+        let mut encoder = RangeEncoder::new(&mut buf);
+        for i in 0..100 {
+            encoder.encode_bit(&mut prob, (i % 3 == 0) as u32).unwrap();
+            encoder.encode_direct_bits(i, 7).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        // Both decoders read the same bits and consume the same bytes
+        let mut reader = RangeDecoder::new(Cursor::new(&buf)).unwrap();
+        let mut slice = SliceRangeDecoder::new(&buf).unwrap();
+        let mut reader_prob = RangeEncProbability::new();
+        let mut slice_prob = RangeEncProbability::new();
+        for i in 0..100 {
+            assert_eq!(
+                slice.decode_bit(&mut slice_prob).unwrap(),
+                (i % 3 == 0) as u32
+            );
+            assert_eq!(slice.decode_direct_bits(7).unwrap(), i);
+            reader.decode_bit(&mut reader_prob).unwrap();
+            reader.decode_direct_bits(7).unwrap();
+            assert_eq!(slice.position() as u64, reader.get_ref().position());
+        }
+        slice.normalize().unwrap();
+        assert!(slice.is_finished());
+        assert!(slice.remaining().is_empty());
+
+        // Running out of input is an error, like with a reader
+        let mut slice = SliceRangeDecoder::new(&buf[..10]).unwrap();
+        let err = (0..10)
+            .find_map(|_| slice.decode_direct_bits(16).err())
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let err = SliceRangeDecoder::new(&[1, 0, 0, 0, 0]).err().unwrap();
+        assert_eq!(Error::from(err).kind(), ErrorKind::CorruptRangeCoder);
+        let err = SliceRangeDecoder::new(&[0, 0]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     #[test]
     fn test_range_encoder() {
         let mut buf = Vec::new();
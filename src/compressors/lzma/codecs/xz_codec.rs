@@ -0,0 +1,313 @@
+//! # The `.xz` container format
+//!
+//! An `.xz` file is made of one or more streams, optionally separated by stream padding (null bytes
+//! in multiples of 4). Each stream is laid out as:
+//!
+//! - A 12 byte stream header: the magic bytes, the stream flags (which contain the check type) and
+//!   a CRC32 of the flags.
+//! - Any number of blocks. Each block has a header describing its filter chain, the compressed data,
+//!   padding to a multiple of 4 bytes, then the check of the uncompressed data.
+//! - The index, which lists the unpadded and uncompressed size of every block.
+//! - A 12 byte stream footer: a CRC32, the size of the index, the stream flags again, then `YZ`.
+//!
+//! All the multi-byte integers inside the headers and the index are variable length, 7 bits per byte
+//! with the high bit set on every byte but the last.
+//!
+//! The format is described in full at <https://tukaani.org/xz/xz-file-format.txt>.
+
+pub mod block_header;
+pub mod checks;
+pub mod crc32;
+pub mod crc64;
+pub mod index;
+pub mod sha256;
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use byteorder::ReadBytesExt;
+
+use self::{checks::CheckType, crc32::crc32};
+
+pub const STREAM_HEADER_SIZE: usize = 12;
+pub const STREAM_FOOTER_SIZE: usize = 12;
+
+pub const HEADER_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+pub const FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+
+/// The largest value a variable length integer can hold.
+pub const VARINT_MAX: u64 = u64::MAX / 2;
+const VARINT_MAX_BYTES: usize = 9;
+
+/// The different ways an `.xz` file can be invalid or unsupported.
+///
+/// These are returned wrapped in an `io::Error`, and can be retrieved with `io::Error::get_ref()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XzError {
+    /// The stream doesn't start with the `.xz` magic bytes.
+    InvalidHeaderMagic,
+    /// The CRC32 of the stream header doesn't match.
+    StreamHeaderCrcMismatch,
+    /// The stream flags have reserved bits set.
+    UnsupportedStreamFlags,
+    /// The check type isn't one of the supported ones.
+    UnsupportedCheck(u8),
+    /// The CRC32 of a block header doesn't match.
+    BlockHeaderCrcMismatch,
+    /// A block header is malformed, e.g. it has reserved bits set or its fields overflow it.
+    InvalidBlockHeader,
+    /// A block uses a filter that isn't supported.
+    UnsupportedFilter(u64),
+    /// The filter chain of a block isn't valid, e.g. LZMA2 isn't the last filter.
+    InvalidFilterChain,
+    /// The properties of a filter are invalid.
+    InvalidFilterProps,
+    /// The compressed or uncompressed size of a block doesn't match the one in its header.
+    BlockSizeMismatch,
+    /// The padding after a block's compressed data isn't made of null bytes.
+    InvalidBlockPadding,
+    /// The check of a block doesn't match its uncompressed data.
+    CheckMismatch,
+    /// The index is malformed, e.g. it has non-null padding.
+    InvalidIndex,
+    /// The CRC32 of the index doesn't match.
+    IndexCrcMismatch,
+    /// The index doesn't match the blocks that were read.
+    IndexRecordMismatch,
+    /// The stream footer doesn't end with the `YZ` magic bytes.
+    InvalidFooterMagic,
+    /// The CRC32 of the stream footer doesn't match.
+    StreamFooterCrcMismatch,
+    /// The stream flags in the footer differ from the ones in the header.
+    StreamFlagsMismatch,
+    /// The index size stored in the footer doesn't match the actual index.
+    BackwardSizeMismatch,
+    /// The stream padding isn't made of null bytes in a multiple of 4.
+    InvalidStreamPadding,
+    /// A variable length integer is longer than 9 bytes or isn't minimally encoded.
+    InvalidVarint,
+}
+
+impl fmt::Display for XzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XzError::InvalidHeaderMagic => write!(f, "Invalid xz stream header magic"),
+            XzError::StreamHeaderCrcMismatch => write!(f, "xz stream header CRC32 mismatch"),
+            XzError::UnsupportedStreamFlags => write!(f, "Unsupported xz stream flags"),
+            XzError::UnsupportedCheck(id) => write!(f, "Unsupported xz check type {:#04x}", id),
+            XzError::BlockHeaderCrcMismatch => write!(f, "xz block header CRC32 mismatch"),
+            XzError::InvalidBlockHeader => write!(f, "Invalid xz block header"),
+            XzError::UnsupportedFilter(id) => write!(f, "Unsupported xz filter {:#x}", id),
+            XzError::InvalidFilterChain => write!(f, "Invalid xz filter chain"),
+            XzError::InvalidFilterProps => write!(f, "Invalid xz filter properties"),
+            XzError::BlockSizeMismatch => {
+                write!(f, "xz block size doesn't match the block header")
+            }
+            XzError::InvalidBlockPadding => write!(f, "Invalid xz block padding"),
+            XzError::CheckMismatch => write!(f, "xz block check mismatch"),
+            XzError::InvalidIndex => write!(f, "Invalid xz index"),
+            XzError::IndexCrcMismatch => write!(f, "xz index CRC32 mismatch"),
+            XzError::IndexRecordMismatch => {
+                write!(f, "xz index doesn't match the blocks in the stream")
+            }
+            XzError::InvalidFooterMagic => write!(f, "Invalid xz stream footer magic"),
+            XzError::StreamFooterCrcMismatch => write!(f, "xz stream footer CRC32 mismatch"),
+            XzError::StreamFlagsMismatch => {
+                write!(f, "xz stream footer flags don't match the header")
+            }
+            XzError::BackwardSizeMismatch => {
+                write!(f, "xz stream footer index size doesn't match the index")
+            }
+            XzError::InvalidStreamPadding => write!(f, "Invalid xz stream padding"),
+            XzError::InvalidVarint => write!(f, "Invalid xz variable length integer"),
+        }
+    }
+}
+
+impl std::error::Error for XzError {}
+
+impl From<XzError> for io::Error {
+    fn from(err: XzError) -> Self {
+        let kind = match err {
+            XzError::UnsupportedStreamFlags
+            | XzError::UnsupportedCheck(_)
+            | XzError::UnsupportedFilter(_) => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+pub fn read_varint(mut reader: impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..VARINT_MAX_BYTES {
+        let byte = reader.read_u8()?;
+        value |= ((byte & 0x7F) as u64) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            // Only the single byte encoding of zero may end with a zero byte
+            if byte == 0 && i != 0 {
+                return Err(XzError::InvalidVarint.into());
+            }
+            return Ok(value);
+        }
+    }
+
+    Err(XzError::InvalidVarint.into())
+}
+
+pub fn write_varint(mut writer: impl Write, mut value: u64) -> io::Result<()> {
+    debug_assert!(value <= VARINT_MAX);
+
+    while value >= 0x80 {
+        writer.write_all(&[value as u8 | 0x80])?;
+        value >>= 7;
+    }
+    writer.write_all(&[value as u8])
+}
+
+/// The stream flags, as stored in both the stream header and footer.
+fn encode_stream_flags(check: CheckType) -> [u8; 2] {
+    [0x00, check.id()]
+}
+
+fn parse_stream_flags(flags: [u8; 2]) -> Result<CheckType, XzError> {
+    if flags[0] != 0 || flags[1] & 0xF0 != 0 {
+        return Err(XzError::UnsupportedStreamFlags);
+    }
+    CheckType::from_id(flags[1])
+}
+
+pub fn encode_stream_header(check: CheckType) -> [u8; STREAM_HEADER_SIZE] {
+    let flags = encode_stream_flags(check);
+
+    let mut header = [0u8; STREAM_HEADER_SIZE];
+    header[..6].copy_from_slice(&HEADER_MAGIC);
+    header[6..8].copy_from_slice(&flags);
+    header[8..].copy_from_slice(&crc32(&flags).to_le_bytes());
+    header
+}
+
+pub fn parse_stream_header(header: &[u8; STREAM_HEADER_SIZE]) -> Result<CheckType, XzError> {
+    if header[..6] != HEADER_MAGIC {
+        return Err(XzError::InvalidHeaderMagic);
+    }
+
+    if crc32(&header[6..8]).to_le_bytes() != header[8..] {
+        return Err(XzError::StreamHeaderCrcMismatch);
+    }
+
+    parse_stream_flags([header[6], header[7]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFooter {
+    /// The size of the index in bytes, stored as the "backward size"
+    pub index_size: u64,
+    pub stream_flags: [u8; 2],
+}
+
+impl StreamFooter {
+    /// Check that the footer matches the stream it's for.
+    pub fn validate(&self, check: CheckType, index_size: u64) -> Result<(), XzError> {
+        if self.stream_flags != encode_stream_flags(check) {
+            return Err(XzError::StreamFlagsMismatch);
+        }
+        if self.index_size != index_size {
+            return Err(XzError::BackwardSizeMismatch);
+        }
+        Ok(())
+    }
+}
+
+pub fn encode_stream_footer(check: CheckType, index_size: u64) -> [u8; STREAM_FOOTER_SIZE] {
+    debug_assert!(index_size.is_multiple_of(4));
+    let backward_size = (index_size / 4 - 1) as u32;
+
+    let mut footer = [0u8; STREAM_FOOTER_SIZE];
+    footer[4..8].copy_from_slice(&backward_size.to_le_bytes());
+    footer[8..10].copy_from_slice(&encode_stream_flags(check));
+    footer[10..].copy_from_slice(&FOOTER_MAGIC);
+    let crc = crc32(&footer[4..10]);
+    footer[..4].copy_from_slice(&crc.to_le_bytes());
+    footer
+}
+
+pub fn parse_stream_footer(footer: &[u8; STREAM_FOOTER_SIZE]) -> Result<StreamFooter, XzError> {
+    if footer[10..] != FOOTER_MAGIC {
+        return Err(XzError::InvalidFooterMagic);
+    }
+
+    if crc32(&footer[4..10]).to_le_bytes() != footer[..4] {
+        return Err(XzError::StreamFooterCrcMismatch);
+    }
+
+    let backward_size = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+
+    Ok(StreamFooter {
+        index_size: (backward_size as u64 + 1) * 4,
+        stream_flags: [footer[8], footer[9]],
+    })
+}
+
+/// The number of null bytes needed after `size` bytes to reach a multiple of 4.
+pub fn padding_size(size: u64) -> usize {
+    ((4 - size % 4) % 4) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 32, VARINT_MAX] {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, value).unwrap();
+            assert_eq!(read_varint(&encoded[..]).unwrap(), value);
+        }
+
+        let mut encoded = Vec::new();
+        write_varint(&mut encoded, 0x80).unwrap();
+        assert_eq!(encoded, [0x80, 0x01]);
+    }
+
+    #[test]
+    fn test_invalid_varints() {
+        for encoded in [&[0x80, 0x00][..], &[0xFF; 10][..]] {
+            let err = read_varint(encoded).unwrap_err();
+            assert_eq!(
+                err.get_ref().unwrap().downcast_ref::<XzError>(),
+                Some(&XzError::InvalidVarint)
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_header_and_footer() {
+        for check in [
+            CheckType::None,
+            CheckType::Crc32,
+            CheckType::Crc64,
+            CheckType::Sha256,
+        ] {
+            let header = encode_stream_header(check);
+            assert_eq!(parse_stream_header(&header), Ok(check));
+
+            let footer = parse_stream_footer(&encode_stream_footer(check, 64)).unwrap();
+            assert_eq!(footer.validate(check, 64), Ok(()));
+            assert_eq!(
+                footer.validate(check, 68),
+                Err(XzError::BackwardSizeMismatch)
+            );
+        }
+
+        // The header of an empty `xz --check=crc64` file
+        let header = [
+            0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00, 0x00, 0x04, 0xE6, 0xD6, 0xB4, 0x46,
+        ];
+        assert_eq!(encode_stream_header(CheckType::Crc64), header);
+    }
+}
@@ -0,0 +1,305 @@
+//! Block headers, which describe the filter chain a block was compressed with.
+//!
+//! The header size is stored in its first byte as `(size / 4) - 1`, so that a null byte can mark
+//! the start of the index instead. It's followed by the block flags, the optional compressed and
+//! uncompressed sizes, the filter flags, null padding to a multiple of 4 bytes, and a CRC32.
+
+use std::io::{self, Cursor, Read};
+
+use byteorder::ReadBytesExt;
+
+use super::{crc32::crc32, read_varint, write_varint, XzError, VARINT_MAX};
+use crate::compressors::filters::{bcj::BcjKind, delta};
+
+pub const LZMA2_FILTER_ID: u64 = 0x21;
+
+pub const BLOCK_HEADER_SIZE_MAX: usize = 1024;
+pub const FILTERS_MAX: usize = 4;
+
+/// A filter in a block's filter chain, with its raw properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterFlags {
+    pub id: u64,
+    pub props: Vec<u8>,
+}
+
+impl FilterFlags {
+    pub fn lzma2(dict_size: u32) -> Self {
+        Self {
+            id: LZMA2_FILTER_ID,
+            props: vec![lzma2_props_from_dict_size(dict_size)],
+        }
+    }
+
+    /// A delta filter, with a distance from 1 to 256.
+    pub fn delta(distance: usize) -> Self {
+        Self {
+            id: delta::XZ_FILTER_ID,
+            props: vec![delta::props_from_distance(distance)],
+        }
+    }
+
+    /// A BCJ filter. The start offset is only stored if it isn't 0.
+    pub fn bcj(kind: BcjKind, start_offset: u32) -> Self {
+        Self {
+            id: kind.xz_filter_id(),
+            props: match start_offset {
+                0 => Vec::new(),
+                offset => offset.to_le_bytes().to_vec(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub compressed_size: Option<u64>,
+    pub uncompressed_size: Option<u64>,
+    /// The filters in the order they're applied when encoding, so LZMA2 is always last.
+    pub filters: Vec<FilterFlags>,
+}
+
+impl BlockHeader {
+    /// Parse a block header, given its first byte which was already read to tell it apart from the
+    /// index indicator. Returns the header and its total size in bytes.
+    pub fn parse(size_byte: u8, mut reader: impl Read) -> io::Result<(Self, usize)> {
+        debug_assert_ne!(size_byte, 0);
+        let header_size = (size_byte as usize + 1) * 4;
+
+        let mut header = [0u8; BLOCK_HEADER_SIZE_MAX];
+        header[0] = size_byte;
+        reader.read_exact(&mut header[1..header_size])?;
+        let header = &header[..header_size];
+
+        let (content, crc) = header.split_at(header_size - 4);
+        if crc32(content).to_le_bytes() != crc {
+            return Err(XzError::BlockHeaderCrcMismatch.into());
+        }
+
+        // The header is CRC checked, so anything that runs past the end of it is malformed
+        // rather than truncated
+        Self::parse_content(&content[1..])
+            .map_err(|err| {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    XzError::InvalidBlockHeader.into()
+                } else {
+                    err
+                }
+            })
+            .map(|block_header| (block_header, header_size))
+    }
+
+    fn parse_content(content: &[u8]) -> io::Result<Self> {
+        let mut reader = Cursor::new(content);
+
+        let flags = reader.read_u8()?;
+        if flags & 0x3C != 0 {
+            return Err(XzError::InvalidBlockHeader.into());
+        }
+
+        let compressed_size = if flags & 0x40 != 0 {
+            let size = read_varint(&mut reader)?;
+            if size == 0 {
+                return Err(XzError::InvalidBlockHeader.into());
+            }
+            Some(size)
+        } else {
+            None
+        };
+
+        let uncompressed_size = if flags & 0x80 != 0 {
+            Some(read_varint(&mut reader)?)
+        } else {
+            None
+        };
+
+        let filter_count = (flags & 0x03) as usize + 1;
+        let mut filters = Vec::with_capacity(filter_count);
+        for _ in 0..filter_count {
+            let id = read_varint(&mut reader)?;
+            let props_size = read_varint(&mut reader)?;
+            if props_size > content.len() as u64 {
+                return Err(XzError::InvalidBlockHeader.into());
+            }
+
+            let mut props = vec![0u8; props_size as usize];
+            reader.read_exact(&mut props)?;
+            filters.push(FilterFlags { id, props });
+        }
+
+        let padding = &content[reader.position() as usize..];
+        if padding.iter().any(|&b| b != 0) {
+            return Err(XzError::InvalidBlockHeader.into());
+        }
+
+        Ok(Self {
+            compressed_size,
+            uncompressed_size,
+            filters,
+        })
+    }
+
+    /// Encode the header, including its size byte, padding and CRC32.
+    pub fn encode(&self) -> Vec<u8> {
+        debug_assert!((1..=FILTERS_MAX).contains(&self.filters.len()));
+
+        let mut flags = (self.filters.len() - 1) as u8;
+        if self.compressed_size.is_some() {
+            flags |= 0x40;
+        }
+        if self.uncompressed_size.is_some() {
+            flags |= 0x80;
+        }
+
+        // The size byte is filled in once the size is known. Writing to a Vec can't fail.
+        let mut header = vec![0, flags];
+        for size in [self.compressed_size, self.uncompressed_size]
+            .into_iter()
+            .flatten()
+        {
+            debug_assert!(size <= VARINT_MAX);
+            write_varint(&mut header, size).unwrap();
+        }
+        for filter in &self.filters {
+            write_varint(&mut header, filter.id).unwrap();
+            write_varint(&mut header, filter.props.len() as u64).unwrap();
+            header.extend_from_slice(&filter.props);
+        }
+
+        while (header.len() + 4) % 4 != 0 {
+            header.push(0);
+        }
+        header[0] = ((header.len() + 4) / 4 - 1) as u8;
+        debug_assert!(header.len() + 4 <= BLOCK_HEADER_SIZE_MAX);
+
+        let crc = crc32(&header);
+        header.extend_from_slice(&crc.to_le_bytes());
+        header
+    }
+}
+
+/// Decode the dictionary size from the single LZMA2 filter properties byte.
+pub fn lzma2_dict_size_from_props(props: &[u8]) -> Result<u32, XzError> {
+    match props {
+        [40] => Ok(u32::MAX),
+        &[bits] if bits < 40 => Ok((2 | (bits as u32 & 1)) << (bits / 2 + 11)),
+        _ => Err(XzError::InvalidFilterProps),
+    }
+}
+
+/// Encode the dictionary size as the LZMA2 filter properties byte, rounding it up
+/// to the nearest size that can be represented.
+pub fn lzma2_props_from_dict_size(dict_size: u32) -> u8 {
+    (0..40)
+        .find(|&bits| lzma2_dict_size_from_props(&[bits]).unwrap() >= dict_size)
+        .unwrap_or(40)
+}
+
+/// Get the start offset from BCJ filter properties, which are either empty or a 32-bit offset.
+pub fn bcj_start_offset_from_props(props: &[u8]) -> Result<u32, XzError> {
+    match props {
+        [] => Ok(0),
+        &[a, b, c, d] => Ok(u32::from_le_bytes([a, b, c, d])),
+        _ => Err(XzError::InvalidFilterProps),
+    }
+}
+
+pub fn delta_distance_from_props(props: &[u8]) -> Result<usize, XzError> {
+    delta::distance_from_props(props).ok_or(XzError::InvalidFilterProps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let headers = [
+            BlockHeader {
+                compressed_size: None,
+                uncompressed_size: None,
+                filters: vec![FilterFlags::lzma2(1 << 23)],
+            },
+            BlockHeader {
+                compressed_size: Some(12345),
+                uncompressed_size: Some(1 << 40),
+                filters: vec![
+                    FilterFlags {
+                        id: 0x03,
+                        props: vec![0x00],
+                    },
+                    FilterFlags::lzma2(1 << 20),
+                ],
+            },
+        ];
+
+        for header in headers {
+            let encoded = header.encode();
+            assert_eq!(encoded.len() % 4, 0);
+            let (parsed, size) = BlockHeader::parse(encoded[0], &encoded[1..]).unwrap();
+            assert_eq!(parsed, header);
+            assert_eq!(size, encoded.len());
+        }
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let mut encoded = BlockHeader {
+            compressed_size: None,
+            uncompressed_size: None,
+            filters: vec![FilterFlags::lzma2(1 << 23)],
+        }
+        .encode();
+
+        let mut corrupted = encoded.clone();
+        corrupted[4] ^= 1;
+        let err = BlockHeader::parse(corrupted[0], &corrupted[1..]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<XzError>(),
+            Some(&XzError::BlockHeaderCrcMismatch)
+        );
+
+        // Reserved flag bits, with a valid CRC
+        encoded[1] |= 0x04;
+        let crc = crc32(&encoded[..encoded.len() - 4]);
+        let len = encoded.len();
+        encoded[len - 4..].copy_from_slice(&crc.to_le_bytes());
+        let err = BlockHeader::parse(encoded[0], &encoded[1..]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<XzError>(),
+            Some(&XzError::InvalidBlockHeader)
+        );
+    }
+
+    #[test]
+    fn test_lzma2_dict_size_props() {
+        assert_eq!(lzma2_dict_size_from_props(&[0]), Ok(4096));
+        assert_eq!(lzma2_dict_size_from_props(&[1]), Ok(6144));
+        assert_eq!(lzma2_dict_size_from_props(&[22]), Ok(8 << 20));
+        assert_eq!(lzma2_dict_size_from_props(&[40]), Ok(u32::MAX));
+        assert_eq!(
+            lzma2_dict_size_from_props(&[41]),
+            Err(XzError::InvalidFilterProps)
+        );
+
+        assert_eq!(lzma2_props_from_dict_size(8 << 20), 22);
+        assert_eq!(lzma2_props_from_dict_size((8 << 20) + 1), 23);
+        assert_eq!(lzma2_props_from_dict_size(u32::MAX), 40);
+    }
+
+    #[test]
+    fn test_bcj_props() {
+        let filter = FilterFlags::bcj(BcjKind::Arm, 0);
+        assert_eq!(filter.id, 0x07);
+        assert_eq!(bcj_start_offset_from_props(&filter.props), Ok(0));
+
+        let filter = FilterFlags::bcj(BcjKind::X86, 0x1000);
+        assert_eq!(filter.props, [0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(bcj_start_offset_from_props(&filter.props), Ok(0x1000));
+
+        assert_eq!(
+            bcj_start_offset_from_props(&[1, 2]),
+            Err(XzError::InvalidFilterProps)
+        );
+    }
+}
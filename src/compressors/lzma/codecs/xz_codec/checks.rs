@@ -0,0 +1,142 @@
+//! The integrity checks `.xz` can store after each block, computed over the uncompressed data.
+
+use super::{crc32::Crc32, crc64::Crc64, sha256::Sha256, XzError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckType {
+    None,
+    Crc32,
+    Crc64,
+    Sha256,
+}
+
+impl CheckType {
+    pub fn from_id(id: u8) -> Result<Self, XzError> {
+        match id {
+            0x00 => Ok(CheckType::None),
+            0x01 => Ok(CheckType::Crc32),
+            0x04 => Ok(CheckType::Crc64),
+            0x0A => Ok(CheckType::Sha256),
+            _ => Err(XzError::UnsupportedCheck(id)),
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            CheckType::None => 0x00,
+            CheckType::Crc32 => 0x01,
+            CheckType::Crc64 => 0x04,
+            CheckType::Sha256 => 0x0A,
+        }
+    }
+
+    /// The number of bytes the check takes up after each block.
+    pub fn size(self) -> usize {
+        match self {
+            CheckType::None => 0,
+            CheckType::Crc32 => 4,
+            CheckType::Crc64 => 8,
+            CheckType::Sha256 => 32,
+        }
+    }
+}
+
+/// Incrementally computes a check over the uncompressed data of a block.
+#[derive(Debug, Clone)]
+pub enum Checker {
+    None,
+    Crc32(Crc32),
+    Crc64(Crc64),
+    Sha256(Box<Sha256>),
+}
+
+impl Checker {
+    pub fn new(check: CheckType) -> Self {
+        match check {
+            CheckType::None => Checker::None,
+            CheckType::Crc32 => Checker::Crc32(Crc32::new()),
+            CheckType::Crc64 => Checker::Crc64(Crc64::new()),
+            CheckType::Sha256 => Checker::Sha256(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checker::None => {}
+            Checker::Crc32(crc) => crc.update(data),
+            Checker::Crc64(crc) => crc.update(data),
+            Checker::Sha256(sha) => sha.update(data),
+        }
+    }
+
+    /// Get the check bytes, as they're stored in the file.
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Checker::None => Vec::new(),
+            Checker::Crc32(crc) => crc.finish().to_le_bytes().to_vec(),
+            Checker::Crc64(crc) => crc.finish().to_le_bytes().to_vec(),
+            Checker::Sha256(sha) => sha.finish().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn check(check: CheckType, data: &[u8]) -> Vec<u8> {
+        let mut checker = Checker::new(check);
+        checker.update(data);
+        checker.finish()
+    }
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(check(CheckType::None, b"123456789"), Vec::<u8>::new());
+        assert_eq!(
+            check(CheckType::Crc32, b"123456789"),
+            0xCBF43926u32.to_le_bytes()
+        );
+        assert_eq!(
+            check(CheckType::Crc64, b"123456789"),
+            0x995DC9BBDF1939FAu64.to_le_bytes()
+        );
+
+        assert_eq!(
+            hex(&check(CheckType::Sha256, b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&check(CheckType::Sha256, b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&check(
+                CheckType::Sha256,
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_incremental_updates() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+
+        for check_type in [CheckType::Crc32, CheckType::Crc64, CheckType::Sha256] {
+            let expected = check(check_type, &data);
+
+            for split in [1, 55, 63, 64, 65, 500] {
+                let mut checker = Checker::new(check_type);
+                for chunk in data.chunks(split) {
+                    checker.update(chunk);
+                }
+                assert_eq!(checker.finish(), expected);
+            }
+        }
+    }
+}
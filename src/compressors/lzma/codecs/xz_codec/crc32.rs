@@ -0,0 +1,60 @@
+//! CRC32 as used by `.xz` (and zip, gzip, png...), with the reflected polynomial `0xEDB88320`.
+
+const POLY: u32 = 0xEDB88320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.crc;
+        for &byte in data {
+            crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
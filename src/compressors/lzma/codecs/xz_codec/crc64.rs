@@ -0,0 +1,54 @@
+//! CRC64 as used by `.xz`, with the reflected ECMA-182 polynomial `0xC96C5795D7870F42`.
+
+const POLY: u64 = 0xC96C5795D7870F42;
+
+const TABLE: [u64; 256] = make_table();
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone)]
+pub struct Crc64 {
+    crc: u64,
+}
+
+impl Crc64 {
+    pub fn new() -> Self {
+        Self { crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.crc;
+        for &byte in data {
+            crc = TABLE[((crc ^ byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+
+    pub fn finish(&self) -> u64 {
+        !self.crc
+    }
+}
+
+impl Default for Crc64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,162 @@
+//! The index at the end of each stream, listing the sizes of every block in it.
+//!
+//! It starts with a null byte (which is where a block header size would otherwise be), followed by
+//! the number of records, the records themselves, null padding to a multiple of 4 bytes, and a CRC32
+//! of everything before it.
+
+use std::io::{self, Read};
+
+use byteorder::ReadBytesExt;
+
+use super::{crc32::Crc32, padding_size, read_varint, write_varint, XzError};
+
+pub const INDEX_INDICATOR: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexRecord {
+    /// The size of the block header, compressed data and check, excluding the block padding.
+    pub unpadded_size: u64,
+    pub uncompressed_size: u64,
+}
+
+/// Encode the index, including the indicator, padding and CRC32.
+pub fn encode_index(records: &[IndexRecord]) -> Vec<u8> {
+    // Writing to a Vec can't fail
+    let mut index = vec![INDEX_INDICATOR];
+    write_varint(&mut index, records.len() as u64).unwrap();
+    for record in records {
+        write_varint(&mut index, record.unpadded_size).unwrap();
+        write_varint(&mut index, record.uncompressed_size).unwrap();
+    }
+
+    index.resize(index.len() + padding_size(index.len() as u64), 0);
+
+    let mut crc = Crc32::new();
+    crc.update(&index);
+    index.extend_from_slice(&crc.finish().to_le_bytes());
+    index
+}
+
+/// A reader that keeps a CRC32 and count of everything read through it.
+struct IndexReader<R: Read> {
+    inner: R,
+    crc: Crc32,
+    size: u64,
+}
+
+impl<R: Read> Read for IndexReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+/// Parse the index, after its indicator byte was already read. Returns the records and
+/// the total size of the index in bytes, including the indicator.
+pub fn parse_index(reader: impl Read) -> io::Result<(Vec<IndexRecord>, u64)> {
+    let mut reader = IndexReader {
+        inner: reader,
+        crc: Crc32::new(),
+        size: 1,
+    };
+    reader.crc.update(&[INDEX_INDICATOR]);
+
+    let count = read_varint(&mut reader).map_err(invalid_varint_to_invalid_index)?;
+
+    // The count isn't trusted for preallocation, as it could be anything
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let unpadded_size = read_varint(&mut reader).map_err(invalid_varint_to_invalid_index)?;
+        let uncompressed_size =
+            read_varint(&mut reader).map_err(invalid_varint_to_invalid_index)?;
+        if unpadded_size == 0 {
+            return Err(XzError::InvalidIndex.into());
+        }
+
+        records.push(IndexRecord {
+            unpadded_size,
+            uncompressed_size,
+        });
+    }
+
+    for _ in 0..padding_size(reader.size) {
+        if reader.read_u8()? != 0 {
+            return Err(XzError::InvalidIndex.into());
+        }
+    }
+
+    let expected_crc = reader.crc.finish();
+    let mut crc = [0u8; 4];
+    reader.inner.read_exact(&mut crc)?;
+    if crc != expected_crc.to_le_bytes() {
+        return Err(XzError::IndexCrcMismatch.into());
+    }
+
+    Ok((records, reader.size + 4))
+}
+
+fn invalid_varint_to_invalid_index(err: io::Error) -> io::Error {
+    match err.get_ref().and_then(|e| e.downcast_ref::<XzError>()) {
+        Some(XzError::InvalidVarint) => XzError::InvalidIndex.into(),
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for records in [
+            vec![],
+            vec![IndexRecord {
+                unpadded_size: 100,
+                uncompressed_size: 1000,
+            }],
+            (1..100)
+                .map(|i| IndexRecord {
+                    unpadded_size: i * 1000,
+                    uncompressed_size: i << 30,
+                })
+                .collect(),
+        ] {
+            let encoded = encode_index(&records);
+            assert_eq!(encoded.len() % 4, 0);
+            assert_eq!(encoded[0], INDEX_INDICATOR);
+
+            let (parsed, size) = parse_index(&encoded[1..]).unwrap();
+            assert_eq!(parsed, records);
+            assert_eq!(size, encoded.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let records = [IndexRecord {
+            unpadded_size: 100,
+            uncompressed_size: 1000,
+        }];
+        let encoded = encode_index(&records);
+
+        let mut corrupted = encoded.clone();
+        corrupted[2] ^= 1;
+        let err = parse_index(&corrupted[1..]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<XzError>(),
+            Some(&XzError::IndexCrcMismatch)
+        );
+
+        // Non-null padding
+        let mut corrupted = encoded.clone();
+        let len = corrupted.len();
+        corrupted[len - 5] = 1;
+        let err = parse_index(&corrupted[1..]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<XzError>(),
+            Some(&XzError::InvalidIndex)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Feeds corrupted and truncated streams to every reader, which must return errors instead of panicking.
+//! The fuzz targets in `fuzz/` do the same with far more inputs; these are a quick check that runs with the tests.
+
+use std::io::{self, Cursor, Read, Write};
+
+use super::{
+    codecs::xz_codec::{block_header::FilterFlags, checks::CheckType},
+    lzip_streams::{LzipReader, LzipWriter},
+    lzma2_streams::{Lzma2Reader, Lzma2Writer},
+    one_shot::decompress_into,
+    push_decoder::LzmaPushDecoder,
+    streams::{LzmaReader, LzmaWriter},
+    xz_mt_streams::{XzMtReader, XzMtWriter},
+    xz_seek_streams::XzSeekReader,
+    xz_streams::{XzReader, XzWriter},
+};
+use crate::{compressors::filters::bcj::BcjKind, test_utils::PROPS};
+
+/// A small xorshift generator, so the corruptions are the same in every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn test_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in 0..3000u32 {
+        data.extend_from_slice(format!("{} {} ", i % 97, i % 13).as_bytes());
+        if i % 100 == 0 {
+            // Some incompressible data, so LZMA2 stores uncompressed chunks too
+            data.extend((0..200u32).map(|j| (j * 7919 + i * 31) as u8 ^ (j >> 3) as u8));
+        }
+    }
+    data
+}
+
+/// Variants of a valid stream with a few random bytes changed, or cut short.
+fn corrupted_variants(compressed: &[u8], count: usize) -> Vec<Vec<u8>> {
+    let mut rng = Rng(0x2545F4914F6CDD1D ^ compressed.len() as u64);
+    let mut variants = Vec::new();
+
+    for i in 0..count {
+        let mut variant = compressed.to_vec();
+        if i % 4 == 3 {
+            variant.truncate(rng.below(compressed.len()));
+        } else {
+            // Mostly corrupt the start, where the headers and the first packets are
+            let range = if i % 2 == 0 { 64 } else { compressed.len() };
+            for _ in 0..1 + rng.below(3) {
+                let pos = rng.below(range.min(compressed.len()));
+                variant[pos] = rng.next() as u8;
+            }
+        }
+        variants.push(variant);
+    }
+
+    variants
+}
+
+/// Read everything, discarding the data. Errors are expected, panics and hangs aren't.
+fn drain(reader: io::Result<impl Read>) {
+    if let Ok(mut reader) = reader {
+        let mut buf = [0; 4096];
+        while let Ok(read) = reader.read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_corrupt_lzma() {
+    let data = test_data();
+    for size in [Some(data.len() as u64), None] {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, size).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        for variant in corrupted_variants(&compressed, 300) {
+            drain(LzmaReader::new(Cursor::new(&variant)));
+            let _ = decompress_into(&variant, &mut vec![0; data.len()]);
+
+            let mut decoder = LzmaPushDecoder::new();
+            let mut output = vec![0; 1000];
+            let mut input = &variant[..];
+            while let Ok(progress) = decoder.decode(&input[..input.len().min(100)], &mut output) {
+                input = &input[progress.consumed..];
+                if progress.consumed == 0 && progress.written == 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_corrupt_lzma2() {
+    let data = test_data();
+    let mut writer = Lzma2Writer::new(Vec::new(), PROPS, 1 << 16).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    for variant in corrupted_variants(&compressed, 300) {
+        drain(Ok(Lzma2Reader::new(Cursor::new(&variant), 1 << 16)));
+    }
+}
+
+#[test]
+fn test_corrupt_xz() {
+    let data = test_data();
+    let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc64).unwrap();
+    writer.set_block_size(Some(10_000)).unwrap();
+    writer
+        .set_filters(vec![
+            FilterFlags::delta(2),
+            FilterFlags::bcj(BcjKind::X86, 0),
+        ])
+        .unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    for variant in corrupted_variants(&compressed, 300) {
+        drain(XzReader::new(Cursor::new(&variant)));
+        drain(XzSeekReader::new(Cursor::new(&variant)));
+    }
+
+    // The multithreaded writer stores the block sizes in the block headers
+    let mut writer =
+        XzMtWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc32, 10_000, 2).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    for variant in corrupted_variants(&compressed, 300) {
+        drain(XzReader::new(Cursor::new(&variant)));
+        drain(XzSeekReader::new(Cursor::new(&variant)));
+        drain(XzMtReader::new(Cursor::new(&variant), 2, 1 << 20));
+    }
+}
+
+#[test]
+fn test_corrupt_lzip() {
+    let data = test_data();
+    let mut writer = LzipWriter::new(Vec::new(), 1 << 16).unwrap();
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    for variant in corrupted_variants(&compressed, 300) {
+        drain(LzipReader::new(Cursor::new(&variant)));
+    }
+}
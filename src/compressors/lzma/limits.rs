@@ -0,0 +1,315 @@
+//! # Limits for decoding untrusted data
+//!
+//! The dictionary size of a stream comes from its header, and the decoders allocate the whole
+//! dictionary up front, so a tiny file can ask for gigabytes of memory. A tiny file can also
+//! decompress into far more data than expected. `DecoderLimits` bounds both, and is checked before
+//! anything is allocated. Exceeding a limit returns an error of kind
+//! `ErrorKind::MemoryLimitExceeded` or `ErrorKind::OutputLimitExceeded`.
+
+use super::{
+    codecs::{header_codec::LzmaHeader, lzma_stream_codec::LZMACodecDecoder},
+    streams::UNKNOWN_UNCOMPRESSED_SIZE,
+};
+use crate::error::{Error, ErrorKind};
+
+/// Get estimated memory usage of a decoder in bytes, which is mostly the dictionary buffer.
+pub fn get_decoder_mem_usage(dict_size: u32, lc: u32, lp: u32) -> u64 {
+    dict_size as u64 + LZMACodecDecoder::get_mem_usage(lc, lp)
+}
+
+/// Get estimated memory usage of an LZMA2 decoder in bytes. The properties can change between
+/// chunks, so this assumes the most literal probabilities, with `lc + lp = 4`.
+pub fn get_lzma2_decoder_mem_usage(dict_size: u32) -> u64 {
+    get_decoder_mem_usage(dict_size, 4, 0)
+}
+
+/// Limits on the resources a decoder may use. The default has no limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// The most memory in bytes that a decoder may allocate, estimated by `get_decoder_mem_usage()`
+    pub memory_limit: Option<u64>,
+    /// The most bytes that may be decompressed
+    pub max_output_size: Option<u64>,
+}
+
+impl DecoderLimits {
+    pub fn check_mem_usage(&self, required: u64) -> Result<(), Error> {
+        match self.memory_limit {
+            Some(limit) if required > limit => {
+                Err(ErrorKind::MemoryLimitExceeded { required, limit }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_output_size(&self, size: u64) -> Result<(), Error> {
+        match self.max_output_size {
+            Some(limit) if size > limit => Err(ErrorKind::OutputLimitExceeded { limit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the memory a `.lzma` stream needs and its uncompressed size, if it's known.
+    pub(crate) fn check_lzma_header(&self, header: &LzmaHeader) -> Result<(), Error> {
+        let props = &header.props;
+        self.check_mem_usage(get_decoder_mem_usage(
+            header.dict_size,
+            props.lc as u32,
+            props.lp as u32,
+        ))?;
+
+        // An unknown size can only be checked while decoding
+        if header.uncompressed_size != UNKNOWN_UNCOMPRESSED_SIZE {
+            self.check_output_size(header.uncompressed_size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::{
+            codecs::{
+                header_codec::{write_lzma_header, DICT_SIZE_MAX},
+                xz_codec::checks::CheckType,
+            },
+            lzip_streams::{LzipReader, LzipWriter},
+            lzma2_streams::Lzma2Reader,
+            push_decoder::LzmaPushDecoder,
+            streams::{LzmaReader, LzmaWriter},
+            xz_seek_streams::XzSeekReader,
+            xz_streams::{XzReader, XzWriter},
+        },
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::{self, Cursor, Read, Write};
+
+    fn limit_error(err: io::Error) -> ErrorKind {
+        Error::from(err).kind()
+    }
+
+    fn read_all(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let limits = DecoderLimits {
+            memory_limit: Some(1 << 20),
+            max_output_size: None,
+        };
+        let required = get_decoder_mem_usage(DICT_SIZE_MAX, 3, 0);
+
+        // Just a header, asking for the biggest dictionary
+        let mut file = Vec::new();
+        let header = LzmaHeader {
+            props: PROPS,
+            dict_size: DICT_SIZE_MAX,
+            uncompressed_size: UNKNOWN_UNCOMPRESSED_SIZE,
+        };
+        write_lzma_header(&mut file, &header).unwrap();
+
+        let err = LzmaReader::new_with_limits(Cursor::new(&file), limits)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded {
+                required,
+                limit: 1 << 20
+            }
+        );
+
+        let mut decoder = LzmaPushDecoder::new_with_limits(limits);
+        let err = decoder.decode(&file, &mut [0; 16]).unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+
+        // Raw streams are checked up front, since their properties are known
+        let err = LzmaPushDecoder::new_raw_with_limits(PROPS, DICT_SIZE_MAX, None, limits)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+
+        let err = Lzma2Reader::new_with_limits(Cursor::new(&file), DICT_SIZE_MAX, limits)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+
+        // The same limit is enough for small dictionaries
+        let data = test_data(10_000);
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, None).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let reader = LzmaReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        assert_eq!(read_all(reader).unwrap(), data);
+    }
+
+    #[test]
+    fn test_memory_limit_containers() {
+        let data = test_data(10_000);
+        let limits = DecoderLimits {
+            memory_limit: Some(1 << 20),
+            max_output_size: None,
+        };
+
+        // xz only needs a buffer as big as the block, so the block has to be bigger than the limit
+        let big_data = data.repeat(30);
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 22, CheckType::Crc32).unwrap();
+        writer.write_all(&big_data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let err = read_all(XzReader::new_with_limits(Cursor::new(&compressed), limits).unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+        let reader = XzSeekReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        let err = read_all(reader).unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc32).unwrap();
+        writer.write_all(&big_data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let reader = XzReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        assert_eq!(read_all(reader).unwrap(), big_data);
+        let reader = XzSeekReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        assert_eq!(read_all(reader).unwrap(), big_data);
+
+        let mut writer = LzipWriter::new(Vec::new(), 1 << 22).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let err = LzipReader::new_with_limits(Cursor::new(&compressed), limits)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let data = test_data(10_000);
+        let len = data.len() as u64;
+        let limited = DecoderLimits {
+            memory_limit: None,
+            max_output_size: Some(len - 1),
+        };
+        let exact = DecoderLimits {
+            memory_limit: None,
+            max_output_size: Some(len),
+        };
+
+        for size in [Some(len), None] {
+            let mut writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, size).unwrap();
+            writer.write_all(&data).unwrap();
+            let compressed = writer.finish().unwrap();
+
+            // A known size is checked up front, an unknown one while decoding
+            let err = LzmaReader::new_with_limits(Cursor::new(&compressed), limited)
+                .and_then(read_all)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+            assert_eq!(
+                limit_error(err),
+                ErrorKind::OutputLimitExceeded { limit: len - 1 }
+            );
+
+            let mut decoder = LzmaPushDecoder::new_with_limits(limited);
+            let err = decoder
+                .decode(&compressed, &mut vec![0; data.len()])
+                .unwrap_err();
+            assert!(matches!(
+                limit_error(err),
+                ErrorKind::OutputLimitExceeded { .. }
+            ));
+
+            let reader = LzmaReader::new_with_limits(Cursor::new(&compressed), exact).unwrap();
+            assert_eq!(read_all(reader).unwrap(), data);
+        }
+
+        let err = LzmaPushDecoder::new_raw_with_limits(PROPS, 1 << 16, Some(len), limited)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::OutputLimitExceeded { .. }
+        ));
+
+        let mut writer = LzmaWriter::new_raw(Vec::new(), PROPS, 1 << 16, true).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        let mut decoder =
+            LzmaPushDecoder::new_raw_with_limits(PROPS, 1 << 16, None, limited).unwrap();
+        let err = decoder
+            .decode(&compressed, &mut vec![0; data.len()])
+            .unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::OutputLimitExceeded { .. }
+        ));
+    }
+
+    #[test]
+    fn test_output_limit_containers() {
+        let data = test_data(10_000);
+        let len = data.len() as u64;
+        let limits = DecoderLimits {
+            memory_limit: None,
+            max_output_size: Some(len * 2 - 1),
+        };
+
+        // The limit covers all the streams and members together
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::Crc32).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap().repeat(2);
+        let reader = XzReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        let err = read_all(reader).unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::OutputLimitExceeded { .. }
+        ));
+
+        // The seekable reader knows the total size from the indexes up front
+        let err = XzSeekReader::new_with_limits(Cursor::new(&compressed), limits)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::OutputLimitExceeded { .. }
+        ));
+        let reader =
+            XzSeekReader::new_with_limits(Cursor::new(&compressed[..compressed.len() / 2]), limits);
+        assert_eq!(read_all(reader.unwrap()).unwrap(), data);
+
+        let mut writer = LzipWriter::new(Vec::new(), 1 << 16).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap().repeat(2);
+        let reader = LzipReader::new_with_limits(Cursor::new(&compressed), limits).unwrap();
+        let err = read_all(reader).unwrap_err();
+        assert!(matches!(
+            limit_error(err),
+            ErrorKind::OutputLimitExceeded { .. }
+        ));
+
+        let reader = LzipReader::new_with_limits(&compressed[..compressed.len() / 2], limits);
+        assert_eq!(read_all(reader.unwrap()).unwrap(), data);
+    }
+}
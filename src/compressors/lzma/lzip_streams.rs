@@ -0,0 +1,507 @@
+//! # Streaming wrappers for the `.lz` (lzip) format
+//!
+//! The reader decompresses every member of the file in turn, verifying the CRC32, data size and
+//! member size in each trailer. Anything after the last member that isn't another member is an error.
+//!
+//! The writer produces a single member by default, but can start a new member every fixed number of
+//! uncompressed bytes.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use super::{
+    codecs::{
+        lzip_codec::{
+            encode_header, parse_header, LzipError, LzipTrailer, DICT_SIZE_MAX, DICT_SIZE_MIN,
+            HEADER_MAGIC, HEADER_SIZE, PROPS, TRAILER_SIZE,
+        },
+        xz_codec::crc32::Crc32,
+    },
+    limits::{get_decoder_mem_usage, DecoderLimits},
+    streams::{LzmaReader, LzmaWriter},
+    xz_streams::read_up_to,
+};
+use crate::{
+    error::{offset_position, with_position},
+    utils::counting_io::{CountingReader, CountingWriter},
+};
+
+/// Parse the header of the member after the previous one, returning its dictionary size.
+/// Returns `None` at the end of the file.
+fn read_next_member_header(mut inner: impl Read) -> io::Result<Option<u32>> {
+    let mut header = [0u8; HEADER_SIZE];
+    let read = read_up_to(&mut inner, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+
+    let magic_len = read.min(HEADER_MAGIC.len());
+    if header[..magic_len] != HEADER_MAGIC[..magic_len] {
+        return Err(LzipError::TrailingData.into());
+    }
+    if read < HEADER_SIZE {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(Some(parse_header(&header)?))
+}
+
+/// Decompresses a single member, verifying its trailer once it ends.
+struct MemberReader<R: Read> {
+    lzma: LzmaReader<CountingReader<R>>,
+    crc: Crc32,
+    data_size: u64,
+}
+
+impl<R: Read> MemberReader<R> {
+    fn new(inner: R, dict_size: u32, limits: &DecoderLimits) -> io::Result<Self> {
+        let mem_usage = get_decoder_mem_usage(dict_size, PROPS.lc as u32, PROPS.lp as u32);
+        limits.check_mem_usage(mem_usage)?;
+
+        Ok(Self {
+            lzma: LzmaReader::new_raw(CountingReader::new(inner), PROPS, dict_size, None)?,
+            crc: Crc32::new(),
+            data_size: 0,
+        })
+    }
+
+    /// The position in the member's compressed data, after its header
+    fn compressed_pos(&self) -> u64 {
+        self.lzma.get_ref().count()
+    }
+
+    /// Read and validate the trailer once the LZMA stream ended, returning the inner reader
+    /// positioned after the member and the member size.
+    fn finish(self) -> io::Result<(R, u64)> {
+        let counting = self.lzma.into_inner();
+        let member_size = (HEADER_SIZE + TRAILER_SIZE) as u64 + counting.count();
+        let mut inner = counting.into_inner();
+
+        let mut trailer = [0u8; TRAILER_SIZE];
+        inner.read_exact(&mut trailer)?;
+        LzipTrailer::parse(&trailer).validate(&LzipTrailer {
+            crc32: self.crc.finish(),
+            data_size: self.data_size,
+            member_size,
+        })?;
+
+        Ok((inner, member_size))
+    }
+}
+
+impl<R: Read> Read for MemberReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.lzma.read(buf)?;
+        self.crc.update(&buf[..read]);
+        self.data_size += read as u64;
+        Ok(read)
+    }
+}
+
+enum ReaderState<R: Read> {
+    Member(Box<MemberReader<R>>),
+    Finished(R),
+    /// A previous read failed, so the position in the file is unknown
+    Failed,
+}
+
+/// A reader that decompresses an `.lz` file from the inner reader.
+pub struct LzipReader<R: Read> {
+    state: ReaderState<R>,
+    limits: DecoderLimits,
+    /// The number of bytes decompressed so far, from all the members
+    output_size: u64,
+    /// Where the current member starts in the file, and how much was decompressed before it
+    member_start: u64,
+    member_output_start: u64,
+}
+
+impl<R: Read> LzipReader<R> {
+    /// Parse the header of the first member from the reader and prepare to decompress the data after it.
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::new_with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The memory limit applies to each member separately.
+    pub fn new_with_limits(mut inner: R, limits: DecoderLimits) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        let member = inner
+            .read_exact(&mut header)
+            .and_then(|_| parse_header(&header).map_err(io::Error::from))
+            .and_then(|dict_size| MemberReader::new(inner, dict_size, &limits))
+            .map_err(|err| with_position(err, 0, 0))?;
+
+        Ok(Self {
+            state: ReaderState::Member(Box::new(member)),
+            limits,
+            output_size: 0,
+            member_start: 0,
+            member_output_start: 0,
+        })
+    }
+
+    /// Move on to the next member, once there's no data left to read from the current one.
+    fn advance(&mut self, state: ReaderState<R>) -> io::Result<ReaderState<R>> {
+        match state {
+            ReaderState::Member(member) => {
+                let trailer_start =
+                    self.member_start + (HEADER_SIZE as u64) + member.compressed_pos();
+                let (mut inner, member_size) = member
+                    .finish()
+                    .map_err(|err| with_position(err, trailer_start, self.output_size))?;
+                self.member_start += member_size;
+                self.member_output_start = self.output_size;
+
+                let header_error = |err| with_position(err, self.member_start, self.output_size);
+                match read_next_member_header(&mut inner).map_err(header_error)? {
+                    Some(dict_size) => {
+                        let member = MemberReader::new(inner, dict_size, &self.limits)
+                            .map_err(header_error)?;
+                        Ok(ReaderState::Member(Box::new(member)))
+                    }
+                    None => Ok(ReaderState::Finished(inner)),
+                }
+            }
+            ReaderState::Finished(inner) => Ok(ReaderState::Finished(inner)),
+            ReaderState::Failed => Err(io::Error::other("LzipReader used after a failed read")),
+        }
+    }
+}
+
+impl<R: Read> Read for LzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match &mut self.state {
+                ReaderState::Member(member) => match member.read(buf) {
+                    Ok(0) => {}
+                    Ok(read) => {
+                        self.output_size += read as u64;
+                        if let Err(err) = self.limits.check_output_size(self.output_size) {
+                            let offset =
+                                self.member_start + (HEADER_SIZE as u64) + member.compressed_pos();
+                            return Err(with_position(err.into(), offset, self.output_size));
+                        }
+                        return Ok(read);
+                    }
+                    Err(err) => {
+                        // The LZMA data starts after the member header
+                        let member_data_start = self.member_start + HEADER_SIZE as u64;
+                        let err = offset_position(err, member_data_start, self.member_output_start);
+                        self.state = ReaderState::Failed;
+                        return Err(err);
+                    }
+                },
+                ReaderState::Finished(_) => return Ok(0),
+                ReaderState::Failed => {}
+            }
+
+            let state = mem::replace(&mut self.state, ReaderState::Failed);
+            self.state = self.advance(state)?;
+        }
+    }
+}
+
+struct MemberWriter<W: Write> {
+    lzma: LzmaWriter<CountingWriter<W>>,
+    size_limit: Option<u64>,
+    crc: Crc32,
+    data_size: u64,
+}
+
+enum WriterState<W: Write> {
+    BetweenMembers(W),
+    Member(Box<MemberWriter<W>>),
+}
+
+/// A writer that compresses data into an `.lz` file, writing it to the inner writer.
+///
+/// The file must be completed by calling `finish()`. Dropping the writer will try to
+/// finish the file, ignoring any errors.
+pub struct LzipWriter<W: Write> {
+    state: Option<WriterState<W>>,
+    dict_size: u32,
+    member_size: Option<u64>,
+    members_written: u64,
+}
+
+impl<W: Write> LzipWriter<W> {
+    /// Prepare to compress data with the given dictionary size, which lzip limits to 4 KiB - 512 MiB.
+    /// The header stores the dictionary size rounded up slightly, to fit in a single byte.
+    pub fn new(inner: W, dict_size: u32) -> io::Result<Self> {
+        if !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&dict_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid lzip dictionary size",
+            ));
+        }
+
+        Ok(Self {
+            state: Some(WriterState::BetweenMembers(inner)),
+            dict_size,
+            member_size: None,
+            members_written: 0,
+        })
+    }
+
+    /// Split the data into members of `member_size` uncompressed bytes, or write everything into a
+    /// single member if `None`. Members are compressed independently of each other.
+    ///
+    /// This takes effect from the next member.
+    pub fn set_member_size(&mut self, member_size: Option<u64>) -> io::Result<()> {
+        if member_size == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The lzip member size can't be zero",
+            ));
+        }
+
+        self.member_size = member_size;
+        Ok(())
+    }
+
+    fn start_member(&mut self, mut inner: W) -> io::Result<MemberWriter<W>> {
+        inner.write_all(&encode_header(self.dict_size))?;
+
+        Ok(MemberWriter {
+            lzma: LzmaWriter::new_raw(CountingWriter::new(inner), PROPS, self.dict_size, true)?,
+            size_limit: self.member_size,
+            crc: Crc32::new(),
+            data_size: 0,
+        })
+    }
+
+    fn finish_member(&mut self, member: MemberWriter<W>) -> io::Result<W> {
+        let counting = member.lzma.finish()?;
+        let compressed_size = counting.count();
+        let mut inner = counting.into_inner();
+
+        let trailer = LzipTrailer {
+            crc32: member.crc.finish(),
+            data_size: member.data_size,
+            member_size: (HEADER_SIZE + TRAILER_SIZE) as u64 + compressed_size,
+        };
+        inner.write_all(&trailer.encode())?;
+        self.members_written += 1;
+
+        Ok(inner)
+    }
+
+    fn finish_file(&mut self) -> io::Result<W> {
+        match self.state.take().expect("LzipWriter used after finishing") {
+            WriterState::Member(member) => self.finish_member(*member),
+            // Even empty data needs a member
+            WriterState::BetweenMembers(inner) if self.members_written == 0 => {
+                let member = self.start_member(inner)?;
+                self.finish_member(member)
+            }
+            WriterState::BetweenMembers(inner) => Ok(inner),
+        }
+    }
+
+    /// Finish the compressed file, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_file()
+    }
+}
+
+impl<W: Write> Write for LzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut member = match self.state.take().expect("LzipWriter used after finishing") {
+            WriterState::BetweenMembers(inner) => Box::new(self.start_member(inner)?),
+            WriterState::Member(member) => member,
+        };
+
+        // Members are finished as soon as they reach their size, so there's always space left
+        let len = match member.size_limit {
+            Some(size) => (buf.len() as u64).min(size - member.data_size) as usize,
+            None => buf.len(),
+        };
+
+        let written = member.lzma.write(&buf[..len])?;
+        member.crc.update(&buf[..written]);
+        member.data_size += written as u64;
+
+        self.state = if member.size_limit == Some(member.data_size) {
+            Some(WriterState::BetweenMembers(self.finish_member(*member)?))
+        } else {
+            Some(WriterState::Member(member))
+        };
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.as_mut() {
+            Some(WriterState::BetweenMembers(inner)) => inner.flush(),
+            Some(WriterState::Member(member)) => member.lzma.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for LzipWriter<W> {
+    fn drop(&mut self) {
+        if self.state.is_some() {
+            let _ = self.finish_file();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::{Error, ErrorKind},
+        test_utils::test_data,
+    };
+    use std::io::Cursor;
+
+    /// `hello hello lzip\n` as a single member with a 4 KiB dictionary, from liblzma's raw LZMA
+    /// encoder. `xz --format=lzip -d` accepts it.
+    const HELLO_LZ: [u8; 49] = [
+        0x4c, 0x5a, 0x49, 0x50, 0x01, 0x0c, 0x00, 0x34, 0x19, 0x49, 0xee, 0x8d, 0xe9, 0x45, 0xbf,
+        0xb8, 0x17, 0xda, 0xbb, 0xcf, 0x4e, 0x3f, 0x63, 0xff, 0xff, 0xf0, 0x6f, 0x40, 0x00, 0xe8,
+        0x63, 0x67, 0xc5, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x31, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn compress(data: &[u8], member_size: Option<u64>) -> Vec<u8> {
+        let mut writer = LzipWriter::new(Vec::new(), 0x10000).unwrap();
+        writer.set_member_size(member_size).unwrap();
+        io::copy(&mut Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decompress(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = LzipReader::new(Cursor::new(compressed))?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    fn decompress_err(compressed: &[u8]) -> ErrorKind {
+        Error::from(decompress(compressed).unwrap_err()).kind()
+    }
+
+    #[test]
+    fn test_read_reference() {
+        assert_eq!(decompress(&HELLO_LZ).unwrap(), b"hello hello lzip\n");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for member_size in [None, Some(10_000)] {
+            for data in [test_data(6000), b"x".to_vec(), Vec::new()] {
+                let compressed = compress(&data, member_size);
+                assert_eq!(decompress(&compressed).unwrap(), data);
+
+                // liblzma's one-shot decoder stops after the first member
+                if member_size.is_none() {
+                    assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+                }
+            }
+        }
+
+        // Empty data is still a whole member, the same 36 bytes lzip writes
+        assert_eq!(compress(&[], None).len(), 36);
+    }
+
+    #[test]
+    fn test_multiple_members() {
+        let data = test_data(6000);
+        let compressed = compress(&data, Some(10_000));
+
+        let members = compressed.windows(4).filter(|w| *w == HEADER_MAGIC).count();
+        assert_eq!(members, data.len().div_ceil(10_000));
+
+        // Members from separate files can be concatenated too
+        let mut concatenated = compressed.clone();
+        concatenated.extend_from_slice(&HELLO_LZ);
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"hello hello lzip\n");
+        assert_eq!(decompress(&concatenated).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_corrupt_trailers() {
+        let cases = [
+            (29, ErrorKind::ChecksumMismatch),
+            (33, ErrorKind::Lzip(LzipError::DataSizeMismatch)),
+            (41, ErrorKind::Lzip(LzipError::MemberSizeMismatch)),
+            (48, ErrorKind::Lzip(LzipError::MemberSizeMismatch)),
+        ];
+        for (pos, expected) in cases {
+            let mut file = HELLO_LZ;
+            file[pos] ^= 1;
+            assert_eq!(decompress_err(&file), expected);
+        }
+
+        // Trailer errors point at the start of the trailer, after all the data
+        let mut file = HELLO_LZ.to_vec();
+        file.extend_from_slice(&HELLO_LZ);
+        let len = file.len();
+        file[len - TRAILER_SIZE] ^= 1;
+        let err = Error::from(decompress(&file).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        assert_eq!(err.compressed_offset(), Some((len - TRAILER_SIZE) as u64));
+        assert_eq!(err.uncompressed_pos(), Some(34));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let mut file = HELLO_LZ;
+        file[4] = 2;
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Lzip(LzipError::UnsupportedVersion(2))
+        );
+
+        let mut file = HELLO_LZ;
+        file[5] = 0x1F;
+        assert_eq!(decompress_err(&file), ErrorKind::InvalidDictSize);
+
+        let mut file = HELLO_LZ.to_vec();
+        file.extend_from_slice(b"garbage");
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Lzip(LzipError::TrailingData)
+        );
+
+        let mut file = HELLO_LZ.to_vec();
+        file.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Lzip(LzipError::TrailingData)
+        );
+
+        // Truncated anywhere, including in the header of a following member
+        let mut file = HELLO_LZ.to_vec();
+        file.extend_from_slice(b"LZI");
+        for len in [0, 3, 6, 20, 30, 48, file.len()] {
+            let err = decompress(&file[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        for dict_size in [0, DICT_SIZE_MIN - 1, DICT_SIZE_MAX + 1] {
+            let result = LzipWriter::new(Vec::new(), dict_size);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let mut writer = LzipWriter::new(Vec::new(), 0x10000).unwrap();
+        assert!(writer.set_member_size(Some(0)).is_err());
+    }
+}
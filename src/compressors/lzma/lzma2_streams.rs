@@ -0,0 +1,432 @@
+//! # Streaming wrappers for raw LZMA2 data
+//!
+//! LZMA2 streams don't have a header, so the dictionary size (and the properties when writing)
+//! have to be given separately. They're usually stored by the container, e.g. in the `.xz` filter
+//! properties or the 7z coder properties.
+
+use std::io::{self, Read, Take, Write};
+
+use super::{
+    codecs::{
+        header_codec::{LzmaHeaderProps, DICT_SIZE_MAX, DICT_SIZE_MIN},
+        length_codec::MATCH_LEN_MAX,
+        lzma2_codec::{
+            are_props_valid, LZMA2CodecDecoder, LZMA2CodecEncoder, ENCODER_EXTRA_HISTORY,
+        },
+        lzma_stream_codec::{
+            data_buffers::DecoderDataBuffer,
+            encoders::{match_finding::AnyMatchFinder, AnyInstructionPicker, LZMAEncoderInput},
+            DecodeOutcome, LZMACodecEncoder,
+        },
+        range_codec::RangeDecoder,
+    },
+    limits::{get_lzma2_decoder_mem_usage, DecoderLimits},
+    options::LzmaOptions,
+    streams::UNKNOWN_UNCOMPRESSED_SIZE,
+};
+use crate::{error::with_position, utils::counting_io::CountingReader};
+
+/// A reader that decompresses a raw LZMA2 stream from the inner reader.
+pub struct Lzma2Reader<R: Read> {
+    rc: RangeDecoder<Take<CountingReader<R>>>,
+    decoder: LZMA2CodecDecoder,
+    buffer: DecoderDataBuffer,
+    limits: DecoderLimits,
+    finished: bool,
+}
+
+impl<R: Read> Lzma2Reader<R> {
+    /// The dictionary size must be at least as big as the one the stream was compressed with.
+    pub fn new(inner: R, dict_size: u32) -> Self {
+        let dict_size = dict_size.clamp(DICT_SIZE_MIN, DICT_SIZE_MAX);
+        Self::with_limits(inner, dict_size, DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. Use this for untrusted files.
+    pub fn new_with_limits(inner: R, dict_size: u32, limits: DecoderLimits) -> io::Result<Self> {
+        let dict_size = dict_size.clamp(DICT_SIZE_MIN, DICT_SIZE_MAX);
+        limits
+            .check_mem_usage(get_lzma2_decoder_mem_usage(dict_size))
+            .map_err(|err| with_position(err.into(), 0, 0))?;
+        Ok(Self::with_limits(inner, dict_size, limits))
+    }
+
+    fn with_limits(inner: R, dict_size: u32, limits: DecoderLimits) -> Self {
+        Self {
+            rc: RangeDecoder::new_uninitialized(CountingReader::new(inner).take(u64::MAX)),
+            decoder: LZMA2CodecDecoder::new(),
+            buffer: DecoderDataBuffer::new(dict_size, UNKNOWN_UNCOMPRESSED_SIZE),
+            limits,
+            finished: false,
+        }
+    }
+
+    /// Use a preset dictionary, which must be the same one the stream was compressed with.
+    /// Only the last `dict_size` bytes of the dictionary are used.
+    ///
+    /// This must be called before anything is read.
+    pub fn set_preset_dict(&mut self, dict: &[u8]) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Preset dictionaries must be set before reading",
+            ));
+        }
+
+        self.decoder.load_preset_dict(&mut self.buffer, dict);
+        Ok(())
+    }
+
+    /// Decode packets until there's either enough data to fill `wanted` bytes,
+    /// the buffer needs flushing, or the stream ends.
+    fn decode_until(&mut self, wanted: usize) -> io::Result<()> {
+        while !self.finished
+            && (self.buffer.flushable_bytes() as usize) < wanted
+            && !self.buffer.must_flush_now_or_data_will_be_lost()
+        {
+            let max_bytes = (wanted - self.buffer.flushable_bytes() as usize) as u64;
+            let outcome = self
+                .decoder
+                .decode_until(&mut self.rc, &mut self.buffer, max_bytes)?;
+            self.limits.check_output_size(self.buffer.decoded_bytes())?;
+
+            if outcome == DecodeOutcome::EndOfStream {
+                self.finished = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the inner reader. If the stream was read to the end, it is positioned right after the LZMA2 data.
+    pub fn get_ref(&self) -> &R {
+        self.rc.get_ref().get_ref().get_ref()
+    }
+
+    /// Get a mutable reference to the inner reader. Reading from it directly would skip over compressed data.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.rc.inner().get_mut().get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rc.into_inner().into_inner().into_inner()
+    }
+}
+
+impl<R: Read> Read for Lzma2Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.decode_until(buf.len()).map_err(|err| {
+            let offset = self.rc.get_ref().get_ref().count();
+            with_position(err, offset, self.buffer.decoded_bytes())
+        })?;
+
+        Ok(self.buffer.flush(buf))
+    }
+}
+
+/// A writer that compresses data into a raw LZMA2 stream, writing it to the inner writer.
+///
+/// The stream must be completed by calling `finish()`. Dropping the writer will try to
+/// finish the stream, ignoring any errors.
+pub struct Lzma2Writer<W: Write> {
+    inner: Option<W>,
+    encoder: LZMA2CodecEncoder<AnyInstructionPicker>,
+    input: LZMAEncoderInput<AnyMatchFinder>,
+}
+
+impl<W: Write> Lzma2Writer<W> {
+    /// Compress with the default preset, using the given properties and dictionary size.
+    /// LZMA2 requires `lc + lp <= 4`.
+    pub fn new(inner: W, props: LzmaHeaderProps, dict_size: u32) -> io::Result<Self> {
+        let options = LzmaOptions {
+            dict_size,
+            ..Default::default()
+        };
+        Self::new_with_options(inner, &options.with_props(&props))
+    }
+
+    /// LZMA2 requires `lc + lp <= 4`.
+    pub fn new_with_options(inner: W, options: &LzmaOptions) -> io::Result<Self> {
+        options.validate()?;
+
+        let props = options.props();
+        if !are_props_valid(&props) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA2 properties",
+            ));
+        }
+
+        let encoder = LZMACodecEncoder::new(options, options.new_instruction_picker());
+        let input = LZMAEncoderInput::new_with_extra_history(
+            options.new_match_finder(),
+            options.dict_size,
+            ENCODER_EXTRA_HISTORY,
+        );
+
+        Ok(Self {
+            inner: Some(inner),
+            encoder: LZMA2CodecEncoder::new(encoder, &props),
+            input,
+        })
+    }
+
+    /// Use a preset dictionary, which the written data can reference as if it came just before it.
+    /// The same dictionary must be given to the reader. Only the last `dict_size` bytes are used.
+    ///
+    /// This must be called before anything is written.
+    pub fn set_preset_dict(&mut self, dict: &[u8]) -> io::Result<()> {
+        if self.input.pos() != 0 || self.input.forward_bytes() != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Preset dictionaries must be set before writing",
+            ));
+        }
+
+        self.encoder.load_preset_dict(&mut self.input, dict);
+        Ok(())
+    }
+
+    /// Encode packets while there's enough data ahead to find full length matches.
+    fn encode_available(&mut self) -> io::Result<()> {
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("Lzma2Writer used after finishing");
+        while self.input.forward_bytes() > MATCH_LEN_MAX {
+            self.encoder.encode_one_packet(inner, &mut self.input)?;
+        }
+        Ok(())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<W> {
+        let mut inner = self.inner.take().expect("Lzma2Writer used after finishing");
+
+        let end = self.input.pos() + self.input.forward_bytes() as u64;
+        while self.encoder.position() < end {
+            self.encoder
+                .encode_one_packet(&mut inner, &mut self.input)?;
+        }
+
+        self.encoder.finish(&mut inner, &self.input)?;
+
+        Ok(inner)
+    }
+
+    /// Get the inner writer, e.g. to take the compressed data written to it so far.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner
+            .as_mut()
+            .expect("Lzma2Writer used after finishing")
+    }
+
+    /// Finish the compressed stream, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()
+    }
+}
+
+impl<W: Write> Write for Lzma2Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.input.available_append_bytes());
+        self.input.append_data(&buf[..len]);
+
+        self.encode_available()?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for Lzma2Writer<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish_stream();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_data, PROPS};
+    use std::io::Cursor;
+
+    /// Pseudo random bytes that don't compress, so they end up in uncompressed chunks.
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut state = 0x12345678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut writer = Lzma2Writer::new(Vec::new(), PROPS, 0x10000).unwrap();
+        io::copy(&mut Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut reader = Lzma2Reader::new(Cursor::new(compressed), 0x10000);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        output
+    }
+
+    fn compress_reference(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let options = lzma_rust::LZMA2Options {
+            dict_size: 0x10000,
+            ..Default::default()
+        };
+        let mut writer =
+            lzma_rust::LZMA2Writer::new(lzma_rust::CountingWriter::new(&mut compressed), &options);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        compressed
+    }
+
+    fn decompress_reference(compressed: &[u8]) -> Vec<u8> {
+        let mut reader = lzma_rust::LZMA2Reader::new(Cursor::new(compressed), 0x10000, None);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        output
+    }
+
+    /// Compressible data, followed by random data, followed by more compressible data.
+    /// This covers LZMA chunks, uncompressed chunks, and state resets after them.
+    fn mixed_data() -> Vec<u8> {
+        let mut data = test_data(6000);
+        data.extend(random_data(100_000));
+        data.extend(test_data(6000));
+        data
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for data in [test_data(6000), mixed_data(), Vec::new()] {
+            let compressed = compress(&data);
+            assert_eq!(decompress(&compressed), data);
+        }
+    }
+
+    #[test]
+    fn test_read_reference() {
+        for data in [test_data(6000), mixed_data(), Vec::new()] {
+            let compressed = compress_reference(&data);
+            assert_eq!(decompress(&compressed), data);
+        }
+    }
+
+    #[test]
+    fn test_write_readable_by_reference() {
+        for data in [test_data(6000), mixed_data(), Vec::new()] {
+            let compressed = compress(&data);
+            assert_eq!(decompress_reference(&compressed), data);
+        }
+    }
+
+    #[test]
+    fn test_chunk_limits() {
+        // Highly compressible data hits the uncompressed size limit of each chunk
+        let data = test_data(10_000).repeat(60);
+        assert!(data.len() > 2 << 20);
+        let compressed = compress(&data);
+        assert_eq!(compressed[0] & 0xE0, 0xE0);
+        let first_chunk_size = ((compressed[0] as u32 & 0x1F) << 16)
+            + ((compressed[1] as u32) << 8)
+            + compressed[2] as u32
+            + 1;
+        assert!(first_chunk_size <= 2 << 20);
+        assert!(first_chunk_size > (2 << 20) - 1024);
+        assert_eq!(decompress(&compressed), data);
+
+        // Incompressible data gets stored in uncompressed chunks of at most 64 KiB
+        let data = random_data(200_000);
+        let compressed = compress(&data);
+        assert_eq!(compressed[0], 0x01);
+        assert_eq!(&compressed[1..3], &[0xFF, 0xFF]);
+        assert!(compressed.len() < data.len() + data.len() / 100);
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn test_preset_dict() {
+        let data = test_data(6000);
+        let (dict, data) = data.split_at(0x2000);
+        let data = &data[..0x2000];
+
+        let mut writer = Lzma2Writer::new(Vec::new(), PROPS, 0x10000).unwrap();
+        writer.set_preset_dict(dict).unwrap();
+        writer.write_all(data).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.len() < compress(data).len());
+
+        let mut reader = Lzma2Reader::new(Cursor::new(&compressed), 0x10000);
+        reader.set_preset_dict(dict).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_dict_reset_mid_stream() {
+        // Two independent streams can be joined by dropping the end byte of the first,
+        // as the second starts with a dictionary reset.
+        let first = test_data(6000);
+        let second = mixed_data();
+
+        let mut compressed = compress(&first);
+        assert_eq!(compressed.pop(), Some(0x00));
+        compressed.extend(compress(&second));
+
+        let mut expected = first;
+        expected.extend(second);
+        assert_eq!(decompress(&compressed), expected);
+    }
+
+    #[test]
+    fn test_invalid_streams() {
+        let errors = [
+            // Doesn't start with a dictionary reset
+            &[0x02, 0x00, 0x00, 0x41, 0x00][..],
+            // LZMA chunk without properties
+            &[0x01, 0x00, 0x00, 0x41, 0xA0, 0x00, 0x00, 0x00, 0x04, 0x00][..],
+            // Invalid control byte
+            &[0x03][..],
+            // lc + lp > 4
+            &[0xE0, 0x00, 0x00, 0x00, 0x04, 0x0D][..],
+        ];
+
+        for compressed in errors {
+            let mut reader = Lzma2Reader::new(Cursor::new(compressed), 0x10000);
+            let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Truncated streams
+        let compressed = compress(&mixed_data());
+        for len in [0, 3, 100, compressed.len() - 1] {
+            let mut reader = Lzma2Reader::new(Cursor::new(&compressed[..len]), 0x10000);
+            let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
@@ -1 +1,16 @@
+#[cfg(feature = "tokio")]
+pub mod async_streams;
 pub mod codecs;
+#[cfg(test)]
+mod corrupt_input_tests;
+pub mod limits;
+pub mod lzip_streams;
+pub mod lzma2_streams;
+pub mod one_shot;
+pub mod options;
+pub mod push_decoder;
+pub mod streams;
+pub mod xz_mt_streams;
+pub mod xz_push_decoder;
+pub mod xz_seek_streams;
+pub mod xz_streams;
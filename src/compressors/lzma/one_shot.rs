@@ -0,0 +1,310 @@
+//! # One-shot compression and decompression of in-memory `.lzma` data
+//!
+//! When the compressed stream is already in memory and the uncompressed data fits in a slice,
+//! the slice itself can be the dictionary. The streaming readers decode into a cyclic buffer and
+//! copy the data out of it, while these functions write every byte straight to its final place,
+//! without allocating a dictionary.
+//!
+//! Compression works the same way in reverse: the encoder reads from the input slice directly,
+//! instead of copying it into a cyclic buffer in small pieces like `LzmaWriter` does.
+
+use std::io;
+
+use super::{
+    codecs::{
+        header_codec::{
+            parse_lzma_header, write_lzma_header, LzmaHeader, LzmaHeaderProps, LZMA_HEADER_SIZE,
+        },
+        lzma_stream_codec::{
+            data_buffers::SliceDataBuffer, DecodeOutcome, LZMACodecDecoder, LZMACodecEncoder,
+        },
+        range_codec::{RangeDecode, RangeEncoder, SliceRangeDecoder},
+    },
+    options::LzmaOptions,
+    streams::{check_decode_outcome, UNKNOWN_UNCOMPRESSED_SIZE},
+};
+use crate::error::with_position;
+
+/// Compress all of `input` into a `.lzma` file, with the uncompressed size stored in the header.
+pub fn compress(input: &[u8], options: &LzmaOptions) -> io::Result<Vec<u8>> {
+    // Validate the options before writing anything
+    options.validate()?;
+
+    let header = LzmaHeader {
+        props: options.props(),
+        dict_size: options.dict_size,
+        uncompressed_size: input.len() as u64,
+    };
+    let mut output = Vec::new();
+    write_lzma_header(&mut output, &header)?;
+
+    encode_into(input, options, false, output)
+}
+
+/// Compress all of `input` into a raw LZMA stream without a header, e.g. for zip or 7z archives.
+/// The end of stream marker is only written if `use_end_marker` is true.
+pub fn compress_raw(
+    input: &[u8],
+    options: &LzmaOptions,
+    use_end_marker: bool,
+) -> io::Result<Vec<u8>> {
+    encode_into(input, options, use_end_marker, Vec::new())
+}
+
+fn encode_into(
+    input: &[u8],
+    options: &LzmaOptions,
+    use_end_marker: bool,
+    output: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let (mut encoder, mut data) = LZMACodecEncoder::from_options_with_slice(options, input)?;
+    let mut rc = RangeEncoder::new(output);
+
+    while encoder.position() < input.len() as u64 {
+        encoder.encode_one_packet(&mut rc, &mut data)?;
+    }
+    if use_end_marker {
+        encoder.encode_end_marker(&mut rc)?;
+    }
+
+    rc.finish()
+}
+
+/// Decompress a whole `.lzma` file into `output`, returning the number of bytes written.
+///
+/// If the header stores the uncompressed size, `output` must be at least that long. Otherwise the
+/// stream must end with an end of stream marker, and fails with `io::ErrorKind::InvalidInput` if
+/// the data doesn't fit. Any input after the end of the stream is ignored.
+pub fn decompress_into(input: &[u8], output: &mut [u8]) -> io::Result<usize> {
+    let mut data = input;
+    let header = parse_lzma_header(&mut data).map_err(|err| with_position(err, 0, 0))?;
+    decode_into(data, &header, LZMA_HEADER_SIZE, output)
+}
+
+/// Decompress a raw LZMA stream without a header into `output`, returning the number of bytes written.
+///
+/// If the uncompressed size is unknown, the stream must end with an end of stream marker.
+/// If it is known, an end of stream marker is allowed but not required.
+pub fn decompress_raw_into(
+    input: &[u8],
+    props: LzmaHeaderProps,
+    dict_size: u32,
+    uncompressed_size: Option<u64>,
+    output: &mut [u8],
+) -> io::Result<usize> {
+    let header = LzmaHeader {
+        props,
+        dict_size,
+        uncompressed_size: uncompressed_size.unwrap_or(UNKNOWN_UNCOMPRESSED_SIZE),
+    };
+    decode_into(input, &header, 0, output)
+}
+
+fn decode_into(
+    input: &[u8],
+    header: &LzmaHeader,
+    header_size: u64,
+    output: &mut [u8],
+) -> io::Result<usize> {
+    // With a known size, any data past it is an error in the stream rather than a short output
+    let output = if header.uncompressed_size == UNKNOWN_UNCOMPRESSED_SIZE {
+        output
+    } else if header.uncompressed_size <= output.len() as u64 {
+        &mut output[..header.uncompressed_size as usize]
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The output is smaller than the uncompressed size",
+        ));
+    };
+
+    let mut buffer = SliceDataBuffer::new(output, header.dict_size);
+    let mut rc = SliceRangeDecoder::new_uninitialized(input);
+    let mut decoder = LZMACodecDecoder::new(
+        header.props.lc as u32,
+        header.props.lp as u32,
+        header.props.pb as u32,
+    );
+
+    match decode_packets(&mut decoder, &mut rc, &mut buffer, header) {
+        Ok(()) => Ok(buffer.decoded_bytes() as usize),
+        Err(err) => Err(with_position(
+            err,
+            header_size + rc.position() as u64,
+            buffer.decoded_bytes(),
+        )),
+    }
+}
+
+fn decode_packets(
+    decoder: &mut LZMACodecDecoder,
+    rc: &mut SliceRangeDecoder,
+    buffer: &mut SliceDataBuffer,
+    header: &LzmaHeader,
+) -> io::Result<()> {
+    rc.reset()?;
+
+    loop {
+        if buffer.decoded_bytes() == header.uncompressed_size {
+            return Ok(());
+        }
+
+        // An unknown size is stored as `u64::MAX`, so this decodes until the end marker
+        let max_bytes = header.uncompressed_size - buffer.decoded_bytes();
+        let outcome = decoder.decode_until(rc, buffer, max_bytes)?;
+        check_decode_outcome(header, buffer.decoded_bytes(), outcome)?;
+        if buffer.is_overflowed() {
+            // A known size was checked above, so this is an unknown size that didn't fit
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The output is too small for the decompressed data",
+            ));
+        }
+
+        if outcome == DecodeOutcome::EndOfStream {
+            // Read the last byte of the stream, so that a truncated stream is noticed
+            rc.normalize()?;
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::{
+            options::LzmaMode,
+            streams::{LzmaReader, LzmaWriter},
+        },
+        error::{Error, ErrorKind},
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::{Cursor, Read, Write};
+
+    fn compress_with_writer(data: &[u8], size: Option<u64>, dict_size: u32) -> Vec<u8> {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, dict_size, size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_compress() {
+        let data = test_data(5000);
+
+        for preset in [0, 6, 9] {
+            let options = LzmaOptions::from_preset(preset, false).unwrap();
+            let compressed = compress(&data, &options).unwrap();
+            assert!(compressed.len() < data.len() / 4);
+
+            let mut reader = LzmaReader::new(Cursor::new(&compressed)).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+
+            // The fast mode only looks one match ahead, so it picks the same instructions as
+            // with the buffered input. The normal mode can see further ahead in the slice.
+            if options.mode == LzmaMode::Fast {
+                let size = Some(data.len() as u64);
+                let mut writer = LzmaWriter::new_with_options(Vec::new(), &options, size).unwrap();
+                writer.write_all(&data).unwrap();
+                assert_eq!(writer.finish().unwrap(), compressed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compress_raw() {
+        let data = test_data(5000);
+        let options = LzmaOptions {
+            dict_size: 4096,
+            ..Default::default()
+        };
+
+        for use_end_marker in [true, false] {
+            let compressed = compress_raw(&data, &options, use_end_marker).unwrap();
+            let size = (!use_end_marker).then_some(data.len() as u64);
+            let mut output = vec![0; data.len()];
+            let written =
+                decompress_raw_into(&compressed, options.props(), 4096, size, &mut output).unwrap();
+            assert_eq!(written, data.len());
+            assert_eq!(output, data);
+        }
+
+        let compressed = compress(&[], &options).unwrap();
+        assert_eq!(decompress_into(&compressed, &mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decompress_into() {
+        let data = test_data(5000);
+
+        for size in [Some(data.len() as u64), None] {
+            // A dictionary much smaller than the data, so matches are limited by it
+            let compressed = compress_with_writer(&data, size, 4096);
+
+            let mut output = vec![0; data.len() + 100];
+            let written = decompress_into(&compressed, &mut output).unwrap();
+            assert_eq!(&output[..written], &data[..]);
+
+            let mut reader = LzmaReader::new(Cursor::new(&compressed)).unwrap();
+            let mut expected = Vec::new();
+            reader.read_to_end(&mut expected).unwrap();
+            assert_eq!(expected, data);
+
+            let raw = &compressed[LZMA_HEADER_SIZE as usize..];
+            let written = decompress_raw_into(raw, PROPS, 4096, size, &mut output).unwrap();
+            assert_eq!(&output[..written], &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_decompress_into_empty() {
+        for size in [Some(0), None] {
+            let compressed = compress_with_writer(&[], size, 4096);
+            assert_eq!(decompress_into(&compressed, &mut []).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_output_too_small() {
+        let data = test_data(5000);
+
+        for size in [Some(data.len() as u64), None] {
+            let compressed = compress_with_writer(&data, size, 1 << 16);
+            let mut output = vec![0; data.len() - 1];
+            let err = decompress_into(&compressed, &mut output).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+            let mut output = vec![0; data.len()];
+            assert_eq!(
+                decompress_into(&compressed, &mut output).unwrap(),
+                data.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_corrupt_input() {
+        let data = test_data(5000);
+        let compressed = compress_with_writer(&data, Some(data.len() as u64), 1 << 16);
+        let mut output = vec![0; data.len()];
+
+        let half = compressed.len() / 2;
+        let err = Error::from(decompress_into(&compressed[..half], &mut output).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(err.compressed_offset(), Some(half as u64));
+
+        let mut file = compressed.clone();
+        file[LZMA_HEADER_SIZE as usize] = 1;
+        let err = Error::from(decompress_into(&file, &mut output).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::CorruptRangeCoder);
+        assert_eq!(err.compressed_offset(), Some(LZMA_HEADER_SIZE + 1));
+
+        // Stopping at the wrong size is noticed, the same as with the reader
+        let raw = &compressed[LZMA_HEADER_SIZE as usize..];
+        let size = Some(data.len() as u64 - 1);
+        let err = decompress_raw_into(raw, PROPS, 1 << 16, size, &mut output).unwrap_err();
+        assert_eq!(Error::from(err).kind(), ErrorKind::SizeMismatch);
+    }
+}
@@ -0,0 +1,353 @@
+//! # Encoder options for LZMA based formats
+//!
+//! `LzmaOptions` holds every setting of the LZMA encoder, and can be created from the same
+//! compression presets as xz uses. Presets 0-3 use the fast mode with a hash chain match finder,
+//! while 4-9 use the normal mode with a binary tree. The extreme variants spend more time on
+//! searching for matches, for a slightly better compression ratio.
+
+use std::io;
+
+use super::codecs::{
+    header_codec::{LzmaHeaderProps, DICT_SIZE_MAX, DICT_SIZE_MIN},
+    length_codec::{MATCH_LEN_MAX, MATCH_LEN_MIN},
+    lzma_stream_codec::encoders::{
+        instructions_fast::LZMAFastInstructionPicker,
+        instructions_normal::LZMANormalInstructionPicker,
+        match_finding::{
+            bt2::BT2MatchFinder, bt3::BT3MatchFinder, bt4::BT4MatchFinder, hc3::HC3MatchFinder,
+            hc4::HC4MatchFinder, AnyMatchFinder,
+        },
+        AnyInstructionPicker,
+    },
+};
+
+pub const PRESET_MAX: u32 = 9;
+pub const PRESET_DEFAULT: u32 = 6;
+
+pub const LC_MAX: u32 = 8;
+pub const LP_MAX: u32 = 4;
+pub const PB_MAX: u32 = 4;
+
+/// The dictionary size of each preset, as a power of 2
+const PRESET_DICT_SIZE_LOG2: [u32; 10] = [18, 20, 21, 22, 22, 23, 23, 24, 25, 26];
+
+/// How the encoder picks which instruction to encode next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LzmaMode {
+    /// Take the longest match at each position, which is a lot faster.
+    Fast,
+    /// Compare the prices of every match and literal up to a few kilobytes ahead.
+    Normal,
+}
+
+/// The match finder that searches the dictionary for matches, by how many bytes it hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchFinderKind {
+    HC3,
+    HC4,
+    BT2,
+    BT3,
+    BT4,
+}
+
+impl MatchFinderKind {
+    /// The number of bytes that are hashed, which is also the shortest usable `nice_len`.
+    pub fn hash_bytes(&self) -> u32 {
+        match self {
+            Self::BT2 => 2,
+            Self::HC3 | Self::BT3 => 3,
+            Self::HC4 | Self::BT4 => 4,
+        }
+    }
+
+    /// Get estimated memory usage in bytes
+    pub fn get_mem_usage(&self, dict_size: u32) -> u64 {
+        match self {
+            Self::HC3 => HC3MatchFinder::get_mem_usage(dict_size),
+            Self::HC4 => HC4MatchFinder::get_mem_usage(dict_size),
+            Self::BT2 => BT2MatchFinder::get_mem_usage(dict_size),
+            Self::BT3 => BT3MatchFinder::get_mem_usage(dict_size),
+            Self::BT4 => BT4MatchFinder::get_mem_usage(dict_size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LzmaOptions {
+    pub dict_size: u32,
+    pub lc: u32,
+    pub lp: u32,
+    pub pb: u32,
+
+    pub mode: LzmaMode,
+    /// Matches at least this long are taken without searching for longer ones.
+    pub nice_len: u32,
+    pub match_finder: MatchFinderKind,
+    /// How many positions the match finder checks at most. 0 picks a default based on `nice_len`.
+    pub depth: i32,
+}
+
+impl LzmaOptions {
+    /// Create the options of an xz compatible preset, from 0 (fastest) to 9 (best compression).
+    ///
+    /// The extreme variant of a preset uses the same dictionary size, but searches for matches more thoroughly.
+    pub fn from_preset(preset: u32, extreme: bool) -> io::Result<Self> {
+        if preset > PRESET_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA preset",
+            ));
+        }
+
+        let mut options = Self {
+            dict_size: 1 << PRESET_DICT_SIZE_LOG2[preset as usize],
+            lc: 3,
+            lp: 0,
+            pb: 2,
+            mode: LzmaMode::Normal,
+            nice_len: 64,
+            match_finder: MatchFinderKind::BT4,
+            depth: 0,
+        };
+
+        if preset <= 3 {
+            options.mode = LzmaMode::Fast;
+            options.match_finder = if preset == 0 {
+                MatchFinderKind::HC3
+            } else {
+                MatchFinderKind::HC4
+            };
+            options.nice_len = if preset <= 1 { 128 } else { 273 };
+            options.depth = [4, 8, 24, 48][preset as usize];
+        } else {
+            options.nice_len = match preset {
+                4 => 16,
+                5 => 32,
+                _ => 64,
+            };
+        }
+
+        if extreme {
+            options.mode = LzmaMode::Normal;
+            options.match_finder = MatchFinderKind::BT4;
+            if preset == 3 || preset == 5 {
+                options.nice_len = 192;
+                options.depth = 0;
+            } else {
+                options.nice_len = 273;
+                options.depth = 512;
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Check that every option is in range, so an encoder can be created from them.
+    pub fn validate(&self) -> io::Result<()> {
+        if !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&self.dict_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA dictionary size",
+            ));
+        }
+
+        if self.lc > LC_MAX || self.lp > LP_MAX || self.pb > PB_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA properties",
+            ));
+        }
+
+        let min_nice_len = self.match_finder.hash_bytes().max(MATCH_LEN_MIN as u32);
+        if !(min_nice_len..=MATCH_LEN_MAX as u32).contains(&self.nice_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA nice length",
+            ));
+        }
+
+        if self.depth < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA match finder depth",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn props(&self) -> LzmaHeaderProps {
+        LzmaHeaderProps {
+            lc: self.lc as u8,
+            lp: self.lp as u8,
+            pb: self.pb as u8,
+        }
+    }
+
+    /// Replace the literal and position bits, e.g. with the properties passed to a writer.
+    pub fn with_props(mut self, props: &LzmaHeaderProps) -> Self {
+        self.lc = props.lc as u32;
+        self.lp = props.lp as u32;
+        self.pb = props.pb as u32;
+        self
+    }
+
+    /// Get estimated memory usage of the match finder in bytes, which is most of the encoder's memory.
+    pub fn get_mem_usage(&self) -> u64 {
+        self.match_finder.get_mem_usage(self.dict_size)
+    }
+
+    pub(crate) fn new_match_finder(&self) -> AnyMatchFinder {
+        let max_len = MATCH_LEN_MAX as u32;
+        let (dict_size, nice_len, depth) = (self.dict_size, self.nice_len, self.depth);
+
+        match self.match_finder {
+            MatchFinderKind::HC3 => {
+                AnyMatchFinder::HC3(HC3MatchFinder::new(dict_size, nice_len, max_len, depth))
+            }
+            MatchFinderKind::HC4 => {
+                AnyMatchFinder::HC4(HC4MatchFinder::new(dict_size, nice_len, max_len, depth))
+            }
+            MatchFinderKind::BT2 => {
+                AnyMatchFinder::BT2(BT2MatchFinder::new(dict_size, nice_len, max_len, depth))
+            }
+            MatchFinderKind::BT3 => {
+                AnyMatchFinder::BT3(BT3MatchFinder::new(dict_size, nice_len, max_len, depth))
+            }
+            MatchFinderKind::BT4 => {
+                AnyMatchFinder::BT4(BT4MatchFinder::new(dict_size, nice_len, max_len, depth))
+            }
+        }
+    }
+
+    pub(crate) fn new_instruction_picker(&self) -> AnyInstructionPicker {
+        match self.mode {
+            LzmaMode::Fast => {
+                AnyInstructionPicker::Fast(LZMAFastInstructionPicker::new(self.nice_len))
+            }
+            LzmaMode::Normal => AnyInstructionPicker::Normal(LZMANormalInstructionPicker::new(
+                self.nice_len,
+                self.pb,
+            )),
+        }
+    }
+}
+
+impl Default for LzmaOptions {
+    fn default() -> Self {
+        Self::from_preset(PRESET_DEFAULT, false).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::streams::{LzmaReader, LzmaWriter},
+        test_utils::test_data,
+    };
+    use std::io::{Cursor, Read, Write};
+
+    #[test]
+    fn test_presets() {
+        let fast = LzmaOptions::from_preset(0, false).unwrap();
+        assert_eq!(fast.dict_size, 1 << 18);
+        assert_eq!(fast.mode, LzmaMode::Fast);
+        assert_eq!(fast.match_finder, MatchFinderKind::HC3);
+
+        let default = LzmaOptions::default();
+        assert_eq!(default, LzmaOptions::from_preset(6, false).unwrap());
+        assert_eq!(default.dict_size, 1 << 23);
+        assert_eq!(default.mode, LzmaMode::Normal);
+        assert_eq!(default.match_finder, MatchFinderKind::BT4);
+        assert_eq!(default.nice_len, 64);
+
+        let extreme = LzmaOptions::from_preset(3, true).unwrap();
+        assert_eq!(extreme.dict_size, 1 << 22);
+        assert_eq!(extreme.mode, LzmaMode::Normal);
+        assert_eq!(extreme.nice_len, 192);
+
+        for preset in 0..=PRESET_MAX {
+            for extreme in [false, true] {
+                LzmaOptions::from_preset(preset, extreme)
+                    .unwrap()
+                    .validate()
+                    .unwrap();
+            }
+        }
+        assert!(LzmaOptions::from_preset(PRESET_MAX + 1, false).is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let invalid = [
+            LzmaOptions {
+                dict_size: DICT_SIZE_MIN - 1,
+                ..Default::default()
+            },
+            LzmaOptions {
+                lc: LC_MAX + 1,
+                ..Default::default()
+            },
+            LzmaOptions {
+                lp: LP_MAX + 1,
+                ..Default::default()
+            },
+            LzmaOptions {
+                pb: PB_MAX + 1,
+                ..Default::default()
+            },
+            LzmaOptions {
+                nice_len: MATCH_LEN_MAX as u32 + 1,
+                ..Default::default()
+            },
+            // BT4 hashes 4 bytes, so it can't stop at shorter matches
+            LzmaOptions {
+                nice_len: 3,
+                ..Default::default()
+            },
+            LzmaOptions {
+                depth: -1,
+                ..Default::default()
+            },
+        ];
+
+        for options in invalid {
+            let err = options.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(LzmaWriter::new_with_options(Vec::new(), &options, None).is_err());
+        }
+
+        let options = LzmaOptions {
+            nice_len: 2,
+            match_finder: MatchFinderKind::BT2,
+            ..Default::default()
+        };
+        options.validate().unwrap();
+    }
+
+    #[test]
+    fn test_compress_with_presets() {
+        let data = test_data(5000);
+
+        for preset in 0..=PRESET_MAX {
+            for extreme in [false, true] {
+                // The preset dictionaries are far bigger than the data, which would only waste memory
+                let options = LzmaOptions {
+                    dict_size: 1 << 16,
+                    ..LzmaOptions::from_preset(preset, extreme).unwrap()
+                };
+
+                let mut writer = LzmaWriter::new_with_options(Vec::new(), &options, None).unwrap();
+                writer.write_all(&data).unwrap();
+                let compressed = writer.finish().unwrap();
+                assert!(compressed.len() < data.len() / 4);
+
+                let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+                let mut output = Vec::new();
+                reader.read_to_end(&mut output).unwrap();
+                assert_eq!(output, data);
+            }
+        }
+    }
+}
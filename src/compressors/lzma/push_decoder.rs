@@ -0,0 +1,512 @@
+//! # Push based decoding for `.lzma` and raw LZMA streams
+//!
+//! `LzmaReader` pulls its input from a blocking reader. `LzmaPushDecoder` instead takes the input
+//! in slices of any size as it arrives, which suits event loops and async code.
+//!
+//! When a slice runs out in the middle of a packet, the decoder keeps the few bytes it couldn't
+//! use yet and asks for more input. Packets are only decoded once all of their input is there,
+//! so decoding resumes exactly where it stopped.
+
+use std::io;
+
+use super::{
+    codecs::{
+        header_codec::{parse_lzma_header, LzmaHeader, LzmaHeaderProps},
+        lzma_stream_codec::{data_buffers::DecoderDataBuffer, DecodeOutcome, LZMACodecDecoder},
+        range_codec::{RangeDecoder, RangeDecoderState},
+    },
+    limits::DecoderLimits,
+    streams::{check_decode_outcome, UNKNOWN_UNCOMPRESSED_SIZE},
+};
+use crate::error::{offset_position, with_position};
+
+/// The size of the `.lzma` header
+const HEADER_SIZE: usize = 13;
+
+/// The range coded data starts with a zero byte, followed by the initial 4 byte code
+const RC_INIT_SIZE: usize = 5;
+
+/// The most input that a single packet can need. The range decoder reads at most one byte
+/// for each decoded bit, and the longest packets (matches with the largest distances) are 48 bits.
+/// The end of stream marker needs one more byte after it.
+const MAX_PACKET_INPUT: usize = 49;
+
+/// Why `LzmaPushDecoder::decode` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStatus {
+    /// All the input was used, and more is needed to continue.
+    NeedsInput,
+    /// The output slice is full, and there may be more data to decompress.
+    OutputFull,
+    /// The stream ended, and all its data was written to the output.
+    /// Any input after the end of the stream isn't consumed.
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeProgress {
+    /// The number of bytes used from the input slice. Bytes that are kept for the next call count as used.
+    pub consumed: usize,
+    /// The number of bytes written to the output slice
+    pub written: usize,
+    pub status: DecodeStatus,
+}
+
+/// The state of a stream whose header is known.
+struct ActiveStream {
+    header: LzmaHeader,
+    decoder: LZMACodecDecoder,
+    buffer: DecoderDataBuffer,
+    limits: DecoderLimits,
+    /// `None` until the start of the range coded data has been read
+    rc: Option<RangeDecoderState>,
+    finished: bool,
+}
+
+/// The result of decoding as much as possible from one input slice.
+struct Step {
+    used: usize,
+    needs_input: bool,
+}
+
+impl ActiveStream {
+    fn new(header: LzmaHeader, limits: DecoderLimits) -> Self {
+        let decoder = LZMACodecDecoder::new(
+            header.props.lc as u32,
+            header.props.lp as u32,
+            header.props.pb as u32,
+        );
+        let buffer = DecoderDataBuffer::new(header.dict_size, header.uncompressed_size);
+
+        Self {
+            header,
+            decoder,
+            buffer,
+            limits,
+            rc: None,
+            finished: false,
+        }
+    }
+
+    fn is_size_known(&self) -> bool {
+        self.header.uncompressed_size != UNKNOWN_UNCOMPRESSED_SIZE
+    }
+
+    /// Decode packets from `input` until `wanted` bytes can be flushed, the buffer needs flushing,
+    /// the stream ends or the next packet needs more input than there is.
+    fn decode_packets(&mut self, input: &[u8], wanted: usize) -> io::Result<Step> {
+        let Some(rc_state) = self.rc else {
+            if input.len() < RC_INIT_SIZE {
+                return Ok(Step {
+                    used: 0,
+                    needs_input: true,
+                });
+            }
+
+            let mut rc = RangeDecoder::new_uninitialized(&input[..RC_INIT_SIZE]);
+            if let Err(err) = rc.reset() {
+                let used = RC_INIT_SIZE - rc.get_ref().len();
+                return Err(with_position(err, used as u64, 0));
+            }
+            self.rc = Some(rc.state());
+            return Ok(Step {
+                used: RC_INIT_SIZE,
+                needs_input: false,
+            });
+        };
+
+        let mut rc = RangeDecoder::from_state(input, rc_state);
+        let result = self.decode_available(&mut rc, wanted);
+        let used = input.len() - rc.get_ref().len();
+        let needs_input =
+            result.map_err(|err| with_position(err, used as u64, self.buffer.decoded_bytes()))?;
+
+        self.rc = Some(rc.state());
+        Ok(Step { used, needs_input })
+    }
+
+    /// Decode packets from the range decoder's input, returning whether it needs more input.
+    fn decode_available(
+        &mut self,
+        rc: &mut RangeDecoder<&[u8]>,
+        wanted: usize,
+    ) -> io::Result<bool> {
+        let mut needs_input = false;
+
+        while !self.finished
+            && (self.buffer.flushable_bytes() as usize) < wanted
+            && !self.buffer.must_flush_now_or_data_will_be_lost()
+        {
+            if self.is_size_known() && self.buffer.decoded_bytes() == self.header.uncompressed_size
+            {
+                self.finished = true;
+                break;
+            }
+
+            let outcome = if rc.inner().len() >= MAX_PACKET_INPUT {
+                decode_packet(&mut self.decoder, rc, &mut self.buffer)?
+            } else {
+                // The packet might need more input than there is. Decode it with a copy of the
+                // probabilities, so that nothing changes if it runs out. The output is only
+                // written after all the bits of a packet are decoded.
+                let saved_rc = (*rc.inner(), rc.state());
+                let mut decoder = self.decoder.clone();
+
+                match decode_packet(&mut decoder, rc, &mut self.buffer) {
+                    Ok(outcome) => {
+                        self.decoder = decoder;
+                        outcome
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        *rc = RangeDecoder::from_state(saved_rc.0, saved_rc.1);
+                        needs_input = true;
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+
+            check_decode_outcome(&self.header, self.buffer.decoded_bytes(), outcome)?;
+            self.limits.check_output_size(self.buffer.decoded_bytes())?;
+            if outcome == DecodeOutcome::EndOfStream {
+                self.finished = true;
+            }
+        }
+
+        Ok(needs_input)
+    }
+}
+
+/// Decode one packet, including the last byte of the stream after the end of stream marker.
+fn decode_packet(
+    decoder: &mut LZMACodecDecoder,
+    rc: &mut RangeDecoder<&[u8]>,
+    buffer: &mut DecoderDataBuffer,
+) -> io::Result<DecodeOutcome> {
+    let outcome = decoder.decode_one_packet(rc, buffer)?;
+    if outcome == DecodeOutcome::EndOfStream {
+        // The range decoder only normalizes before decoding a bit, so the last byte is still unread
+        rc.normalize()?;
+    }
+    Ok(outcome)
+}
+
+/// A decoder for `.lzma` and raw LZMA streams that is fed the compressed data, instead of reading it.
+///
+/// Call `decode()` with each slice of input as it arrives, until it returns `DecodeStatus::Finished`.
+/// If the input ends before that, the stream is truncated.
+pub struct LzmaPushDecoder {
+    /// `None` until the `.lzma` header has been parsed
+    stream: Option<ActiveStream>,
+    /// The end of the previous input, which wasn't enough for the next step
+    pending: Vec<u8>,
+    limits: DecoderLimits,
+    /// Where the input of the next step starts in the stream
+    position: u64,
+}
+
+impl LzmaPushDecoder {
+    /// Decode a `.lzma` stream, starting with its header.
+    pub fn new() -> Self {
+        Self::new_with_limits(DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The header is checked as soon as it's parsed.
+    pub fn new_with_limits(limits: DecoderLimits) -> Self {
+        Self {
+            stream: None,
+            pending: Vec::new(),
+            limits,
+            position: 0,
+        }
+    }
+
+    /// Decode a raw LZMA stream without a header, e.g. from inside a zip or 7z archive.
+    ///
+    /// If the uncompressed size is unknown, the stream must end with an end of stream marker.
+    pub fn new_raw(props: LzmaHeaderProps, dict_size: u32, uncompressed_size: Option<u64>) -> Self {
+        let header = raw_header(props, dict_size, uncompressed_size);
+        Self::with_raw_header(header, DecoderLimits::default())
+    }
+
+    /// Like `new_raw()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow.
+    pub fn new_raw_with_limits(
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+        limits: DecoderLimits,
+    ) -> io::Result<Self> {
+        let header = raw_header(props, dict_size, uncompressed_size);
+        limits
+            .check_lzma_header(&header)
+            .map_err(|err| with_position(err.into(), 0, 0))?;
+        Ok(Self::with_raw_header(header, limits))
+    }
+
+    fn with_raw_header(header: LzmaHeader, limits: DecoderLimits) -> Self {
+        Self {
+            stream: Some(ActiveStream::new(header, limits)),
+            pending: Vec::new(),
+            limits,
+            position: 0,
+        }
+    }
+
+    /// The header of the stream, once it has been parsed.
+    pub fn header(&self) -> Option<&LzmaHeader> {
+        self.stream.as_ref().map(|stream| &stream.header)
+    }
+
+    /// Check if the stream ended, and all its data was written to the output.
+    pub fn is_finished(&self) -> bool {
+        self.stream
+            .as_ref()
+            .is_some_and(|stream| stream.finished && stream.buffer.flushable_bytes() == 0)
+    }
+
+    /// Decode as much as possible from `input` into `output`. The input that wasn't consumed
+    /// must be passed again in the next call, followed by any new input.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<DecodeProgress> {
+        let mut consumed = 0;
+        let mut written = 0;
+
+        loop {
+            if let Some(stream) = &mut self.stream {
+                written += stream.buffer.flush(&mut output[written..]);
+            }
+
+            let status = if self.is_finished() {
+                Some(DecodeStatus::Finished)
+            } else if written == output.len() {
+                Some(DecodeStatus::OutputFull)
+            } else {
+                None
+            };
+            if let Some(status) = status {
+                return Ok(DecodeProgress {
+                    consumed,
+                    written,
+                    status,
+                });
+            }
+
+            let wanted = output.len() - written;
+            let step = if self.pending.is_empty() {
+                let remaining = &input[consumed..];
+                let step = Self::decode_step(&mut self.stream, &self.limits, remaining, wanted)
+                    .map_err(|err| offset_position(err, self.position, 0))?;
+
+                if step.needs_input {
+                    // Everything after the used input is part of the next step
+                    self.pending.extend_from_slice(&remaining[step.used..]);
+                    consumed = input.len();
+                } else {
+                    consumed += step.used;
+                }
+                step
+            } else {
+                // Add enough of the new input to complete any step
+                let added = MAX_PACKET_INPUT
+                    .saturating_sub(self.pending.len())
+                    .min(input.len() - consumed);
+                self.pending
+                    .extend_from_slice(&input[consumed..consumed + added]);
+                consumed += added;
+
+                let step = Self::decode_step(&mut self.stream, &self.limits, &self.pending, wanted)
+                    .map_err(|err| offset_position(err, self.position, 0))?;
+
+                if !step.needs_input {
+                    // Give back the new input that wasn't needed, so it's decoded in place
+                    let unused = (self.pending.len() - step.used).min(added);
+                    consumed -= unused;
+                    self.pending.truncate(self.pending.len() - unused);
+                }
+                self.pending.drain(..step.used.min(self.pending.len()));
+                step
+            };
+
+            self.position += step.used as u64;
+            if step.needs_input && consumed == input.len() {
+                return Ok(DecodeProgress {
+                    consumed,
+                    written,
+                    status: DecodeStatus::NeedsInput,
+                });
+            }
+        }
+    }
+
+    /// Parse the header, or decode packets if it was already parsed.
+    fn decode_step(
+        stream: &mut Option<ActiveStream>,
+        limits: &DecoderLimits,
+        input: &[u8],
+        wanted: usize,
+    ) -> io::Result<Step> {
+        match stream {
+            Some(stream) => stream.decode_packets(input, wanted),
+            None => {
+                if input.len() < HEADER_SIZE {
+                    return Ok(Step {
+                        used: 0,
+                        needs_input: true,
+                    });
+                }
+
+                let header = parse_lzma_header(&input[..HEADER_SIZE])
+                    .map_err(|err| with_position(err, 0, 0))?;
+                limits
+                    .check_lzma_header(&header)
+                    .map_err(|err| with_position(err.into(), HEADER_SIZE as u64, 0))?;
+                *stream = Some(ActiveStream::new(header, *limits));
+                Ok(Step {
+                    used: HEADER_SIZE,
+                    needs_input: false,
+                })
+            }
+        }
+    }
+}
+
+fn raw_header(
+    props: LzmaHeaderProps,
+    dict_size: u32,
+    uncompressed_size: Option<u64>,
+) -> LzmaHeader {
+    LzmaHeader {
+        props,
+        dict_size,
+        uncompressed_size: uncompressed_size.unwrap_or(UNKNOWN_UNCOMPRESSED_SIZE),
+    }
+}
+
+impl Default for LzmaPushDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::streams::LzmaWriter,
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::Write;
+
+    fn compress(data: &[u8], uncompressed_size: Option<u64>) -> Vec<u8> {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 1 << 16, uncompressed_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Feed the input in fragments of `input_len` bytes, reading into `output_len` byte slices.
+    fn decode_in_fragments(
+        decoder: &mut LzmaPushDecoder,
+        input: &[u8],
+        input_len: usize,
+        output_len: usize,
+    ) -> io::Result<(Vec<u8>, usize)> {
+        let mut output = Vec::new();
+        let mut out_buf = vec![0; output_len];
+        let mut pos = 0;
+
+        loop {
+            let end = (pos + input_len).min(input.len());
+            let progress = decoder.decode(&input[pos..end], &mut out_buf)?;
+            pos += progress.consumed;
+            output.extend_from_slice(&out_buf[..progress.written]);
+
+            match progress.status {
+                DecodeStatus::Finished => return Ok((output, pos)),
+                DecodeStatus::NeedsInput if end == input.len() => {
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                }
+                DecodeStatus::NeedsInput => assert_eq!(pos, end),
+                DecodeStatus::OutputFull => assert_eq!(progress.written, output_len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_in_fragments() {
+        let data = test_data(30_000);
+
+        for size in [Some(data.len() as u64), None] {
+            let compressed = compress(&data, size);
+
+            for (input_len, output_len) in [(1, 1 << 16), (7, 100), (100, 7), (4096, 4096)] {
+                let mut decoder = LzmaPushDecoder::new();
+                let (output, consumed) =
+                    decode_in_fragments(&mut decoder, &compressed, input_len, output_len).unwrap();
+
+                assert_eq!(output, data);
+                assert_eq!(consumed, compressed.len());
+                assert!(decoder.is_finished());
+                assert_eq!(decoder.header().unwrap().dict_size, 1 << 16);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_raw() {
+        let data = test_data(30_000);
+
+        let mut writer = LzmaWriter::new_raw(Vec::new(), PROPS, 1 << 16, true).unwrap();
+        writer.write_all(&data).unwrap();
+        let mut compressed = writer.finish().unwrap();
+
+        // Data after the end of the stream is left for the caller
+        let stream_len = compressed.len();
+        compressed.extend_from_slice(b"trailing");
+
+        let mut decoder = LzmaPushDecoder::new_raw(PROPS, 1 << 16, None);
+        let (output, consumed) = decode_in_fragments(&mut decoder, &compressed, 3, 1000).unwrap();
+        assert_eq!(output, data);
+        assert_eq!(consumed, stream_len);
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let data = test_data(30_000);
+        let compressed = compress(&data, None);
+
+        for len in [5, 13, 17, compressed.len() / 2, compressed.len() - 1] {
+            let mut decoder = LzmaPushDecoder::new();
+            let err = decode_in_fragments(&mut decoder, &compressed[..len], 10, 1000).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(!decoder.is_finished());
+        }
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let data = test_data(30_000);
+        let compressed = compress(&data, None);
+
+        // A dictionary size that's too small
+        let mut invalid = compressed.clone();
+        invalid[1..5].copy_from_slice(&[0, 0, 0, 0]);
+        let mut decoder = LzmaPushDecoder::new();
+        let err = decoder.decode(&invalid, &mut [0; 100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The range coded data must start with a zero byte
+        let mut invalid = compressed.clone();
+        invalid[HEADER_SIZE] = 1;
+        let mut decoder = LzmaPushDecoder::new();
+        let err = decoder.decode(&invalid, &mut [0; 100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The end marker comes before the size in the header
+        let mut invalid = compressed.clone();
+        invalid[5..13].copy_from_slice(&(data.len() as u64 + 1).to_le_bytes());
+        let mut decoder = LzmaPushDecoder::new();
+        let err = decode_in_fragments(&mut decoder, &invalid, 10, 1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
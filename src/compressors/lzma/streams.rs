@@ -0,0 +1,826 @@
+//! # Streaming wrappers for the `.lzma` (LZMA-alone) format
+//!
+//! The `.lzma` format is the raw LZMA stream prefixed by a 13 byte header, containing the
+//! properties byte, the dictionary size and the uncompressed size. The uncompressed size can
+//! be `u64::MAX`, in which case the stream must be terminated with an end of stream marker.
+
+use std::io::{self, Read, Write};
+
+use super::codecs::{
+    header_codec::{
+        parse_lzma_header, write_lzma_header, LzmaHeader, LzmaHeaderProps, LZMA_HEADER_SIZE,
+    },
+    length_codec::MATCH_LEN_MAX,
+    lzma_stream_codec::{
+        data_buffers::DecoderDataBuffer,
+        encoders::{match_finding::AnyMatchFinder, AnyInstructionPicker, LZMAEncoderInput},
+        DecodeOutcome, LZMACodecDecoder, LZMACodecEncoder,
+    },
+    range_codec::{RangeDecoder, RangeEncoder},
+};
+use super::{limits::DecoderLimits, options::LzmaOptions};
+use crate::{
+    error::{with_position, Error, ErrorKind},
+    utils::counting_io::CountingReader,
+};
+
+/// The uncompressed size value used in the header when the size is not known ahead of time.
+pub const UNKNOWN_UNCOMPRESSED_SIZE: u64 = u64::MAX;
+
+/// Check the output after decoding packets against the uncompressed size in the header.
+pub(crate) fn check_decode_outcome(
+    header: &LzmaHeader,
+    decoded_bytes: u64,
+    outcome: DecodeOutcome,
+) -> io::Result<()> {
+    if header.uncompressed_size == UNKNOWN_UNCOMPRESSED_SIZE {
+        return Ok(());
+    }
+
+    match outcome {
+        DecodeOutcome::Packet if decoded_bytes > header.uncompressed_size => {
+            Err(Error::with_message(
+                ErrorKind::SizeMismatch,
+                "LZMA stream is longer than the uncompressed size in the header",
+            )
+            .into())
+        }
+        DecodeOutcome::EndOfStream if decoded_bytes != header.uncompressed_size => {
+            Err(Error::with_message(
+                ErrorKind::SizeMismatch,
+                "LZMA end marker found before the uncompressed size was reached",
+            )
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A reader that decompresses a `.lzma` stream from the inner reader.
+pub struct LzmaReader<R: Read> {
+    header: LzmaHeader,
+    /// The size of the header in the inner reader, which is 0 for raw streams
+    header_size: u64,
+    rc: RangeDecoder<CountingReader<R>>,
+    decoder: LZMACodecDecoder,
+    buffer: DecoderDataBuffer,
+    limits: DecoderLimits,
+    finished: bool,
+}
+
+impl<R: Read> LzmaReader<R> {
+    /// Parse the `.lzma` header from the reader and prepare to decompress the data after it.
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::new_with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. Use this for untrusted files.
+    pub fn new_with_limits(mut inner: R, limits: DecoderLimits) -> io::Result<Self> {
+        let header = parse_lzma_header(&mut inner).map_err(|err| with_position(err, 0, 0))?;
+        Self::with_header_size(inner, header, LZMA_HEADER_SIZE, limits)
+    }
+
+    /// Decompress a raw LZMA stream without a header, e.g. from inside a zip or 7z archive.
+    ///
+    /// If the uncompressed size is unknown, the stream must end with an end of stream marker.
+    /// If it is known, an end of stream marker is allowed but not required.
+    pub fn new_raw(
+        inner: R,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let header = LzmaHeader {
+            props,
+            dict_size,
+            uncompressed_size: uncompressed_size.unwrap_or(UNKNOWN_UNCOMPRESSED_SIZE),
+        };
+        Self::new_with_header(inner, header)
+    }
+
+    /// Decompress a raw LZMA stream using an already parsed header.
+    pub fn new_with_header(inner: R, header: LzmaHeader) -> io::Result<Self> {
+        Self::new_with_header_and_limits(inner, header, DecoderLimits::default())
+    }
+
+    /// Decompress a raw LZMA stream using an already parsed header, within the given limits.
+    pub fn new_with_header_and_limits(
+        inner: R,
+        header: LzmaHeader,
+        limits: DecoderLimits,
+    ) -> io::Result<Self> {
+        Self::with_header_size(inner, header, 0, limits)
+    }
+
+    fn with_header_size(
+        inner: R,
+        header: LzmaHeader,
+        header_size: u64,
+        limits: DecoderLimits,
+    ) -> io::Result<Self> {
+        limits
+            .check_lzma_header(&header)
+            .map_err(|err| with_position(err.into(), header_size, 0))?;
+
+        let mut rc = RangeDecoder::new_uninitialized(CountingReader::new(inner));
+        if let Err(err) = rc.reset() {
+            return Err(with_position(err, header_size + rc.get_ref().count(), 0));
+        }
+        let decoder = LZMACodecDecoder::new(
+            header.props.lc as u32,
+            header.props.lp as u32,
+            header.props.pb as u32,
+        );
+        let buffer = DecoderDataBuffer::new(header.dict_size, header.uncompressed_size);
+
+        Ok(Self {
+            header,
+            header_size,
+            rc,
+            decoder,
+            buffer,
+            limits,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &LzmaHeader {
+        &self.header
+    }
+
+    /// Use a preset dictionary, which must be the same one the stream was compressed with.
+    /// Only the last `dict_size` bytes of the dictionary are used.
+    ///
+    /// This must be called before anything is read.
+    pub fn set_preset_dict(&mut self, dict: &[u8]) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Preset dictionaries must be set before reading",
+            ));
+        }
+
+        self.buffer.load_preset_dict(dict);
+        Ok(())
+    }
+
+    fn is_size_known(&self) -> bool {
+        self.header.uncompressed_size != UNKNOWN_UNCOMPRESSED_SIZE
+    }
+
+    /// Decode packets until there's either enough data to fill `wanted` bytes,
+    /// the buffer needs flushing, or the stream ends.
+    fn decode_until(&mut self, wanted: usize) -> io::Result<()> {
+        while !self.finished
+            && (self.buffer.flushable_bytes() as usize) < wanted
+            && !self.buffer.must_flush_now_or_data_will_be_lost()
+        {
+            if self.is_size_known() && self.buffer.decoded_bytes() == self.header.uncompressed_size
+            {
+                self.finished = true;
+                break;
+            }
+
+            let mut max_bytes = (wanted - self.buffer.flushable_bytes() as usize) as u64;
+            if self.is_size_known() {
+                max_bytes =
+                    max_bytes.min(self.header.uncompressed_size - self.buffer.decoded_bytes());
+            }
+
+            let outcome = self
+                .decoder
+                .decode_until(&mut self.rc, &mut self.buffer, max_bytes)?;
+            check_decode_outcome(&self.header, self.buffer.decoded_bytes(), outcome)?;
+            self.limits.check_output_size(self.buffer.decoded_bytes())?;
+
+            if outcome == DecodeOutcome::EndOfStream {
+                // The range decoder only normalizes before decoding a bit, so the last byte
+                // of the stream hasn't been read yet. Reading it leaves the inner reader right
+                // after the stream, for containers that have more data after it.
+                self.rc.normalize()?;
+                self.finished = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.rc.get_ref().get_ref()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rc.into_inner().into_inner()
+    }
+}
+
+impl<R: Read> Read for LzmaReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.decode_until(buf.len()).map_err(|err| {
+            let offset = self.header_size + self.rc.get_ref().count();
+            with_position(err, offset, self.buffer.decoded_bytes())
+        })?;
+
+        Ok(self.buffer.flush(buf))
+    }
+}
+
+/// A writer that compresses data into a `.lzma` stream, writing it to the inner writer.
+///
+/// If the uncompressed size isn't known ahead of time, the header stores it as unknown
+/// and the stream is terminated with an end of stream marker instead. Raw streams without
+/// a header can be written with `new_raw()`, choosing whether to add the marker.
+///
+/// The stream must be completed by calling `finish()`. Dropping the writer will try to
+/// finish the stream, ignoring any errors.
+pub struct LzmaWriter<W: Write> {
+    rc: Option<RangeEncoder<W>>,
+    encoder: LZMACodecEncoder<AnyInstructionPicker>,
+    input: LZMAEncoderInput<AnyMatchFinder>,
+
+    uncompressed_size: Option<u64>,
+    use_end_marker: bool,
+    written: u64,
+
+    /// The number of preset dictionary bytes loaded before the written data
+    preset_len: u64,
+}
+
+impl<W: Write> LzmaWriter<W> {
+    /// Compress with the default preset, using the given properties and dictionary size.
+    pub fn new(
+        inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        uncompressed_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let options = LzmaOptions {
+            dict_size,
+            ..Default::default()
+        };
+        Self::new_with_options(inner, &options.with_props(&props), uncompressed_size)
+    }
+
+    pub fn new_with_options(
+        inner: W,
+        options: &LzmaOptions,
+        uncompressed_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let header = LzmaHeader {
+            props: options.props(),
+            dict_size: options.dict_size,
+            uncompressed_size: uncompressed_size.unwrap_or(UNKNOWN_UNCOMPRESSED_SIZE),
+        };
+
+        // Validate the parameters before writing anything
+        let mut writer = Self::new_raw_with_options(inner, options, uncompressed_size.is_none())?;
+        writer.uncompressed_size = uncompressed_size;
+
+        let rc = writer.rc.as_mut().expect("LzmaWriter used after finishing");
+        write_lzma_header(rc.inner(), &header)?;
+
+        Ok(writer)
+    }
+
+    /// Write a raw LZMA stream without a header, e.g. for zip or 7z archives.
+    /// The end of stream marker is only written if `use_end_marker` is true.
+    pub fn new_raw(
+        inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        use_end_marker: bool,
+    ) -> io::Result<Self> {
+        let options = LzmaOptions {
+            dict_size,
+            ..Default::default()
+        };
+        Self::new_raw_with_options(inner, &options.with_props(&props), use_end_marker)
+    }
+
+    pub fn new_raw_with_options(
+        inner: W,
+        options: &LzmaOptions,
+        use_end_marker: bool,
+    ) -> io::Result<Self> {
+        let (encoder, input) = LZMACodecEncoder::from_options(options)?;
+
+        Ok(Self {
+            rc: Some(RangeEncoder::new(inner)),
+            encoder,
+            input,
+            uncompressed_size: None,
+            use_end_marker,
+            written: 0,
+            preset_len: 0,
+        })
+    }
+
+    /// Use a preset dictionary, which the written data can reference as if it came just before it.
+    /// The same dictionary must be given to the reader. Only the last `dict_size` bytes are used.
+    ///
+    /// This must be called before anything is written.
+    pub fn set_preset_dict(&mut self, dict: &[u8]) -> io::Result<()> {
+        if self.written != 0 || self.preset_len != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Preset dictionaries must be set before writing",
+            ));
+        }
+
+        self.encoder.load_preset_dict(&mut self.input, dict);
+        self.preset_len = self.input.pos();
+        Ok(())
+    }
+
+    /// Encode packets while there's enough data ahead to find full length matches.
+    fn encode_available(&mut self) -> io::Result<()> {
+        let rc = self.rc.as_mut().expect("LzmaWriter used after finishing");
+        while self.input.forward_bytes() > MATCH_LEN_MAX {
+            self.encoder.encode_one_packet(rc, &mut self.input)?;
+        }
+        Ok(())
+    }
+
+    fn encode_remaining(&mut self, rc: &mut RangeEncoder<W>) -> io::Result<()> {
+        while self.encoder.position() < self.preset_len + self.written {
+            self.encoder.encode_one_packet(rc, &mut self.input)?;
+        }
+
+        if let Some(size) = self.uncompressed_size {
+            if size != self.written {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Written data length doesn't match the uncompressed size in the header",
+                ));
+            }
+        }
+
+        if self.use_end_marker {
+            self.encoder.encode_end_marker(rc)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<W> {
+        let mut rc = self.rc.take().expect("LzmaWriter used after finishing");
+
+        // Finish the range coder even on errors, so that it doesn't panic on drop
+        let result = self.encode_remaining(&mut rc);
+        let inner = rc.finish()?;
+
+        result.map(|_| inner)
+    }
+
+    /// Get the inner writer, e.g. to take the compressed data written to it so far.
+    /// The encoder holds on to the last few bytes until more data is written or the stream is finished.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.rc
+            .as_mut()
+            .expect("LzmaWriter used after finishing")
+            .inner()
+    }
+
+    /// Finish the compressed stream, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()
+    }
+}
+
+impl<W: Write> Write for LzmaWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(size) = self.uncompressed_size {
+            if self.written + buf.len() as u64 > size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Written data is longer than the uncompressed size in the header",
+                ));
+            }
+        }
+
+        let len = buf.len().min(self.input.available_append_bytes());
+        self.input.append_data(&buf[..len]);
+        self.written += len as u64;
+
+        self.encode_available()?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.rc.as_mut() {
+            Some(rc) => rc.inner().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for LzmaWriter<W> {
+    fn drop(&mut self) {
+        if self.rc.is_some() {
+            let _ = self.finish_stream();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::{
+            codecs::{
+                lzma_stream_codec::data_buffers::SliceDataBuffer, range_codec::SliceRangeDecoder,
+            },
+            push_decoder::LzmaPushDecoder,
+        },
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::{Cursor, Write};
+
+    fn compress_reference(data: &[u8], known_size: bool) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let options = lzma_rust::LZMA2Options {
+            dict_size: 0x4000,
+            ..Default::default()
+        };
+
+        let counting_writer = lzma_rust::CountingWriter::new(&mut compressed);
+        let size = known_size.then_some(data.len() as u64);
+        let mut writer =
+            lzma_rust::LZMAWriter::new(counting_writer, &options, true, !known_size, size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+
+        compressed
+    }
+
+    #[test]
+    fn test_read_known_size() {
+        let data = test_data(6000);
+        let compressed = compress_reference(&data, true);
+
+        let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_read_unknown_size() {
+        let data = test_data(6000);
+        let compressed = compress_reference(&data, false);
+
+        let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+        assert_eq!(reader.header().uncompressed_size, UNKNOWN_UNCOMPRESSED_SIZE);
+
+        let mut output = Vec::new();
+        io::copy(&mut reader, &mut output).unwrap();
+
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_read_small_chunks() {
+        let data = test_data(6000);
+        let compressed = compress_reference(&data, true);
+
+        let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+        let mut output = Vec::new();
+        let mut chunk = [0; 7];
+        loop {
+            let read = reader.read(&mut chunk).unwrap();
+            if read == 0 {
+                break;
+            }
+            output.extend_from_slice(&chunk[..read]);
+        }
+
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_decode_until_matches_single_packets() {
+        let data = test_data(6000);
+        let compressed = compress_reference(&data, false);
+        let input = &compressed[LZMA_HEADER_SIZE as usize..];
+
+        let mut expected = vec![0; data.len()];
+        let mut buffer = SliceDataBuffer::new(&mut expected, 0x4000);
+        let mut rc = SliceRangeDecoder::new(input).unwrap();
+        let mut decoder = LZMACodecDecoder::new(3, 0, 2);
+        while decoder.decode_one_packet(&mut rc, &mut buffer).unwrap() == DecodeOutcome::Packet {}
+        assert_eq!(buffer.decoded_bytes(), data.len() as u64);
+        let end = rc.position();
+
+        // Stopping and resuming the loop anywhere doesn't change the result
+        for max_bytes in [1, 7, 300] {
+            let mut output = vec![0; data.len()];
+            let mut buffer = SliceDataBuffer::new(&mut output, 0x4000);
+            let mut rc = SliceRangeDecoder::new(input).unwrap();
+            let mut decoder = LZMACodecDecoder::new(3, 0, 2);
+            loop {
+                let start = buffer.decoded_bytes();
+                let outcome = decoder
+                    .decode_until(&mut rc, &mut buffer, max_bytes)
+                    .unwrap();
+                if outcome == DecodeOutcome::EndOfStream {
+                    break;
+                }
+                assert!(buffer.decoded_bytes() - start >= max_bytes);
+            }
+            assert_eq!(buffer.decoded_bytes(), data.len() as u64);
+            assert_eq!(rc.position(), end);
+            assert_eq!(output, expected);
+        }
+        assert_eq!(expected, data);
+    }
+
+    fn compress(data: &[u8], known_size: bool) -> Vec<u8> {
+        let size = known_size.then_some(data.len() as u64);
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x4000, size).unwrap();
+        io::copy(&mut Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_write_roundtrip() {
+        let data = test_data(6000);
+
+        for known_size in [true, false] {
+            let compressed = compress(&data, known_size);
+            assert!(compressed.len() < data.len() / 2);
+
+            let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_write_readable_by_reference() {
+        let data = test_data(6000);
+
+        for known_size in [true, false] {
+            let compressed = compress(&data, known_size);
+
+            let mut reader =
+                lzma_rust::LZMAReader::new_mem_limit(Cursor::new(compressed), u32::MAX, None)
+                    .unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_write_leading_zeros() {
+        // Zeros at the start of the stream must not be matched against bytes before the start
+        let mut data = vec![0; 1000];
+        data.extend(test_data(6000));
+
+        for len in [1, 2, 5, 300, data.len()] {
+            let compressed = compress(&data[..len], true);
+
+            let mut reader =
+                lzma_rust::LZMAReader::new_mem_limit(Cursor::new(compressed), u32::MAX, None)
+                    .unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+
+            assert_eq!(output, &data[..len]);
+        }
+    }
+
+    #[test]
+    fn test_write_empty() {
+        let compressed = compress(&[], false);
+
+        let mut reader = LzmaReader::new(Cursor::new(compressed)).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_raw_end_marker_with_known_size() {
+        let data = test_data(6000);
+
+        let mut with_marker = LzmaWriter::new_raw(Vec::new(), PROPS, 0x4000, true).unwrap();
+        with_marker.write_all(&data).unwrap();
+        let with_marker = with_marker.finish().unwrap();
+
+        let mut without_marker = LzmaWriter::new_raw(Vec::new(), PROPS, 0x4000, false).unwrap();
+        without_marker.write_all(&data).unwrap();
+        let without_marker = without_marker.finish().unwrap();
+
+        assert!(with_marker.len() > without_marker.len());
+
+        // The marker is accepted both when the size is known and when it isn't
+        for size in [Some(data.len() as u64), None] {
+            let mut reader =
+                LzmaReader::new_raw(Cursor::new(&with_marker), PROPS, 0x4000, size).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+        }
+
+        let mut reader = LzmaReader::new_raw(
+            Cursor::new(&without_marker),
+            PROPS,
+            0x4000,
+            Some(data.len() as u64),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_end_marker_before_size() {
+        let data = test_data(6000);
+
+        let mut writer = LzmaWriter::new_raw(Vec::new(), PROPS, 0x4000, true).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let size = Some(data.len() as u64 + 1);
+        let mut reader = LzmaReader::new_raw(Cursor::new(compressed), PROPS, 0x4000, size).unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_write_size_mismatch() {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x4000, Some(4)).unwrap();
+        assert!(writer.write_all(b"too long").is_err());
+
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x4000, Some(4)).unwrap();
+        writer.write_all(b"abc").unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    /// A writer that fails after accepting `remaining` bytes.
+    #[derive(Debug)]
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::other("write failed"));
+            }
+
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let new_writer = |remaining| {
+            let inner = FailingWriter { remaining };
+            LzmaWriter::new(inner, PROPS, 0x4000, None)
+        };
+
+        // Writing the header fails
+        let err = new_writer(0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+
+        // Writing the compressed data fails, and the writer is dropped without finishing
+        let mut data = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..100_000 {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push((x >> 16) as u8);
+        }
+        let mut writer = new_writer(LZMA_HEADER_SIZE as usize + 100).unwrap();
+        let err = writer.write_all(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        drop(writer);
+
+        // Only the last bytes fail to write when finishing
+        let mut writer = new_writer(LZMA_HEADER_SIZE as usize + 1).unwrap();
+        writer.write_all(b"some data").unwrap();
+        let err = writer.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    fn roundtrip_with_preset_dict(data: &[u8], dict: &[u8]) -> usize {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x4000, None).unwrap();
+        writer.set_preset_dict(dict).unwrap();
+        writer.write_all(data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let mut reader = LzmaReader::new(Cursor::new(&compressed)).unwrap();
+        reader.set_preset_dict(dict).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        compressed.len()
+    }
+
+    #[test]
+    fn test_preset_dict() {
+        let data = test_data(6000);
+        let (dict, data) = data.split_at(0x2000);
+        let data = &data[..0x2000];
+
+        let with_dict = roundtrip_with_preset_dict(data, dict);
+        let without_dict = roundtrip_with_preset_dict(data, &[]);
+        assert!(with_dict < without_dict);
+    }
+
+    #[test]
+    fn test_preset_dict_larger_than_dict_size() {
+        let dict = test_data(6000);
+        assert!(dict.len() > 0x4000);
+
+        // Only the end of the dictionary is kept, which contains all of the data
+        let data = &dict[dict.len() - 0x1000..];
+        let compressed_len = roundtrip_with_preset_dict(data, &dict);
+        assert!(compressed_len < 100);
+    }
+
+    #[test]
+    fn test_preset_dict_after_data() {
+        let mut writer = LzmaWriter::new(Vec::new(), PROPS, 0x4000, None).unwrap();
+        writer.write_all(b"data").unwrap();
+        let err = writer.set_preset_dict(b"dict").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let data = test_data(6000);
+        let compressed = compress_reference(&data, true);
+
+        for len in [5, 13, 20, compressed.len() / 2] {
+            let result = LzmaReader::new(Cursor::new(&compressed[..len]))
+                .and_then(|mut reader| reader.read_to_end(&mut Vec::new()));
+
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let data = test_data(6000);
+        let compressed = compress(&data, true);
+
+        // A truncated stream is noticed once everything before the end was read
+        let half = compressed.len() / 2;
+        let mut reader = LzmaReader::new(Cursor::new(&compressed[..half])).unwrap();
+        let err = Error::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(err.compressed_offset(), Some(half as u64));
+        let pos = err.uncompressed_pos().unwrap();
+        assert!(pos > 0 && pos < data.len() as u64);
+
+        // The range coded data has to start with a null byte, right after the header
+        let mut file = compressed.clone();
+        file[LZMA_HEADER_SIZE as usize] = 1;
+        let err = Error::from(LzmaReader::new(Cursor::new(&file)).err().unwrap());
+        assert_eq!(err.kind(), ErrorKind::CorruptRangeCoder);
+        assert_eq!(err.compressed_offset(), Some(LZMA_HEADER_SIZE + 1));
+        assert_eq!(err.uncompressed_pos(), Some(0));
+
+        let mut decoder = LzmaPushDecoder::new();
+        let err = Error::from(decoder.decode(&file, &mut [0; 16]).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::CorruptRangeCoder);
+        assert_eq!(err.compressed_offset(), Some(LZMA_HEADER_SIZE + 1));
+
+        // The header is checked before anything else
+        let mut file = compressed.clone();
+        file[0] = 0xFF;
+        let err = Error::from(LzmaReader::new(Cursor::new(&file)).err().unwrap());
+        assert_eq!(err.kind(), ErrorKind::InvalidProperties);
+        assert_eq!(err.compressed_offset(), Some(0));
+
+        // Stopping at the wrong size is noticed at the end of the data
+        let size = Some(data.len() as u64 - 1);
+        let raw = &compressed[LZMA_HEADER_SIZE as usize..];
+        let mut reader = LzmaReader::new_raw(Cursor::new(raw), PROPS, 0x4000, size).unwrap();
+        let err = Error::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::SizeMismatch);
+        assert!(err.uncompressed_pos().unwrap() >= data.len() as u64);
+    }
+}
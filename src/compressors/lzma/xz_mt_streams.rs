@@ -0,0 +1,1020 @@
+//! # Multithreaded `.xz` compression and decompression
+//!
+//! When compressing, the input is split into blocks of a fixed uncompressed size, and each block is
+//! compressed on a worker thread with its own encoder, after applying the filters to it. Blocks are independent of each other, so the
+//! output only depends on the settings and never on the thread count or on how the work was scheduled.
+//!
+//! At most `threads` blocks are held in memory at once, including the one currently being filled,
+//! and each worker has its own encoder. Memory usage is therefore bounded by roughly
+//! `threads * (block_size + options.get_mem_usage())`, where `options` are the default
+//! `LzmaOptions` with the writer's dictionary size that each block's `Lzma2Writer` uses, plus
+//! the compressed output waiting to be written.
+//!
+//! When decompressing, blocks whose sizes are known up front are read into memory whole and decoded
+//! on worker threads, each with its own decoder and dictionary buffer. The sizes come from the block
+//! headers (which the multithreaded writer always fills in), or from the indexes if the reader is
+//! seekable. Blocks with unknown sizes, or too big for the memory limit, are decoded on the reading
+//! thread instead, like the single threaded reader does. That still needs the block's dictionary
+//! buffer, so a block whose dictionary alone is over the memory limit fails to decode.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use byteorder::ReadBytesExt;
+
+use super::{
+    codecs::{
+        header_codec::{LzmaHeaderProps, DICT_SIZE_MAX, DICT_SIZE_MIN},
+        lzma2_codec::are_props_valid,
+        xz_codec::{
+            block_header::{BlockHeader, FilterFlags},
+            checks::{CheckType, Checker},
+            encode_stream_footer, encode_stream_header,
+            index::{encode_index, IndexRecord, INDEX_INDICATOR},
+            padding_size, parse_stream_header, XzError, STREAM_HEADER_SIZE,
+        },
+    },
+    limits::{get_lzma2_decoder_mem_usage, DecoderLimits},
+    lzma2_streams::Lzma2Writer,
+    xz_seek_streams::read_all_index_records,
+    xz_streams::{
+        block_dict_buffer_size, create_filter_chain, read_next_stream_header, read_stream_end,
+        validate_encoder_filters, BlockReader,
+    },
+};
+use crate::{
+    compressors::filters::Filter,
+    error::{offset_position, with_position},
+    utils::counting_io::CountingReader,
+};
+
+#[derive(Debug, Clone)]
+struct BlockOptions {
+    props: LzmaHeaderProps,
+    dict_size: u32,
+    check: CheckType,
+}
+
+struct CompressedBlock {
+    /// The whole block, from the header to the check
+    data: Vec<u8>,
+    record: IndexRecord,
+}
+
+struct Job {
+    data: Vec<u8>,
+    /// The filters to apply before LZMA2
+    filters: Vec<FilterFlags>,
+    result: Sender<io::Result<CompressedBlock>>,
+}
+
+/// Compress a block into memory. The compressed size is known before the header is written,
+/// so unlike the single threaded writer, the header contains both sizes.
+/// The filters convert the data in place, once the check was calculated.
+fn compress_block(
+    data: &mut [u8],
+    filters: &[FilterFlags],
+    options: &BlockOptions,
+) -> io::Result<CompressedBlock> {
+    let mut checker = Checker::new(options.check);
+    checker.update(data);
+    let check = checker.finish();
+
+    let mut chain = create_filter_chain(filters, true)?;
+    let converted = chain.convert(data);
+    chain.finish(&mut data[converted..]);
+
+    let mut lzma2 = Lzma2Writer::new(Vec::new(), options.props.clone(), options.dict_size)?;
+    lzma2.write_all(data)?;
+    let compressed = lzma2.finish()?;
+
+    let header = BlockHeader {
+        compressed_size: Some(compressed.len() as u64),
+        uncompressed_size: Some(data.len() as u64),
+        filters: [filters, &[FilterFlags::lzma2(options.dict_size)]].concat(),
+    }
+    .encode();
+
+    let padding = padding_size(compressed.len() as u64);
+    let mut block = Vec::with_capacity(header.len() + compressed.len() + padding + check.len());
+    block.extend_from_slice(&header);
+    block.extend_from_slice(&compressed);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&check);
+
+    Ok(CompressedBlock {
+        record: IndexRecord {
+            unpadded_size: (header.len() + compressed.len() + check.len()) as u64,
+            uncompressed_size: data.len() as u64,
+        },
+        data: block,
+    })
+}
+
+fn run_worker(jobs: Arc<Mutex<Receiver<Job>>>, options: Arc<BlockOptions>) {
+    loop {
+        // The lock is only held while waiting for the next job, not while compressing it
+        let mut job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        // The writer may have been dropped in the meantime, in which case nobody wants the result
+        let _ = job
+            .result
+            .send(compress_block(&mut job.data, &job.filters, &options));
+    }
+}
+
+/// A writer that compresses data into an `.xz` file on multiple threads, writing it to the inner writer.
+///
+/// The stream must be completed by calling `finish()`. Dropping the writer will try to
+/// finish the stream, ignoring any errors.
+pub struct XzMtWriter<W: Write> {
+    inner: Option<W>,
+    options: Arc<BlockOptions>,
+    block_size: usize,
+    threads: usize,
+    filters: Vec<FilterFlags>,
+
+    /// The uncompressed data of the block currently being filled
+    buffer: Vec<u8>,
+    /// The blocks being compressed, in the order they need to be written
+    pending: VecDeque<Receiver<io::Result<CompressedBlock>>>,
+    records: Vec<IndexRecord>,
+
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<W: Write> XzMtWriter<W> {
+    /// Write the stream header and start `threads` worker threads, which compress blocks of
+    /// `block_size` uncompressed bytes with LZMA2. LZMA2 requires `lc + lp <= 4`.
+    ///
+    /// The dictionary size used by the encoders is limited to the block size, as blocks can't
+    /// reference each other anyway.
+    pub fn new(
+        mut inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        check: CheckType,
+        block_size: usize,
+        threads: usize,
+    ) -> io::Result<Self> {
+        if !are_props_valid(&props) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA2 properties",
+            ));
+        }
+
+        if !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&dict_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA dictionary size",
+            ));
+        }
+
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The xz block size can't be zero",
+            ));
+        }
+
+        if threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one thread is required",
+            ));
+        }
+
+        inner.write_all(&encode_stream_header(check))?;
+
+        let options = Arc::new(BlockOptions {
+            props,
+            dict_size: (block_size as u64).clamp(DICT_SIZE_MIN as u64, dict_size as u64) as u32,
+            check,
+        });
+
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let options = options.clone();
+                thread::spawn(move || run_worker(receiver, options))
+            })
+            .collect();
+
+        Ok(Self {
+            inner: Some(inner),
+            options,
+            block_size,
+            threads,
+            filters: Vec::new(),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            records: Vec::new(),
+            jobs: Some(jobs),
+            workers,
+        })
+    }
+
+    /// Apply filters to the data before compressing it with LZMA2, e.g. a BCJ filter for executables
+    /// or a delta filter for audio.
+    /// The filters are applied in order, and at most 3 can be used.
+    ///
+    /// This takes effect from the next block.
+    pub fn set_filters(&mut self, filters: Vec<FilterFlags>) -> io::Result<()> {
+        validate_encoder_filters(&filters)?;
+        self.filters = filters;
+        Ok(())
+    }
+
+    /// Write the oldest pending block, waiting for it to be compressed if needed.
+    /// If `wait` is false, only write it if it's already done.
+    fn write_oldest_block(&mut self, wait: bool) -> io::Result<bool> {
+        let Some(receiver) = self.pending.front() else {
+            return Ok(false);
+        };
+
+        let result = if wait {
+            receiver.recv().ok()
+        } else {
+            match receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        self.pending.pop_front();
+
+        let block = result.ok_or_else(|| io::Error::other("xz compression thread panicked"))??;
+
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("XzMtWriter used after finishing");
+        inner.write_all(&block.data)?;
+        self.records.push(block.record);
+
+        Ok(true)
+    }
+
+    /// Send the buffered data off to be compressed as a block.
+    fn dispatch_block(&mut self) -> io::Result<()> {
+        let (result, receiver) = mpsc::channel();
+        let job = Job {
+            data: std::mem::take(&mut self.buffer),
+            filters: self.filters.clone(),
+            result,
+        };
+
+        self.jobs
+            .as_ref()
+            .expect("XzMtWriter used after finishing")
+            .send(job)
+            .map_err(|_| io::Error::other("xz compression threads exited"))?;
+        self.pending.push_back(receiver);
+
+        // Write out whatever is already done, so the compressed data doesn't pile up
+        while self.write_oldest_block(false)? {}
+
+        Ok(())
+    }
+
+    fn finish_stream(&mut self) -> io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.dispatch_block()?;
+        }
+        while self.write_oldest_block(true)? {}
+
+        let mut inner = self.inner.take().expect("XzMtWriter used after finishing");
+
+        let index = encode_index(&self.records);
+        inner.write_all(&index)?;
+        inner.write_all(&encode_stream_footer(
+            self.options.check,
+            index.len() as u64,
+        ))?;
+
+        Ok(inner)
+    }
+
+    /// Finish the compressed stream, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()
+    }
+}
+
+impl<W: Write> Write for XzMtWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.buffer.is_empty() {
+            // The block being filled counts towards the limit, so wait for a slot before starting one
+            while self.pending.len() >= self.threads {
+                self.write_oldest_block(true)?;
+            }
+            self.buffer.reserve_exact(self.block_size);
+        }
+
+        let len = buf.len().min(self.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == self.block_size {
+            self.dispatch_block()?;
+        }
+
+        Ok(len)
+    }
+
+    /// Write out all the blocks that were already sent off to be compressed, then flush the inner writer.
+    /// The block currently being filled is kept, as ending it early would make the output depend on
+    /// when the writer was flushed.
+    fn flush(&mut self) -> io::Result<()> {
+        while self.write_oldest_block(true)? {}
+
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for XzMtWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.finish_stream();
+        }
+
+        // Closing the job channel makes the workers exit once they're done
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct DecodeJob {
+    /// The whole block, from the header to the check
+    data: Vec<u8>,
+    check: CheckType,
+    record: IndexRecord,
+    /// Where the block starts in the file and in the decompressed data
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+    result: Sender<io::Result<Vec<u8>>>,
+}
+
+fn decode_block(job: &DecodeJob) -> io::Result<Vec<u8>> {
+    decode_block_data(job).map_err(|err| {
+        let err = offset_position(err, job.compressed_offset, job.uncompressed_offset);
+        with_position(err, job.compressed_offset, job.uncompressed_offset)
+    })
+}
+
+/// Decompress a block that was read into memory, verifying it against the expected sizes.
+fn decode_block_data(job: &DecodeJob) -> io::Result<Vec<u8>> {
+    let mut block = BlockReader::new(job.data[0], Cursor::new(&job.data[1..]), job.check)?;
+
+    let size = job.record.uncompressed_size;
+    let mut output = Vec::with_capacity(size as usize);
+    (&mut block).take(size).read_to_end(&mut output)?;
+
+    // The block has to end exactly at the expected size
+    if output.len() as u64 != size || block.read(&mut [0u8])? != 0 {
+        return Err(XzError::IndexRecordMismatch.into());
+    }
+
+    let (_, record) = block.finish()?;
+    if record != job.record {
+        return Err(XzError::IndexRecordMismatch.into());
+    }
+
+    Ok(output)
+}
+
+fn run_decode_worker(jobs: Arc<Mutex<Receiver<DecodeJob>>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let _ = job.result.send(decode_block(&job));
+    }
+}
+
+struct PendingBlock {
+    result: Receiver<io::Result<Vec<u8>>>,
+    memory: u64,
+}
+
+/// A block header that was read, but whose block hasn't been started yet.
+struct NextBlock {
+    /// Where the block starts in the file
+    offset: u64,
+    header_bytes: Vec<u8>,
+    header: BlockHeader,
+    /// The sizes of the block, if they're known ahead of time
+    record: Option<IndexRecord>,
+    /// How much memory decoding the block on a worker would take
+    memory: u64,
+    /// How much memory decoding the block on the reading thread would take, for its dictionary buffer
+    inline_memory: u64,
+}
+
+/// A block decoded on the reading thread, and its sizes if they're known ahead of time.
+type InlineBlock<R> = (Box<BlockReader<CountingReader<R>>>, Option<IndexRecord>);
+
+/// A reader that decompresses an `.xz` file from the inner reader, decoding multiple blocks in parallel.
+///
+/// The memory limit caps the compressed data, decompressed data and dictionary buffers of the blocks
+/// held by the worker threads. Blocks that don't fit in the limit on their own are decoded on the
+/// reading thread, one chunk at a time, which only needs the dictionary buffer. If even that is over
+/// the limit, reading fails with `ErrorKind::MemoryLimitExceeded`.
+pub struct XzMtReader<R: Read> {
+    /// `None` while a block is being decoded on the reading thread
+    inner: Option<CountingReader<R>>,
+    inline_block: Option<InlineBlock<R>>,
+    /// Where the block decoded on the reading thread starts in the file
+    inline_block_offset: u64,
+    /// The uncompressed size of the blocks before the next one to be started
+    blocks_output_size: u64,
+    /// The number of bytes returned from `read()` so far
+    read_size: u64,
+    next_block: Option<NextBlock>,
+    check: CheckType,
+    /// The blocks read so far in the current stream, to validate the index against
+    records: Vec<IndexRecord>,
+    /// The sizes of the remaining blocks, if they were read from the indexes
+    index_records: Option<VecDeque<IndexRecord>>,
+    input_finished: bool,
+    failed: bool,
+
+    pending: VecDeque<PendingBlock>,
+    memory_used: u64,
+    memory_limit: u64,
+    threads: usize,
+
+    /// The decompressed block currently being returned, and the memory it's accounted for
+    output: Vec<u8>,
+    output_pos: usize,
+    output_memory: u64,
+
+    jobs: Option<Sender<DecodeJob>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<R: Read> XzMtReader<R> {
+    /// Parse the header of the first stream from the reader and start `threads` worker threads.
+    pub fn new(inner: R, threads: usize, memory_limit: u64) -> io::Result<Self> {
+        if threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one thread is required",
+            ));
+        }
+
+        let mut inner = CountingReader::new(inner);
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        let check = inner
+            .read_exact(&mut header)
+            .and_then(|_| parse_stream_header(&header).map_err(io::Error::from))
+            .map_err(|err| with_position(err, 0, 0))?;
+
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || run_decode_worker(receiver))
+            })
+            .collect();
+
+        Ok(Self {
+            inner: Some(inner),
+            inline_block: None,
+            inline_block_offset: 0,
+            blocks_output_size: 0,
+            read_size: 0,
+            next_block: None,
+            check,
+            records: Vec::new(),
+            index_records: None,
+            input_finished: false,
+            failed: false,
+            pending: VecDeque::new(),
+            memory_used: 0,
+            memory_limit,
+            threads,
+            output: Vec::new(),
+            output_pos: 0,
+            output_memory: 0,
+            jobs: Some(jobs),
+            workers,
+        })
+    }
+
+    /// Read the next block header, or the index and footer if the stream ends.
+    fn read_next_block(&mut self) -> io::Result<Option<NextBlock>> {
+        let inner = self.inner.as_mut().expect("No block is decoded inline");
+
+        let offset = inner.count();
+        let size_byte = inner.read_u8()?;
+        if size_byte == INDEX_INDICATOR {
+            read_stream_end(&mut *inner, self.check, &self.records)?;
+            self.records.clear();
+
+            match read_next_stream_header(inner)? {
+                Some(check) => self.check = check,
+                None => self.input_finished = true,
+            }
+            return Ok(None);
+        }
+
+        let mut header_bytes = vec![0u8; (size_byte as usize + 1) * 4];
+        header_bytes[0] = size_byte;
+        inner.read_exact(&mut header_bytes[1..])?;
+        let (header, header_size) = BlockHeader::parse(size_byte, &header_bytes[1..])?;
+
+        let index_record = match self.index_records.as_mut() {
+            Some(records) => Some(records.pop_front().ok_or(XzError::IndexRecordMismatch)?),
+            None => None,
+        };
+
+        let record = match (header.compressed_size, header.uncompressed_size) {
+            (Some(compressed_size), Some(uncompressed_size)) => Some(IndexRecord {
+                unpadded_size: header_size as u64 + compressed_size + self.check.size() as u64,
+                uncompressed_size,
+            }),
+            _ => index_record,
+        };
+
+        let inline_memory = get_lzma2_decoder_mem_usage(block_dict_buffer_size(&header)?);
+        let memory = record.map_or(u64::MAX, |record| {
+            (record.unpadded_size + 3)
+                .saturating_add(record.uncompressed_size)
+                .saturating_add(inline_memory)
+        });
+
+        Ok(Some(NextBlock {
+            offset,
+            header_bytes,
+            header,
+            record,
+            memory,
+            inline_memory,
+        }))
+    }
+
+    /// Read the rest of a block into memory and send it off to be decoded.
+    fn dispatch_block(&mut self, next: NextBlock, record: IndexRecord) -> io::Result<()> {
+        let header_size = next.header_bytes.len();
+        if record.unpadded_size < (header_size + self.check.size()) as u64 {
+            return Err(XzError::IndexRecordMismatch.into());
+        }
+
+        let padded_size = record.unpadded_size + padding_size(record.unpadded_size) as u64;
+        let mut data = next.header_bytes;
+        data.resize(padded_size as usize, 0);
+        self.inner
+            .as_mut()
+            .expect("No block is decoded inline")
+            .read_exact(&mut data[header_size..])?;
+
+        let (result, receiver) = mpsc::channel();
+        let job = DecodeJob {
+            data,
+            check: self.check,
+            record,
+            compressed_offset: next.offset,
+            uncompressed_offset: self.blocks_output_size,
+            result,
+        };
+        self.jobs
+            .as_ref()
+            .expect("Jobs are only closed when dropping")
+            .send(job)
+            .map_err(|_| io::Error::other("xz decompression threads exited"))?;
+
+        self.pending.push_back(PendingBlock {
+            result: receiver,
+            memory: next.memory,
+        });
+        self.memory_used += next.memory;
+        self.records.push(record);
+        self.blocks_output_size += record.uncompressed_size;
+        Ok(())
+    }
+
+    /// Start decoding blocks until all the threads are busy, the memory limit is reached,
+    /// or a block has to be decoded on the reading thread.
+    fn fill_pipeline(&mut self) -> io::Result<()> {
+        while self.inline_block.is_none()
+            && !self.input_finished
+            && self.pending.len() < self.threads
+        {
+            let next = match self.next_block.take() {
+                Some(next) => next,
+                None => match self.read_next_block()? {
+                    Some(next) => next,
+                    None => continue,
+                },
+            };
+
+            match next.record {
+                Some(record) if next.memory <= self.memory_limit => {
+                    // Wait for some memory to be freed up, unless nothing else is using any
+                    if self.memory_used > 0 && self.memory_used + next.memory > self.memory_limit {
+                        self.next_block = Some(next);
+                        break;
+                    }
+                    self.dispatch_block(next, record)?;
+                }
+                _ => {
+                    // The dictionary size comes from the untrusted header, so it's checked too
+                    let limits = DecoderLimits {
+                        memory_limit: Some(self.memory_limit),
+                        max_output_size: None,
+                    };
+                    limits.check_mem_usage(next.inline_memory)?;
+
+                    // Only read from once the blocks before it are done
+                    let inner = self.inner.take().expect("No block is decoded inline");
+                    let header_size = next.header_bytes.len();
+                    let block =
+                        BlockReader::with_header(next.header, header_size, inner, self.check)
+                            .map_err(|err| {
+                                offset_position(err, next.offset, self.blocks_output_size)
+                            })?;
+                    self.inline_block = Some((Box::new(block), next.record));
+                    self.inline_block_offset = next.offset;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish_inline_block(&mut self) -> io::Result<()> {
+        let (block, expected_record) = self.inline_block.take().expect("Inline block exists");
+        let (inner, record) = block.finish().map_err(|err| {
+            offset_position(err, self.inline_block_offset, self.blocks_output_size)
+        })?;
+        if expected_record.is_some_and(|expected| expected != record) {
+            return Err(XzError::IndexRecordMismatch.into());
+        }
+
+        self.records.push(record);
+        self.blocks_output_size += record.uncompressed_size;
+        self.inner = Some(inner);
+        Ok(())
+    }
+
+    /// How far into the file has been read
+    fn compressed_pos(&self) -> u64 {
+        match (&self.inner, &self.inline_block) {
+            (Some(inner), _) => inner.count(),
+            (None, Some((block, _))) => block.get_ref().count(),
+            (None, None) => 0,
+        }
+    }
+
+    fn read_next(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.output_pos < self.output.len() {
+                let len = buf.len().min(self.output.len() - self.output_pos);
+                buf[..len].copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+                self.output_pos += len;
+                return Ok(len);
+            }
+
+            self.output = Vec::new();
+            self.output_pos = 0;
+            self.memory_used -= self.output_memory;
+            self.output_memory = 0;
+
+            self.fill_pipeline()?;
+
+            if let Some(pending) = self.pending.pop_front() {
+                self.output = pending
+                    .result
+                    .recv()
+                    .map_err(|_| io::Error::other("xz decompression thread panicked"))??;
+                self.output_memory = pending.memory;
+                continue;
+            }
+
+            if let Some((block, _)) = self.inline_block.as_mut() {
+                let read = block.read(buf).map_err(|err| {
+                    offset_position(err, self.inline_block_offset, self.blocks_output_size)
+                })?;
+                if read > 0 {
+                    return Ok(read);
+                }
+                self.finish_inline_block()?;
+                continue;
+            }
+
+            if self.input_finished {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> XzMtReader<R> {
+    /// Like `new()`, but first reads the indexes from the end of the file, so that blocks can be
+    /// decoded in parallel even if their headers don't contain their sizes.
+    pub fn new_with_index(mut inner: R, threads: usize, memory_limit: u64) -> io::Result<Self> {
+        let records = read_all_index_records(&mut inner)?;
+        inner.seek(SeekFrom::Start(0))?;
+
+        let mut reader = Self::new(inner, threads, memory_limit)?;
+        reader.index_records = Some(records.into());
+        Ok(reader)
+    }
+}
+
+impl<R: Read> Read for XzMtReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.failed {
+            return Err(io::Error::other("XzMtReader used after a failed read"));
+        }
+
+        match self.read_next(buf) {
+            Ok(read) => {
+                self.read_size += read as u64;
+                Ok(read)
+            }
+            Err(err) => {
+                self.failed = true;
+                Err(with_position(err, self.compressed_pos(), self.read_size))
+            }
+        }
+    }
+}
+
+impl<R: Read> Drop for XzMtReader<R> {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::xz_streams::{XzReader, XzWriter},
+        error::{Error, ErrorKind},
+        test_utils::{test_data, PROPS},
+    };
+
+    fn compress(data: &[u8], block_size: usize, threads: usize, chunk_size: usize) -> Vec<u8> {
+        let mut writer = XzMtWriter::new(
+            Vec::new(),
+            PROPS,
+            0x10000,
+            CheckType::Crc64,
+            block_size,
+            threads,
+        )
+        .unwrap();
+        for chunk in data.chunks(chunk_size) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut reader = XzReader::new(Cursor::new(compressed)).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = test_data(6000).repeat(3);
+
+        for block_size in [4096, 50_000, data.len(), data.len() * 2] {
+            let compressed = compress(&data, block_size, 3, 10_000);
+            assert_eq!(decompress(&compressed), data);
+            assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+        }
+
+        let compressed = compress(&[], 4096, 3, 1);
+        assert_eq!(decompress(&compressed), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_output_independent_of_threads() {
+        let data = test_data(6000).repeat(3);
+        let expected = compress(&data, 20_000, 1, data.len());
+
+        for threads in [2, 4, 7] {
+            for chunk_size in [1000, 20_000, 65_536] {
+                assert_eq!(compress(&data, 20_000, threads, chunk_size), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flush_keeps_output_identical() {
+        let data = test_data(6000);
+        let expected = compress(&data, 10_000, 2, data.len());
+
+        let mut writer =
+            XzMtWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc64, 10_000, 2).unwrap();
+        for chunk in data.chunks(7000) {
+            writer.write_all(chunk).unwrap();
+            writer.flush().unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_bcj_filter() {
+        use crate::compressors::filters::bcj::BcjKind;
+
+        let mut data = Vec::new();
+        for i in 0..20_000i32 {
+            data.push(0xE8);
+            data.extend_from_slice(&(0x1000 * (i % 5) - data.len() as i32 - 4).to_le_bytes());
+        }
+
+        let mut writer =
+            XzMtWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc64, 30_000, 3).unwrap();
+        writer
+            .set_filters(vec![FilterFlags::bcj(BcjKind::X86, 0)])
+            .unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        assert!(compressed.len() < compress(&data, 30_000, 3, data.len()).len() / 2);
+        assert_eq!(decompress(&compressed), data);
+        assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+        assert_eq!(decompress_mt(&compressed, 3, u64::MAX).unwrap(), data);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        for (block_size, threads) in [(0, 1), (4096, 0)] {
+            let result = XzMtWriter::new(
+                Vec::new(),
+                PROPS,
+                0x10000,
+                CheckType::Crc64,
+                block_size,
+                threads,
+            );
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    fn decompress_mt(compressed: &[u8], threads: usize, memory_limit: u64) -> io::Result<Vec<u8>> {
+        let mut reader = XzMtReader::new(Cursor::new(compressed), threads, memory_limit)?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    fn compress_single_threaded(data: &[u8], block_size: Option<u64>) -> Vec<u8> {
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc32).unwrap();
+        writer.set_block_size(block_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_parallel_decompression() {
+        let data = test_data(6000).repeat(3);
+        let compressed = compress(&data, 10_000, 2, data.len());
+
+        // Small limits make blocks wait for each other, or get decoded on the reading thread.
+        // Each block's dictionary buffer is only as big as the block.
+        let inline_memory = get_lzma2_decoder_mem_usage(10_000);
+        for threads in [1, 3, 8] {
+            for memory_limit in [
+                inline_memory,
+                inline_memory + 30_000,
+                inline_memory + 100_000,
+                u64::MAX,
+            ] {
+                assert_eq!(
+                    decompress_mt(&compressed, threads, memory_limit).unwrap(),
+                    data
+                );
+            }
+        }
+
+        let compressed = compress(&[], 4096, 1, 1);
+        assert_eq!(
+            decompress_mt(&compressed, 2, u64::MAX).unwrap(),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn test_parallel_decompression_without_header_sizes() {
+        let data = test_data(6000);
+
+        // Without the index, blocks without sizes in their headers are decoded on the reading thread
+        let mut compressed = compress_single_threaded(&data, Some(5000));
+        compressed.extend(compress_single_threaded(b"second stream", None));
+        compressed.extend(lzma::compress(&data, 6).unwrap());
+
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"second stream");
+        expected.extend_from_slice(&data);
+        assert_eq!(decompress_mt(&compressed, 3, u64::MAX).unwrap(), expected);
+
+        // The last stream's block has an 8 MiB dictionary, and no sizes in its header
+        let inline_memory = get_lzma2_decoder_mem_usage(1 << 23);
+        for memory_limit in [inline_memory, inline_memory + 20_000, u64::MAX] {
+            let mut reader =
+                XzMtReader::new_with_index(Cursor::new(&compressed), 3, memory_limit).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_parallel_decompression_errors() {
+        let data = test_data(6000);
+        let compressed = compress(&data, 10_000, 2, data.len());
+
+        // Corrupt the check of the first block, which has a 12 byte stream header before it
+        let mut file = compressed.clone();
+        let header_size = (file[12] as usize + 1) * 4;
+        let compressed_size = file[14] as usize | (file[15] as usize & 0x7F) << 7;
+        let check_pos = 12 + header_size + compressed_size + padding_size(compressed_size as u64);
+        file[check_pos] ^= 1;
+
+        let err = Error::from(decompress_mt(&file, 3, u64::MAX).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        // The check comes after the block's compressed data and padding
+        let padding_start = 12 + header_size + compressed_size;
+        assert_eq!(err.compressed_offset(), Some(padding_start as u64));
+        assert_eq!(err.uncompressed_pos(), Some(10_000));
+
+        // Truncated files
+        for len in [20, 100, compressed.len() - 1] {
+            let err = decompress_mt(&compressed[..len], 3, u64::MAX).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_memory_limit_inline_blocks() {
+        use crate::compressors::lzma::codecs::xz_codec::crc32::crc32;
+
+        let data = test_data(6000);
+        let compressed = compress_single_threaded(&data, None);
+
+        // The block can't be decoded on the reading thread with less memory than its dictionary
+        let inline_memory = get_lzma2_decoder_mem_usage(0x10000);
+        let err = Error::from(decompress_mt(&compressed, 3, inline_memory - 1).unwrap_err());
+        assert!(matches!(err.kind(), ErrorKind::MemoryLimitExceeded { .. }));
+        assert_eq!(decompress_mt(&compressed, 3, inline_memory).unwrap(), data);
+
+        // Ask for a 1.5 GiB dictionary in the block header, which has no sizes in it
+        let mut file = compressed.clone();
+        let header_size = (file[12] as usize + 1) * 4;
+        let header = &mut file[12..12 + header_size];
+        let lzma2_pos = header.windows(2).position(|w| w == [0x21, 0x01]).unwrap();
+        header[lzma2_pos + 2] = 37;
+        let crc = crc32(&header[..header_size - 4]);
+        header[header_size - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        let err = Error::from(decompress_mt(&file, 3, 1 << 30).unwrap_err());
+        assert_eq!(
+            err.kind(),
+            ErrorKind::MemoryLimitExceeded {
+                required: get_lzma2_decoder_mem_usage(3 << 29),
+                limit: 1 << 30,
+            }
+        );
+        assert_eq!(err.compressed_offset(), Some(12 + header_size as u64));
+    }
+}
@@ -0,0 +1,561 @@
+//! # Push based decoding for `.xz` files
+//!
+//! Like `LzmaPushDecoder`, `XzPushDecoder` takes the compressed data in slices of any size as it
+//! arrives, instead of pulling it from a blocking reader.
+//!
+//! Stream headers, block headers, indexes and footers are parsed once all of their bytes are there.
+//! Blocks are decompressed by the same block reader as `XzReader`, reading from a queue of the input
+//! that only hands out whole LZMA2 chunks. When the queue runs dry, the block reader is always
+//! between two chunks, so it can continue where it stopped once more input arrives.
+
+use std::{
+    io::{self, Read},
+    mem,
+};
+
+use super::{
+    codecs::{
+        lzma2_codec::chunk_size,
+        xz_codec::{
+            block_header::BlockHeader,
+            checks::CheckType,
+            index::{IndexRecord, INDEX_INDICATOR},
+            padding_size, parse_stream_header, XzError, HEADER_MAGIC, STREAM_HEADER_SIZE,
+        },
+    },
+    limits::{get_lzma2_decoder_mem_usage, DecoderLimits},
+    push_decoder::{DecodeProgress, DecodeStatus},
+    xz_streams::{block_dict_buffer_size, read_stream_end, BlockReader},
+};
+use crate::error::{offset_position, with_position};
+
+/// How much of the input is added to the queue at once, when the queued input isn't enough to continue
+const INPUT_STEP: usize = 1 << 16;
+
+/// The input that arrived but wasn't used yet.
+#[derive(Default)]
+struct InputQueue {
+    data: Vec<u8>,
+    /// Where the unused data starts
+    pos: usize,
+    /// The end of the data that the block reader may read
+    readable: usize,
+    /// Where `data` starts in the file
+    start: u64,
+}
+
+impl InputQueue {
+    fn available(&self) -> &[u8] {
+        &self.data[self.pos..]
+    }
+
+    /// The position of the unused data in the file.
+    fn position(&self) -> u64 {
+        self.start + self.pos as u64
+    }
+
+    fn push(&mut self, input: &[u8]) {
+        // Drop the used data, so the queue only grows as big as the input that's needed at once
+        self.data.drain(..self.pos);
+        self.start += self.pos as u64;
+        self.readable = self.readable.saturating_sub(self.pos);
+        self.pos = 0;
+
+        self.data.extend_from_slice(input);
+    }
+}
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let readable = &self.data[self.pos..self.readable.max(self.pos)];
+        if readable.is_empty() && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = buf.len().min(readable.len());
+        buf[..len].copy_from_slice(&readable[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+struct ActiveBlock {
+    reader: BlockReader<InputQueue>,
+    /// Where the LZMA2 data starts in the file
+    lzma2_start: u64,
+    check_size: usize,
+    /// Whether the end of the LZMA2 data was handed out
+    lzma2_ended: bool,
+}
+
+impl ActiveBlock {
+    /// Let the block reader read the chunks that arrived in full. The end of the LZMA2 data is only
+    /// handed out along with the block padding and check, so the block can be finished right away.
+    fn expose_chunks(&mut self) {
+        let input = self.reader.get_mut();
+
+        while !self.lzma2_ended {
+            let rest = &input.data[input.readable..];
+            let Some(mut size) = chunk_size(rest) else {
+                break;
+            };
+
+            // The end of the LZMA2 data, or an invalid control byte that the block reader reports
+            let end = size == 1;
+            if end {
+                let lzma2_size = input.start + input.readable as u64 + 1 - self.lzma2_start;
+                size += padding_size(lzma2_size) + self.check_size;
+            }
+            if rest.len() < size {
+                break;
+            }
+
+            self.lzma2_ended = end;
+            input.readable += size;
+        }
+    }
+}
+
+enum DecoderState {
+    /// The next thing in the file is a stream header
+    StreamHeader,
+    /// The next thing in the stream is either a block header or the index
+    BetweenBlocks,
+    Block(Box<ActiveBlock>),
+    /// After the footer of a stream, where the file may end
+    StreamPadding,
+    /// A previous step failed, so the position in the file is unknown
+    Failed,
+}
+
+/// What a single step of decoding did.
+enum Step {
+    /// Decoding moved on, writing this many bytes to the output
+    Wrote(usize),
+    NeedsInput,
+}
+
+/// A decoder for `.xz` files that is fed the compressed data, instead of reading it.
+///
+/// Call `decode()` with each slice of input as it arrives. More streams can follow any stream, so
+/// the decoder can't tell by itself that the file ended, and never returns `DecodeStatus::Finished`.
+/// Once there is no more input, `is_finished()` tells whether the file is complete.
+pub struct XzPushDecoder {
+    state: DecoderState,
+    /// The input, while it isn't being read by a block reader
+    input: InputQueue,
+    check: CheckType,
+    /// The blocks read so far in the current stream, to validate the index against
+    records: Vec<IndexRecord>,
+    limits: DecoderLimits,
+    /// The number of bytes decompressed so far, from all the streams
+    output_size: u64,
+    /// Where the current block, or the index and the headers after it, start in the file
+    structure_start: u64,
+}
+
+impl XzPushDecoder {
+    pub fn new() -> Self {
+        Self::new_with_limits(DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The memory limit applies to each block separately.
+    pub fn new_with_limits(limits: DecoderLimits) -> Self {
+        Self {
+            state: DecoderState::StreamHeader,
+            input: InputQueue::default(),
+            check: CheckType::None,
+            records: Vec::new(),
+            limits,
+            output_size: 0,
+            structure_start: 0,
+        }
+    }
+
+    /// The check type of the stream currently being read.
+    pub fn check_type(&self) -> CheckType {
+        self.check
+    }
+
+    /// Check if the input so far is a complete file, i.e. it ends after a stream or its padding.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, DecoderState::StreamPadding) && self.input.available().is_empty()
+    }
+
+    /// Decode as much as possible from `input` into `output`. The input that wasn't consumed
+    /// must be passed again in the next call, followed by any new input.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<DecodeProgress> {
+        let mut consumed = 0;
+        let mut written = 0;
+
+        loop {
+            if written == output.len() {
+                return Ok(DecodeProgress {
+                    consumed,
+                    written,
+                    status: DecodeStatus::OutputFull,
+                });
+            }
+
+            let state = mem::replace(&mut self.state, DecoderState::Failed);
+            let (state, step) = self.step(state, &mut output[written..])?;
+            self.state = state;
+
+            match step {
+                Step::Wrote(len) => written += len,
+                Step::NeedsInput if consumed < input.len() => {
+                    let added = (input.len() - consumed).min(INPUT_STEP);
+                    self.input_mut().push(&input[consumed..consumed + added]);
+                    consumed += added;
+                }
+                Step::NeedsInput => {
+                    return Ok(DecodeProgress {
+                        consumed,
+                        written,
+                        status: DecodeStatus::NeedsInput,
+                    })
+                }
+            }
+        }
+    }
+
+    fn input_mut(&mut self) -> &mut InputQueue {
+        match &mut self.state {
+            DecoderState::Block(block) => block.reader.get_mut(),
+            _ => &mut self.input,
+        }
+    }
+
+    fn step(&mut self, state: DecoderState, output: &mut [u8]) -> io::Result<(DecoderState, Step)> {
+        match state {
+            DecoderState::StreamHeader => {
+                let position = self.input.position();
+                let step = self
+                    .start_stream()
+                    .map_err(|err| with_position(err, position, self.output_size))?;
+                Ok(match step {
+                    Some(state) => (state, Step::Wrote(0)),
+                    None => (DecoderState::StreamHeader, Step::NeedsInput),
+                })
+            }
+            DecoderState::BetweenBlocks => {
+                self.structure_start = self.input.position();
+                let step = self
+                    .start_block_or_index()
+                    .map_err(|err| with_position(err, self.structure_start, self.output_size))?;
+                Ok(match step {
+                    Some(state) => (state, Step::Wrote(0)),
+                    None => (DecoderState::BetweenBlocks, Step::NeedsInput),
+                })
+            }
+            DecoderState::Block(block) => self.read_block(block, output),
+            DecoderState::StreamPadding => {
+                let available = self.input.available();
+                if available.starts_with(&[0; 4]) {
+                    self.input.pos += 4;
+                    Ok((DecoderState::StreamPadding, Step::Wrote(0)))
+                } else if available.iter().all(|&b| b == 0) {
+                    Ok((DecoderState::StreamPadding, Step::NeedsInput))
+                } else {
+                    Ok((DecoderState::StreamHeader, Step::Wrote(0)))
+                }
+            }
+            DecoderState::Failed => Err(io::Error::other("XzPushDecoder used after an error")),
+        }
+    }
+
+    /// Parse a stream header. Returns `None` if it didn't arrive in full yet.
+    fn start_stream(&mut self) -> io::Result<Option<DecoderState>> {
+        let available = self.input.available();
+        let Some(header) = available.get(..STREAM_HEADER_SIZE) else {
+            let len = available.len().min(HEADER_MAGIC.len());
+            if available[..len] != HEADER_MAGIC[..len] {
+                return Err(XzError::InvalidHeaderMagic.into());
+            }
+            return Ok(None);
+        };
+
+        self.check = parse_stream_header(header.try_into().unwrap())?;
+        self.input.pos += STREAM_HEADER_SIZE;
+        Ok(Some(DecoderState::BetweenBlocks))
+    }
+
+    /// Parse a block header, or the index and footer. Returns `None` if they didn't arrive in full yet.
+    fn start_block_or_index(&mut self) -> io::Result<Option<DecoderState>> {
+        let available = self.input.available();
+        let Some(&size_byte) = available.first() else {
+            return Ok(None);
+        };
+
+        if size_byte == INDEX_INDICATOR {
+            // The index is parsed again from the start once more input arrives
+            let mut rest = &available[1..];
+            return match read_stream_end(&mut rest, self.check, &self.records) {
+                Ok(()) => {
+                    self.input.pos += available.len() - rest.len();
+                    self.records.clear();
+                    Ok(Some(DecoderState::StreamPadding))
+                }
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                Err(err) => Err(err),
+            };
+        }
+
+        let Some(header) = available.get(1..(size_byte as usize + 1) * 4) else {
+            return Ok(None);
+        };
+        let (header, header_size) = BlockHeader::parse(size_byte, header)?;
+        let dict_size = block_dict_buffer_size(&header)?;
+        self.limits
+            .check_mem_usage(get_lzma2_decoder_mem_usage(dict_size))?;
+
+        let mut input = mem::take(&mut self.input);
+        input.pos += header_size;
+        input.readable = input.pos;
+        let lzma2_start = input.position();
+
+        let reader = BlockReader::with_header(header, header_size, input, self.check)?;
+        Ok(Some(DecoderState::Block(Box::new(ActiveBlock {
+            reader,
+            lzma2_start,
+            check_size: self.check.size(),
+            lzma2_ended: false,
+        }))))
+    }
+
+    fn read_block(
+        &mut self,
+        mut block: Box<ActiveBlock>,
+        output: &mut [u8],
+    ) -> io::Result<(DecoderState, Step)> {
+        let block_output_start = self.output_size - block.reader.uncompressed_size();
+        block.expose_chunks();
+
+        match block.reader.read(output) {
+            Ok(0) => {
+                let (input, record) = block.reader.finish().map_err(|err| {
+                    offset_position(err, self.structure_start, block_output_start)
+                })?;
+                self.input = input;
+                self.records.push(record);
+                Ok((DecoderState::BetweenBlocks, Step::Wrote(0)))
+            }
+            Ok(read) => {
+                self.output_size += read as u64;
+                if let Err(err) = self.limits.check_output_size(self.output_size) {
+                    let offset = block.reader.get_ref().position();
+                    return Err(with_position(err.into(), offset, self.output_size));
+                }
+                Ok((DecoderState::Block(block), Step::Wrote(read)))
+            }
+            // The block reader is between two chunks, and continues once more input arrives
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Ok((DecoderState::Block(block), Step::NeedsInput))
+            }
+            Err(err) => Err(offset_position(
+                err,
+                self.structure_start,
+                block_output_start,
+            )),
+        }
+    }
+}
+
+impl Default for XzPushDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::{codecs::xz_codec::block_header::FilterFlags, xz_streams::XzWriter},
+        error::{Error, ErrorKind},
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::Write;
+
+    fn compress(data: &[u8], check: CheckType, block_size: Option<u64>) -> Vec<u8> {
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, check).unwrap();
+        writer.set_block_size(block_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Feed the input in fragments of `input_len` bytes, reading into `output_len` byte slices.
+    /// Returns the output once all the input was used.
+    fn decode_in_fragments(
+        decoder: &mut XzPushDecoder,
+        input: &[u8],
+        input_len: usize,
+        output_len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut out_buf = vec![0; output_len];
+        let mut pos = 0;
+
+        loop {
+            let end = (pos + input_len).min(input.len());
+            let progress = decoder.decode(&input[pos..end], &mut out_buf)?;
+            pos += progress.consumed;
+            output.extend_from_slice(&out_buf[..progress.written]);
+
+            match progress.status {
+                DecodeStatus::NeedsInput if end == input.len() => return Ok(output),
+                DecodeStatus::NeedsInput => assert_eq!(pos, end),
+                DecodeStatus::OutputFull => assert_eq!(progress.written, output_len),
+                DecodeStatus::Finished => unreachable!(),
+            }
+        }
+    }
+
+    fn decode_err(input: &[u8]) -> ErrorKind {
+        let err = decode_in_fragments(&mut XzPushDecoder::new(), input, 100, 1000).unwrap_err();
+        Error::from(err).kind()
+    }
+
+    #[test]
+    fn test_decode_in_fragments() {
+        let data = test_data(30_000);
+
+        for (check, block_size) in [(CheckType::Crc64, None), (CheckType::Sha256, Some(30_000))] {
+            let compressed = compress(&data, check, block_size);
+
+            for (input_len, output_len) in [(1, 1 << 16), (7, 100), (100, 7), (1 << 20, 4096)] {
+                let mut decoder = XzPushDecoder::new();
+                let output =
+                    decode_in_fragments(&mut decoder, &compressed, input_len, output_len).unwrap();
+
+                assert_eq!(output, data);
+                assert!(decoder.is_finished());
+                assert_eq!(decoder.check_type(), check);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concatenated_streams_and_filters() {
+        let first = test_data(30_000);
+        let mut second = Vec::new();
+        for i in 0..20_000u16 {
+            second.extend_from_slice(&i.wrapping_mul(300).to_le_bytes());
+        }
+
+        let mut compressed = compress(&first, CheckType::Crc32, Some(10_000));
+        compressed.extend_from_slice(&[0; 8]);
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 1 << 16, CheckType::None).unwrap();
+        writer.set_filters(vec![FilterFlags::delta(2)]).unwrap();
+        writer.write_all(&second).unwrap();
+        compressed.extend(writer.finish().unwrap());
+        compressed.extend_from_slice(&[0; 4]);
+
+        let mut expected = first;
+        expected.extend_from_slice(&second);
+
+        for input_len in [3, 1000] {
+            let mut decoder = XzPushDecoder::new();
+            let output = decode_in_fragments(&mut decoder, &compressed, input_len, 777).unwrap();
+            assert_eq!(output, expected);
+            assert!(decoder.is_finished());
+        }
+
+        // Padding that isn't a multiple of 4 bytes is incomplete
+        compressed.extend_from_slice(&[0; 3]);
+        let mut decoder = XzPushDecoder::new();
+        decode_in_fragments(&mut decoder, &compressed, 1000, 1000).unwrap();
+        assert!(!decoder.is_finished());
+
+        // Anything else after a stream is an error
+        compressed.extend_from_slice(b"garbage");
+        assert_eq!(
+            decode_err(&compressed),
+            ErrorKind::Xz(XzError::InvalidHeaderMagic)
+        );
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let data = test_data(30_000);
+        let compressed = compress(&data, CheckType::Crc64, Some(30_000));
+
+        for len in [
+            0,
+            5,
+            12,
+            13,
+            40,
+            compressed.len() / 2,
+            compressed.len() - 13,
+        ] {
+            let mut decoder = XzPushDecoder::new();
+            decode_in_fragments(&mut decoder, &compressed[..len], 10, 1000).unwrap();
+            assert!(!decoder.is_finished());
+        }
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let data = test_data(30_000);
+        let compressed = compress(&data, CheckType::Crc64, None);
+
+        assert_eq!(
+            decode_err(b"not an xz file"),
+            ErrorKind::Xz(XzError::InvalidHeaderMagic)
+        );
+
+        let mut corrupt = compressed.clone();
+        let check_pos = compressed.len() - 12 - 12 - 8;
+        corrupt[check_pos] ^= 1;
+        assert_eq!(decode_err(&corrupt), ErrorKind::ChecksumMismatch);
+
+        // Decoding doesn't continue after an error
+        let mut decoder = XzPushDecoder::new();
+        assert!(decoder
+            .decode(&corrupt, &mut vec![0; data.len() + 1])
+            .is_err());
+        assert!(decoder.decode(&[], &mut [0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = test_data(30_000);
+        let compressed = compress(&data, CheckType::Crc32, None);
+
+        let limits = DecoderLimits {
+            memory_limit: Some(1 << 16),
+            max_output_size: None,
+        };
+        let err = decode_in_fragments(
+            &mut XzPushDecoder::new_with_limits(limits),
+            &compressed,
+            100,
+            1000,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            Error::from(err).kind(),
+            ErrorKind::MemoryLimitExceeded { .. }
+        ));
+
+        let limits = DecoderLimits {
+            memory_limit: None,
+            max_output_size: Some(data.len() as u64 - 1),
+        };
+        let err = decode_in_fragments(
+            &mut XzPushDecoder::new_with_limits(limits),
+            &compressed,
+            100,
+            1000,
+        )
+        .unwrap_err();
+        assert_eq!(
+            Error::from(err).kind(),
+            ErrorKind::OutputLimitExceeded {
+                limit: data.len() as u64 - 1
+            }
+        );
+    }
+}
@@ -0,0 +1,463 @@
+//! # Random access into `.xz` files
+//!
+//! Every stream ends with an index listing the size of each of its blocks, and the footer after it
+//! stores the size of the index. Reading the streams backwards from the end of the file gives the
+//! position of every block without decompressing anything.
+//!
+//! Blocks are independent, so seeking only requires decompressing the block containing the target
+//! position, from the start of that block. Files with a single block (e.g. from a single threaded
+//! `xz`) can still be read, but every backwards seek restarts decompression from the beginning.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::ReadBytesExt;
+
+use super::{
+    codecs::xz_codec::{
+        block_header::BlockHeader,
+        checks::CheckType,
+        index::{parse_index, IndexRecord, INDEX_INDICATOR},
+        padding_size, parse_stream_footer, parse_stream_header, XzError, STREAM_FOOTER_SIZE,
+        STREAM_HEADER_SIZE,
+    },
+    limits::{get_lzma2_decoder_mem_usage, DecoderLimits},
+    xz_streams::{block_dict_buffer_size, BlockReader},
+};
+use crate::error::{offset_position, with_position};
+
+/// The location of a block in the file.
+#[derive(Debug, Clone)]
+struct BlockInfo {
+    /// The offset of the block header in the compressed file
+    compressed_offset: u64,
+    /// The offset of the block's data in the uncompressed data
+    uncompressed_offset: u64,
+    record: IndexRecord,
+    check: CheckType,
+}
+
+impl BlockInfo {
+    fn uncompressed_end(&self) -> u64 {
+        self.uncompressed_offset + self.record.uncompressed_size
+    }
+}
+
+/// Read the indexes of all the streams in the file, starting from the end.
+fn parse_blocks<R: Read + Seek>(inner: &mut R) -> io::Result<Vec<BlockInfo>> {
+    let mut end = inner.seek(SeekFrom::End(0))?;
+    if end % 4 != 0 {
+        return Err(XzError::InvalidStreamPadding.into());
+    }
+
+    // Blocks of each stream, from the last stream to the first
+    let mut streams = Vec::new();
+
+    while end > 0 {
+        let mut word = [0u8; 4];
+        inner.seek(SeekFrom::Start(end - 4))?;
+        inner.read_exact(&mut word)?;
+        if word == [0; 4] {
+            end -= 4;
+            // Stream padding is only allowed between or after streams
+            if end == 0 {
+                return Err(XzError::InvalidStreamPadding.into());
+            }
+            continue;
+        }
+
+        if end < (STREAM_HEADER_SIZE + STREAM_FOOTER_SIZE) as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut footer = [0u8; STREAM_FOOTER_SIZE];
+        inner.seek(SeekFrom::Start(end - STREAM_FOOTER_SIZE as u64))?;
+        inner.read_exact(&mut footer)?;
+        let footer = parse_stream_footer(&footer)?;
+
+        let index_start = (end - STREAM_FOOTER_SIZE as u64)
+            .checked_sub(footer.index_size)
+            .ok_or(XzError::BackwardSizeMismatch)?;
+        inner.seek(SeekFrom::Start(index_start))?;
+        if inner.read_u8()? != INDEX_INDICATOR {
+            return Err(XzError::BackwardSizeMismatch.into());
+        }
+        let (records, index_size) = parse_index(&mut *inner)?;
+
+        let blocks_size = records
+            .iter()
+            .try_fold(0u64, |size, record| {
+                let padded = record.unpadded_size + padding_size(record.unpadded_size) as u64;
+                size.checked_add(padded)
+            })
+            .ok_or(XzError::InvalidIndex)?;
+        let stream_start = index_start
+            .checked_sub(blocks_size)
+            .and_then(|start| start.checked_sub(STREAM_HEADER_SIZE as u64))
+            .ok_or(XzError::InvalidIndex)?;
+
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        inner.seek(SeekFrom::Start(stream_start))?;
+        inner.read_exact(&mut header)?;
+        let check = parse_stream_header(&header)?;
+        footer.validate(check, index_size)?;
+
+        let mut offset = stream_start + STREAM_HEADER_SIZE as u64;
+        let blocks: Vec<_> = records
+            .into_iter()
+            .map(|record| {
+                let block = BlockInfo {
+                    compressed_offset: offset,
+                    uncompressed_offset: 0,
+                    record,
+                    check,
+                };
+                offset += record.unpadded_size + padding_size(record.unpadded_size) as u64;
+                block
+            })
+            .collect();
+        streams.push(blocks);
+
+        end = stream_start;
+    }
+
+    let mut uncompressed_offset = 0u64;
+    let mut blocks: Vec<_> = streams.into_iter().rev().flatten().collect();
+    for block in &mut blocks {
+        block.uncompressed_offset = uncompressed_offset;
+        uncompressed_offset = uncompressed_offset
+            .checked_add(block.record.uncompressed_size)
+            .ok_or(XzError::InvalidIndex)?;
+    }
+
+    Ok(blocks)
+}
+
+/// Read the index records of every block in the file, in order, without decompressing anything.
+pub(crate) fn read_all_index_records<R: Read + Seek>(
+    inner: &mut R,
+) -> io::Result<Vec<IndexRecord>> {
+    Ok(parse_blocks(inner)?
+        .into_iter()
+        .map(|block| block.record)
+        .collect())
+}
+
+enum SeekState<R: Read> {
+    Idle(R),
+    /// Decompressing the block at the index
+    Block(usize, Box<BlockReader<R>>),
+    /// A previous read failed, so the position in the file is unknown
+    Failed,
+}
+
+/// A reader that decompresses an `.xz` file and supports seeking within the uncompressed data.
+///
+/// The index of every stream is read when the reader is created, which requires seeking around
+/// the file. Blocks are still fully verified when they're read up to the end.
+pub struct XzSeekReader<R: Read + Seek> {
+    blocks: Vec<BlockInfo>,
+    state: SeekState<R>,
+    limits: DecoderLimits,
+    pos: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> XzSeekReader<R> {
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::new_with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The memory limit applies to each block separately, and the output
+    /// limit to the total uncompressed size stored in the indexes.
+    pub fn new_with_limits(mut inner: R, limits: DecoderLimits) -> io::Result<Self> {
+        let blocks = parse_blocks(&mut inner)?;
+        let len = blocks.last().map_or(0, |block| block.uncompressed_end());
+        limits.check_output_size(len)?;
+
+        Ok(Self {
+            blocks,
+            state: SeekState::Idle(inner),
+            limits,
+            pos: 0,
+            len,
+        })
+    }
+
+    /// The total size of the uncompressed data.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.len
+    }
+
+    /// The number of blocks in the file, across all streams.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Make sure the block at `index` is being decompressed and hasn't gone past `pos` yet,
+    /// restarting it from its beginning if needed.
+    fn prepare_block(&mut self, index: usize) -> io::Result<()> {
+        let pos_in_block = self.pos - self.blocks[index].uncompressed_offset;
+
+        let inner = match std::mem::replace(&mut self.state, SeekState::Failed) {
+            SeekState::Block(current, block)
+                if current == index && block.uncompressed_size() <= pos_in_block =>
+            {
+                self.state = SeekState::Block(current, block);
+                return Ok(());
+            }
+            SeekState::Block(_, block) => block.into_inner(),
+            SeekState::Idle(inner) => inner,
+            SeekState::Failed => {
+                return Err(io::Error::other("XzSeekReader used after a failed read"))
+            }
+        };
+
+        let mut inner = inner;
+        let info = &self.blocks[index];
+        inner.seek(SeekFrom::Start(info.compressed_offset))?;
+        let size_byte = inner.read_u8()?;
+        if size_byte == INDEX_INDICATOR {
+            return Err(XzError::IndexRecordMismatch.into());
+        }
+
+        let (header, header_size) = BlockHeader::parse(size_byte, &mut inner)?;
+        let dict_size = block_dict_buffer_size(&header)?;
+        self.limits
+            .check_mem_usage(get_lzma2_decoder_mem_usage(dict_size))?;
+
+        let block = BlockReader::with_header(header, header_size, inner, info.check)?;
+        self.state = SeekState::Block(index, Box::new(block));
+        Ok(())
+    }
+
+    /// Read from the block at `index`, discarding everything before the current position.
+    fn read_from_block(&mut self, index: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let info = &self.blocks[index];
+        let pos_in_block = self.pos - info.uncompressed_offset;
+        let block_size = info.record.uncompressed_size;
+        let expected_record = info.record;
+
+        let SeekState::Block(_, block) = &mut self.state else {
+            unreachable!("The block is prepared before reading");
+        };
+
+        let mut skip_buf = [0u8; 4096];
+        while block.uncompressed_size() < pos_in_block {
+            let len = (pos_in_block - block.uncompressed_size()).min(skip_buf.len() as u64);
+            if block.read(&mut skip_buf[..len as usize])? == 0 {
+                return Err(XzError::IndexRecordMismatch.into());
+            }
+        }
+
+        let len = (buf.len() as u64).min(block_size - pos_in_block) as usize;
+        let read = block.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(XzError::IndexRecordMismatch.into());
+        }
+
+        if block.uncompressed_size() == block_size {
+            // The LZMA2 data must end here, then the block can be verified
+            if block.read(&mut [0u8])? != 0 {
+                return Err(XzError::IndexRecordMismatch.into());
+            }
+
+            let SeekState::Block(_, block) = std::mem::replace(&mut self.state, SeekState::Failed)
+            else {
+                unreachable!();
+            };
+            let (inner, record) = block.finish()?;
+            if record != expected_record {
+                return Err(XzError::IndexRecordMismatch.into());
+            }
+            self.state = SeekState::Idle(inner);
+        }
+
+        Ok(read)
+    }
+
+    /// Get the inner reader back. Its position is unspecified.
+    pub fn into_inner(self) -> Option<R> {
+        match self.state {
+            SeekState::Idle(inner) => Some(inner),
+            SeekState::Block(_, block) => Some(block.into_inner()),
+            SeekState::Failed => None,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for XzSeekReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        // Empty blocks are skipped over, as they end at or before the position
+        let index = self
+            .blocks
+            .partition_point(|block| block.uncompressed_end() <= self.pos);
+
+        let result = self
+            .prepare_block(index)
+            .and_then(|_| self.read_from_block(index, buf));
+        match result {
+            Ok(read) => {
+                self.pos += read as u64;
+                Ok(read)
+            }
+            Err(err) => {
+                self.state = SeekState::Failed;
+                let info = &self.blocks[index];
+                let err = offset_position(err, info.compressed_offset, info.uncompressed_offset);
+                Err(with_position(err, info.compressed_offset, self.pos))
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for XzSeekReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::xz_streams::XzWriter,
+        error::{Error, ErrorKind},
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::{Cursor, Write};
+
+    fn compress(data: &[u8], block_size: Option<u64>) -> Vec<u8> {
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc32).unwrap();
+        writer.set_block_size(block_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn read_at(reader: &mut XzSeekReader<Cursor<Vec<u8>>>, pos: u64, len: usize) -> Vec<u8> {
+        reader.seek(SeekFrom::Start(pos)).unwrap();
+        let mut output = Vec::new();
+        reader.take(len as u64).read_to_end(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_sequential_read() {
+        let data = test_data(6000);
+        for block_size in [None, Some(5000)] {
+            let mut reader = XzSeekReader::new(Cursor::new(compress(&data, block_size))).unwrap();
+            assert_eq!(reader.uncompressed_len(), data.len() as u64);
+
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_random_access() {
+        let data = test_data(6000);
+        let mut reader = XzSeekReader::new(Cursor::new(compress(&data, Some(5000)))).unwrap();
+        assert_eq!(reader.block_count(), data.len().div_ceil(5000));
+
+        for (pos, len) in [
+            (30_000, 100),
+            (0, 10),
+            (4990, 20),
+            (30_050, 100),
+            (12_345, 15_000),
+            (data.len() as u64 - 5, 100),
+        ] {
+            let start = pos as usize;
+            let end = (start + len).min(data.len());
+            assert_eq!(read_at(&mut reader, pos, len), &data[start..end]);
+        }
+
+        assert_eq!(
+            read_at(&mut reader, data.len() as u64 + 10, 10),
+            Vec::<u8>::new()
+        );
+
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, &data[data.len() - 5..]);
+
+        assert!(reader
+            .seek(SeekFrom::Current(-(data.len() as i64) - 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        let first = test_data(6000);
+        let second = b"second stream".to_vec();
+
+        let mut compressed = compress(&first, Some(5000));
+        compressed.extend_from_slice(&[0; 8]);
+        compressed.extend(compress(&[], None));
+        compressed.extend(compress(&second, None));
+        compressed.extend_from_slice(&[0; 4]);
+
+        let mut expected = first;
+        expected.extend(second);
+
+        let mut reader = XzSeekReader::new(Cursor::new(compressed)).unwrap();
+        assert_eq!(reader.uncompressed_len(), expected.len() as u64);
+        let pos = expected.len() as u64 - 20;
+        assert_eq!(read_at(&mut reader, pos, 20), &expected[pos as usize..]);
+        assert_eq!(read_at(&mut reader, 0, expected.len()), expected);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let xz_error = |file: Vec<u8>| {
+            let err = XzSeekReader::new(Cursor::new(file)).err().unwrap();
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<XzError>())
+                .copied()
+        };
+
+        let compressed = compress(&test_data(6000), Some(5000));
+
+        let mut file = compressed.clone();
+        file.push(0);
+        assert_eq!(xz_error(file), Some(XzError::InvalidStreamPadding));
+
+        let mut file = compressed.clone();
+        let len = file.len();
+        file[len - 1] = 0;
+        assert_eq!(xz_error(file), Some(XzError::InvalidFooterMagic));
+
+        let mut file = compressed.clone();
+        file[0] ^= 1;
+        assert_eq!(xz_error(file), Some(XzError::InvalidHeaderMagic));
+
+        // A corrupted check is only noticed when its block is read, the last one here
+        let mut file = compressed.clone();
+        let len = file.len();
+        let index_size = (u32::from_le_bytes(file[len - 8..len - 4].try_into().unwrap()) + 1) * 4;
+        file[len - STREAM_FOOTER_SIZE - index_size as usize - 1] ^= 1;
+        let mut reader = XzSeekReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(read_at(&mut reader, 0, 100), &test_data(6000)[..100]);
+        reader.seek(SeekFrom::End(-1)).unwrap();
+        let err = reader.read(&mut [0u8]).unwrap_err();
+        assert_eq!(Error::from(err).kind(), ErrorKind::ChecksumMismatch);
+    }
+}
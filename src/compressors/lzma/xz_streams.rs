@@ -0,0 +1,1117 @@
+//! # Streaming wrappers for the `.xz` format
+//!
+//! The reader handles everything the format allows: any of the supported checks, block headers with
+//! or without sizes, multiple blocks, and multiple concatenated streams with stream padding between
+//! them. Every block check is verified, as are the index and footer of every stream.
+//!
+//! The writer produces a single stream, compressed with LZMA2. The data can optionally be split into
+//! multiple blocks of a fixed uncompressed size.
+//!
+//! Besides LZMA2, the BCJ and delta filters are supported in the filter chain of a block.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use byteorder::ReadBytesExt;
+
+use super::{
+    codecs::{
+        header_codec::{LzmaHeaderProps, DICT_SIZE_MAX, DICT_SIZE_MIN},
+        lzma2_codec::are_props_valid,
+        xz_codec::{
+            block_header::{
+                bcj_start_offset_from_props, delta_distance_from_props, lzma2_dict_size_from_props,
+                BlockHeader, FilterFlags, FILTERS_MAX, LZMA2_FILTER_ID,
+            },
+            checks::{CheckType, Checker},
+            encode_stream_footer, encode_stream_header,
+            index::{encode_index, parse_index, IndexRecord, INDEX_INDICATOR},
+            padding_size, parse_stream_footer, parse_stream_header, XzError, HEADER_MAGIC,
+            STREAM_FOOTER_SIZE, STREAM_HEADER_SIZE,
+        },
+    },
+    limits::{get_lzma2_decoder_mem_usage, DecoderLimits},
+    lzma2_streams::{Lzma2Reader, Lzma2Writer},
+};
+use crate::{
+    compressors::filters::{
+        bcj::{BcjFilter, BcjKind},
+        chain::FilterChain,
+        delta::{self, DeltaFilter},
+        streams::{finish_filtered, write_filtered, FilterBuffer},
+        Filter,
+    },
+    error::{offset_position, with_position},
+    utils::counting_io::{CountingReader, CountingWriter},
+};
+
+/// Create the filters that come before LZMA2 in a block's filter chain, given in the order they're
+/// applied when encoding. The decoders are chained in reverse, so that they undo the encoders.
+pub(crate) fn create_filter_chain(
+    filters: &[FilterFlags],
+    encoder: bool,
+) -> Result<FilterChain, XzError> {
+    let mut chain = Vec::with_capacity(filters.len());
+    for filter in filters {
+        let filter: Box<dyn Filter + Send> = if filter.id == delta::XZ_FILTER_ID {
+            let distance = delta_distance_from_props(&filter.props)?;
+            // The distance from the properties is always valid
+            Box::new(if encoder {
+                DeltaFilter::new_encoder(distance).unwrap()
+            } else {
+                DeltaFilter::new_decoder(distance).unwrap()
+            })
+        } else if let Some(kind) = BcjKind::from_xz_filter_id(filter.id) {
+            let start_offset = bcj_start_offset_from_props(&filter.props)?;
+            Box::new(if encoder {
+                BcjFilter::new_encoder(kind, start_offset)
+            } else {
+                BcjFilter::new_decoder(kind, start_offset)
+            })
+        } else if filter.id == LZMA2_FILTER_ID {
+            return Err(XzError::InvalidFilterChain);
+        } else {
+            return Err(XzError::UnsupportedFilter(filter.id));
+        };
+        chain.push(filter);
+    }
+
+    if !encoder {
+        chain.reverse();
+    }
+    Ok(FilterChain::new(chain))
+}
+
+/// Check that the filters to put before LZMA2 when writing are supported and fit in a block header.
+pub(crate) fn validate_encoder_filters(filters: &[FilterFlags]) -> io::Result<()> {
+    if filters.len() >= FILTERS_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too many xz filters",
+        ));
+    }
+
+    create_filter_chain(filters, true)?;
+    Ok(())
+}
+
+/// Get the LZMA2 dictionary size from a block's filter chain, which must be LZMA2 with
+/// any supported filters before it.
+pub(crate) fn lzma2_dict_size(filters: &[FilterFlags]) -> Result<u32, XzError> {
+    let (last, rest) = filters.split_last().ok_or(XzError::InvalidFilterChain)?;
+
+    create_filter_chain(rest, false)?;
+
+    if last.id != LZMA2_FILTER_ID {
+        return Err(XzError::UnsupportedFilter(last.id));
+    }
+
+    lzma2_dict_size_from_props(&last.props)
+}
+
+/// The size of the dictionary buffer needed to decompress a block. If the header has the
+/// uncompressed size, the buffer doesn't need to be any bigger than the data.
+pub(crate) fn block_dict_buffer_size(header: &BlockHeader) -> Result<u32, XzError> {
+    let dict_size = lzma2_dict_size(&header.filters)?;
+    Ok(match header.uncompressed_size {
+        Some(size) => size.clamp(DICT_SIZE_MIN as u64, dict_size as u64) as u32,
+        None => dict_size,
+    })
+}
+
+/// Read and validate the index and footer of a stream, after the index indicator was read.
+/// `records` are the blocks that were read from the stream.
+pub(crate) fn read_stream_end(
+    mut inner: impl Read,
+    check: CheckType,
+    records: &[IndexRecord],
+) -> io::Result<()> {
+    let (index_records, index_size) = parse_index(&mut inner)?;
+    if index_records != records {
+        return Err(XzError::IndexRecordMismatch.into());
+    }
+
+    let mut footer = [0u8; STREAM_FOOTER_SIZE];
+    inner.read_exact(&mut footer)?;
+    parse_stream_footer(&footer)?.validate(check, index_size)?;
+    Ok(())
+}
+
+/// Skip the stream padding after a stream and parse the header of the next stream.
+/// Returns `None` at the end of the file.
+pub(crate) fn read_next_stream_header(mut inner: impl Read) -> io::Result<Option<CheckType>> {
+    loop {
+        let mut word = [0u8; 4];
+        let read = read_up_to(&mut inner, &mut word)?;
+        let word = &word[..read];
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if word.iter().all(|&b| b == 0) {
+            if read < 4 {
+                return Err(XzError::InvalidStreamPadding.into());
+            }
+            continue;
+        }
+
+        if word != &HEADER_MAGIC[..read] {
+            return Err(XzError::InvalidHeaderMagic.into());
+        }
+
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        header[..read].copy_from_slice(word);
+        inner.read_exact(&mut header[read..])?;
+        return Ok(Some(parse_stream_header(&header)?));
+    }
+}
+
+/// Read as many bytes as possible into `buf`, stopping early only at the end of the reader.
+pub(crate) fn read_up_to(mut reader: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Decompresses a single block, verifying its sizes and check once it ends.
+///
+/// The positions in its errors are relative to the start of the block, for the readers to move
+/// to where the block is in the file.
+pub(crate) struct BlockReader<R: Read> {
+    lzma2: Lzma2Reader<CountingReader<R>>,
+    /// The decoders for the filters before LZMA2, if there are any
+    filters: Option<(FilterChain, FilterBuffer)>,
+    header: BlockHeader,
+    header_size: usize,
+    check: CheckType,
+    checker: Checker,
+    uncompressed_size: u64,
+}
+
+impl<R: Read> BlockReader<R> {
+    /// Parse the block header and prepare to decompress the block, given the first byte of
+    /// the header which was already read to tell it apart from the index.
+    pub(crate) fn new(size_byte: u8, mut inner: R, check: CheckType) -> io::Result<Self> {
+        let (header, header_size) =
+            BlockHeader::parse(size_byte, &mut inner).map_err(|err| with_position(err, 0, 0))?;
+        Self::with_header(header, header_size, inner, check)
+    }
+
+    /// Prepare to decompress a block whose header was already parsed.
+    pub(crate) fn with_header(
+        header: BlockHeader,
+        header_size: usize,
+        inner: R,
+        check: CheckType,
+    ) -> io::Result<Self> {
+        let dict_size = block_dict_buffer_size(&header)
+            .and_then(|dict_size| {
+                create_filter_chain(&header.filters[..header.filters.len() - 1], false)
+                    .map(|filters| (dict_size, filters))
+            })
+            .map_err(|err| with_position(err.into(), 0, 0));
+        let (dict_size, filters) = dict_size?;
+
+        Ok(Self {
+            lzma2: Lzma2Reader::new(CountingReader::new(inner), dict_size),
+            filters: (!filters.is_empty()).then(|| (filters, FilterBuffer::new())),
+            header,
+            header_size,
+            check,
+            checker: Checker::new(check),
+            uncompressed_size: 0,
+        })
+    }
+
+    /// The number of bytes decompressed so far.
+    pub(crate) fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+
+    /// The number of bytes read from the block so far, including the header.
+    fn compressed_pos(&self) -> u64 {
+        self.header_size as u64 + self.lzma2.get_ref().count()
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        self.lzma2.get_ref().get_ref()
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        self.lzma2.get_mut().get_mut()
+    }
+
+    /// Get the inner reader back without finishing the block, leaving it somewhere inside the block.
+    pub(crate) fn into_inner(self) -> R {
+        self.lzma2.into_inner().into_inner()
+    }
+
+    /// Validate the sizes, padding and check once the LZMA2 data ended, i.e. once `read()`
+    /// returned 0. Returns the inner reader positioned after the block, and the block's index record.
+    pub(crate) fn finish(self) -> io::Result<(R, IndexRecord)> {
+        let end = self.compressed_pos();
+        let uncompressed_size = self.uncompressed_size;
+        self.finish_block()
+            .map_err(|err| with_position(err, end, uncompressed_size))
+    }
+
+    fn finish_block(self) -> io::Result<(R, IndexRecord)> {
+        let counting = self.lzma2.into_inner();
+        let compressed_size = counting.count();
+        let mut inner = counting.into_inner();
+
+        let header = &self.header;
+        if header
+            .compressed_size
+            .is_some_and(|size| size != compressed_size)
+            || header
+                .uncompressed_size
+                .is_some_and(|size| size != self.uncompressed_size)
+        {
+            return Err(XzError::BlockSizeMismatch.into());
+        }
+
+        for _ in 0..padding_size(compressed_size) {
+            if inner.read_u8()? != 0 {
+                return Err(XzError::InvalidBlockPadding.into());
+            }
+        }
+
+        let mut check = vec![0u8; self.check.size()];
+        inner.read_exact(&mut check)?;
+        if check != self.checker.finish() {
+            return Err(XzError::CheckMismatch.into());
+        }
+
+        let record = IndexRecord {
+            unpadded_size: self.header_size as u64 + compressed_size + check.len() as u64,
+            uncompressed_size: self.uncompressed_size,
+        };
+
+        Ok((inner, record))
+    }
+}
+
+impl<R: Read> BlockReader<R> {
+    fn read_block(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.filters {
+            Some((filters, buffer)) => buffer.read(filters, &mut self.lzma2, buf)?,
+            None => self.lzma2.read(buf)?,
+        };
+        self.checker.update(&buf[..read]);
+        self.uncompressed_size += read as u64;
+
+        if let Some(size) = self.header.uncompressed_size {
+            if self.uncompressed_size > size {
+                return Err(XzError::BlockSizeMismatch.into());
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_block(buf).map_err(|err| {
+            // The LZMA2 data starts after the block header
+            let err = offset_position(err, self.header_size as u64, 0);
+            with_position(err, self.compressed_pos(), self.uncompressed_size)
+        })
+    }
+}
+
+enum ReaderState<R: Read> {
+    /// The next thing in the stream is either a block header or the index
+    BetweenBlocks(R),
+    Block(Box<BlockReader<R>>),
+    Finished(R),
+    /// A previous read failed, so the position in the stream is unknown
+    Failed,
+}
+
+/// A reader that decompresses an `.xz` file from the inner reader.
+pub struct XzReader<R: Read> {
+    state: ReaderState<CountingReader<R>>,
+    check: CheckType,
+    /// The blocks read so far in the current stream, to validate the index against
+    records: Vec<IndexRecord>,
+    limits: DecoderLimits,
+    /// The number of bytes decompressed so far, from all the streams
+    output_size: u64,
+    /// Where the current block, or the index and the headers after it, start in the file
+    structure_start: u64,
+}
+
+impl<R: Read> XzReader<R> {
+    /// Parse the header of the first stream from the reader and prepare to decompress the data after it.
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::new_with_limits(inner, DecoderLimits::default())
+    }
+
+    /// Like `new()`, but fails instead of allocating more memory or decompressing more data
+    /// than the limits allow. The memory limit applies to each block separately.
+    pub fn new_with_limits(inner: R, limits: DecoderLimits) -> io::Result<Self> {
+        let mut inner = CountingReader::new(inner);
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        let check = inner
+            .read_exact(&mut header)
+            .and_then(|_| parse_stream_header(&header).map_err(io::Error::from))
+            .map_err(|err| with_position(err, 0, 0))?;
+
+        Ok(Self {
+            state: ReaderState::BetweenBlocks(inner),
+            check,
+            records: Vec::new(),
+            limits,
+            output_size: 0,
+            structure_start: 0,
+        })
+    }
+
+    /// The check type of the stream currently being read.
+    pub fn check_type(&self) -> CheckType {
+        self.check
+    }
+
+    fn start_block_or_index(
+        &mut self,
+        mut inner: CountingReader<R>,
+    ) -> io::Result<ReaderState<CountingReader<R>>> {
+        let size_byte = inner.read_u8()?;
+        if size_byte == INDEX_INDICATOR {
+            return self.finish_stream(inner);
+        }
+
+        let (header, header_size) = BlockHeader::parse(size_byte, &mut inner)?;
+        let dict_size = block_dict_buffer_size(&header)?;
+        self.limits
+            .check_mem_usage(get_lzma2_decoder_mem_usage(dict_size))?;
+
+        let block = BlockReader::with_header(header, header_size, inner, self.check)?;
+        Ok(ReaderState::Block(Box::new(block)))
+    }
+
+    fn finish_block(
+        &mut self,
+        block: BlockReader<CountingReader<R>>,
+    ) -> io::Result<CountingReader<R>> {
+        let (inner, record) = block.finish()?;
+        self.records.push(record);
+        Ok(inner)
+    }
+
+    /// Read and validate the index and footer, after the index indicator was read.
+    fn finish_stream(
+        &mut self,
+        mut inner: CountingReader<R>,
+    ) -> io::Result<ReaderState<CountingReader<R>>> {
+        read_stream_end(&mut inner, self.check, &self.records)?;
+        self.records.clear();
+
+        match read_next_stream_header(&mut inner)? {
+            Some(check) => {
+                self.check = check;
+                Ok(ReaderState::BetweenBlocks(inner))
+            }
+            None => Ok(ReaderState::Finished(inner)),
+        }
+    }
+
+    /// Move on to whatever comes after the current state, once there's no data left to read from it.
+    fn advance(
+        &mut self,
+        state: ReaderState<CountingReader<R>>,
+    ) -> io::Result<ReaderState<CountingReader<R>>> {
+        match state {
+            ReaderState::BetweenBlocks(inner) => {
+                self.structure_start = inner.count();
+                self.start_block_or_index(inner)
+                    .map_err(|err| with_position(err, self.structure_start, self.output_size))
+            }
+            ReaderState::Block(block) => {
+                let block_output_start = self.output_size - block.uncompressed_size();
+                let inner = self.finish_block(*block).map_err(|err| {
+                    offset_position(err, self.structure_start, block_output_start)
+                })?;
+                Ok(ReaderState::BetweenBlocks(inner))
+            }
+            ReaderState::Finished(inner) => Ok(ReaderState::Finished(inner)),
+            ReaderState::Failed => Err(io::Error::other("XzReader used after a failed read")),
+        }
+    }
+}
+
+impl<R: Read> Read for XzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match &mut self.state {
+                ReaderState::Block(block) => match block.read(buf) {
+                    Ok(0) => {}
+                    Ok(read) => {
+                        self.output_size += read as u64;
+                        if let Err(err) = self.limits.check_output_size(self.output_size) {
+                            let offset = block.get_ref().count();
+                            return Err(with_position(err.into(), offset, self.output_size));
+                        }
+                        return Ok(read);
+                    }
+                    Err(err) => {
+                        let block_output_start = self.output_size - block.uncompressed_size();
+                        let err = offset_position(err, self.structure_start, block_output_start);
+                        self.state = ReaderState::Failed;
+                        return Err(err);
+                    }
+                },
+                ReaderState::Finished(_) => return Ok(0),
+                _ => {}
+            }
+
+            let state = mem::replace(&mut self.state, ReaderState::Failed);
+            self.state = self.advance(state)?;
+        }
+    }
+}
+
+struct BlockWriter<W: Write> {
+    lzma2: Lzma2Writer<CountingWriter<W>>,
+    /// The encoders for the filters before LZMA2, and the bytes they're holding on to
+    filters: FilterChain,
+    pending: Vec<u8>,
+    header_size: usize,
+    size_limit: Option<u64>,
+    checker: Checker,
+    uncompressed_size: u64,
+}
+
+enum WriterState<W: Write> {
+    BetweenBlocks(W),
+    Block(Box<BlockWriter<W>>),
+}
+
+/// A writer that compresses data into an `.xz` file, writing it to the inner writer.
+///
+/// The stream must be completed by calling `finish()`. Dropping the writer will try to
+/// finish the stream, ignoring any errors.
+pub struct XzWriter<W: Write> {
+    state: Option<WriterState<W>>,
+    props: LzmaHeaderProps,
+    dict_size: u32,
+    check: CheckType,
+    block_size: Option<u64>,
+    filters: Vec<FilterFlags>,
+    records: Vec<IndexRecord>,
+}
+
+impl<W: Write> XzWriter<W> {
+    /// Write the stream header and prepare to compress data with LZMA2, which requires `lc + lp <= 4`.
+    pub fn new(
+        mut inner: W,
+        props: LzmaHeaderProps,
+        dict_size: u32,
+        check: CheckType,
+    ) -> io::Result<Self> {
+        if !are_props_valid(&props) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA2 properties",
+            ));
+        }
+
+        if !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&dict_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid LZMA dictionary size",
+            ));
+        }
+
+        inner.write_all(&encode_stream_header(check))?;
+
+        Ok(Self {
+            state: Some(WriterState::BetweenBlocks(inner)),
+            props,
+            dict_size,
+            check,
+            block_size: None,
+            filters: Vec::new(),
+            records: Vec::new(),
+        })
+    }
+
+    /// Split the data into blocks of `block_size` uncompressed bytes, or write everything into a single
+    /// block if `None`. Smaller blocks compress worse, but can be decompressed independently.
+    ///
+    /// This takes effect from the next block.
+    pub fn set_block_size(&mut self, block_size: Option<u64>) -> io::Result<()> {
+        if block_size == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The xz block size can't be zero",
+            ));
+        }
+
+        self.block_size = block_size;
+        Ok(())
+    }
+
+    /// Apply filters to the data before compressing it with LZMA2, e.g. a BCJ filter for executables
+    /// or a delta filter for audio.
+    /// The filters are applied in order, and at most 3 can be used.
+    ///
+    /// This takes effect from the next block.
+    pub fn set_filters(&mut self, filters: Vec<FilterFlags>) -> io::Result<()> {
+        validate_encoder_filters(&filters)?;
+        self.filters = filters;
+        Ok(())
+    }
+
+    fn start_block(&mut self, mut inner: W) -> io::Result<BlockWriter<W>> {
+        let header = BlockHeader {
+            compressed_size: None,
+            uncompressed_size: None,
+            filters: [&self.filters[..], &[FilterFlags::lzma2(self.dict_size)]].concat(),
+        }
+        .encode();
+        inner.write_all(&header)?;
+
+        Ok(BlockWriter {
+            lzma2: Lzma2Writer::new(
+                CountingWriter::new(inner),
+                self.props.clone(),
+                self.dict_size,
+            )?,
+            filters: create_filter_chain(&self.filters, true)?,
+            pending: Vec::new(),
+            header_size: header.len(),
+            size_limit: self.block_size,
+            checker: Checker::new(self.check),
+            uncompressed_size: 0,
+        })
+    }
+
+    fn finish_block(&mut self, mut block: BlockWriter<W>) -> io::Result<W> {
+        finish_filtered(&mut block.filters, &mut block.pending, &mut block.lzma2)?;
+        let counting = block.lzma2.finish()?;
+        let compressed_size = counting.count();
+        let mut inner = counting.into_inner();
+
+        inner.write_all(&[0u8; 3][..padding_size(compressed_size)])?;
+
+        let check = block.checker.finish();
+        inner.write_all(&check)?;
+
+        self.records.push(IndexRecord {
+            unpadded_size: block.header_size as u64 + compressed_size + check.len() as u64,
+            uncompressed_size: block.uncompressed_size,
+        });
+
+        Ok(inner)
+    }
+
+    fn finish_stream(&mut self) -> io::Result<W> {
+        let mut inner = match self.state.take().expect("XzWriter used after finishing") {
+            WriterState::BetweenBlocks(inner) => inner,
+            WriterState::Block(block) => self.finish_block(*block)?,
+        };
+
+        let index = encode_index(&self.records);
+        inner.write_all(&index)?;
+        inner.write_all(&encode_stream_footer(self.check, index.len() as u64))?;
+
+        Ok(inner)
+    }
+
+    /// Get the inner writer, e.g. to take the compressed data written to it so far.
+    pub fn get_mut(&mut self) -> &mut W {
+        match self.state.as_mut().expect("XzWriter used after finishing") {
+            WriterState::BetweenBlocks(inner) => inner,
+            WriterState::Block(block) => block.lzma2.get_mut().get_mut(),
+        }
+    }
+
+    /// Finish the compressed stream, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finish_stream()
+    }
+}
+
+impl<W: Write> Write for XzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut block = match self.state.take().expect("XzWriter used after finishing") {
+            WriterState::BetweenBlocks(inner) => Box::new(self.start_block(inner)?),
+            WriterState::Block(block) => block,
+        };
+
+        // Blocks are finished as soon as they reach their size, so there's always space left
+        let len = match block.size_limit {
+            Some(size) => (buf.len() as u64).min(size - block.uncompressed_size) as usize,
+            None => buf.len(),
+        };
+
+        let written = if block.filters.is_empty() {
+            block.lzma2.write(&buf[..len])?
+        } else {
+            write_filtered(
+                &mut block.filters,
+                &mut block.pending,
+                &mut block.lzma2,
+                &buf[..len],
+            )?;
+            len
+        };
+        block.checker.update(&buf[..written]);
+        block.uncompressed_size += written as u64;
+
+        self.state = if block.size_limit == Some(block.uncompressed_size) {
+            Some(WriterState::BetweenBlocks(self.finish_block(*block)?))
+        } else {
+            Some(WriterState::Block(block))
+        };
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.as_mut() {
+            Some(WriterState::BetweenBlocks(inner)) => inner.flush(),
+            Some(WriterState::Block(block)) => block.lzma2.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for XzWriter<W> {
+    fn drop(&mut self) {
+        if self.state.is_some() {
+            let _ = self.finish_stream();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compressors::lzma::codecs::xz_codec::crc32::crc32,
+        error::{Error, ErrorKind},
+        test_utils::{test_data, PROPS},
+    };
+    use std::io::Cursor;
+
+    const CHECKS: [CheckType; 4] = [
+        CheckType::None,
+        CheckType::Crc32,
+        CheckType::Crc64,
+        CheckType::Sha256,
+    ];
+
+    /// `printf hello | xz --check=sha256`, which stores both sizes in the block header.
+    const HELLO_XZ: [u8; 96] = [
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x0a, 0xe1, 0xfb, 0x0c, 0xa1, 0x04, 0xc0, 0x09,
+        0x05, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x79,
+        0x25, 0x67, 0x01, 0x00, 0x04, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00, 0x00, 0x2c,
+        0xf2, 0x4d, 0xba, 0x5f, 0xb0, 0xa3, 0x0e, 0x26, 0xe8, 0x3b, 0x2a, 0xc5, 0xb9, 0xe2, 0x9e,
+        0x1b, 0x16, 0x1e, 0x5c, 0x1f, 0xa7, 0x42, 0x5e, 0x73, 0x04, 0x33, 0x62, 0x93, 0x8b, 0x98,
+        0x24, 0x00, 0x01, 0x3d, 0x05, 0x1a, 0x09, 0x04, 0x3a, 0x18, 0x9b, 0x4b, 0x9a, 0x01, 0x00,
+        0x00, 0x00, 0x00, 0x0a, 0x59, 0x5a,
+    ];
+
+    /// `xz --riscv --lzma2 --check=crc32` of `riscv_code()`.
+    const RISCV_XZ: [u8; 116] = [
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x01, 0x69, 0x22, 0xde, 0x36, 0x04, 0xc1, 0x38,
+        0x80, 0x01, 0x0b, 0x00, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc8, 0xdc,
+        0x42, 0xd8, 0xe0, 0x00, 0x7f, 0x00, 0x30, 0x5d, 0x00, 0x0b, 0x9d, 0x3e, 0xb0, 0x17, 0x80,
+        0x44, 0x53, 0x56, 0x1b, 0x07, 0xc9, 0x7e, 0x6f, 0x06, 0xe1, 0xfd, 0xf9, 0x26, 0x82, 0x37,
+        0xa0, 0x21, 0x4f, 0x17, 0xda, 0x47, 0x17, 0x18, 0xae, 0x59, 0x95, 0x9f, 0x8e, 0x7d, 0xca,
+        0x3a, 0x76, 0x9f, 0x5b, 0xa1, 0x80, 0x33, 0x02, 0x34, 0xd5, 0x8b, 0x19, 0x00, 0xfa, 0xa5,
+        0xf6, 0x70, 0x00, 0x01, 0x50, 0x80, 0x01, 0x00, 0x00, 0x00, 0xdd, 0x0b, 0x81, 0xc6, 0x3e,
+        0x30, 0x0d, 0x8b, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x59, 0x5a,
+    ];
+
+    /// `xz --delta=dist=4 --lzma2 --check=crc64` of `stereo_ramp()`.
+    const DELTA_XZ: [u8; 92] = [
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x04, 0xe6, 0xd6, 0xb4, 0x46, 0x04, 0xc1, 0x1b,
+        0x80, 0x02, 0x03, 0x01, 0x03, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf5, 0x52,
+        0x5f, 0x69, 0xe0, 0x00, 0xff, 0x00, 0x13, 0x5d, 0x00, 0x00, 0x68, 0x89, 0x5e, 0x0a, 0x01,
+        0x30, 0x03, 0x22, 0xc9, 0x89, 0x6f, 0x13, 0xbe, 0xcd, 0x59, 0x68, 0x55, 0x00, 0x00, 0x00,
+        0x2b, 0xec, 0xea, 0x2b, 0x67, 0xdf, 0x5f, 0x8d, 0x00, 0x01, 0x37, 0x80, 0x02, 0x00, 0x00,
+        0x00, 0xd1, 0x9b, 0xc5, 0xc1, 0xb1, 0xc4, 0x67, 0xfb, 0x02, 0x00, 0x00, 0x00, 0x00, 0x04,
+        0x59, 0x5a,
+    ];
+
+    /// 16-bit stereo samples rising by 300 each, with both channels the same
+    fn stereo_ramp(samples: u16) -> Vec<u8> {
+        (0..samples)
+            .flat_map(|i| i.wrapping_mul(300).to_le_bytes().repeat(2))
+            .collect()
+    }
+
+    /// `auipc ra, 0; jalr ra, 16(ra); jal ra, ...; addi a0, a0, 0`, repeated
+    fn riscv_code() -> Vec<u8> {
+        [
+            0x97, 0x00, 0x00, 0x00, 0xe7, 0x80, 0x00, 0x01, 0xef, 0x00, 0x40, 0x00, 0x13, 0x05,
+            0x05, 0x00,
+        ]
+        .repeat(8)
+    }
+
+    /// Bytes that look like branch instructions for every architecture, mixed with noise
+    fn code_like_data() -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..50_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let opcodes = [0xE8, 0xEB, 0x48, 0x40, 0x94, 0x90, 0xEF, 0x97, 0xF0, 0xF8];
+                match (state >> 16) % 3 {
+                    0 => opcodes[(state >> 20) as usize % opcodes.len()],
+                    1 => 0x00,
+                    _ => (state >> 24) as u8,
+                }
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8], check: CheckType, block_size: Option<u64>) -> Vec<u8> {
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, check).unwrap();
+        writer.set_block_size(block_size).unwrap();
+        io::copy(&mut Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decompress(compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = XzReader::new(Cursor::new(compressed))?;
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    fn decompress_err(compressed: &[u8]) -> ErrorKind {
+        Error::from(decompress(compressed).unwrap_err()).kind()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for check in CHECKS {
+            for block_size in [None, Some(10_000)] {
+                for data in [test_data(6000), Vec::new()] {
+                    let compressed = compress(&data, check, block_size);
+                    assert_eq!(decompress(&compressed).unwrap(), data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_size() {
+        let data = test_data(6000);
+        let compressed = compress(&data, CheckType::Crc64, Some(10_000));
+
+        let index_size = u32::from_le_bytes(
+            compressed[compressed.len() - 8..compressed.len() - 4]
+                .try_into()
+                .unwrap(),
+        ) as usize
+            * 4
+            + 4;
+        let index_start = compressed.len() - STREAM_FOOTER_SIZE - index_size;
+        let (records, _) = parse_index(&compressed[index_start + 1..]).unwrap();
+
+        assert_eq!(records.len(), data.len().div_ceil(10_000));
+        assert!(records[..records.len() - 1]
+            .iter()
+            .all(|record| record.uncompressed_size == 10_000));
+    }
+
+    #[test]
+    fn test_read_reference() {
+        let data = test_data(6000);
+        let compressed = lzma::compress(&data, 6).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let mut reader = XzReader::new(Cursor::new(&HELLO_XZ)).unwrap();
+        assert_eq!(reader.check_type(), CheckType::Sha256);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello");
+    }
+
+    #[test]
+    fn test_write_readable_by_reference() {
+        for check in CHECKS {
+            for block_size in [None, Some(10_000)] {
+                for data in [test_data(6000), Vec::new()] {
+                    let compressed = compress(&data, check, block_size);
+                    assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bcj_filters() {
+        let data = code_like_data();
+        for kind in BcjKind::ALL {
+            let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc32).unwrap();
+            writer.set_block_size(Some(20_000)).unwrap();
+            writer
+                .set_filters(vec![FilterFlags::bcj(kind, 0x100)])
+                .unwrap();
+            io::copy(&mut Cursor::new(&data), &mut writer).unwrap();
+            let compressed = writer.finish().unwrap();
+
+            assert_eq!(decompress(&compressed).unwrap(), data);
+            // The system liblzma may be too old to know about RISC-V
+            if kind != BcjKind::RiscV {
+                assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+            }
+        }
+
+        assert_eq!(decompress(&RISCV_XZ).unwrap(), riscv_code());
+    }
+
+    #[test]
+    fn test_delta_filter() {
+        assert_eq!(decompress(&DELTA_XZ).unwrap(), stereo_ramp(64));
+
+        let data = stereo_ramp(50_000);
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc64).unwrap();
+        writer.set_filters(vec![FilterFlags::delta(4)]).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        assert!(compressed.len() < compress(&data, CheckType::Crc64, None).len() / 4);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+
+        // A delta filter followed by a BCJ filter
+        let data = code_like_data();
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc64).unwrap();
+        writer
+            .set_filters(vec![
+                FilterFlags::delta(1),
+                FilterFlags::bcj(BcjKind::X86, 0),
+            ])
+            .unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(lzma::decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_filter_chain_errors() {
+        let mut writer = XzWriter::new(Vec::new(), PROPS, 0x10000, CheckType::Crc32).unwrap();
+        let bcj = FilterFlags::bcj(BcjKind::X86, 0);
+        assert!(writer.set_filters(vec![bcj.clone(); 4]).is_err());
+        assert!(writer
+            .set_filters(vec![FilterFlags::lzma2(0x10000)])
+            .is_err());
+
+        // Two filters, applied in order
+        writer
+            .set_filters(vec![bcj, FilterFlags::bcj(BcjKind::Arm, 0)])
+            .unwrap();
+        writer.write_all(&code_like_data()).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), code_like_data());
+        assert_eq!(lzma::decompress(&compressed).unwrap(), code_like_data());
+
+        // BCJ properties that are neither empty nor a start offset
+        let mut file = RISCV_XZ;
+        file[18..23].copy_from_slice(&[0x01, 0x00, 0x21, 0x01, 0x16]);
+        fix_block_header_crc(&mut file);
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::InvalidFilterProps)
+        );
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        let first = test_data(6000);
+        let second = b"second stream".to_vec();
+
+        let mut compressed = compress(&first, CheckType::Crc32, None);
+        compressed.extend_from_slice(&[0; 8]);
+        compressed.extend(compress(&second, CheckType::Sha256, None));
+        compressed.extend_from_slice(&[0; 4]);
+
+        let mut expected = first;
+        expected.extend(second);
+        assert_eq!(decompress(&compressed).unwrap(), expected);
+
+        // Padding must be a multiple of 4 bytes
+        compressed.extend_from_slice(&[0; 3]);
+        assert_eq!(
+            decompress_err(&compressed),
+            ErrorKind::Xz(XzError::InvalidStreamPadding)
+        );
+
+        // Anything else after a stream is an error
+        let mut compressed = compress(&expected, CheckType::Crc32, None);
+        compressed.extend_from_slice(b"garbage");
+        assert_eq!(
+            decompress_err(&compressed),
+            ErrorKind::Xz(XzError::InvalidHeaderMagic)
+        );
+    }
+
+    /// Recompute the CRC32 of the first block header after modifying it, for files where the
+    /// header is 20 bytes long like in `HELLO_XZ` and `RISCV_XZ`.
+    fn fix_block_header_crc(file: &mut [u8]) {
+        let crc = crc32(&file[12..28]);
+        file[28..32].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_corruption_errors() {
+        let corrupt = |file: &[u8], pos: usize| {
+            let mut file = file.to_vec();
+            file[pos] ^= 0x01;
+            decompress_err(&file)
+        };
+
+        let compressed = compress(&test_data(6000), CheckType::Crc64, None);
+        let len = compressed.len();
+        let index_start = len - STREAM_FOOTER_SIZE - 12;
+        assert_eq!(compressed[index_start], INDEX_INDICATOR);
+
+        let errors = [
+            (0, ErrorKind::Xz(XzError::InvalidHeaderMagic)),
+            (8, ErrorKind::Xz(XzError::StreamHeaderCrcMismatch)),
+            (14, ErrorKind::Xz(XzError::BlockHeaderCrcMismatch)),
+            (index_start - 1, ErrorKind::ChecksumMismatch),
+            (index_start + 2, ErrorKind::Xz(XzError::IndexCrcMismatch)),
+            (len - 12, ErrorKind::Xz(XzError::StreamFooterCrcMismatch)),
+            (len - 1, ErrorKind::Xz(XzError::InvalidFooterMagic)),
+        ];
+        for (pos, error) in errors {
+            assert_eq!(corrupt(&compressed, pos), error, "offset {}", pos);
+        }
+
+        // Footers that are valid on their own, but don't match the stream
+        let mut file = compressed.clone();
+        file[len - 12..].copy_from_slice(&encode_stream_footer(CheckType::Crc64, 16));
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::BackwardSizeMismatch)
+        );
+        file[len - 12..].copy_from_slice(&encode_stream_footer(CheckType::Crc32, 12));
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::StreamFlagsMismatch)
+        );
+
+        // An index that's valid on its own, but doesn't match the blocks
+        let (mut records, _) = parse_index(&compressed[index_start + 1..]).unwrap();
+        records[0].uncompressed_size += 1;
+        let mut file = compressed.clone();
+        file[index_start..len - 12].copy_from_slice(&encode_index(&records));
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::IndexRecordMismatch)
+        );
+
+        // Block padding, after the 9 bytes of compressed data in `HELLO_XZ`
+        assert_eq!(
+            corrupt(&HELLO_XZ, 41),
+            ErrorKind::Xz(XzError::InvalidBlockPadding)
+        );
+
+        // Sizes in the block header that don't match the data
+        for pos in [14, 15] {
+            let mut file = HELLO_XZ;
+            file[pos] += 1;
+            fix_block_header_crc(&mut file);
+            assert_eq!(
+                decompress_err(&file),
+                ErrorKind::Xz(XzError::BlockSizeMismatch)
+            );
+        }
+
+        // Filter chains that don't end with LZMA2
+        let mut file = HELLO_XZ;
+        file[16] = 0x03;
+        fix_block_header_crc(&mut file);
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::UnsupportedFilter(0x03))
+        );
+
+        // Unsupported check type
+        let mut file = HELLO_XZ;
+        file[7] = 0x02;
+        let crc = crc32(&file[6..8]);
+        file[8..12].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            decompress_err(&file),
+            ErrorKind::Xz(XzError::UnsupportedCheck(0x02))
+        );
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let data = test_data(6000);
+        let compressed = compress(&data, CheckType::Crc64, None);
+        let index_start = compressed.len() - STREAM_FOOTER_SIZE - 12;
+
+        // LZMA2 errors are moved to where the block's data is in the file
+        let data_start = STREAM_HEADER_SIZE + (compressed[STREAM_HEADER_SIZE] as usize + 1) * 4;
+        let mut file = compressed.clone();
+        file[data_start] = 0x03;
+        let err = Error::from(decompress(&file).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::InvalidLzma2Chunk);
+        assert_eq!(err.compressed_offset(), Some(data_start as u64 + 1));
+        assert_eq!(err.uncompressed_pos(), Some(0));
+
+        // The check is verified after all the block's data was read
+        let mut file = compressed.clone();
+        file[index_start - 1] ^= 1;
+        let err = Error::from(decompress(&file).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        assert!(err.compressed_offset().unwrap() <= index_start as u64 - 8);
+        assert_eq!(err.uncompressed_pos(), Some(data.len() as u64));
+
+        // Index errors point at the start of the index
+        let mut file = compressed.clone();
+        file[index_start + 2] ^= 1;
+        let err = Error::from(decompress(&file).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::Xz(XzError::IndexCrcMismatch));
+        assert_eq!(err.compressed_offset(), Some(index_start as u64));
+    }
+
+    #[test]
+    fn test_truncated_streams() {
+        let compressed = compress(&test_data(6000), CheckType::Crc64, Some(10_000));
+        for len in [
+            0,
+            6,
+            12,
+            20,
+            100,
+            compressed.len() - 13,
+            compressed.len() - 1,
+        ] {
+            let err = decompress(&compressed[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "length {}", len);
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod bzip2;
+pub mod filters;
 pub mod lzma;
@@ -0,0 +1,330 @@
+//! # Structured errors for the decoders
+//!
+//! The readers and writers implement `Read` and `Write`, so their errors are `io::Error`s. When
+//! the problem is in the compressed data, the `io::Error` wraps an `Error` from this module, which
+//! says what went wrong and where. Convert with `Error::from(io_error)` to inspect it; errors from
+//! the inner reader or writer convert into `ErrorKind::Io`.
+//!
+//! The streaming readers fill in the compressed offset and the uncompressed position where the
+//! problem was detected. For errors in a container structure, like an xz index or an lzip trailer,
+//! the compressed offset is where that structure starts.
+
+use std::{fmt, io};
+
+use crate::compressors::lzma::codecs::{lzip_codec::LzipError, xz_codec::XzError};
+
+/// What went wrong while decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The lc, lp and pb properties are out of range.
+    InvalidProperties,
+    /// The dictionary size is out of range.
+    InvalidDictSize,
+    /// The range coded data is invalid, e.g. it doesn't start with a null byte.
+    CorruptRangeCoder,
+    /// A match refers to data before the start of the stream, or further back than the dictionary.
+    DistanceBeyondWindow,
+    /// The decompressed data doesn't match the uncompressed size stored for it.
+    SizeMismatch,
+    /// An LZMA2 chunk header is invalid, or a chunk doesn't match its sizes.
+    InvalidLzma2Chunk,
+    /// The checksum of the decompressed data doesn't match the one stored for it.
+    ChecksumMismatch,
+    /// Decoding needs more memory than the limit allows.
+    MemoryLimitExceeded { required: u64, limit: u64 },
+    /// The decompressed data is longer than the limit allows.
+    OutputLimitExceeded { limit: u64 },
+    /// The compressed data ended before the stream was complete.
+    UnexpectedEof,
+    /// The `.xz` container structure is invalid or unsupported.
+    Xz(XzError),
+    /// The `.lz` container structure is invalid or unsupported.
+    Lzip(LzipError),
+    /// The inner reader or writer failed.
+    Io(io::ErrorKind),
+}
+
+impl ErrorKind {
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            ErrorKind::MemoryLimitExceeded { .. } => io::ErrorKind::OutOfMemory,
+            ErrorKind::OutputLimitExceeded { .. } => io::ErrorKind::FileTooLarge,
+            ErrorKind::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            ErrorKind::Xz(err) => io::Error::from(*err).kind(),
+            ErrorKind::Lzip(err) => io::Error::from(*err).kind(),
+            ErrorKind::Io(kind) => *kind,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<XzError> for ErrorKind {
+    fn from(err: XzError) -> Self {
+        match err {
+            XzError::CheckMismatch => ErrorKind::ChecksumMismatch,
+            err => ErrorKind::Xz(err),
+        }
+    }
+}
+
+impl From<LzipError> for ErrorKind {
+    fn from(err: LzipError) -> Self {
+        match err {
+            LzipError::CrcMismatch => ErrorKind::ChecksumMismatch,
+            LzipError::InvalidDictSize => ErrorKind::InvalidDictSize,
+            err => ErrorKind::Lzip(err),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidProperties => write!(f, "Invalid LZMA properties"),
+            ErrorKind::InvalidDictSize => write!(f, "Invalid dictionary size"),
+            ErrorKind::CorruptRangeCoder => write!(f, "Corrupt range coder data"),
+            ErrorKind::DistanceBeyondWindow => {
+                write!(f, "LZMA match distance is beyond the start of the data")
+            }
+            ErrorKind::SizeMismatch => {
+                write!(
+                    f,
+                    "Decompressed data doesn't match the stored uncompressed size"
+                )
+            }
+            ErrorKind::InvalidLzma2Chunk => write!(f, "Invalid LZMA2 chunk"),
+            ErrorKind::ChecksumMismatch => write!(f, "Decompressed data checksum mismatch"),
+            ErrorKind::MemoryLimitExceeded { required, limit } => write!(
+                f,
+                "Decoding needs {} bytes of memory, but the limit is {} bytes",
+                required, limit
+            ),
+            ErrorKind::OutputLimitExceeded { limit } => write!(
+                f,
+                "Decompressed data is longer than the limit of {} bytes",
+                limit
+            ),
+            ErrorKind::UnexpectedEof => write!(f, "Compressed data ended unexpectedly"),
+            ErrorKind::Xz(err) => err.fmt(f),
+            ErrorKind::Lzip(err) => err.fmt(f),
+            ErrorKind::Io(kind) => kind.fmt(f),
+        }
+    }
+}
+
+/// An error while decoding, with the position in the stream where it was detected.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    /// A more specific message than the kind's, for kinds that cover several problems
+    message: Option<&'static str>,
+    compressed_offset: Option<u64>,
+    uncompressed_pos: Option<u64>,
+    /// The `io::Error` this was converted from, if any
+    source: Option<io::Error>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            message: None,
+            compressed_offset: None,
+            uncompressed_pos: None,
+            source: None,
+        }
+    }
+
+    pub(crate) fn with_message(kind: ErrorKind, message: &'static str) -> Self {
+        Self {
+            message: Some(message),
+            ..Self::new(kind)
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The number of compressed bytes read before the error was detected, counted from the
+    /// start of the stream or file given to the reader.
+    pub fn compressed_offset(&self) -> Option<u64> {
+        self.compressed_offset
+    }
+
+    /// The number of bytes decompressed before the error was detected.
+    pub fn uncompressed_pos(&self) -> Option<u64> {
+        self.uncompressed_pos
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.message, &self.source) {
+            (Some(message), _) => write!(f, "{}", message)?,
+            (None, Some(source)) => write!(f, "{}", source)?,
+            (None, None) => write!(f, "{}", self.kind)?,
+        }
+
+        if let Some(offset) = self.compressed_offset {
+            write!(f, " at compressed offset {}", offset)?;
+        }
+        if let Some(pos) = self.uncompressed_pos {
+            write!(f, " (uncompressed position {})", pos)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|err| err as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl From<XzError> for Error {
+    fn from(err: XzError) -> Self {
+        io::Error::from(err).into()
+    }
+}
+
+impl From<LzipError> for Error {
+    fn from(err: LzipError) -> Self {
+        io::Error::from(err).into()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+
+        let inner = err.get_ref();
+        let kind = if let Some(xz) = inner.and_then(|inner| inner.downcast_ref::<XzError>()) {
+            (*xz).into()
+        } else if let Some(lzip) = inner.and_then(|inner| inner.downcast_ref::<LzipError>()) {
+            (*lzip).into()
+        } else if err.kind() == io::ErrorKind::UnexpectedEof {
+            ErrorKind::UnexpectedEof
+        } else {
+            ErrorKind::Io(err.kind())
+        };
+
+        Self {
+            source: Some(err),
+            ..Self::new(kind)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.kind.io_kind(), err)
+    }
+}
+
+/// Record where an error in the compressed data was detected, unless it's already known.
+/// Errors from the inner reader are returned unchanged.
+pub(crate) fn with_position(
+    err: io::Error,
+    compressed_offset: u64,
+    uncompressed_pos: u64,
+) -> io::Error {
+    let mut err = Error::from(err);
+    if let (ErrorKind::Io(_), Some(source)) = (err.kind, err.source.take()) {
+        return source;
+    }
+
+    err.compressed_offset.get_or_insert(compressed_offset);
+    err.uncompressed_pos.get_or_insert(uncompressed_pos);
+    err.into()
+}
+
+/// Make the position of an error from a nested reader relative to the outer stream, by adding
+/// where the nested data starts in it.
+pub(crate) fn offset_position(
+    err: io::Error,
+    compressed_start: u64,
+    uncompressed_start: u64,
+) -> io::Error {
+    match err.downcast::<Error>() {
+        Ok(mut err) => {
+            if let Some(offset) = err.compressed_offset.as_mut() {
+                *offset += compressed_start;
+            }
+            if let Some(pos) = err.uncompressed_pos.as_mut() {
+                *pos += uncompressed_start;
+            }
+            err.into()
+        }
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_round_trip() {
+        let err: io::Error = Error::with_message(ErrorKind::InvalidLzma2Chunk, "Bad chunk").into();
+        let err = with_position(err, 12, 34);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Bad chunk at compressed offset 12 (uncompressed position 34)"
+        );
+
+        let err = Error::from(offset_position(err, 100, 1000));
+        assert_eq!(err.kind(), ErrorKind::InvalidLzma2Chunk);
+        assert_eq!(err.compressed_offset(), Some(112));
+        assert_eq!(err.uncompressed_pos(), Some(1034));
+
+        // The positions closest to the problem are kept
+        let err = Error::from(with_position(err.into(), 0, 0));
+        assert_eq!(err.compressed_offset(), Some(112));
+
+        let err: io::Error = Error::from(ErrorKind::MemoryLimitExceeded {
+            required: 2,
+            limit: 1,
+        })
+        .into();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let err = Error::from(io::Error::from(XzError::CheckMismatch));
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        assert_eq!(err.to_string(), "xz block check mismatch");
+
+        let err = Error::from(io::Error::from(XzError::InvalidIndex));
+        assert_eq!(err.kind(), ErrorKind::Xz(XzError::InvalidIndex));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+
+        let err = Error::from(io::Error::from(LzipError::UnsupportedVersion(2)));
+        assert_eq!(
+            err.kind(),
+            ErrorKind::Lzip(LzipError::UnsupportedVersion(2))
+        );
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Unsupported);
+
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert_eq!(Error::from(eof).kind(), ErrorKind::UnexpectedEof);
+
+        // Errors from the inner reader pass through the readers as they are
+        let err = with_position(io::Error::other("Disk on fire"), 1, 2);
+        assert_eq!(err.to_string(), "Disk on fire");
+        assert_eq!(Error::from(err).kind(), ErrorKind::Io(io::ErrorKind::Other));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod compressors;
+pub mod error;
+#[cfg(test)]
+pub(crate) mod test_utils;
 mod utils;
@@ -0,0 +1,19 @@
+//! Helpers shared by the tests of different modules.
+
+use crate::compressors::lzma::codecs::header_codec::LzmaHeaderProps;
+
+/// The default properties of `xz` and the LZMA SDK.
+pub(crate) const PROPS: LzmaHeaderProps = LzmaHeaderProps {
+    lc: 3,
+    lp: 0,
+    pb: 2,
+};
+
+/// `count` pairs of numbers that repeat with different periods, which compresses well but not trivially.
+pub(crate) fn test_data(count: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in 0..count {
+        data.extend_from_slice(format!("{} {} ", i % 97, i % 13).as_bytes());
+    }
+    data
+}